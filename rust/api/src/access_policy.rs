@@ -0,0 +1,488 @@
+//! Operator allow and deny lists for the invite endpoints.
+//!
+//! The policy file lists networks to refuse outright and networks exempt
+//! from the Tor block and the per-IP and per-network limits. Proof of work
+//! and the hourly ceilings apply to everyone. A deny entry wins over an
+//! allow entry covering the same address.
+//!
+//! Entries are CIDRs (a bare address is a single host) or, where the
+//! operator would rather not keep the address in the clear, the hex SHA-256
+//! of one. A hashed entry with a `prefix` matches the hash of the network
+//! string, for example `sha256("203.0.113.0/24")` with `prefix = 24`.
+//!
+//! ```toml
+//! [[deny]]
+//! net = "198.51.100.0/24"
+//! note = "spam wave"
+//! added = "2026-07-14T09:12:00Z"
+//!
+//! [[allow]]
+//! sha256 = "0cf75236cce089f9c592bb2b50925c48cbbb4d0f83094b2cd091dda4b53e1a4c"
+//! ```
+//!
+//! Like the notary keys, the file is reloaded on SIGHUP and when it changes
+//! on disk, and a file that does not parse leaves the previous policy in
+//! force. `ghostkey-api policy` edits it.
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// How often the file is checked for changes when no SIGHUP arrives.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// The testing exemption that was compiled in before the policy file
+/// existed. It still applies while there is no file, and a file created by
+/// `ghostkey-api policy` starts with it so nothing changes until the
+/// operator removes it.
+pub const BUILTIN_ALLOW_SHA256: &str =
+    "0cf75236cce089f9c592bb2b50925c48cbbb4d0f83094b2cd091dda4b53e1a4c";
+
+#[derive(Error, Debug)]
+pub enum PolicyError {
+    #[error("cannot read {path}: {source}")]
+    Read { path: PathBuf, source: io::Error },
+    #[error("cannot write {path}: {source}")]
+    Write { path: PathBuf, source: io::Error },
+    #[error("{path} is not a valid policy file: {reason}")]
+    Parse { path: PathBuf, reason: String },
+}
+
+/// What the policy says about one address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Allow,
+    Deny,
+    /// Not listed; the usual checks apply.
+    Unlisted,
+}
+
+/// One line of the policy file.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub net: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// For `sha256` entries: hash the address's network at this length
+    /// rather than the address itself.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prefix: Option<u8>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub added: Option<DateTime<Utc>>,
+}
+
+impl PolicyEntry {
+    /// An entry for `target`, an address or CIDR, kept in the clear or
+    /// hashed.
+    pub fn new(target: &str, hashed: bool, note: Option<String>) -> Result<Self, String> {
+        let net = parse_net(target)?;
+        let mut entry = PolicyEntry {
+            note,
+            added: Some(Utc::now()),
+            ..Default::default()
+        };
+        if hashed {
+            let single = net.prefix_len() == net.max_prefix_len();
+            entry.sha256 = Some(if single {
+                sha256_hex(&net.addr().to_string())
+            } else {
+                sha256_hex(&net.to_string())
+            });
+            entry.prefix = (!single).then_some(net.prefix_len());
+        } else {
+            entry.net = Some(net.to_string());
+        }
+        Ok(entry)
+    }
+
+    /// Whether this entry is the one `target` (an address, CIDR or hash)
+    /// names.
+    pub fn is(&self, target: &str) -> bool {
+        if self.sha256.as_deref() == Some(&target.to_ascii_lowercase()) {
+            return true;
+        }
+        let Ok(net) = parse_net(target) else {
+            return false;
+        };
+        let entry = |hashed| Self::new(&net.to_string(), hashed, None).ok();
+        match (&self.net, &self.sha256) {
+            (Some(own), _) => entry(false).and_then(|e| e.net).as_deref() == Some(own),
+            (_, Some(own)) => entry(true).and_then(|e| e.sha256).as_deref() == Some(own),
+            _ => false,
+        }
+    }
+
+    /// How `policy list` shows the entry.
+    pub fn describe(&self) -> String {
+        let mut line = match (&self.net, &self.sha256, self.prefix) {
+            (Some(net), _, _) => net.clone(),
+            (None, Some(hash), Some(prefix)) => format!("sha256:{hash} (/{prefix})"),
+            (None, Some(hash), None) => format!("sha256:{hash}"),
+            _ => "(empty entry)".to_string(),
+        };
+        if let Some(note) = &self.note {
+            line.push_str(&format!("  # {note}"));
+        }
+        if let Some(added) = &self.added {
+            line.push_str(&format!("  (added {})", added.format("%Y-%m-%d %H:%M UTC")));
+        }
+        line
+    }
+}
+
+/// The policy file as stored.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyFile {
+    #[serde(default)]
+    pub allow: Vec<PolicyEntry>,
+    #[serde(default)]
+    pub deny: Vec<PolicyEntry>,
+}
+
+impl PolicyFile {
+    /// A missing file reads as `None`.
+    pub fn read(path: &Path) -> Result<Option<Self>, PolicyError> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(source) => {
+                return Err(PolicyError::Read {
+                    path: path.to_path_buf(),
+                    source,
+                })
+            }
+        };
+        let file: Self = toml::from_str(&content).map_err(|e| PolicyError::Parse {
+            path: path.to_path_buf(),
+            reason: e.to_string(),
+        })?;
+        // Compile once here so a bad entry is reported by the editor and
+        // the loader alike.
+        Policy::compile(&file).map_err(|reason| PolicyError::Parse {
+            path: path.to_path_buf(),
+            reason,
+        })?;
+        Ok(Some(file))
+    }
+
+    /// The file at `path`, or a new one holding the built-in exemption.
+    pub fn read_or_new(path: &Path) -> Result<Self, PolicyError> {
+        Ok(Self::read(path)?.unwrap_or_else(Self::builtin))
+    }
+
+    fn builtin() -> Self {
+        PolicyFile {
+            allow: vec![PolicyEntry {
+                sha256: Some(BUILTIN_ALLOW_SHA256.to_string()),
+                note: Some("built-in testing exemption".to_string()),
+                ..Default::default()
+            }],
+            deny: Vec::new(),
+        }
+    }
+
+    /// Replace the file whole, through a temporary file and a rename.
+    pub fn write(&self, path: &Path) -> Result<(), PolicyError> {
+        let write_error = |source| PolicyError::Write {
+            path: path.to_path_buf(),
+            source,
+        };
+        let content = toml::to_string(self).map_err(|e| write_error(io::Error::other(e)))?;
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent).map_err(write_error)?;
+            }
+        }
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        let mut file = File::create(&temp).map_err(write_error)?;
+        file.write_all(content.as_bytes()).map_err(write_error)?;
+        file.sync_all().map_err(write_error)?;
+        fs::rename(&temp, path).map_err(write_error)
+    }
+
+    /// Remove every entry `target` names from both lists. Returns how many.
+    pub fn remove(&mut self, target: &str) -> usize {
+        let before = self.allow.len() + self.deny.len();
+        self.allow.retain(|entry| !entry.is(target));
+        self.deny.retain(|entry| !entry.is(target));
+        before - self.allow.len() - self.deny.len()
+    }
+}
+
+fn parse_net(s: &str) -> Result<IpNet, String> {
+    s.parse::<IpNet>()
+        .or_else(|_| s.parse::<IpAddr>().map(IpNet::from))
+        .map(|net| net.trunc())
+        .map_err(|_| format!("{s:?} is not an address or CIDR"))
+}
+
+fn sha256_hex(s: &str) -> String {
+    hex::encode(Sha256::digest(s.as_bytes()))
+}
+
+/// One list, ready for lookups.
+#[derive(Debug, Default)]
+struct Rules {
+    nets: Vec<IpNet>,
+    /// Hashes of addresses, and of networks by prefix length.
+    hashes: Vec<(Option<u8>, String)>,
+}
+
+impl Rules {
+    fn compile(entries: &[PolicyEntry]) -> Result<Self, String> {
+        let mut rules = Rules::default();
+        for entry in entries {
+            match (&entry.net, &entry.sha256) {
+                (Some(net), None) => rules.nets.push(parse_net(net)?),
+                (None, Some(hash)) => {
+                    if hash.len() != 64 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+                        return Err(format!("{hash:?} is not a hex SHA-256"));
+                    }
+                    rules.hashes.push((entry.prefix, hash.to_ascii_lowercase()));
+                }
+                _ => return Err("each entry needs exactly one of `net` and `sha256`".to_string()),
+            }
+        }
+        Ok(rules)
+    }
+
+    fn matches(&self, ip: IpAddr) -> bool {
+        if self.nets.iter().any(|net| net.contains(&ip)) {
+            return true;
+        }
+        self.hashes.iter().any(|(prefix, hash)| {
+            let hashed = match prefix {
+                None => sha256_hex(&ip.to_string()),
+                Some(prefix) => match IpNet::new(ip, *prefix) {
+                    Ok(net) => sha256_hex(&net.trunc().to_string()),
+                    Err(_) => return false,
+                },
+            };
+            &hashed == hash
+        })
+    }
+}
+
+/// A compiled policy.
+#[derive(Debug, Default)]
+pub struct Policy {
+    allow: Rules,
+    deny: Rules,
+}
+
+impl Policy {
+    fn compile(file: &PolicyFile) -> Result<Self, String> {
+        Ok(Self {
+            allow: Rules::compile(&file.allow).map_err(|e| format!("allow: {e}"))?,
+            deny: Rules::compile(&file.deny).map_err(|e| format!("deny: {e}"))?,
+        })
+    }
+
+    pub fn decide(&self, ip: IpAddr) -> Decision {
+        if self.deny.matches(ip) {
+            Decision::Deny
+        } else if self.allow.matches(ip) {
+            Decision::Allow
+        } else {
+            Decision::Unlisted
+        }
+    }
+}
+
+/// Fingerprint of the file for change polling: size and mtime, or `None`
+/// while it does not exist.
+type FileStamp = Option<(u64, Option<SystemTime>)>;
+
+fn stamp(path: &Path) -> FileStamp {
+    fs::metadata(path)
+        .ok()
+        .map(|metadata| (metadata.len(), metadata.modified().ok()))
+}
+
+/// The live policy, swapped whole on reload.
+pub struct PolicyStore {
+    path: Option<PathBuf>,
+    current: RwLock<Arc<Policy>>,
+    stamp: Mutex<FileStamp>,
+}
+
+impl PolicyStore {
+    /// Load the policy at `path`. A missing file is the built-in policy.
+    pub fn load(path: &Path) -> Result<Self, PolicyError> {
+        let stamp = stamp(path);
+        let policy = Self::read(path)?;
+        Ok(Self {
+            path: Some(path.to_path_buf()),
+            current: RwLock::new(Arc::new(policy)),
+            stamp: Mutex::new(stamp),
+        })
+    }
+
+    /// The built-in policy with no file behind it.
+    pub fn builtin() -> Self {
+        Self {
+            path: None,
+            current: RwLock::new(Arc::new(
+                Policy::compile(&PolicyFile::builtin()).expect("built-in policy compiles"),
+            )),
+            stamp: Mutex::new(None),
+        }
+    }
+
+    fn read(path: &Path) -> Result<Policy, PolicyError> {
+        let file = PolicyFile::read_or_new(path)?;
+        Policy::compile(&file).map_err(|reason| PolicyError::Parse {
+            path: path.to_path_buf(),
+            reason,
+        })
+    }
+
+    /// Re-read the file; on error the current policy stays in force.
+    pub fn reload(&self) -> Result<(), PolicyError> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let stamp = stamp(path);
+        let policy = Self::read(path)?;
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(policy);
+        *self.stamp.lock().unwrap_or_else(|e| e.into_inner()) = stamp;
+        Ok(())
+    }
+
+    fn reload_if_changed(&self) -> Option<Result<(), PolicyError>> {
+        let path = self.path.as_ref()?;
+        let current = stamp(path);
+        if *self.stamp.lock().unwrap_or_else(|e| e.into_inner()) == current {
+            return None;
+        }
+        Some(self.reload())
+    }
+
+    pub fn decide(&self, ip: IpAddr) -> Decision {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .decide(ip)
+    }
+}
+
+fn log_reload(trigger: &str, result: Result<(), PolicyError>) {
+    match result {
+        Ok(()) => info!("Reloaded access policy ({trigger})"),
+        Err(e) => error!("Access policy reload ({trigger}) failed, keeping the previous one: {e}"),
+    }
+}
+
+/// Reload `store` on SIGHUP and whenever its file changes.
+pub fn spawn_reloader(store: Arc<PolicyStore>) {
+    if store.path.is_none() {
+        return;
+    }
+    let polled = Arc::clone(&store);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Some(result) = polled.reload_if_changed() {
+                log_reload("file changed", result);
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                warn!("Cannot listen for SIGHUP; access policy reloads on file change only: {e}");
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            log_reload("SIGHUP", store.reload());
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn deny_wins_and_hashed_entries_match() {
+        let mut file = PolicyFile::default();
+        file.allow
+            .push(PolicyEntry::new("203.0.113.0/24", false, None).unwrap());
+        file.allow
+            .push(PolicyEntry::new("2001:db8::/32", true, None).unwrap());
+        file.deny
+            .push(PolicyEntry::new("203.0.113.66", true, None).unwrap());
+        let policy = Policy::compile(&file).unwrap();
+
+        assert_eq!(policy.decide(ip("203.0.113.5")), Decision::Allow);
+        assert_eq!(policy.decide(ip("203.0.113.66")), Decision::Deny);
+        assert_eq!(policy.decide(ip("2001:db8:5::1")), Decision::Allow);
+        assert_eq!(policy.decide(ip("2001:db9::1")), Decision::Unlisted);
+        assert_eq!(policy.decide(ip("198.51.100.1")), Decision::Unlisted);
+        // The hashed entries keep the address out of the file.
+        let written = toml::to_string(&file).unwrap();
+        assert!(!written.contains("203.0.113.66"));
+        assert!(!written.contains("2001:db8"));
+    }
+
+    #[test]
+    fn entries_are_found_by_what_they_were_added_as() {
+        let mut file = PolicyFile::default();
+        file.deny
+            .push(PolicyEntry::new("198.51.100.0/24", false, None).unwrap());
+        file.deny
+            .push(PolicyEntry::new("192.0.2.7", true, None).unwrap());
+        assert_eq!(file.remove("198.51.100.9/24"), 1, "same network");
+        assert_eq!(file.remove("192.0.2.8"), 0);
+        assert_eq!(file.remove("192.0.2.7"), 1, "hashed entries by address");
+        assert!(file.deny.is_empty());
+    }
+
+    #[test]
+    fn reload_swaps_in_a_good_file_and_keeps_the_old_policy_on_a_bad_one() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("access_policy.toml");
+        let store = PolicyStore::load(&path).unwrap();
+        assert_eq!(store.decide(ip("198.51.100.1")), Decision::Unlisted);
+
+        let mut file = PolicyFile::read_or_new(&path).unwrap();
+        assert_eq!(file.allow[0].sha256.as_deref(), Some(BUILTIN_ALLOW_SHA256));
+        file.deny
+            .push(PolicyEntry::new("198.51.100.0/24", false, None).unwrap());
+        file.write(&path).unwrap();
+        store.reload().unwrap();
+        assert_eq!(store.decide(ip("198.51.100.1")), Decision::Deny);
+
+        fs::write(&path, "[[deny]]\nnet = \"not-a-net\"\n").unwrap();
+        assert!(store.reload().is_err());
+        assert_eq!(store.decide(ip("198.51.100.1")), Decision::Deny);
+    }
+}
@@ -0,0 +1,1064 @@
+//! TLS certificates from an ACME (RFC 8555) certificate authority such as
+//! Let's Encrypt.
+//!
+//! With `[acme]` configured gkapi needs no certbot: it keeps an account key
+//! in the cache directory, orders a certificate for the configured domains,
+//! answers the CA's HTTP-01 challenges from memory on the challenge listener,
+//! and renews the certificate before it expires. A renewed certificate is
+//! swapped into the running TLS listener; connections already open finish
+//! on the old one.
+//!
+//! The certificate and its key are cached beside the account key, so a
+//! restart reuses them instead of ordering again. Keys are P-256 ECDSA.
+
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use axum_server::tls_rustls::RustlsConfig;
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine as _};
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use ring::rand::SystemRandom;
+use ring::signature::{
+    EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_ASN1_SIGNING, ECDSA_P256_SHA256_FIXED_SIGNING,
+};
+use rustls::pki_types::PrivateKeyDer;
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::x509;
+
+pub const LETS_ENCRYPT_DIRECTORY: &str = "https://acme-v02.api.letsencrypt.org/directory";
+
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// How often, and how many times, a pending authorization or order is
+/// polled: two minutes in all.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const POLL_ATTEMPTS: usize = 60;
+/// Attempts at a request the CA refused for a stale nonce.
+const NONCE_ATTEMPTS: usize = 3;
+/// The renewer looks again at least this often, so a certificate replaced
+/// in the cache or a jump in the clock is noticed.
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 3600);
+/// Waits between failed attempts, the last repeated. Let's Encrypt allows
+/// five failed validations an hour.
+const RETRY_SECS: &[u64] = &[60, 300, 900, 3600];
+
+#[derive(Error, Debug)]
+pub enum AcmeError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("{url} refused: {kind}: {detail}")]
+    Problem {
+        url: String,
+        kind: String,
+        detail: String,
+    },
+    #[error("ACME protocol error: {0}")]
+    Protocol(String),
+    #[error("key error: {0}")]
+    Key(String),
+}
+
+/// Where to order from, and what.
+#[derive(Debug, Clone, PartialEq)]
+pub struct AcmeSettings {
+    pub directory: String,
+    pub domains: Vec<String>,
+    /// `mailto:` URLs the CA may write to about the account.
+    pub contact: Vec<String>,
+    pub cache_dir: PathBuf,
+    /// Renew once the certificate has less than this left.
+    pub renew_before: chrono::Duration,
+    /// A root certificate to trust for the directory besides the usual
+    /// ones, for a test CA such as Pebble.
+    pub ca_file: Option<PathBuf>,
+}
+
+/// Key authorizations for pending HTTP-01 challenges, by token.
+#[derive(Default)]
+pub struct Challenges {
+    tokens: RwLock<HashMap<String, String>>,
+}
+
+impl Challenges {
+    pub fn get(&self, token: &str) -> Option<String> {
+        self.tokens
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(token)
+            .cloned()
+    }
+
+    fn insert(&self, token: &str, key_authorization: String) {
+        self.tokens
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(token.to_string(), key_authorization);
+    }
+
+    fn remove(&self, token: &str) {
+        self.tokens
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .remove(token);
+    }
+}
+
+/// A certificate chain and its key, both PEM.
+pub struct CertifiedPems {
+    pub chain: String,
+    pub key: String,
+    pub not_after: DateTime<Utc>,
+}
+
+impl CertifiedPems {
+    fn new(chain: String, key: String) -> Result<Self, AcmeError> {
+        let leaf = rustls_pemfile::certs(&mut chain.as_bytes())
+            .next()
+            .ok_or_else(|| AcmeError::Protocol("no certificate in the chain".to_string()))??;
+        let not_after = x509::not_after(&leaf).map_err(AcmeError::Protocol)?;
+        Ok(Self {
+            chain,
+            key,
+            not_after,
+        })
+    }
+
+    async fn tls_config(&self) -> Result<RustlsConfig, AcmeError> {
+        Ok(RustlsConfig::from_pem(
+            self.chain.clone().into_bytes(),
+            self.key.clone().into_bytes(),
+        )
+        .await?)
+    }
+}
+
+fn b64(bytes: &[u8]) -> String {
+    URL_SAFE_NO_PAD.encode(bytes)
+}
+
+fn read_pkcs8(path: &Path) -> Result<Vec<u8>, AcmeError> {
+    let pem = fs::read(path)?;
+    match rustls_pemfile::private_key(&mut pem.as_slice())? {
+        Some(PrivateKeyDer::Pkcs8(key)) => Ok(key.secret_pkcs8_der().to_vec()),
+        _ => Err(AcmeError::Key(format!(
+            "{} holds no PKCS#8 private key",
+            path.display()
+        ))),
+    }
+}
+
+/// Run `work`, which touches the cache directory, off the async runtime.
+async fn blocking<T: Send + 'static>(
+    work: impl FnOnce() -> Result<T, AcmeError> + Send + 'static,
+) -> Result<T, AcmeError> {
+    tokio::task::spawn_blocking(work)
+        .await
+        .map_err(|e| AcmeError::Io(std::io::Error::other(e)))?
+}
+
+/// Replace `path` through a rename. Only the owner can read it.
+fn write_private(path: &Path, content: &str) -> Result<(), AcmeError> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&temp)?;
+    file.write_all(content.as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp, path)?;
+    Ok(())
+}
+
+/// The account key and what the protocol derives from it.
+struct Account {
+    key: EcdsaKeyPair,
+    jwk: Value,
+    /// RFC 7638 thumbprint, the second half of every key authorization.
+    thumbprint: String,
+}
+
+impl Account {
+    fn load_or_create(path: &Path) -> Result<Self, AcmeError> {
+        let rng = SystemRandom::new();
+        let pkcs8 = if path.exists() {
+            read_pkcs8(path)?
+        } else {
+            let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng)
+                .map_err(|_| AcmeError::Key("could not generate an account key".to_string()))?;
+            write_private(path, &x509::pem("PRIVATE KEY", pkcs8.as_ref()))?;
+            info!("Created ACME account key {}", path.display());
+            pkcs8.as_ref().to_vec()
+        };
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &pkcs8, &rng)
+            .map_err(|e| AcmeError::Key(format!("{}: {e}", path.display())))?;
+        // An uncompressed point: 0x04, then x and y.
+        let point = key.public_key().as_ref();
+        let (x, y) = (b64(&point[1..33]), b64(&point[33..]));
+        // Members in lexicographic order with no whitespace, as RFC 7638
+        // hashes them.
+        let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":"{x}","y":"{y}"}}"#);
+        Ok(Self {
+            thumbprint: b64(&Sha256::digest(canonical.as_bytes())),
+            jwk: json!({ "crv": "P-256", "kty": "EC", "x": x, "y": y }),
+            key,
+        })
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Problem {
+    #[serde(rename = "type", default)]
+    kind: String,
+    #[serde(default)]
+    detail: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    #[serde(default)]
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+    error: Option<Problem>,
+}
+
+#[derive(Deserialize)]
+struct Identifier {
+    value: String,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    identifier: Identifier,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+    error: Option<Problem>,
+}
+
+/// One conversation with the CA: the nonce chain and, once registered, the
+/// account URL that signs every later request.
+struct Session<'a> {
+    http: &'a reqwest::Client,
+    directory: Directory,
+    account: &'a Account,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+fn problem(url: &str, problem: Problem) -> AcmeError {
+    AcmeError::Problem {
+        url: url.to_string(),
+        kind: problem.kind,
+        detail: problem.detail,
+    }
+}
+
+impl Session<'_> {
+    async fn nonce(&mut self) -> Result<String, AcmeError> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let response = self
+            .http
+            .head(&self.directory.new_nonce)
+            .send()
+            .await?
+            .error_for_status()?;
+        replay_nonce(&response)
+            .ok_or_else(|| AcmeError::Protocol("the CA sent no nonce".to_string()))
+    }
+
+    /// A JWS-signed POST; `None` is a POST-as-GET.
+    async fn post(
+        &mut self,
+        url: &str,
+        payload: Option<&Value>,
+    ) -> Result<reqwest::Response, AcmeError> {
+        let payload = payload
+            .map(|p| b64(p.to_string().as_bytes()))
+            .unwrap_or_default();
+        for attempt in 1..=NONCE_ATTEMPTS {
+            let mut protected = json!({ "alg": "ES256", "nonce": self.nonce().await?, "url": url });
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.account.jwk.clone(),
+            }
+            let protected = b64(protected.to_string().as_bytes());
+            let signature = self
+                .account
+                .key
+                .sign(
+                    &SystemRandom::new(),
+                    format!("{protected}.{payload}").as_bytes(),
+                )
+                .map_err(|_| AcmeError::Key("could not sign a request".to_string()))?;
+            let response = self
+                .http
+                .post(url)
+                .header("content-type", "application/jose+json")
+                .body(
+                    json!({
+                        "protected": protected,
+                        "payload": payload,
+                        "signature": b64(signature.as_ref()),
+                    })
+                    .to_string(),
+                )
+                .send()
+                .await?;
+            self.nonce = replay_nonce(&response);
+            if response.status().is_success() {
+                return Ok(response);
+            }
+            let refused: Problem = body(response).await?;
+            if refused.kind.ends_with(":badNonce") && attempt < NONCE_ATTEMPTS {
+                continue;
+            }
+            return Err(problem(url, refused));
+        }
+        unreachable!("the last attempt returns")
+    }
+
+    async fn register(&mut self, contact: &[String]) -> Result<(), AcmeError> {
+        let url = self.directory.new_account.clone();
+        let response = self
+            .post(
+                &url,
+                Some(&json!({ "termsOfServiceAgreed": true, "contact": contact })),
+            )
+            .await?;
+        self.kid = Some(location(&response)?);
+        Ok(())
+    }
+}
+
+fn replay_nonce(response: &reqwest::Response) -> Option<String> {
+    response
+        .headers()
+        .get("replay-nonce")?
+        .to_str()
+        .ok()
+        .map(str::to_string)
+}
+
+/// A JSON response body. (reqwest is built without its `json` feature.)
+async fn body<T: DeserializeOwned>(response: reqwest::Response) -> Result<T, AcmeError> {
+    let url = response.url().to_string();
+    let bytes = response.bytes().await?;
+    serde_json::from_slice(&bytes)
+        .map_err(|e| AcmeError::Protocol(format!("unexpected response from {url}: {e}")))
+}
+
+fn location(response: &reqwest::Response) -> Result<String, AcmeError> {
+    response
+        .headers()
+        .get("location")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+        .ok_or_else(|| AcmeError::Protocol(format!("no Location from {}", response.url())))
+}
+
+pub struct AcmeManager {
+    settings: AcmeSettings,
+    challenges: Arc<Challenges>,
+    http: reqwest::Client,
+}
+
+impl AcmeManager {
+    pub fn new(settings: AcmeSettings) -> Result<Self, AcmeError> {
+        let mut http = reqwest::Client::builder()
+            .timeout(REQUEST_TIMEOUT)
+            .user_agent(concat!("gkapi/", env!("CARGO_PKG_VERSION")));
+        if let Some(ca_file) = &settings.ca_file {
+            http = http.add_root_certificate(reqwest::Certificate::from_pem(&fs::read(ca_file)?)?);
+        }
+        Ok(Self {
+            http: http.build()?,
+            challenges: Arc::default(),
+            settings,
+        })
+    }
+
+    /// What the challenge listener answers from.
+    pub fn challenges(&self) -> Arc<Challenges> {
+        Arc::clone(&self.challenges)
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.settings.cache_dir.join(name)
+    }
+
+    fn renew_at(&self, not_after: DateTime<Utc>) -> DateTime<Utc> {
+        not_after - self.settings.renew_before
+    }
+
+    /// The cached certificate, if it is for the configured domains.
+    async fn cached(&self) -> Result<Option<CertifiedPems>, AcmeError> {
+        let [domains_file, chain_file, key_file] =
+            ["certificate.domains", "certificate.pem", "certificate.key"].map(|f| self.path(f));
+        let wanted = self.settings.domains.clone();
+        blocking(move || {
+            let domains = match fs::read_to_string(domains_file) {
+                Ok(domains) => domains,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(e) => return Err(e.into()),
+            };
+            if domains.lines().ne(wanted.iter().map(String::as_str)) {
+                info!("Cached certificate is for other domains; ordering a new one");
+                return Ok(None);
+            }
+            let chain = fs::read_to_string(chain_file)?;
+            let key = fs::read_to_string(key_file)?;
+            CertifiedPems::new(chain, key).map(Some)
+        })
+        .await
+    }
+
+    async fn store(&self, pems: &CertifiedPems) -> Result<(), AcmeError> {
+        let [domains_file, chain_file, key_file] =
+            ["certificate.domains", "certificate.pem", "certificate.key"].map(|f| self.path(f));
+        let (key, chain) = (pems.key.clone(), pems.chain.clone());
+        let mut domains = self.settings.domains.join("\n");
+        domains.push('\n');
+        blocking(move || {
+            write_private(&key_file, &key)?;
+            write_private(&chain_file, &chain)?;
+            write_private(&domains_file, &domains)
+        })
+        .await
+    }
+
+    /// Order a certificate for the configured domains and cache it.
+    pub async fn obtain(&self) -> Result<CertifiedPems, AcmeError> {
+        let dir = self.settings.cache_dir.clone();
+        let key_file = self.path("account.key");
+        let account = blocking(move || {
+            fs::create_dir_all(&dir)?;
+            Account::load_or_create(&key_file)
+        })
+        .await?;
+        let directory = self
+            .http
+            .get(&self.settings.directory)
+            .send()
+            .await?
+            .error_for_status()?;
+        let directory: Directory = body(directory).await?;
+        let mut session = Session {
+            http: &self.http,
+            directory,
+            account: &account,
+            kid: None,
+            nonce: None,
+        };
+        session.register(&self.settings.contact).await?;
+
+        let identifiers: Vec<Value> = self
+            .settings
+            .domains
+            .iter()
+            .map(|domain| json!({ "type": "dns", "value": domain }))
+            .collect();
+        let new_order = session.directory.new_order.clone();
+        let response = session
+            .post(&new_order, Some(&json!({ "identifiers": identifiers })))
+            .await?;
+        let order_url = location(&response)?;
+        let order: Order = body(response).await?;
+        for url in &order.authorizations {
+            self.authorize(&mut session, url).await?;
+        }
+
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng)
+            .map_err(|_| AcmeError::Key("could not generate a certificate key".to_string()))?;
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+            .map_err(|e| AcmeError::Key(e.to_string()))?;
+        let csr = x509::csr(&key, &self.settings.domains).map_err(AcmeError::Key)?;
+        session
+            .post(&order.finalize, Some(&json!({ "csr": b64(&csr) })))
+            .await?;
+
+        let mut attempts = 0;
+        let certificate = loop {
+            let order: Order = body(session.post(&order_url, None).await?).await?;
+            match order.status.as_str() {
+                "valid" => {
+                    break order.certificate.ok_or_else(|| {
+                        AcmeError::Protocol("a valid order has no certificate".to_string())
+                    })?
+                }
+                "invalid" => {
+                    return Err(order.error.map_or_else(
+                        || AcmeError::Protocol("the order became invalid".to_string()),
+                        |refused| problem(&order_url, refused),
+                    ))
+                }
+                _ if attempts == POLL_ATTEMPTS => {
+                    return Err(AcmeError::Protocol(format!(
+                        "the order is still {} after two minutes",
+                        order.status
+                    )))
+                }
+                _ => {
+                    attempts += 1;
+                    tokio::time::sleep(POLL_INTERVAL).await;
+                }
+            }
+        };
+        let chain = session.post(&certificate, None).await?.text().await?;
+        let pems = CertifiedPems::new(chain, x509::pem("PRIVATE KEY", pkcs8.as_ref()))?;
+        self.store(&pems).await?;
+        info!(
+            "Obtained a certificate for {} valid until {}",
+            self.settings.domains.join(", "),
+            pems.not_after.to_rfc3339()
+        );
+        Ok(pems)
+    }
+
+    /// Answer one authorization's HTTP-01 challenge and wait for the CA to
+    /// check it.
+    async fn authorize(&self, session: &mut Session<'_>, url: &str) -> Result<(), AcmeError> {
+        let authorization: Authorization = body(session.post(url, None).await?).await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let domain = authorization.identifier.value;
+        let challenge = authorization
+            .challenges
+            .into_iter()
+            .find(|challenge| challenge.kind == "http-01")
+            .ok_or_else(|| AcmeError::Protocol(format!("no HTTP-01 challenge for {domain}")))?;
+        self.challenges.insert(
+            &challenge.token,
+            format!("{}.{}", challenge.token, session.account.thumbprint),
+        );
+        let result = async {
+            session.post(&challenge.url, Some(&json!({}))).await?;
+            for _ in 0..POLL_ATTEMPTS {
+                tokio::time::sleep(POLL_INTERVAL).await;
+                let authorization: Authorization = body(session.post(url, None).await?).await?;
+                match authorization.status.as_str() {
+                    "valid" => return Ok(()),
+                    "pending" => {}
+                    status => {
+                        let refused = authorization
+                            .challenges
+                            .into_iter()
+                            .find(|c| c.token == challenge.token)
+                            .and_then(|c| c.error);
+                        return Err(refused.map_or_else(
+                            || {
+                                AcmeError::Protocol(format!(
+                                    "authorization for {domain} is {status}"
+                                ))
+                            },
+                            |refused| problem(&challenge.url, refused),
+                        ));
+                    }
+                }
+            }
+            Err(AcmeError::Protocol(format!(
+                "{domain} was not validated within two minutes"
+            )))
+        }
+        .await;
+        self.challenges.remove(&challenge.token);
+        result
+    }
+
+    /// The TLS configuration to start with: the cached certificate while it
+    /// is not due for renewal, otherwise a new one. Retries until it has one.
+    pub async fn initial_config(&self) -> (RustlsConfig, DateTime<Utc>) {
+        match self.cached().await {
+            Ok(Some(pems)) if Utc::now() < self.renew_at(pems.not_after) => {
+                match pems.tls_config().await {
+                    Ok(config) => {
+                        info!(
+                            "Using the cached certificate, valid until {}",
+                            pems.not_after.to_rfc3339()
+                        );
+                        return (config, pems.not_after);
+                    }
+                    Err(e) => warn!("Cached certificate does not load: {e}; ordering a new one"),
+                }
+            }
+            Ok(Some(_)) => info!("Cached certificate is due for renewal"),
+            Ok(None) => {}
+            Err(e) => warn!("Cached certificate does not read: {e}; ordering a new one"),
+        }
+        let mut attempt = 0;
+        loop {
+            match self.obtain().await {
+                Ok(pems) => match pems.tls_config().await {
+                    Ok(config) => return (config, pems.not_after),
+                    Err(e) => error!("The new certificate does not load: {e}"),
+                },
+                Err(e) => error!("Could not obtain a certificate: {e}"),
+            }
+            let wait = RETRY_SECS[attempt.min(RETRY_SECS.len() - 1)];
+            attempt += 1;
+            warn!("Serving no HTTPS until a certificate is obtained; retrying in {wait}s");
+            tokio::time::sleep(Duration::from_secs(wait)).await;
+        }
+    }
+}
+
+/// Renew the certificate in `tls`, which expires at `not_after`, whenever
+/// it falls due.
+pub fn spawn_renewer(manager: Arc<AcmeManager>, tls: RustlsConfig, mut not_after: DateTime<Utc>) {
+    tokio::spawn(async move {
+        let mut failures = 0;
+        loop {
+            let renew_at = manager.renew_at(not_after);
+            let wait = (renew_at - Utc::now())
+                .to_std()
+                .unwrap_or(Duration::ZERO)
+                .min(CHECK_INTERVAL);
+            tokio::time::sleep(wait).await;
+            if Utc::now() < renew_at {
+                continue;
+            }
+            let renewed = match manager.obtain().await {
+                Ok(pems) => tls
+                    .reload_from_pem(pems.chain.into_bytes(), pems.key.into_bytes())
+                    .await
+                    .map(|()| pems.not_after)
+                    .map_err(AcmeError::from),
+                Err(e) => Err(e),
+            };
+            match renewed {
+                Ok(renewed) => {
+                    info!("Renewed certificate is now served");
+                    not_after = renewed;
+                    failures = 0;
+                    if manager.renew_at(not_after) <= Utc::now() {
+                        // Issued for less than renew_days_before; renewing
+                        // again at once would only spend the CA's rate limit.
+                        warn!("The CA issues certificates shorter than renew_days_before");
+                        tokio::time::sleep(CHECK_INTERVAL).await;
+                    }
+                }
+                Err(e) => {
+                    let wait = RETRY_SECS[failures.min(RETRY_SECS.len() - 1)];
+                    failures += 1;
+                    error!(
+                        "Certificate renewal failed: {e}; the current one expires {}, retrying in {wait}s",
+                        not_after.to_rfc3339()
+                    );
+                    tokio::time::sleep(Duration::from_secs(wait)).await;
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Mutex;
+
+    use axum::{
+        extract::{Path as UrlPath, State},
+        http::StatusCode,
+        response::{IntoResponse, Response},
+        routing::{get, post},
+        Json, Router,
+    };
+    use ring::signature::{UnparsedPublicKey, ECDSA_P256_SHA256_FIXED};
+
+    use super::*;
+    use crate::x509::tests::{issue, p256_key};
+
+    const TOKEN: &str = "evaGxfADs6pSRb2LAv9IZf17Dt3juxGJ-PCt92wr-oA";
+
+    /// A CA that holds the client to the protocol: every request signed by
+    /// the account key over a fresh nonce and its own URL. It validates the
+    /// challenge by looking in [`Challenges`] rather than over HTTP.
+    struct FakeCa {
+        base: String,
+        key: EcdsaKeyPair,
+        challenges: Arc<Challenges>,
+        state: Mutex<CaState>,
+    }
+
+    #[derive(Default)]
+    struct CaState {
+        nonces: HashSet<String>,
+        issued_nonces: u64,
+        refused_a_nonce: bool,
+        account: Option<(Vec<u8>, String)>,
+        orders: usize,
+        validated: bool,
+        certificate: Option<String>,
+    }
+
+    impl FakeCa {
+        fn nonce(&self, state: &mut CaState) -> String {
+            state.issued_nonces += 1;
+            let nonce = format!("nonce-{}", state.issued_nonces);
+            state.nonces.insert(nonce.clone());
+            nonce
+        }
+
+        fn reply(&self, state: &mut CaState, status: StatusCode, body: Value) -> Response {
+            let nonce = self.nonce(state);
+            (status, [("replay-nonce", nonce)], Json(body)).into_response()
+        }
+
+        fn order(&self, state: &CaState) -> Value {
+            let (status, certificate) = match (&state.certificate, state.validated) {
+                (Some(_), _) => ("valid", Some(format!("{}/cert", self.base))),
+                (None, true) => ("ready", None),
+                (None, false) => ("pending", None),
+            };
+            json!({
+                "status": status,
+                "authorizations": [format!("{}/authz", self.base)],
+                "finalize": format!("{}/finalize", self.base),
+                "certificate": certificate,
+            })
+        }
+    }
+
+    fn decode(part: &Value) -> Vec<u8> {
+        URL_SAFE_NO_PAD.decode(part.as_str().unwrap()).unwrap()
+    }
+
+    async fn directory(State(ca): State<Arc<FakeCa>>) -> Json<Value> {
+        Json(json!({
+            "newNonce": format!("{}/nonce", ca.base),
+            "newAccount": format!("{}/account", ca.base),
+            "newOrder": format!("{}/new-order", ca.base),
+        }))
+    }
+
+    async fn new_nonce(State(ca): State<Arc<FakeCa>>) -> Response {
+        let mut state = ca.state.lock().unwrap();
+        ([("replay-nonce", ca.nonce(&mut state))], "").into_response()
+    }
+
+    async fn acme_post(
+        State(ca): State<Arc<FakeCa>>,
+        UrlPath(resource): UrlPath<String>,
+        Json(jws): Json<Value>,
+    ) -> Response {
+        let mut state = ca.state.lock().unwrap();
+        let protected: Value = serde_json::from_slice(&decode(&jws["protected"])).unwrap();
+        assert_eq!(protected["alg"], "ES256");
+        assert_eq!(protected["url"], format!("{}/{resource}", ca.base));
+        let nonce = protected["nonce"].as_str().unwrap();
+        assert!(
+            state.nonces.remove(nonce),
+            "nonce {nonce} reused or invented"
+        );
+        if !state.refused_a_nonce {
+            state.refused_a_nonce = true;
+            let body = json!({ "type": "urn:ietf:params:acme:error:badNonce", "detail": "stale" });
+            return ca.reply(&mut state, StatusCode::BAD_REQUEST, body);
+        }
+
+        let public_key = if resource == "account" {
+            let jwk = &protected["jwk"];
+            let point = [&[4][..], &decode(&jwk["x"]), &decode(&jwk["y"])].concat();
+            let canonical = format!(
+                r#"{{"crv":"P-256","kty":"EC","x":{},"y":{}}}"#,
+                jwk["x"], jwk["y"]
+            );
+            let thumbprint = b64(&Sha256::digest(canonical.as_bytes()));
+            state.account = Some((point.clone(), thumbprint));
+            point
+        } else {
+            assert_eq!(protected["kid"], format!("{}/account/1", ca.base));
+            assert!(protected.get("jwk").is_none());
+            state.account.clone().expect("registered first").0
+        };
+        let signed = format!(
+            "{}.{}",
+            jws["protected"].as_str().unwrap(),
+            jws["payload"].as_str().unwrap()
+        );
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_FIXED, &public_key)
+            .verify(signed.as_bytes(), &decode(&jws["signature"]))
+            .expect("signed by the account key");
+        let payload: Option<Value> = match jws["payload"].as_str().unwrap() {
+            "" => None,
+            _ => Some(serde_json::from_slice(&decode(&jws["payload"])).unwrap()),
+        };
+
+        match resource.as_str() {
+            "account" => {
+                assert_eq!(payload.unwrap()["termsOfServiceAgreed"], true);
+                let nonce = ca.nonce(&mut state);
+                (
+                    StatusCode::CREATED,
+                    [
+                        ("replay-nonce", nonce),
+                        ("location", format!("{}/account/1", ca.base)),
+                    ],
+                    Json(json!({ "status": "valid" })),
+                )
+                    .into_response()
+            }
+            "new-order" => {
+                assert_eq!(
+                    payload.unwrap()["identifiers"],
+                    json!([{ "type": "dns", "value": "gkapi.test" }])
+                );
+                state.orders += 1;
+                let nonce = ca.nonce(&mut state);
+                (
+                    StatusCode::CREATED,
+                    [
+                        ("replay-nonce", nonce),
+                        ("location", format!("{}/order", ca.base)),
+                    ],
+                    Json(ca.order(&state)),
+                )
+                    .into_response()
+            }
+            "authz" => {
+                let status = if state.validated { "valid" } else { "pending" };
+                let body = json!({
+                    "status": status,
+                    "identifier": { "type": "dns", "value": "gkapi.test" },
+                    "challenges": [
+                        { "type": "dns-01", "url": format!("{}/dns", ca.base), "token": "other" },
+                        { "type": "http-01", "url": format!("{}/challenge", ca.base), "token": TOKEN },
+                    ],
+                });
+                ca.reply(&mut state, StatusCode::OK, body)
+            }
+            "challenge" => {
+                let thumbprint = &state.account.as_ref().unwrap().1;
+                assert_eq!(
+                    ca.challenges.get(TOKEN),
+                    Some(format!("{TOKEN}.{thumbprint}"))
+                );
+                state.validated = true;
+                ca.reply(
+                    &mut state,
+                    StatusCode::OK,
+                    json!({ "status": "processing" }),
+                )
+            }
+            "finalize" => {
+                assert!(state.validated);
+                let csr = decode(&payload.unwrap()["csr"]);
+                let not_after = Utc::now() + chrono::Duration::days(90);
+                let not_after = not_after
+                    - chrono::Duration::nanoseconds(not_after.timestamp_subsec_nanos().into());
+                state.certificate =
+                    Some(x509::pem("CERTIFICATE", &issue(&csr, &ca.key, not_after)));
+                let body = ca.order(&state);
+                ca.reply(&mut state, StatusCode::OK, body)
+            }
+            "order" => {
+                let body = ca.order(&state);
+                ca.reply(&mut state, StatusCode::OK, body)
+            }
+            "cert" => {
+                let nonce = ca.nonce(&mut state);
+                (
+                    [("replay-nonce", nonce)],
+                    state.certificate.clone().unwrap(),
+                )
+                    .into_response()
+            }
+            other => panic!("unexpected POST to {other}"),
+        }
+    }
+
+    async fn fake_ca(challenges: Arc<Challenges>) -> Arc<FakeCa> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let ca = Arc::new(FakeCa {
+            base: format!("http://{}", listener.local_addr().unwrap()),
+            key: p256_key().0,
+            challenges,
+            state: Mutex::default(),
+        });
+        let app = Router::new()
+            .route("/directory", get(directory))
+            .route("/nonce", get(new_nonce))
+            .route("/:resource", post(acme_post))
+            .with_state(Arc::clone(&ca));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        ca
+    }
+
+    fn settings(cache: &Path) -> AcmeSettings {
+        AcmeSettings {
+            directory: String::new(),
+            domains: vec!["gkapi.test".to_string()],
+            contact: vec!["mailto:ops@gkapi.test".to_string()],
+            cache_dir: cache.join("acme"),
+            renew_before: chrono::Duration::days(30),
+            ca_file: None,
+        }
+    }
+
+    /// A manager ordering from a fresh [`FakeCa`].
+    async fn with_fake_ca(cache: &Path) -> (AcmeManager, Arc<FakeCa>) {
+        let mut manager = AcmeManager::new(settings(cache)).unwrap();
+        let ca = fake_ca(manager.challenges()).await;
+        manager.settings.directory = format!("{}/directory", ca.base);
+        (manager, ca)
+    }
+
+    fn leaf(chain: &str) -> Vec<u8> {
+        rustls_pemfile::certs(&mut chain.as_bytes())
+            .next()
+            .unwrap()
+            .unwrap()
+            .to_vec()
+    }
+
+    /// The certificate a TLS listener presents, over a new connection.
+    async fn served_certificate(addr: std::net::SocketAddr) -> Vec<u8> {
+        let client = reqwest::Client::builder()
+            .danger_accept_invalid_certs(true)
+            .tls_info(true)
+            .build()
+            .unwrap();
+        let response = client.get(format!("https://{addr}/")).send().await.unwrap();
+        response
+            .extensions()
+            .get::<reqwest::tls::TlsInfo>()
+            .and_then(|info| info.peer_certificate())
+            .expect("a peer certificate")
+            .to_vec()
+    }
+
+    #[tokio::test]
+    async fn a_certificate_is_ordered_cached_and_reused() {
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let cache = tempfile::tempdir().unwrap();
+        let (mut manager, ca) = with_fake_ca(cache.path()).await;
+        let mut settings = manager.settings.clone();
+
+        let pems = manager.obtain().await.unwrap();
+        let days_left = (pems.not_after - Utc::now()).num_days();
+        assert!((89..=90).contains(&days_left), "{days_left}");
+        assert!(manager.challenges.get(TOKEN).is_none());
+        pems.tls_config().await.unwrap();
+        for file in ["account.key", "certificate.key", "certificate.pem"] {
+            let mode = fs::metadata(manager.path(file))
+                .unwrap()
+                .permissions()
+                .mode();
+            assert_eq!(mode & 0o777, 0o600, "{file}");
+        }
+
+        // A restart serves the cached certificate without a second order.
+        let (_, not_after) = manager.initial_config().await;
+        assert_eq!(not_after, pems.not_after);
+        assert_eq!(ca.state.lock().unwrap().orders, 1);
+
+        settings.domains.push("api.gkapi.test".to_string());
+        manager.settings = settings;
+        assert!(manager.cached().await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn a_renewed_certificate_is_swapped_into_the_listener() {
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let cache = tempfile::tempdir().unwrap();
+        let (manager, ca) = with_fake_ca(cache.path()).await;
+        let first = manager.obtain().await.unwrap();
+        let tls = first.tls_config().await.unwrap();
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/", get(|| async { "ok" }));
+        let server = axum_server::from_tcp_rustls(listener, tls.clone());
+        tokio::spawn(server.serve(app.into_make_service()));
+        assert_eq!(served_certificate(addr).await, leaf(&first.chain));
+
+        // Claimed to expire now, so it is due at once.
+        let manager = Arc::new(manager);
+        spawn_renewer(Arc::clone(&manager), tls, Utc::now());
+        let deadline = tokio::time::Instant::now() + Duration::from_secs(10);
+        let served = loop {
+            let served = served_certificate(addr).await;
+            if served != leaf(&first.chain) {
+                break served;
+            }
+            assert!(
+                tokio::time::Instant::now() < deadline,
+                "the renewed certificate was never served"
+            );
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        };
+        let renewed = manager.cached().await.unwrap().unwrap();
+        assert_eq!(served, leaf(&renewed.chain));
+        assert_eq!(ca.state.lock().unwrap().orders, 2);
+    }
+
+    /// Live check against [Pebble](https://github.com/letsencrypt/pebble).
+    ///
+    /// `#[ignore]`d since it needs Pebble running with its default config,
+    /// which serves the directory on :14000 and validates HTTP-01 on :5002:
+    ///   `PEBBLE_DIR=~/pebble cargo test --bins -- --ignored pebble --nocapture`
+    #[tokio::test]
+    #[ignore]
+    async fn a_certificate_is_obtained_from_pebble() {
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let pebble = PathBuf::from(std::env::var("PEBBLE_DIR").expect("PEBBLE_DIR is set"));
+        let cache = tempfile::tempdir().unwrap();
+        let mut settings = settings(cache.path());
+        settings.directory = "https://localhost:14000/dir".to_string();
+        settings.domains = vec!["localhost".to_string()];
+        settings.ca_file = Some(pebble.join("test/certs/pebble.minica.pem"));
+        let manager = AcmeManager::new(settings).unwrap();
+
+        let challenges = manager.challenges();
+        let app = Router::new().route(
+            "/.well-known/acme-challenge/:token",
+            get(move |UrlPath(token): UrlPath<String>| async move {
+                challenges.get(&token).ok_or(StatusCode::NOT_FOUND)
+            }),
+        );
+        let listener = tokio::net::TcpListener::bind("0.0.0.0:5002").await.unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let pems = manager.obtain().await.unwrap();
+        println!("Pebble issued a certificate valid until {}", pems.not_after);
+        assert!(pems.not_after > Utc::now());
+        pems.tls_config().await.unwrap();
+        assert!(manager.cached().await.unwrap().is_some());
+    }
+}
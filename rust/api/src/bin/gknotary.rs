@@ -0,0 +1,183 @@
+//! `gknotary`: holds the notary signing keys and blind-signs for `gkapi` over a
+//! local Unix socket, so the internet-facing process never loads them. See
+//! `ghostkey_api::notary_signer` for the protocol and threat model.
+
+use std::collections::HashMap;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use clap::{value_parser, Arg, ArgAction, Command};
+use ed25519_dalek::VerifyingKey;
+use ghostkey_api::notary_signer::{self, Quotas, SignerServer, DEFAULT_TIER_QUOTA_PER_HOUR};
+use ghostkey_api::notary_store::{self, NotaryStore};
+use ghostkey_lib::armorable::Armorable;
+use ghostkey_lib::FREENET_MASTER_VERIFYING_KEY_BASE64;
+use log::{error, info, LevelFilter};
+use tokio::net::UnixListener;
+
+fn cli() -> Command {
+    Command::new("gknotary")
+        .about("Holds notary signing keys and blind-signs for gkapi over a Unix socket")
+        .arg(
+            Arg::new("notary-dir")
+                .long("notary-dir")
+                .env("NOTARY_DIR")
+                .required(true)
+                .help("Directory holding notary_certificate_N.pem / notary_signing_key_N.pem"),
+        )
+        .arg(
+            Arg::new("socket")
+                .long("socket")
+                .default_value("/run/gknotary/gknotary.sock")
+                .help("Unix socket to listen on; replaced if it already exists"),
+        )
+        .arg(
+            Arg::new("auth-key-file")
+                .long("auth-key-file")
+                .required(true)
+                .help("Key shared with gkapi (notary.signer_auth_key_file), at least 32 bytes"),
+        )
+        .arg(
+            Arg::new("master-verifying-key")
+                .long("master-verifying-key")
+                .help("Base64 master verifying key; defaults to the Freenet master key"),
+        )
+        .arg(
+            Arg::new("default-quota")
+                .long("default-quota")
+                .value_parser(value_parser!(usize))
+                .help("Signatures per tier per hour (default 60); 0 disables the quota"),
+        )
+        .arg(
+            Arg::new("quota")
+                .long("quota")
+                .action(ArgAction::Append)
+                .value_name("TIER=N")
+                .help("Per-tier override of --default-quota, e.g. --quota 10000=2"),
+        )
+        .arg(
+            Arg::new("allowed-uid")
+                .long("allowed-uid")
+                .action(ArgAction::Append)
+                .value_parser(value_parser!(u32))
+                .help("Only accept connections from this uid (repeatable)"),
+        )
+        .arg(
+            Arg::new("audit-log")
+                .long("audit-log")
+                .help("Append one JSON line per request here; defaults to the process log"),
+        )
+}
+
+fn fail(message: impl std::fmt::Display) -> ! {
+    error!("{message}");
+    std::process::exit(1);
+}
+
+#[tokio::main]
+async fn main() {
+    let matches = cli().get_matches();
+
+    env_logger::builder()
+        .format_timestamp(Some(env_logger::TimestampPrecision::Millis))
+        .format_module_path(false)
+        .format_target(false)
+        .filter_level(LevelFilter::Info)
+        .init();
+
+    let dir = PathBuf::from(matches.get_one::<String>("notary-dir").unwrap());
+    let socket = PathBuf::from(matches.get_one::<String>("socket").unwrap());
+    let auth_key_file = PathBuf::from(matches.get_one::<String>("auth-key-file").unwrap());
+
+    let master_vk = matches
+        .get_one::<String>("master-verifying-key")
+        .map(String::as_str)
+        .unwrap_or(FREENET_MASTER_VERIFYING_KEY_BASE64);
+    let master_vk = VerifyingKey::from_base64(master_vk)
+        .unwrap_or_else(|e| fail(format!("master verifying key is not valid: {e}")));
+    let auth_key = notary_signer::load_auth_key(&auth_key_file).unwrap_or_else(|e| fail(e));
+
+    let mut overrides = HashMap::new();
+    for spec in matches.get_many::<String>("quota").into_iter().flatten() {
+        let (tier, limit) = notary_signer::parse_quota(spec).unwrap_or_else(|e| fail(e));
+        overrides.insert(tier, limit);
+    }
+    let default_quota = matches
+        .get_one::<usize>("default-quota")
+        .copied()
+        .unwrap_or(DEFAULT_TIER_QUOTA_PER_HOUR);
+    let quotas = Quotas::new(default_quota, overrides);
+    let allowed_uids: Vec<u32> = matches
+        .get_many::<u32>("allowed-uid")
+        .into_iter()
+        .flatten()
+        .copied()
+        .collect();
+
+    let store = NotaryStore::load(&dir, master_vk)
+        .unwrap_or_else(|e| fail(format!("Failed to load notaries: {e}")));
+    let store = Arc::new(store);
+    info!(
+        "Loaded notary tiers {:?} from {}",
+        store.tiers(),
+        dir.display()
+    );
+    notary_store::spawn_reloader(Arc::clone(&store));
+
+    let audit_log = matches.get_one::<String>("audit-log").map(PathBuf::from);
+    let server = SignerServer::new(store, auth_key, quotas, allowed_uids, audit_log.as_deref())
+        .unwrap_or_else(|e| fail(format!("Cannot open audit log: {e}")));
+
+    // A socket left behind by a previous run would make bind fail.
+    if socket.exists() {
+        std::fs::remove_file(&socket)
+            .unwrap_or_else(|e| fail(format!("Cannot remove stale {}: {e}", socket.display())));
+    }
+    let listener = bind_private(&socket)
+        .unwrap_or_else(|e| fail(format!("Cannot bind {}: {e}", socket.display())));
+    info!("gknotary listening on {}", socket.display());
+
+    if let Err(e) = Arc::new(server).serve(listener).await {
+        fail(format!("gknotary stopped accepting connections: {e}"));
+    }
+}
+
+/// Bind `socket` without it ever being reachable under the default umask:
+/// the socket is created in a directory only we can enter, restricted to
+/// owner and group (gkapi reaches it through a shared group), and only then
+/// renamed into place.
+fn bind_private(socket: &Path) -> std::io::Result<UnixListener> {
+    let name = socket
+        .file_name()
+        .ok_or_else(|| std::io::Error::other("socket path has no file name"))?;
+    let mut staging = socket.to_path_buf();
+    staging.set_file_name(format!(".{}.bind", name.to_string_lossy()));
+    if staging.exists() {
+        std::fs::remove_dir_all(&staging)?;
+    }
+    std::fs::DirBuilder::new().mode(0o700).create(&staging)?;
+    let bound = staging.join(name);
+    let result = UnixListener::bind(&bound).and_then(|listener| {
+        std::fs::set_permissions(&bound, std::fs::Permissions::from_mode(0o660))?;
+        std::fs::rename(&bound, socket)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_dir_all(&staging);
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn socket_is_bound_owner_and_group_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let socket = dir.path().join("gknotary.sock");
+        let _listener = bind_private(&socket).unwrap();
+        let mode = std::fs::metadata(&socket).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+}
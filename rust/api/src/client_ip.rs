@@ -0,0 +1,529 @@
+//! Which address a request really came from.
+//!
+//! The per-IP rate limiter and the Tor exit check are only as good as the
+//! address they are given. Directly on the internet that is the TCP peer, and
+//! nothing the client sends can change it. Behind nginx or a CDN the peer is
+//! the proxy, so every requester would share one address; the real one is in
+//! a header or a PROXY protocol preamble that the proxy adds.
+//!
+//! Those are trusted only when the peer is a configured proxy. From anyone
+//! else they are ignored: a client that could name its own address could
+//! rotate it and walk straight through the limits. For the same reason only
+//! one header is consulted, the one the operator says the proxy sets. A proxy
+//! passes other headers through untouched, so a client could fill them in.
+//!
+//! Handlers take [`ClientIp`] and never look at the peer address themselves.
+
+use std::future::Future;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::pin::Pin;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts};
+use axum::http::request::Parts;
+use axum::http::{HeaderMap, StatusCode};
+use axum_server::accept::Accept;
+use ipnet::IpNet;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tower_http::add_extension::AddExtension;
+
+/// How long a trusted proxy gets to send its PROXY header.
+const PROXY_HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// `\r\n\r\n\0\r\nQUIT\n`, the fixed start of every PROXY v2 header.
+const PROXY_V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+/// The header a trusted proxy records the client in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ForwardedHeader {
+    #[default]
+    XForwardedFor,
+    /// RFC 7239 `Forwarded: for=...`.
+    Forwarded,
+}
+
+impl FromStr for ForwardedHeader {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "x-forwarded-for" => Ok(Self::XForwardedFor),
+            "forwarded" => Ok(Self::Forwarded),
+            other => Err(format!(
+                "unknown forwarded header {other:?}; use \"x-forwarded-for\" or \"forwarded\""
+            )),
+        }
+    }
+}
+
+/// The proxies whose word is taken for the client address.
+#[derive(Debug, Clone, Default)]
+pub struct ProxyTrust {
+    proxies: Vec<IpNet>,
+    header: ForwardedHeader,
+}
+
+impl ProxyTrust {
+    pub fn new(proxies: Vec<IpNet>, header: ForwardedHeader) -> Self {
+        Self { proxies, header }
+    }
+
+    /// Parse `10.0.0.0/8`-style entries; a bare address is a single host.
+    pub fn parse(entries: &[String], header: ForwardedHeader) -> Result<Self, String> {
+        Ok(Self::new(parse_nets(entries, "trusted proxy")?, header))
+    }
+
+    pub fn is_trusted(&self, ip: IpAddr) -> bool {
+        let ip = canonical(ip);
+        self.proxies.iter().any(|net| net.contains(&ip))
+    }
+
+    /// The client behind `peer`, given the request headers.
+    ///
+    /// Hops are read from the nearest outward, skipping trusted proxies, and
+    /// the first address not in the trusted set is the client. Everything to
+    /// its left was written by the client and is ignored. An entry that is
+    /// not an address ends the walk at the last trusted hop rather than
+    /// guessing past it.
+    pub fn resolve(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let client = canonical(peer);
+        if !self.is_trusted(client) {
+            return client;
+        }
+        self.walk(client, headers)
+    }
+
+    /// The client behind a peer on a Unix socket. Only processes on this
+    /// host can connect to one, so the peer is a proxy however the trusted
+    /// set reads; with no header, the request came from the host itself.
+    pub fn resolve_local(&self, headers: &HeaderMap) -> IpAddr {
+        self.walk(IpAddr::V4(Ipv4Addr::LOCALHOST), headers)
+    }
+
+    /// Walk the header outward from `client`, a trusted hop.
+    fn walk(&self, mut client: IpAddr, headers: &HeaderMap) -> IpAddr {
+        let hops = match self.header {
+            ForwardedHeader::XForwardedFor => x_forwarded_for(headers),
+            ForwardedHeader::Forwarded => forwarded(headers),
+        };
+        for hop in hops.iter().rev() {
+            match hop {
+                Some(ip) => {
+                    client = canonical(*ip);
+                    if !self.is_trusted(client) {
+                        return client;
+                    }
+                }
+                None => return client,
+            }
+        }
+        client
+    }
+}
+
+/// An IPv4 peer on a dual-stack socket arrives as `::ffff:a.b.c.d`; compare
+/// and key it as the IPv4 address it is.
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => v6
+            .to_ipv4_mapped()
+            .map(IpAddr::V4)
+            .unwrap_or(IpAddr::V6(v6)),
+        v4 => v4,
+    }
+}
+
+/// Every `X-Forwarded-For` entry, left to right, across repeated headers.
+fn x_forwarded_for(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .flat_map(|value| {
+            value
+                .to_str()
+                .map(|s| s.split(',').map(parse_hop).collect())
+                .unwrap_or_else(|_| vec![None])
+        })
+        .collect()
+}
+
+/// The `for=` of every RFC 7239 `Forwarded` element, left to right.
+fn forwarded(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    headers
+        .get_all("forwarded")
+        .iter()
+        .flat_map(|value| {
+            let Ok(value) = value.to_str() else {
+                return vec![None];
+            };
+            value
+                .split(',')
+                .map(|element| {
+                    element
+                        .split(';')
+                        .filter_map(|pair| pair.split_once('='))
+                        .find(|(key, _)| key.trim().eq_ignore_ascii_case("for"))
+                        .and_then(|(_, node)| parse_hop(node))
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// One hop: `1.2.3.4`, `1.2.3.4:80`, `2001:db8::1`, `[2001:db8::1]:80`,
+/// optionally quoted. `unknown` and obfuscated identifiers are not addresses.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim().trim_matches('"');
+    if let Ok(ip) = hop.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Ok(addr) = hop.parse::<SocketAddr>() {
+        return Some(addr.ip());
+    }
+    // `[v6]` without a port.
+    hop.strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+        .and_then(|v6| v6.parse::<Ipv6Addr>().ok())
+        .map(IpAddr::V6)
+}
+
+/// Clients allowed to read `/metrics` and the `/ready` checks: loopback and
+/// the configured addresses. Checked against the [`ClientIp`], so a client
+/// behind a trusted proxy is judged by its own address, not the proxy's.
+#[derive(Debug, Clone, Default)]
+pub struct Operators {
+    nets: Vec<IpNet>,
+}
+
+impl Operators {
+    /// Parse entries as for [`ProxyTrust::parse`].
+    pub fn parse(entries: &[String]) -> Result<Self, String> {
+        Ok(Self {
+            nets: parse_nets(entries, "operator address")?,
+        })
+    }
+
+    pub fn allows(&self, client: IpAddr) -> bool {
+        let client = canonical(client);
+        client.is_loopback() || self.nets.iter().any(|net| net.contains(&client))
+    }
+}
+
+/// `10.0.0.0/8`-style entries; a bare address is a single host.
+fn parse_nets(entries: &[String], what: &str) -> Result<Vec<IpNet>, String> {
+    entries
+        .iter()
+        .map(|entry| {
+            entry
+                .parse::<IpNet>()
+                .or_else(|_| entry.parse::<IpAddr>().map(IpNet::from))
+                .map_err(|_| format!("{what} {entry:?} is not an address or CIDR"))
+        })
+        .collect()
+}
+
+/// Marks a request that came in on a Unix socket, which has no peer
+/// address; see [`ProxyTrust::resolve_local`].
+#[derive(Debug, Clone, Copy)]
+pub struct UnixPeer;
+
+/// The resolved client address of a request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientIp(pub IpAddr);
+
+/// Source address from a PROXY header, attached to each request on that
+/// connection. `None` when there was no header or it carried no address.
+#[derive(Debug, Clone, Copy)]
+pub struct ProxiedPeer(pub Option<SocketAddr>);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for ClientIp {
+    type Rejection = StatusCode;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if parts.extensions.get::<UnixPeer>().is_some() {
+            let ip = match parts.extensions.get::<Arc<ProxyTrust>>() {
+                Some(trust) => trust.resolve_local(&parts.headers),
+                None => ProxyTrust::default().resolve_local(&parts.headers),
+            };
+            return Ok(ClientIp(ip));
+        }
+        let Some(ConnectInfo(peer)) = parts.extensions.get::<ConnectInfo<SocketAddr>>() else {
+            log::error!("ClientIp used on a router served without connect info");
+            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+        };
+        // The acceptor only reads a PROXY header from trusted peers, so its
+        // address is already as good as the peer's.
+        let peer = match parts.extensions.get::<ProxiedPeer>() {
+            Some(ProxiedPeer(Some(source))) => source.ip(),
+            _ => peer.ip(),
+        };
+        // No trust configured means no proxies: the peer is the client.
+        let ip = match parts.extensions.get::<Arc<ProxyTrust>>() {
+            Some(trust) => trust.resolve(peer, &parts.headers),
+            None => canonical(peer),
+        };
+        Ok(ClientIp(ip))
+    }
+}
+
+/// Reads a PROXY protocol v2 header from connections made by trusted proxies
+/// before handing them on, and attaches the address it carries.
+///
+/// Connections from any other peer are passed through untouched: a client
+/// that sends its own PROXY header just sends a malformed HTTP request.
+#[derive(Clone)]
+pub struct ProxyProtocolAcceptor<A> {
+    inner: A,
+    trust: Arc<ProxyTrust>,
+    enabled: bool,
+}
+
+impl<A> ProxyProtocolAcceptor<A> {
+    pub fn new(inner: A, trust: Arc<ProxyTrust>, enabled: bool) -> Self {
+        Self {
+            inner,
+            trust,
+            enabled,
+        }
+    }
+}
+
+impl<A, S> Accept<TcpStream, S> for ProxyProtocolAcceptor<A>
+where
+    A: Accept<TcpStream, AddExtension<S, ProxiedPeer>> + Clone + Send + Sync + 'static,
+    A::Future: Send,
+    S: Send + 'static,
+{
+    type Stream = A::Stream;
+    type Service = A::Service;
+    type Future = Pin<Box<dyn Future<Output = io::Result<(A::Stream, A::Service)>> + Send>>;
+
+    fn accept(&self, mut stream: TcpStream, service: S) -> Self::Future {
+        let inner = self.inner.clone();
+        let trust = Arc::clone(&self.trust);
+        let enabled = self.enabled;
+        Box::pin(async move {
+            let peer = stream.peer_addr()?;
+            let source = if enabled && trust.is_trusted(peer.ip()) {
+                tokio::time::timeout(PROXY_HEADER_TIMEOUT, read_proxy_v2(&mut stream))
+                    .await
+                    .map_err(|_| {
+                        io::Error::new(io::ErrorKind::TimedOut, "no PROXY header from proxy")
+                    })??
+            } else {
+                None
+            };
+            inner
+                .accept(stream, AddExtension::new(service, ProxiedPeer(source)))
+                .await
+        })
+    }
+}
+
+/// Read exactly one PROXY v2 header, leaving the stream at the first byte
+/// after it.
+async fn read_proxy_v2(stream: &mut TcpStream) -> io::Result<Option<SocketAddr>> {
+    let mut fixed = [0u8; 16];
+    stream.read_exact(&mut fixed).await?;
+    let len = u16::from_be_bytes([fixed[14], fixed[15]]) as usize;
+    let mut body = vec![0u8; len];
+    stream.read_exact(&mut body).await?;
+    parse_proxy_v2(&fixed, &body)
+}
+
+fn parse_proxy_v2(fixed: &[u8; 16], body: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+    if fixed[..12] != PROXY_V2_SIGNATURE {
+        return Err(invalid("connection from proxy lacks a PROXY v2 header"));
+    }
+    if fixed[12] >> 4 != 2 {
+        return Err(invalid("unsupported PROXY protocol version"));
+    }
+    match fixed[12] & 0x0f {
+        // LOCAL: the proxy's own health check; no client behind it.
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("unknown PROXY command")),
+    }
+    match fixed[13] >> 4 {
+        // AF_INET: src, dst, src port, dst port.
+        1 if body.len() >= 12 => {
+            let ip = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let port = u16::from_be_bytes([body[8], body[9]]);
+            Ok(Some(SocketAddr::new(IpAddr::V4(ip), port)))
+        }
+        // AF_INET6.
+        2 if body.len() >= 36 => {
+            let octets: [u8; 16] = body[..16].try_into().expect("length checked");
+            let port = u16::from_be_bytes([body[32], body[33]]);
+            Ok(Some(SocketAddr::new(IpAddr::V6(octets.into()), port)))
+        }
+        // AF_UNSPEC or AF_UNIX: nothing usable, keep the peer.
+        0 | 3 => Ok(None),
+        _ => Err(invalid("truncated PROXY v2 address block")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    fn trust(header: ForwardedHeader) -> ProxyTrust {
+        ProxyTrust::parse(
+            &["10.0.0.0/8".to_string(), "2001:db8:ffff::1".to_string()],
+            header,
+        )
+        .unwrap()
+    }
+
+    fn headers(pairs: &[(&'static str, &str)]) -> HeaderMap {
+        let mut map = HeaderMap::new();
+        for (name, value) in pairs {
+            map.append(*name, HeaderValue::from_str(value).unwrap());
+        }
+        map
+    }
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn operators_are_loopback_and_the_listed_networks() {
+        let operators = Operators::parse(&["192.0.2.0/24".to_string()]).unwrap();
+        assert!(operators.allows(ip("127.0.0.1")));
+        assert!(operators.allows(ip("::1")));
+        assert!(operators.allows(ip("::ffff:192.0.2.9")));
+        assert!(!operators.allows(ip("198.51.100.7")));
+        assert!(!Operators::default().allows(ip("10.0.0.1")));
+        let e = Operators::parse(&["192.0.2.300".to_string()]).unwrap_err();
+        assert!(e.contains("operator address"), "{e}");
+    }
+
+    #[test]
+    fn untrusted_peers_cannot_name_their_address() {
+        let trust = trust(ForwardedHeader::XForwardedFor);
+        let spoofed = headers(&[
+            ("x-forwarded-for", "198.51.100.7"),
+            ("forwarded", "for=198.51.100.8"),
+        ]);
+        assert_eq!(
+            trust.resolve(ip("203.0.113.5"), &spoofed),
+            ip("203.0.113.5")
+        );
+        // With no trust configured at all, the same holds.
+        assert_eq!(
+            ProxyTrust::default().resolve(ip("10.0.0.1"), &spoofed),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn the_rightmost_untrusted_hop_is_the_client() {
+        let trust = trust(ForwardedHeader::XForwardedFor);
+        // The client prepended a fake entry; the proxy appended the real one.
+        let request = headers(&[("x-forwarded-for", "198.51.100.7, 203.0.113.5")]);
+        assert_eq!(trust.resolve(ip("10.1.2.3"), &request), ip("203.0.113.5"));
+        // Chained proxies are skipped, also across repeated headers.
+        let request = headers(&[
+            ("x-forwarded-for", "198.51.100.7, 203.0.113.5"),
+            ("x-forwarded-for", "10.9.9.9"),
+        ]);
+        assert_eq!(trust.resolve(ip("10.1.2.3"), &request), ip("203.0.113.5"));
+        // Garbage ends the walk at the last trusted hop, not past it.
+        let request = headers(&[("x-forwarded-for", "198.51.100.7, nonsense, 10.9.9.9")]);
+        assert_eq!(trust.resolve(ip("10.1.2.3"), &request), ip("10.9.9.9"));
+        // A trusted proxy that sent nothing is itself the client.
+        assert_eq!(
+            trust.resolve(ip("10.1.2.3"), &HeaderMap::new()),
+            ip("10.1.2.3")
+        );
+    }
+
+    #[test]
+    fn only_the_configured_header_is_read() {
+        let request = headers(&[
+            ("x-forwarded-for", "198.51.100.7"),
+            (
+                "forwarded",
+                "for=192.0.2.1, for=\"[2001:db8:cafe::17]:4711\";proto=https",
+            ),
+        ]);
+        assert_eq!(
+            trust(ForwardedHeader::Forwarded).resolve(ip("10.0.0.1"), &request),
+            ip("2001:db8:cafe::17")
+        );
+        assert_eq!(
+            trust(ForwardedHeader::XForwardedFor).resolve(ip("10.0.0.1"), &request),
+            ip("198.51.100.7")
+        );
+        let unknown = headers(&[("forwarded", "for=unknown")]);
+        assert_eq!(
+            trust(ForwardedHeader::Forwarded).resolve(ip("10.0.0.1"), &unknown),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn mapped_ipv4_peers_are_matched_as_ipv4() {
+        let trust = trust(ForwardedHeader::XForwardedFor);
+        let request = headers(&[("x-forwarded-for", "203.0.113.5")]);
+        assert_eq!(
+            trust.resolve(ip("::ffff:10.0.0.1"), &request),
+            ip("203.0.113.5")
+        );
+        assert!(trust.is_trusted(ip("2001:db8:ffff::1")));
+        assert!(!trust.is_trusted(ip("2001:db8:ffff::2")));
+    }
+
+    #[test]
+    fn a_unix_socket_peer_is_a_local_proxy() {
+        let trust = trust(ForwardedHeader::XForwardedFor);
+        let request = headers(&[("x-forwarded-for", "198.51.100.7, 203.0.113.5")]);
+        assert_eq!(trust.resolve_local(&request), ip("203.0.113.5"));
+        // The same header over TCP from an untrusted peer is ignored.
+        assert_eq!(trust.resolve(ip("127.0.0.1"), &request), ip("127.0.0.1"));
+        assert_eq!(trust.resolve_local(&HeaderMap::new()), ip("127.0.0.1"));
+    }
+
+    #[test]
+    fn proxy_v2_headers_are_parsed() {
+        let mut fixed = [0u8; 16];
+        fixed[..12].copy_from_slice(&PROXY_V2_SIGNATURE);
+        fixed[12] = 0x21; // v2, PROXY
+        fixed[13] = 0x11; // AF_INET, STREAM
+        let body = [203, 0, 113, 5, 10, 0, 0, 1, 0x1f, 0x90, 0x01, 0xbb];
+        assert_eq!(
+            parse_proxy_v2(&fixed, &body).unwrap(),
+            Some("203.0.113.5:8080".parse().unwrap())
+        );
+
+        fixed[13] = 0x21; // AF_INET6
+        let mut body = [0u8; 36];
+        body[..16].copy_from_slice(&ip6("2001:db8::5").octets());
+        body[32..34].copy_from_slice(&443u16.to_be_bytes());
+        assert_eq!(
+            parse_proxy_v2(&fixed, &body).unwrap(),
+            Some("[2001:db8::5]:443".parse().unwrap())
+        );
+        assert!(parse_proxy_v2(&fixed, &body[..20]).is_err());
+
+        fixed[12] = 0x20; // LOCAL
+        assert_eq!(parse_proxy_v2(&fixed, &[]).unwrap(), None);
+
+        let mut http = [0u8; 16];
+        http.copy_from_slice(b"GET / HTTP/1.1\r\n");
+        assert!(parse_proxy_v2(&http, &[]).is_err());
+    }
+
+    fn ip6(s: &str) -> Ipv6Addr {
+        s.parse().unwrap()
+    }
+}
@@ -0,0 +1,2384 @@
+//! Server configuration file and startup validation.
+//!
+//! gkapi grew a flag or env var per feature, and most of them are only read
+//! when the first request that needs them arrives: a notary tier with a key
+//! that does not match its certificate is discovered by the first donor to
+//! pay for that tier, after their card has been charged. This module gathers
+//! everything into one TOML file and [`Config::check`] validates all of it up
+//! front, both for `gkapi check-config` and at every startup before binding.
+//!
+//! Flags and env vars still work and take precedence over the file, so an
+//! existing systemd unit keeps its behaviour. A flag that was left at its clap
+//! default does NOT override the file; see [`Config::resolve`].
+
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+use std::fs;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use clap::parser::ValueSource;
+use clap::ArgMatches;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use ghostkey_lib::armorable::Armorable;
+use ghostkey_lib::FREENET_MASTER_VERIFYING_KEY_BASE64;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::access_policy::PolicyFile;
+use crate::acme::{self, AcmeSettings};
+use crate::client_ip::{ForwardedHeader, Operators, ProxyTrust};
+use crate::delegates;
+use crate::ghostkey_auth::DEFAULT_INVITES_PER_GHOSTKEY;
+use crate::invite_pow::{
+    difficulty_for, expected_ms, Argon2Params, PowAlgorithm, PowKeys, DEFAULT_POW_DIFFICULTY,
+    DEFAULT_POW_TARGET_MS,
+};
+use crate::issuance_log;
+use crate::listen::{self, Listen};
+use crate::logging::{self, LogSettings};
+use crate::notary_signer::{self, RemoteSigner};
+use crate::pow_difficulty::DifficultySettings;
+use crate::rate_limit::{PrefixLimit, MAX_INVITES_PER_WINDOW};
+use crate::rate_limit_store::StoreBackend;
+use crate::reputation::{self, ReputationAction, SourceLocation, SourceSettings};
+use crate::shutdown;
+use crate::throttle::{self, RouteLimit, Throttle};
+use crate::tor::TorSource;
+use crate::tor_consensus::DIRECTORY_AUTHORITIES;
+
+pub const DEFAULT_RATE_LIMIT_FILE: &str = "/var/lib/gkapi/invite_rate_limits.json";
+pub const DEFAULT_RATE_LIMIT_DB: &str = "/var/lib/gkapi/invite_rate_limits.redb";
+pub const DEFAULT_TOR_EXIT_CACHE: &str = "/var/lib/gkapi/tor_exit_list.txt";
+/// URL reputation sources are cached here as `<name>.txt`.
+pub const DEFAULT_REPUTATION_CACHE_DIR: &str = "/var/lib/gkapi/reputation";
+pub const DEFAULT_ROOM_NAME: &str = "Freenet Chat";
+/// Slug of the room configured by the flat `[invite]` keys (and the
+/// `--room-*` flags), which predate `[[invite.rooms]]`.
+pub const DEFAULT_ROOM_SLUG: &str = "default";
+pub const DEFAULT_GHOSTKEY_RATE_LIMIT_FILE: &str = "/var/lib/gkapi/ghostkey_invite_limits.json";
+pub const DEFAULT_ACCESS_POLICY: &str = "/var/lib/gkapi/access_policy.toml";
+pub const DEFAULT_GHOSTKEY_RATE_LIMIT_DB: &str = "/var/lib/gkapi/ghostkey_invite_limits.redb";
+pub const DEFAULT_INVITE_LEDGER: &str = "/var/lib/gkapi/invite_ledger.jsonl";
+pub const DEFAULT_POW_KEY_FILE: &str = "/var/lib/gkapi/pow_key";
+pub const DEFAULT_POW_USED_FILE: &str = "/var/lib/gkapi/pow_used.json";
+pub const DEFAULT_POW_USED_DB: &str = "/var/lib/gkapi/pow_used.redb";
+pub const DEFAULT_ISSUANCE_LOG: &str = "/var/lib/gkapi/issuance_log.jsonl";
+pub const DEFAULT_CHECKPOINTS_FILE: &str = "/var/lib/gkapi/issuance_checkpoints.jsonl";
+pub const DEFAULT_ACME_CACHE_DIR: &str = "/var/lib/gkapi/acme";
+pub const DEFAULT_ACME_RENEW_DAYS_BEFORE: u32 = 30;
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("could not read config file {path}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("could not parse config file {path}: {source}")]
+    Parse {
+        path: PathBuf,
+        source: toml::de::Error,
+    },
+}
+
+/// Contents of the TOML config file. Every field is optional so a file only
+/// needs to name what differs from the defaults.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub server: ServerConfig,
+    pub tls: TlsConfig,
+    pub acme: AcmeConfig,
+    pub notary: NotaryConfig,
+    pub payment: PaymentConfig,
+    pub invite: InviteConfig,
+    pub rate_limit: RateLimitConfig,
+    pub pow: PowConfig,
+    pub tor: TorConfig,
+    pub reputation: ReputationConfig,
+    pub throttle: ThrottleConfig,
+    pub transparency: TransparencyConfig,
+    pub logging: LoggingConfig,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServerConfig {
+    /// Defaults to 443 with TLS and 8000 without.
+    pub port: Option<u16>,
+    /// `ip:port`, `unix:/path` or `systemd[:name]` entries to accept on, in
+    /// place of 0.0.0.0 on `port`; see [`crate::listen`].
+    pub listen: Vec<String>,
+    /// Directory for HTTP-01 challenge tokens. Setting it starts the :80
+    /// challenge listener.
+    pub challenge_dir: Option<PathBuf>,
+    /// Addresses or CIDRs of reverse proxies in front of gkapi. Only these
+    /// peers are believed about the client address.
+    pub trusted_proxies: Vec<String>,
+    /// `x-forwarded-for` (the default) or `forwarded`: the one header the
+    /// proxies set. The other is ignored even from them.
+    pub forwarded_header: Option<String>,
+    /// Expect a PROXY protocol v2 header on connections from the proxies.
+    pub proxy_protocol: bool,
+    /// Addresses or CIDRs, besides loopback, of clients allowed to read
+    /// `/metrics` and the checks behind `/ready`.
+    pub operator_addresses: Vec<String>,
+    /// `server.listen` for the HTTP-01 challenge listener, in place of
+    /// 0.0.0.0:80, where CAs connect.
+    pub challenge_listen: Vec<String>,
+    /// How long requests in flight may run on after SIGTERM or SIGINT.
+    /// Defaults to 30.
+    pub shutdown_grace_seconds: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TlsConfig {
+    pub cert: Option<PathBuf>,
+    pub key: Option<PathBuf>,
+}
+
+/// Certificates from an ACME CA instead of `[tls]` files.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct AcmeConfig {
+    /// Names the certificate covers. Setting any turns ACME on.
+    pub domains: Vec<String>,
+    /// Email addresses, or `mailto:` URLs, for the CA's expiry notices.
+    pub contact: Vec<String>,
+    /// Directory URL. Defaults to Let's Encrypt.
+    pub directory: Option<String>,
+    /// Account key and certificate. Defaults to /var/lib/gkapi/acme.
+    pub cache_dir: Option<PathBuf>,
+    /// Defaults to 30.
+    pub renew_days_before: Option<u32>,
+    /// Extra root certificate to trust for the directory, for a test CA.
+    pub ca_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotaryConfig {
+    pub dir: Option<PathBuf>,
+    /// Base64 master verifying key the notary certificates must chain to.
+    /// Defaults to the Freenet master key; only tests and staging set this.
+    pub master_verifying_key: Option<String>,
+    /// Unix socket of a `gknotary` daemon. When set, gkapi signs through it
+    /// and `dir` needs to hold only the certificates.
+    pub signer_socket: Option<PathBuf>,
+    /// Key shared with `gknotary` to authenticate signing requests.
+    pub signer_auth_key_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PaymentConfig {
+    pub provider: String,
+    /// File holding the Stripe secret key. When unset the key is read from
+    /// the `STRIPE_SECRET_KEY` env var, as before.
+    pub secret_key_file: Option<PathBuf>,
+}
+
+impl Default for PaymentConfig {
+    fn default() -> Self {
+        Self {
+            provider: "stripe".to_string(),
+            secret_key_file: None,
+        }
+    }
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct InviteConfig {
+    /// Room member's signing key (32 raw bytes).
+    pub signing_key_file: Option<PathBuf>,
+    /// Room owner's verifying key, base58.
+    pub owner_vk: Option<String>,
+    pub room_name: Option<String>,
+    /// Slug used when a request names no room. Defaults to the first room.
+    pub default_room: Option<String>,
+    /// Further rooms served by the same endpoint, selected with `?room=`.
+    pub rooms: Vec<RoomConfig>,
+    /// Every invitation issued, with the ghost key behind it if there was
+    /// one; read by `ghostkey-api export-invites`.
+    pub ledger: Option<PathBuf>,
+    /// Allow and deny lists; see `access_policy`. Edited with
+    /// `ghostkey-api policy`.
+    pub access_policy: Option<PathBuf>,
+}
+
+/// One `[[invite.rooms]]` entry.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RoomConfig {
+    /// Identifier used in `?room=`: lowercase letters, digits and `-`.
+    pub slug: String,
+    /// Display name returned with the invite and listed by `/rooms`.
+    pub name: String,
+    pub signing_key_file: PathBuf,
+    pub owner_vk: String,
+    /// Invites per IP per 24 hours in this room. Defaults to
+    /// `MAX_INVITES_PER_WINDOW`.
+    pub invites_per_ip: Option<usize>,
+    /// Hourly ceiling for this room alone, under the global one. Unset or 0
+    /// leaves the room bounded only by the global ceiling.
+    pub invites_per_hour: Option<usize>,
+    /// Per-IP limiter state. Defaults to `rate_limit.file` with the slug
+    /// inserted before the extension.
+    pub rate_limit_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// `json` (the default), `redb`, or `memory`, which forgets everything on
+    /// restart.
+    pub backend: Option<String>,
+    pub file: Option<PathBuf>,
+    pub global_invites_per_hour: Option<usize>,
+    /// Per-ghost-key limiter state for `/create-invite/ghostkey`.
+    pub ghostkey_file: Option<PathBuf>,
+    pub invites_per_ghostkey: Option<usize>,
+    /// Networks invites are also counted per, on top of each address.
+    pub ipv4_prefix: Option<u8>,
+    pub ipv6_prefix: Option<u8>,
+    /// Invites per network per 24 hours in each room; 0 turns the network
+    /// quota off.
+    pub invites_per_prefix: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PowConfig {
+    /// Leading-zero bits at base load. Takes precedence over `target_ms`.
+    pub difficulty: Option<u8>,
+    /// `sha256` (the default) or `argon2id`.
+    pub algorithm: Option<String>,
+    /// Further algorithms whose proofs are still accepted, for clients that
+    /// fetched a challenge before a switch. Remove once the switch is a few
+    /// minutes old.
+    pub accept: Vec<String>,
+    /// Expected browser solve time at base load, converted to bits.
+    pub target_ms: Option<u64>,
+    /// Replaces the built-in browser speed estimate used with `target_ms`.
+    pub attempts_per_second: Option<f64>,
+    pub argon2_memory_kib: Option<u32>,
+    pub argon2_iterations: Option<u32>,
+    pub argon2_parallelism: Option<u32>,
+    /// Hourly issuance at which load stops raising difficulty. See
+    /// `pow_difficulty`.
+    pub target_invites_per_hour: Option<usize>,
+    /// Most bits load and network terms can add to the base.
+    pub max_extra_bits: Option<u8>,
+    /// Challenge requests per minute a network makes before its difficulty
+    /// rises; 0 turns that off.
+    pub network_requests_per_minute: Option<usize>,
+    /// HMAC keys for challenges, current then previous. Created if missing.
+    pub key_file: Option<PathBuf>,
+    /// Consumed challenges, in a `rate_limit.backend` store.
+    pub used_file: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TorConfig {
+    pub exit_cache: Option<PathBuf>,
+    /// A signed network-status consensus to read exits from instead of the
+    /// bulk list, from a mirror or a file. Needs the key certificates too.
+    pub consensus_url: Option<String>,
+    pub consensus_file: Option<PathBuf>,
+    /// The directory authorities' key certificates (`/tor/keys/all`).
+    pub certificates_url: Option<String>,
+    pub certificates_file: Option<PathBuf>,
+    /// v3 identity fingerprints replacing the built-in authority list.
+    pub authorities: Vec<String>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReputationConfig {
+    pub sources: Vec<ReputationSourceConfig>,
+}
+
+/// One `[[reputation.sources]]` entry.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ReputationSourceConfig {
+    /// Used in logs and the default cache file name.
+    pub name: String,
+    /// Fetched over HTTP(S). Exactly one of `url` and `file` is given.
+    pub url: Option<String>,
+    /// Read from disk, for lists the operator maintains.
+    pub file: Option<PathBuf>,
+    /// `block`, `extra-bits` or `quota`.
+    pub action: String,
+    /// Bits added to challenges, for `extra-bits`.
+    pub extra_bits: Option<u8>,
+    /// Per-address invites per 24 hours, for `quota`.
+    pub invites_per_ip: Option<usize>,
+    /// Last good copy of a URL source.
+    pub cache: Option<PathBuf>,
+    /// Fewer entries than this is a broken list, not a short one.
+    pub min_entries: Option<usize>,
+    /// Refuse invitations while this source has never loaded.
+    pub fail_closed: bool,
+    pub refresh_minutes: Option<u64>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ThrottleConfig {
+    /// Body cap for routes without their own.
+    pub max_body_bytes: Option<usize>,
+    /// Keyed on the route as the router writes it, such as
+    /// `/check-payment-status/:payment_intent_id`. Overrides the built-in
+    /// limits field by field.
+    pub routes: BTreeMap<String, RouteThrottleConfig>,
+}
+
+/// One `[throttle.routes."<path>"]` entry.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RouteThrottleConfig {
+    /// Per client; 0 turns the route's bucket off.
+    pub per_minute: Option<u32>,
+    /// Defaults to `per_minute` on a route with no built-in limit.
+    pub burst: Option<u32>,
+    pub max_body_bytes: Option<usize>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TransparencyConfig {
+    /// Hash-chained log of every blind signature issued.
+    pub log: Option<PathBuf>,
+    /// Master-signed checkpoints served at `/transparency`, one per line.
+    pub checkpoints: Option<PathBuf>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LoggingConfig {
+    /// `text` (the default) or `json`.
+    pub format: Option<String>,
+    /// Filter directives such as `info` or `info,ghostkey_api=debug`.
+    /// `RUST_LOG` takes precedence.
+    pub level: Option<String>,
+}
+
+/// Did the operator actually supply `id`, as opposed to clap filling in its
+/// default? Only supplied values may override the config file.
+fn supplied<T: Clone + Send + Sync + 'static>(matches: &ArgMatches, id: &str) -> Option<T> {
+    match matches.value_source(id) {
+        Some(ValueSource::CommandLine) | Some(ValueSource::EnvVariable) => {
+            matches.get_one::<T>(id).cloned()
+        }
+        _ => None,
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        let content = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        Self::parse(&content).map_err(|source| ConfigError::Parse {
+            path: path.to_path_buf(),
+            source,
+        })
+    }
+
+    pub fn parse(content: &str) -> Result<Self, toml::de::Error> {
+        toml::from_str(content)
+    }
+
+    /// Build the effective configuration: the file named by `--config` (if
+    /// any), overlaid with every flag or env var the operator supplied.
+    pub fn resolve(matches: &ArgMatches) -> Result<Self, ConfigError> {
+        let mut config = match matches.get_one::<String>("config") {
+            Some(path) => Self::load(Path::new(path))?,
+            None => Self::default(),
+        };
+        config.apply_args(matches);
+        Ok(config)
+    }
+
+    fn apply_args(&mut self, matches: &ArgMatches) {
+        let path = |id: &str| supplied::<String>(matches, id).map(PathBuf::from);
+
+        if let Some(dir) = path("notary-dir") {
+            self.notary.dir = Some(dir);
+        }
+        if let Some(vk) = supplied::<String>(matches, "master-verifying-key") {
+            self.notary.master_verifying_key = Some(vk);
+        }
+        if let Some(cert) = path("tls-cert") {
+            self.tls.cert = Some(cert);
+        }
+        if let Some(key) = path("tls-key") {
+            self.tls.key = Some(key);
+        }
+        if let Some(port) = supplied::<u16>(matches, "port") {
+            self.server.port = Some(port);
+        }
+        if let Some(dir) = path("challenge-dir") {
+            self.server.challenge_dir = Some(dir);
+        }
+        if let Some(file) = path("room-signing-key") {
+            self.invite.signing_key_file = Some(file);
+        }
+        if let Some(vk) = supplied::<String>(matches, "room-owner-vk") {
+            self.invite.owner_vk = Some(vk);
+        }
+        if let Some(name) = supplied::<String>(matches, "room-name") {
+            self.invite.room_name = Some(name);
+        }
+        if let Some(file) = path("rate-limit-file") {
+            self.rate_limit.file = Some(file);
+        }
+        if let Some(n) = supplied::<usize>(matches, "global-invites-per-hour") {
+            self.rate_limit.global_invites_per_hour = Some(n);
+        }
+        if let Some(bits) = supplied::<u8>(matches, "invite-pow-difficulty") {
+            self.pow.difficulty = Some(bits);
+        }
+        if let Some(file) = path("tor-exit-cache") {
+            self.tor.exit_cache = Some(file);
+        }
+        if let Some(file) = path("issuance-log") {
+            self.transparency.log = Some(file);
+        }
+        if let Some(format) = supplied::<String>(matches, "log-format") {
+            self.logging.format = Some(format);
+        }
+        if let Some(level) = supplied::<String>(matches, "log-level") {
+            self.logging.level = Some(level);
+        }
+    }
+
+    pub fn room_name(&self) -> String {
+        self.invite
+            .room_name
+            .clone()
+            .unwrap_or_else(|| DEFAULT_ROOM_NAME.to_string())
+    }
+
+    pub fn rate_limit_backend(&self) -> Result<StoreBackend, String> {
+        match &self.rate_limit.backend {
+            Some(name) => name.parse(),
+            None => Ok(StoreBackend::default()),
+        }
+    }
+
+    /// The configured file, or the default for the backend in use.
+    pub fn rate_limit_file(&self) -> PathBuf {
+        self.rate_limit.file.clone().unwrap_or_else(|| {
+            PathBuf::from(match self.rate_limit_backend() {
+                Ok(StoreBackend::Redb) => DEFAULT_RATE_LIMIT_DB,
+                _ => DEFAULT_RATE_LIMIT_FILE,
+            })
+        })
+    }
+
+    pub fn ghostkey_rate_limit_file(&self) -> PathBuf {
+        self.rate_limit.ghostkey_file.clone().unwrap_or_else(|| {
+            PathBuf::from(match self.rate_limit_backend() {
+                Ok(StoreBackend::Redb) => DEFAULT_GHOSTKEY_RATE_LIMIT_DB,
+                _ => DEFAULT_GHOSTKEY_RATE_LIMIT_FILE,
+            })
+        })
+    }
+
+    pub fn invites_per_ghostkey(&self) -> usize {
+        self.rate_limit
+            .invites_per_ghostkey
+            .unwrap_or(DEFAULT_INVITES_PER_GHOSTKEY)
+    }
+
+    /// The per-network quota every room's IP limiter also applies, if any.
+    pub fn prefix_limit(&self) -> Option<PrefixLimit> {
+        let defaults = PrefixLimit::default();
+        let limit = PrefixLimit {
+            ipv4_prefix: self.rate_limit.ipv4_prefix.unwrap_or(defaults.ipv4_prefix),
+            ipv6_prefix: self.rate_limit.ipv6_prefix.unwrap_or(defaults.ipv6_prefix),
+            max_per_window: self
+                .rate_limit
+                .invites_per_prefix
+                .unwrap_or(defaults.max_per_window),
+        };
+        (limit.max_per_window > 0).then_some(limit)
+    }
+
+    pub fn invite_ledger(&self) -> PathBuf {
+        self.invite
+            .ledger
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_INVITE_LEDGER))
+    }
+
+    pub fn access_policy_file(&self) -> PathBuf {
+        self.invite
+            .access_policy
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_ACCESS_POLICY))
+    }
+
+    pub fn tor_exit_cache(&self) -> PathBuf {
+        self.tor
+            .exit_cache
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_TOR_EXIT_CACHE))
+    }
+
+    pub fn issuance_log(&self) -> PathBuf {
+        self.transparency
+            .log
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_ISSUANCE_LOG))
+    }
+
+    pub fn checkpoints_file(&self) -> PathBuf {
+        self.transparency
+            .checkpoints
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_CHECKPOINTS_FILE))
+    }
+
+    /// Networks are grouped by the same prefixes as the per-network quota.
+    pub fn difficulty_settings(&self) -> DifficultySettings {
+        let defaults = DifficultySettings::default();
+        let prefixes = PrefixLimit::default();
+        DifficultySettings {
+            base: self.pow_difficulty(),
+            target_per_hour: self
+                .pow
+                .target_invites_per_hour
+                .unwrap_or(defaults.target_per_hour),
+            max_extra_bits: self.pow.max_extra_bits.unwrap_or(defaults.max_extra_bits),
+            network_requests_per_minute: self
+                .pow
+                .network_requests_per_minute
+                .unwrap_or(defaults.network_requests_per_minute),
+            ipv4_prefix: self.rate_limit.ipv4_prefix.unwrap_or(prefixes.ipv4_prefix),
+            ipv6_prefix: self.rate_limit.ipv6_prefix.unwrap_or(prefixes.ipv6_prefix),
+        }
+    }
+
+    /// The bulk exit list, or a signed consensus if one is configured.
+    pub fn tor_source(&self) -> Result<TorSource, String> {
+        let tor = &self.tor;
+        let location = |what: &str, url: &Option<String>, file: &Option<PathBuf>| match (url, file)
+        {
+            (Some(url), None) => Ok(SourceLocation::Url(url.clone())),
+            (None, Some(file)) => Ok(SourceLocation::File(file.clone())),
+            _ => Err(format!(
+                "tor: give exactly one of {what}_url and {what}_file"
+            )),
+        };
+        let consensus_given = tor.consensus_url.is_some()
+            || tor.consensus_file.is_some()
+            || tor.certificates_url.is_some()
+            || tor.certificates_file.is_some();
+        if !consensus_given {
+            if !tor.authorities.is_empty() {
+                return Err("tor.authorities is only used with a consensus source".to_string());
+            }
+            return Ok(TorSource::default());
+        }
+        let authorities: Vec<String> = if tor.authorities.is_empty() {
+            DIRECTORY_AUTHORITIES
+                .iter()
+                .map(|(_, fingerprint)| fingerprint.to_string())
+                .collect()
+        } else {
+            tor.authorities.clone()
+        };
+        for fingerprint in &authorities {
+            if fingerprint.len() != 40 || hex::decode(fingerprint).is_err() {
+                return Err(format!(
+                    "tor.authorities: {fingerprint:?} is not a 40-digit hex fingerprint"
+                ));
+            }
+        }
+        Ok(TorSource::Consensus {
+            consensus: location("consensus", &tor.consensus_url, &tor.consensus_file)?,
+            certificates: location(
+                "certificates",
+                &tor.certificates_url,
+                &tor.certificates_file,
+            )?,
+            authorities,
+        })
+    }
+
+    /// Every `[[reputation.sources]]` entry, validated.
+    pub fn reputation_sources(&self) -> Result<Vec<SourceSettings>, String> {
+        let mut names = HashSet::new();
+        let mut sources = Vec::new();
+        for source in &self.reputation.sources {
+            let name = &source.name;
+            if !valid_room_slug(name) {
+                return Err(format!(
+                    "reputation source name {name:?} must be lowercase letters, digits and '-'"
+                ));
+            }
+            if !names.insert(name.as_str()) {
+                return Err(format!(
+                    "reputation source name {name:?} is used more than once"
+                ));
+            }
+            let location = match (&source.url, &source.file) {
+                (Some(url), None) => SourceLocation::Url(url.clone()),
+                (None, Some(file)) => SourceLocation::File(file.clone()),
+                _ => {
+                    return Err(format!(
+                        "reputation source {name}: give exactly one of url and file"
+                    ))
+                }
+            };
+            let action = match (
+                source.action.as_str(),
+                source.extra_bits,
+                source.invites_per_ip,
+            ) {
+                ("block", None, None) => ReputationAction::Block,
+                ("extra-bits", Some(bits @ 1..=30), None) => ReputationAction::ExtraBits(bits),
+                ("quota", None, Some(max)) => ReputationAction::Quota(max),
+                ("extra-bits", Some(bits), None) => {
+                    return Err(format!(
+                        "reputation source {name}: extra_bits {bits} is outside 1..=30"
+                    ))
+                }
+                _ => {
+                    return Err(format!(
+                        "reputation source {name}: action must be block, extra-bits with \
+                         extra_bits, or quota with invites_per_ip"
+                    ))
+                }
+            };
+            let cache = match location {
+                SourceLocation::Url(_) => Some(source.cache.clone().unwrap_or_else(|| {
+                    Path::new(DEFAULT_REPUTATION_CACHE_DIR).join(format!("{name}.txt"))
+                })),
+                SourceLocation::File(_) => None,
+            };
+            let refresh = match source.refresh_minutes {
+                Some(0) => {
+                    return Err(format!(
+                        "reputation source {name}: refresh_minutes must be at least 1"
+                    ))
+                }
+                Some(minutes) => Duration::from_secs(minutes * 60),
+                None => reputation::DEFAULT_REFRESH,
+            };
+            sources.push(SourceSettings {
+                name: name.clone(),
+                location,
+                action,
+                cache,
+                min_entries: source.min_entries.unwrap_or(1),
+                fail_closed: source.fail_closed,
+                refresh,
+            });
+        }
+        Ok(sources)
+    }
+
+    pub fn pow_key_file(&self) -> PathBuf {
+        self.pow
+            .key_file
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_POW_KEY_FILE))
+    }
+
+    pub fn pow_used_file(&self) -> PathBuf {
+        self.pow.used_file.clone().unwrap_or_else(|| {
+            PathBuf::from(match self.rate_limit_backend() {
+                Ok(StoreBackend::Redb) => DEFAULT_POW_USED_DB,
+                _ => DEFAULT_POW_USED_FILE,
+            })
+        })
+    }
+
+    pub fn pow_algorithm(&self) -> Result<PowAlgorithm, String> {
+        match &self.pow.algorithm {
+            Some(name) => name.parse(),
+            None => Ok(PowAlgorithm::default()),
+        }
+    }
+
+    pub fn pow_accepted(&self) -> Result<Vec<PowAlgorithm>, String> {
+        self.pow.accept.iter().map(|name| name.parse()).collect()
+    }
+
+    pub fn argon2_params(&self) -> Argon2Params {
+        let defaults = Argon2Params::default();
+        Argon2Params {
+            memory_kib: self.pow.argon2_memory_kib.unwrap_or(defaults.memory_kib),
+            iterations: self.pow.argon2_iterations.unwrap_or(defaults.iterations),
+            parallelism: self.pow.argon2_parallelism.unwrap_or(defaults.parallelism),
+        }
+    }
+
+    pub fn pow_attempts_per_second(&self) -> f64 {
+        self.pow.attempts_per_second.unwrap_or_else(|| {
+            self.pow_algorithm()
+                .unwrap_or_default()
+                .browser_attempts_per_second()
+        })
+    }
+
+    /// Base difficulty: `pow.difficulty` if set, otherwise `pow.target_ms`
+    /// converted to bits. SHA-256 keeps its historical default of 16 bits;
+    /// Argon2id is calibrated to [`DEFAULT_POW_TARGET_MS`].
+    pub fn pow_difficulty(&self) -> u8 {
+        if let Some(bits) = self.pow.difficulty {
+            return bits;
+        }
+        let target_ms = match (self.pow.target_ms, self.pow_algorithm()) {
+            (Some(ms), _) => ms,
+            (None, Ok(PowAlgorithm::Argon2id)) => DEFAULT_POW_TARGET_MS,
+            _ => return DEFAULT_POW_DIFFICULTY,
+        };
+        difficulty_for(target_ms, self.pow_attempts_per_second())
+    }
+
+    /// Whether any invite setting was given. Invites are optional, but half a
+    /// configuration is a mistake worth failing on rather than silently
+    /// running without the endpoint.
+    pub fn invite_requested(&self) -> bool {
+        self.invite.signing_key_file.is_some()
+            || self.invite.owner_vk.is_some()
+            || !self.invite.rooms.is_empty()
+    }
+
+    /// Every configured room: the one from the flat `[invite]` keys, if both
+    /// are given, followed by `[[invite.rooms]]` in file order.
+    pub fn invite_rooms(&self) -> Vec<RoomConfig> {
+        let legacy = match (&self.invite.signing_key_file, &self.invite.owner_vk) {
+            (Some(signing_key_file), Some(owner_vk)) => Some(RoomConfig {
+                slug: DEFAULT_ROOM_SLUG.to_string(),
+                name: self.room_name(),
+                signing_key_file: signing_key_file.clone(),
+                owner_vk: owner_vk.clone(),
+                invites_per_ip: None,
+                invites_per_hour: None,
+                rate_limit_file: None,
+            }),
+            _ => None,
+        };
+        legacy
+            .into_iter()
+            .chain(self.invite.rooms.iter().cloned())
+            .collect()
+    }
+
+    /// The room a request without `?room=` is served from.
+    pub fn default_room(&self) -> Option<String> {
+        self.invite
+            .default_room
+            .clone()
+            .or_else(|| self.invite_rooms().first().map(|room| room.slug.clone()))
+    }
+
+    /// Where a room keeps its per-IP limiter state. The flat `[invite]` room
+    /// keeps using `rate_limit.file` itself so upgrading does not reset it.
+    pub fn room_rate_limit_file(&self, room: &RoomConfig) -> PathBuf {
+        if let Some(file) = &room.rate_limit_file {
+            return file.clone();
+        }
+        let base = self.rate_limit_file();
+        if room.slug == DEFAULT_ROOM_SLUG {
+            return base;
+        }
+        let stem = base
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name = match base.extension() {
+            Some(ext) => format!("{stem}.{}.{}", room.slug, ext.to_string_lossy()),
+            None => format!("{stem}.{}", room.slug),
+        };
+        base.with_file_name(name)
+    }
+
+    pub fn master_verifying_key(&self) -> Result<VerifyingKey, String> {
+        let encoded = self
+            .notary
+            .master_verifying_key
+            .as_deref()
+            .unwrap_or(FREENET_MASTER_VERIFYING_KEY_BASE64);
+        VerifyingKey::from_base64(encoded)
+            .map_err(|e| format!("master verifying key is not valid: {e}"))
+    }
+
+    pub fn proxy_trust(&self) -> Result<ProxyTrust, String> {
+        let header = match &self.server.forwarded_header {
+            Some(name) => name.parse()?,
+            None => ForwardedHeader::default(),
+        };
+        ProxyTrust::parse(&self.server.trusted_proxies, header)
+    }
+
+    pub fn operators(&self) -> Result<Operators, String> {
+        Operators::parse(&self.server.operator_addresses)
+    }
+
+    /// ACME settings, or None when `[acme]` names no domains.
+    pub fn acme(&self) -> Result<Option<AcmeSettings>, String> {
+        let acme = &self.acme;
+        if acme.domains.is_empty() {
+            return Ok(None);
+        }
+        if self.tls.cert.is_some() || self.tls.key.is_some() {
+            return Err(
+                "acme.domains and tls.cert/tls.key both supply the certificate; keep one"
+                    .to_string(),
+            );
+        }
+        let mut domains = Vec::new();
+        for domain in &acme.domains {
+            let domain = domain.to_ascii_lowercase();
+            if domain.starts_with("*.") {
+                return Err(format!(
+                    "acme domain {domain}: HTTP-01 cannot validate a wildcard"
+                ));
+            }
+            let plain = !domain.is_empty()
+                && domain
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.');
+            if !plain || domain.starts_with('.') || domain.ends_with('.') {
+                return Err(format!("acme domain {domain:?} is not a DNS name"));
+            }
+            if !domains.contains(&domain) {
+                domains.push(domain);
+            }
+        }
+        let directory = acme
+            .directory
+            .clone()
+            .unwrap_or_else(|| acme::LETS_ENCRYPT_DIRECTORY.to_string());
+        if !directory.starts_with("https://") {
+            return Err(format!(
+                "acme.directory {directory:?} must be an https:// URL"
+            ));
+        }
+        let contact = acme
+            .contact
+            .iter()
+            .map(|contact| match contact.strip_prefix("mailto:") {
+                Some(address) if address.contains('@') => Ok(contact.clone()),
+                None if contact.contains('@') => Ok(format!("mailto:{contact}")),
+                _ => Err(format!("acme contact {contact:?} is not an email address")),
+            })
+            .collect::<Result<_, _>>()?;
+        let renew_days = acme
+            .renew_days_before
+            .unwrap_or(DEFAULT_ACME_RENEW_DAYS_BEFORE);
+        // Let's Encrypt certificates last 90 days.
+        if !(1..=60).contains(&renew_days) {
+            return Err(format!(
+                "acme.renew_days_before is {renew_days}; use 1 to 60"
+            ));
+        }
+        Ok(Some(AcmeSettings {
+            directory,
+            domains,
+            contact,
+            cache_dir: acme
+                .cache_dir
+                .clone()
+                .unwrap_or_else(|| PathBuf::from(DEFAULT_ACME_CACHE_DIR)),
+            renew_before: chrono::Duration::days(i64::from(renew_days)),
+            ca_file: acme.ca_file.clone(),
+        }))
+    }
+
+    /// Where the API is served: `server.listen`, or 0.0.0.0 on the port.
+    pub fn listen(&self) -> Result<Vec<Listen>, String> {
+        if self.server.listen.is_empty() {
+            let https = (self.tls.cert.is_some() && self.tls.key.is_some())
+                || !self.acme.domains.is_empty();
+            let port = self.server.port.unwrap_or(if https { 443 } else { 8000 });
+            return Ok(vec![Listen::Tcp(SocketAddr::from((
+                Ipv4Addr::UNSPECIFIED,
+                port,
+            )))]);
+        }
+        self.server
+            .listen
+            .iter()
+            .map(|entry| entry.parse())
+            .collect()
+    }
+
+    /// Where HTTP-01 challenges are answered: `server.challenge_listen`, or
+    /// 0.0.0.0:80.
+    pub fn challenge_listen(&self) -> Result<Vec<Listen>, String> {
+        if self.server.challenge_listen.is_empty() {
+            return Ok(vec![Listen::Tcp(SocketAddr::from((
+                Ipv4Addr::UNSPECIFIED,
+                80,
+            )))]);
+        }
+        self.server
+            .challenge_listen
+            .iter()
+            .map(|entry| entry.parse())
+            .collect()
+    }
+
+    /// Whether the challenge listener runs at all.
+    pub fn serves_challenges(&self) -> bool {
+        self.server.challenge_dir.is_some() || !self.acme.domains.is_empty()
+    }
+
+    pub fn shutdown_grace(&self) -> Duration {
+        self.server
+            .shutdown_grace_seconds
+            .map_or(shutdown::DEFAULT_GRACE, Duration::from_secs)
+    }
+
+    pub fn log_settings(&self) -> Result<LogSettings, String> {
+        let mut settings = LogSettings::default();
+        if let Some(format) = &self.logging.format {
+            settings.format = format.parse()?;
+        }
+        if let Some(level) = &self.logging.level {
+            logging::parse_level(level)?;
+            settings.level = level.clone();
+        }
+        Ok(settings)
+    }
+
+    /// Request throttling: the built-in route limits with `[throttle]` laid
+    /// over them.
+    pub fn throttle(&self) -> Result<Throttle, String> {
+        let max_body_bytes = self
+            .throttle
+            .max_body_bytes
+            .unwrap_or(throttle::DEFAULT_MAX_BODY_BYTES);
+        if max_body_bytes == 0 {
+            return Err("throttle.max_body_bytes must be positive".to_string());
+        }
+        let mut routes = throttle::default_routes();
+        for (path, route) in &self.throttle.routes {
+            if !path.starts_with('/') {
+                return Err(format!(
+                    "throttle route {path:?} must be a path as routed, starting with /"
+                ));
+            }
+            let limit = routes.entry(path.clone()).or_insert(RouteLimit {
+                per_minute: 0,
+                burst: route.per_minute.unwrap_or(0),
+                max_body_bytes: None,
+            });
+            if let Some(per_minute) = route.per_minute {
+                limit.per_minute = per_minute;
+            }
+            if let Some(burst) = route.burst {
+                limit.burst = burst;
+            }
+            if route.max_body_bytes.is_some() {
+                limit.max_body_bytes = route.max_body_bytes;
+            }
+            if limit.per_minute > 0 && limit.burst == 0 {
+                return Err(format!(
+                    "throttle route {path}: burst must be positive, or per_minute 0 to turn the limit off"
+                ));
+            }
+            if limit.max_body_bytes == Some(0) {
+                return Err(format!(
+                    "throttle route {path}: max_body_bytes must be positive"
+                ));
+            }
+        }
+        Ok(Throttle::new(routes, max_body_bytes))
+    }
+
+    /// The `gknotary` client, if signing is delegated to one.
+    pub fn remote_signer(&self) -> Result<Option<RemoteSigner>, String> {
+        match (
+            &self.notary.signer_socket,
+            &self.notary.signer_auth_key_file,
+        ) {
+            (None, None) => Ok(None),
+            (Some(socket), Some(key_file)) => {
+                let auth_key = notary_signer::load_auth_key(key_file)?;
+                Ok(Some(RemoteSigner::new(socket.clone(), auth_key)))
+            }
+            _ => Err(
+                "notary.signer_socket and notary.signer_auth_key_file must be given together"
+                    .to_string(),
+            ),
+        }
+    }
+
+    /// The Stripe secret key, from `payment.secret_key_file` if configured and
+    /// otherwise from `STRIPE_SECRET_KEY`.
+    pub fn stripe_secret_key(&self) -> Result<String, String> {
+        match &self.payment.secret_key_file {
+            Some(path) => fs::read_to_string(path)
+                .map(|key| key.trim().to_string())
+                .map_err(|e| format!("could not read {}: {e}", path.display())),
+            None => std::env::var("STRIPE_SECRET_KEY")
+                .map_err(|_| "STRIPE_SECRET_KEY is not set".to_string()),
+        }
+    }
+
+    /// Validate everything that can be validated without serving a request.
+    pub fn check(&self) -> Report {
+        let mut report = Report::default();
+        self.check_logging(&mut report);
+        self.check_server(&mut report);
+        self.check_notaries(&mut report);
+        self.check_tls(&mut report);
+        self.check_acme(&mut report);
+        self.check_payment(&mut report);
+        self.check_invite(&mut report);
+        self.check_reputation(&mut report);
+        self.check_throttle(&mut report);
+        self.check_transparency(&mut report);
+        report
+    }
+
+    fn check_logging(&self, report: &mut Report) {
+        if let Err(e) = self.log_settings() {
+            report.error("logging", e);
+        }
+    }
+
+    fn check_server(&self, report: &mut Report) {
+        if self.server.shutdown_grace_seconds == Some(0) {
+            report.warning(
+                "server",
+                "server.shutdown_grace_seconds is 0; a restart cuts off requests in flight, \
+                 certificate signing included",
+            );
+        }
+        self.check_listeners(report);
+        if let Err(e) = self.proxy_trust() {
+            return report.error("server", e);
+        }
+        if let Err(e) = self.operators() {
+            return report.error("server", e);
+        }
+        if self.server.trusted_proxies.is_empty() {
+            if self.server.proxy_protocol {
+                report.error(
+                    "server",
+                    "server.proxy_protocol needs server.trusted_proxies; no peer would be read from",
+                );
+            }
+            return;
+        }
+        report.ok(
+            "server",
+            format!(
+                "client addresses taken from {} trusted prox{}",
+                self.server.trusted_proxies.len(),
+                if self.server.trusted_proxies.len() == 1 {
+                    "y"
+                } else {
+                    "ies"
+                }
+            ),
+        );
+    }
+
+    fn check_listeners(&self, report: &mut Report) {
+        if !self.server.listen.is_empty() && self.server.port.is_some() {
+            report.error(
+                "server",
+                "server.port (or --port) and server.listen are both set; give the port in each \
+                 listen address instead",
+            );
+        }
+        if !self.server.challenge_listen.is_empty() && !self.serves_challenges() {
+            report.warning(
+                "server",
+                "server.challenge_listen is set, but with neither server.challenge_dir nor \
+                 [acme] there is no challenge listener",
+            );
+        }
+        let challenge = if self.serves_challenges() {
+            self.challenge_listen()
+        } else {
+            Ok(Vec::new())
+        };
+        let (main, challenge) = match (self.listen(), challenge) {
+            (Ok(main), Ok(challenge)) => (main, challenge),
+            (Err(e), _) | (_, Err(e)) => return report.error("server", e),
+        };
+        if let Err(e) = listen::check_lists(&main, &challenge) {
+            return report.error("server", e);
+        }
+        let names = |list: &[Listen]| {
+            list.iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        report.ok("server", format!("listening on {}", names(&main)));
+        if !challenge.is_empty() {
+            report.ok(
+                "server",
+                format!("answering HTTP-01 challenges on {}", names(&challenge)),
+            );
+        }
+    }
+
+    fn check_notaries(&self, report: &mut Report) {
+        let Some(dir) = &self.notary.dir else {
+            report.error(
+                "notary",
+                "no notary directory configured (notary.dir / --notary-dir)",
+            );
+            return;
+        };
+        let master_vk = match self.master_verifying_key() {
+            Ok(vk) => vk,
+            Err(e) => return report.error("notary", e),
+        };
+        let amounts = match delegates::tier_amounts(dir) {
+            Ok(amounts) => amounts,
+            Err(e) => {
+                return report.error(
+                    "notary",
+                    format!("cannot read notary directory {}: {e}", dir.display()),
+                )
+            }
+        };
+        if amounts.is_empty() {
+            report.warning(
+                "notary",
+                format!(
+                    "no notary certificates in {}; every donation will fail",
+                    dir.display()
+                ),
+            );
+        }
+        let signer = match self.remote_signer() {
+            Ok(signer) => signer,
+            Err(e) => return report.error("notary", e),
+        };
+        if let Some(signer) = &signer {
+            if !signer.socket().exists() {
+                report.warning(
+                    "notary",
+                    format!(
+                        "gknotary socket {} does not exist yet; signing fails until it does",
+                        signer.socket().display()
+                    ),
+                );
+            }
+            if delegates::has_signing_keys(dir).unwrap_or(false) {
+                report.warning(
+                    "notary",
+                    format!(
+                        "{} still holds notary signing keys although gknotary signs; \
+                         move them out of gkapi's reach",
+                        dir.display()
+                    ),
+                );
+            }
+        }
+        for amount in amounts {
+            let verified = if signer.is_some() {
+                delegates::load_notary_certificate(dir, amount)
+                    .map_err(|e| e.to_string())
+                    .and_then(|cert| {
+                        cert.verify(&Some(master_vk)).map_err(|e| {
+                            format!("certificate does not chain to the master key: {e}")
+                        })
+                    })
+                    .map(|_| ())
+            } else {
+                delegates::load_notary(dir, amount)
+                    .map_err(|e| e.to_string())
+                    .and_then(|(cert, key)| delegates::verify_notary(&cert, &key, &master_vk))
+            };
+            match verified {
+                Ok(()) => report.ok("notary", format!("${amount} tier verified")),
+                Err(e) => report.error("notary", format!("${amount} tier: {e}")),
+            }
+        }
+    }
+
+    fn check_tls(&self, report: &mut Report) {
+        match (&self.tls.cert, &self.tls.key) {
+            (None, None) if !self.acme.domains.is_empty() => {}
+            (None, None) => report.warning("tls", "no TLS certificate; serving plain HTTP"),
+            (Some(_), None) | (None, Some(_)) => {
+                report.error("tls", "tls.cert and tls.key must be given together")
+            }
+            (Some(cert), Some(key)) => match check_tls_pair(cert, key) {
+                Ok(()) => report.ok("tls", "certificate and key load and match"),
+                Err(e) => report.error("tls", e),
+            },
+        }
+    }
+
+    fn check_acme(&self, report: &mut Report) {
+        let settings = match self.acme() {
+            Ok(Some(settings)) => settings,
+            Ok(None) => return,
+            Err(e) => return report.error("acme", e),
+        };
+        // The account key and certificate are created inside it.
+        let probe = if settings.cache_dir.is_dir() {
+            settings.cache_dir.join("account.key")
+        } else {
+            settings.cache_dir.clone()
+        };
+        if let Err(e) = check_writable_parent(&probe) {
+            return report.error("acme", e);
+        }
+        if let Some(ca_file) = &settings.ca_file {
+            if let Err(e) = fs::read(ca_file) {
+                return report.error("acme", format!("{}: {e}", ca_file.display()));
+            }
+        }
+        if settings.contact.is_empty() {
+            report.warning(
+                "acme",
+                "no acme.contact; the CA cannot warn about a certificate that is not renewed",
+            );
+        }
+        report.ok(
+            "acme",
+            format!(
+                "certificates for {} from {}",
+                settings.domains.join(", "),
+                settings.directory
+            ),
+        );
+    }
+
+    fn check_payment(&self, report: &mut Report) {
+        if self.payment.provider != "stripe" {
+            return report.error(
+                "payment",
+                format!(
+                    "unknown payment provider {:?}; only \"stripe\" is supported",
+                    self.payment.provider
+                ),
+            );
+        }
+        // A missing key only disables donations; invites and the challenge
+        // server still work, so it is not worth refusing to start over.
+        match self.stripe_secret_key() {
+            Ok(key) if key.starts_with("sk_") || key.starts_with("rk_") => {
+                report.ok("payment", "Stripe secret key present")
+            }
+            Ok(_) => report.warning(
+                "payment",
+                "Stripe secret key does not start with sk_ or rk_; donations will likely fail",
+            ),
+            Err(e) => report.warning("payment", format!("{e}; donation endpoints will fail")),
+        }
+    }
+
+    fn check_invite(&self, report: &mut Report) {
+        if !self.invite_requested() {
+            return;
+        }
+        if self.invite.signing_key_file.is_some() || self.invite.owner_vk.is_some() {
+            self.check_flat_room(report);
+        }
+        let rooms = self.invite_rooms();
+        let mut slugs = HashSet::new();
+        for room in &rooms {
+            if !valid_room_slug(&room.slug) {
+                report.error(
+                    "invite",
+                    format!(
+                        "room slug {:?} must be lowercase letters, digits and '-'",
+                        room.slug
+                    ),
+                );
+            }
+            if !slugs.insert(room.slug.as_str()) {
+                report.error(
+                    "invite",
+                    format!("room slug {:?} is used more than once", room.slug),
+                );
+            }
+            if room.invites_per_ip == Some(0) {
+                report.error(
+                    "invite",
+                    format!(
+                        "room {}: invites_per_ip of 0 refuses every invite",
+                        room.slug
+                    ),
+                );
+            }
+            if let Err(e) = check_writable_parent(&self.room_rate_limit_file(room)) {
+                report.error("rate_limit", format!("room {}: {e}", room.slug));
+            }
+        }
+        for room in &self.invite.rooms {
+            if let Err(e) = load_room_signing_key(&room.signing_key_file) {
+                report.error("invite", format!("room {}: {e}", room.slug));
+            }
+            if let Err(e) = parse_room_owner_vk(&room.owner_vk) {
+                report.error("invite", format!("room {}: {e}", room.slug));
+            }
+        }
+        if let Some(default) = &self.invite.default_room {
+            if !rooms.iter().any(|room| &room.slug == default) {
+                report.error(
+                    "invite",
+                    format!("default_room {default:?} is not a configured room"),
+                );
+            }
+        }
+        match self.rate_limit_backend() {
+            Err(e) => report.error("rate_limit", e),
+            Ok(backend) if !backend.uses_files() => report.warning(
+                "rate_limit",
+                "rate_limit.backend is \"memory\"; every limit and used challenge is forgotten \
+                 on restart",
+            ),
+            Ok(_) => {}
+        }
+        if let Some(limit) = self.prefix_limit() {
+            if limit.ipv4_prefix > 32 || limit.ipv6_prefix > 128 {
+                report.error(
+                    "rate_limit",
+                    format!(
+                        "prefix lengths /{} and /{} must be at most /32 and /128",
+                        limit.ipv4_prefix, limit.ipv6_prefix
+                    ),
+                );
+            }
+            let per_ip = rooms
+                .iter()
+                .map(|room| room.invites_per_ip.unwrap_or(MAX_INVITES_PER_WINDOW))
+                .max();
+            if let Some(per_ip) = per_ip {
+                if limit.max_per_window < per_ip {
+                    report.warning(
+                        "rate_limit",
+                        format!(
+                            "invites_per_prefix {} is below a room's invites_per_ip {per_ip}; \
+                             a single address cannot use its allowance",
+                            limit.max_per_window
+                        ),
+                    );
+                }
+            }
+        }
+        if let Err(e) = check_writable_parent(&self.ghostkey_rate_limit_file()) {
+            report.error("rate_limit", e);
+        }
+        // Invitations are refused, not issued unrecorded, when the ledger
+        // cannot be written.
+        if let Err(e) = check_writable_parent(&self.invite_ledger()) {
+            report.error("invite", e);
+        }
+        // A policy that does not parse refuses invitations rather than
+        // dropping its bans.
+        if let Err(e) = PolicyFile::read(&self.access_policy_file()) {
+            report.error("invite", e.to_string());
+        }
+        self.check_pow(report);
+        // The Tor cache is an optimisation for restarts; losing it is logged
+        // at runtime and costs at most one fetch.
+        if let Err(e) = check_writable_parent(&self.tor_exit_cache()) {
+            report.warning("tor", e);
+        }
+        match self.tor_source() {
+            Ok(TorSource::Consensus { authorities, .. }) => report.ok(
+                "tor",
+                format!(
+                    "exits read from a consensus signed by a majority of {} authorities",
+                    authorities.len()
+                ),
+            ),
+            Ok(TorSource::BulkList(_)) => {}
+            Err(e) => report.error("tor", e),
+        }
+    }
+
+    fn check_pow(&self, report: &mut Report) {
+        let algorithm = match self.pow_algorithm() {
+            Ok(algorithm) => algorithm,
+            Err(e) => return report.error("pow", e),
+        };
+        let accepted = match self.pow_accepted() {
+            Ok(accepted) => accepted,
+            Err(e) => return report.error("pow", format!("pow.accept: {e}")),
+        };
+        if algorithm == PowAlgorithm::Argon2id || accepted.contains(&PowAlgorithm::Argon2id) {
+            if let Err(e) = self.argon2_params().validate() {
+                return report.error("pow", e);
+            }
+        }
+        let rate = self.pow_attempts_per_second();
+        if !(rate.is_finite() && rate > 0.0) {
+            return report.error("pow", "attempts_per_second must be positive");
+        }
+        if self.pow.difficulty.is_some() && self.pow.target_ms.is_some() {
+            report.warning("pow", "pow.difficulty is set, so pow.target_ms is ignored");
+        }
+        let key_file = self.pow_key_file();
+        let key_check = if key_file.exists() {
+            PowKeys::read(&key_file).map(|_| ())
+        } else {
+            check_writable_parent(&key_file)
+        };
+        if let Err(e) = key_check {
+            report.error("pow", e);
+        }
+        if let Err(e) = check_writable_parent(&self.pow_used_file()) {
+            report.error("pow", e);
+        }
+        let bits = self.pow_difficulty();
+        if !(1..=30).contains(&bits) {
+            return report.error("pow", format!("difficulty {bits} is outside 1..=30"));
+        }
+        let settings = self.difficulty_settings();
+        if settings.target_per_hour == 0 {
+            return report.error("pow", "target_invites_per_hour must be at least 1");
+        }
+        if u32::from(bits) + u32::from(settings.max_extra_bits) > 30 {
+            report.warning(
+                "pow",
+                format!(
+                    "difficulty {bits} plus max_extra_bits {} exceeds 30; load adds at most {}",
+                    settings.max_extra_bits,
+                    30 - bits
+                ),
+            );
+        }
+        report.ok(
+            "pow",
+            format!(
+                "{algorithm} at {bits} bits, about {} ms in a browser",
+                expected_ms(bits, rate)
+            ),
+        );
+    }
+
+    fn check_reputation(&self, report: &mut Report) {
+        let sources = match self.reputation_sources() {
+            Ok(sources) => sources,
+            Err(e) => return report.error("reputation", e),
+        };
+        if sources.is_empty() {
+            return;
+        }
+        for source in &sources {
+            match (&source.location, &source.cache) {
+                // A missing file leaves the source inactive, or refusing
+                // invitations if it fails closed.
+                (SourceLocation::File(path), _) => {
+                    if let Err(e) = fs::metadata(path) {
+                        let message = format!(
+                            "reputation source {}: {} is not readable: {e}",
+                            source.name,
+                            path.display()
+                        );
+                        if source.fail_closed {
+                            report.error("reputation", message);
+                        } else {
+                            report.warning("reputation", message);
+                        }
+                    }
+                }
+                (SourceLocation::Url(_), Some(cache)) => {
+                    if let Err(e) = check_writable_parent(cache) {
+                        report.warning(
+                            "reputation",
+                            format!("reputation source {}: {e}", source.name),
+                        );
+                    }
+                }
+                (SourceLocation::Url(_), None) => {}
+            }
+        }
+        let names: Vec<String> = sources
+            .iter()
+            .map(|source| format!("{} ({})", source.name, source.action))
+            .collect();
+        report.ok("reputation", names.join(", "));
+    }
+
+    /// The room configured by the flat `[invite]` keys / `--room-*` flags.
+    fn check_flat_room(&self, report: &mut Report) {
+        match &self.invite.signing_key_file {
+            Some(path) => {
+                if let Err(e) = load_room_signing_key(path) {
+                    report.error("invite", e);
+                }
+            }
+            None => report.error(
+                "invite",
+                "invite.owner_vk is set but signing_key_file is not",
+            ),
+        }
+        match &self.invite.owner_vk {
+            Some(vk) => {
+                if let Err(e) = parse_room_owner_vk(vk) {
+                    report.error("invite", e);
+                }
+            }
+            None => report.error(
+                "invite",
+                "invite.signing_key_file is set but owner_vk is not",
+            ),
+        }
+    }
+
+    fn check_throttle(&self, report: &mut Report) {
+        if let Err(e) = self.throttle() {
+            report.error("throttle", e);
+        }
+    }
+
+    fn check_transparency(&self, report: &mut Report) {
+        let log = self.issuance_log();
+        // Signing refuses to proceed without a log entry, so an unwritable log
+        // is as fatal as a missing notary key.
+        if let Err(e) = check_writable_parent(&log) {
+            return report.error("transparency", e);
+        }
+        match issuance_log::checkpoint_of(&log) {
+            Ok(checkpoint) => report.ok(
+                "transparency",
+                format!("issuance log verified ({} entries)", checkpoint.entries),
+            ),
+            Err(e) => report.error("transparency", e.to_string()),
+        }
+        let Ok(master_vk) = self.master_verifying_key() else {
+            return;
+        };
+        let (_, rejected) = issuance_log::read_checkpoints(&self.checkpoints_file(), &master_vk);
+        for reason in rejected {
+            report.warning(
+                "transparency",
+                format!(
+                    "{} {reason}; it will not be published",
+                    self.checkpoints_file().display()
+                ),
+            );
+        }
+    }
+}
+
+/// Slugs appear in URLs and file names, so keep them to a safe alphabet.
+fn valid_room_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug
+            .bytes()
+            .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b == b'-')
+}
+
+/// Read a room member signing key: exactly 32 raw bytes.
+pub fn load_room_signing_key(path: &Path) -> Result<SigningKey, String> {
+    let bytes = fs::read(path).map_err(|e| {
+        format!(
+            "failed to read room signing key from {}: {e}",
+            path.display()
+        )
+    })?;
+    let bytes: [u8; 32] = bytes.as_slice().try_into().map_err(|_| {
+        format!(
+            "room signing key must be exactly 32 bytes, got {}",
+            bytes.len()
+        )
+    })?;
+    Ok(SigningKey::from_bytes(&bytes))
+}
+
+/// Parse a base58 room owner verifying key.
+pub fn parse_room_owner_vk(encoded: &str) -> Result<VerifyingKey, String> {
+    let bytes = bs58::decode(encoded)
+        .into_vec()
+        .map_err(|e| format!("room owner verifying key is not valid base58: {e}"))?;
+    let bytes: [u8; 32] = bytes.as_slice().try_into().map_err(|_| {
+        format!(
+            "room owner verifying key must be exactly 32 bytes, got {}",
+            bytes.len()
+        )
+    })?;
+    VerifyingKey::from_bytes(&bytes)
+        .map_err(|e| format!("room owner verifying key is not a valid key: {e}"))
+}
+
+/// Load a PEM certificate chain and private key and confirm rustls accepts
+/// them as a pair. Catches the classic renewal mistake of pointing at a new
+/// `fullchain.pem` with an old `privkey.pem`.
+pub fn check_tls_pair(cert: &Path, key: &Path) -> Result<(), String> {
+    read_tls_pair(cert, key).map(|_| ())
+}
+
+/// [`check_tls_pair`], returning the PEM bytes it checked so they can be
+/// loaded without reading the files again.
+pub fn read_tls_pair(cert: &Path, key: &Path) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let cert_pem = fs::read(cert).map_err(|e| format!("{}: {e}", cert.display()))?;
+    let key_pem = fs::read(key).map_err(|e| format!("{}: {e}", key.display()))?;
+    let certs = rustls_pemfile::certs(&mut cert_pem.as_slice())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| format!("{}: {e}", cert.display()))?;
+    if certs.is_empty() {
+        return Err(format!("{} contains no certificates", cert.display()));
+    }
+    let private_key = rustls_pemfile::private_key(&mut key_pem.as_slice())
+        .map_err(|e| format!("{}: {e}", key.display()))?
+        .ok_or_else(|| format!("{} contains no private key", key.display()))?;
+    rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, private_key)
+        .map_err(|e| format!("certificate and key do not form a usable pair: {e}"))?;
+    Ok((cert_pem, key_pem))
+}
+
+/// Whether a file could be created or replaced at `path`.
+fn check_writable_parent(path: &Path) -> Result<(), String> {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let metadata =
+        fs::metadata(parent).map_err(|e| format!("{} is not usable: {e}", parent.display()))?;
+    if metadata.permissions().readonly() {
+        return Err(format!("{} is read-only", parent.display()));
+    }
+    Ok(())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+#[derive(Debug)]
+pub struct Finding {
+    pub severity: Severity,
+    pub section: &'static str,
+    pub message: String,
+}
+
+impl fmt::Display for Finding {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let label = match self.severity {
+            Severity::Ok => "ok",
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "[{label}] {}: {}", self.section, self.message)
+    }
+}
+
+/// Outcome of [`Config::check`].
+#[derive(Debug, Default)]
+pub struct Report {
+    pub findings: Vec<Finding>,
+}
+
+impl Report {
+    fn push(&mut self, severity: Severity, section: &'static str, message: impl Into<String>) {
+        self.findings.push(Finding {
+            severity,
+            section,
+            message: message.into(),
+        });
+    }
+
+    fn ok(&mut self, section: &'static str, message: impl Into<String>) {
+        self.push(Severity::Ok, section, message);
+    }
+
+    fn warning(&mut self, section: &'static str, message: impl Into<String>) {
+        self.push(Severity::Warning, section, message);
+    }
+
+    fn error(&mut self, section: &'static str, message: impl Into<String>) {
+        self.push(Severity::Error, section, message);
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.findings
+            .iter()
+            .any(|finding| finding.severity == Severity::Error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ghostkey_lib::notary_certificate::NotaryCertificateV1;
+    use ghostkey_lib::util::create_keypair;
+    use rand_core::OsRng;
+    use tempfile::tempdir;
+
+    fn errors(report: &Report) -> Vec<String> {
+        report
+            .findings
+            .iter()
+            .filter(|f| f.severity == Severity::Error)
+            .map(|f| f.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn parses_a_full_file() {
+        let config = Config::parse(
+            r#"
+            [server]
+            port = 8443
+            challenge_dir = "/srv/acme"
+
+            [tls]
+            cert = "/etc/gkapi/cert.pem"
+            key = "/etc/gkapi/key.pem"
+
+            [notary]
+            dir = "/var/lib/gkapi/notary"
+
+            [payment]
+            provider = "stripe"
+            secret_key_file = "/etc/gkapi/stripe"
+
+            [invite]
+            signing_key_file = "/etc/gkapi/room.key"
+            owner_vk = "93XNNwmRLQ6nwUwi4dDmp3kpjMb5ekMRc2e22x5TAnUY"
+            room_name = "Freenet Official"
+
+            [rate_limit]
+            file = "/var/lib/gkapi/rl.json"
+            global_invites_per_hour = 150
+
+            [pow]
+            difficulty = 18
+
+            [tor]
+            exit_cache = "/var/lib/gkapi/tor.txt"
+            "#,
+        )
+        .unwrap();
+        assert_eq!(config.server.port, Some(8443));
+        assert_eq!(
+            config.notary.dir,
+            Some(PathBuf::from("/var/lib/gkapi/notary"))
+        );
+        assert_eq!(config.rate_limit.global_invites_per_hour, Some(150));
+        assert_eq!(config.pow_difficulty(), 18);
+        assert_eq!(config.room_name(), "Freenet Official");
+    }
+
+    #[test]
+    fn argon2id_difficulty_is_calibrated_in_milliseconds() {
+        let config = Config::parse("[pow]\nalgorithm = \"argon2id\"\n").unwrap();
+        assert_eq!(config.pow_algorithm(), Ok(PowAlgorithm::Argon2id));
+        assert_eq!(
+            config.pow_difficulty(),
+            difficulty_for(DEFAULT_POW_TARGET_MS, 10.0)
+        );
+
+        let config = Config::parse(
+            "[pow]\nalgorithm = \"argon2id\"\naccept = [\"sha256\"]\ntarget_ms = 8000\nattempts_per_second = 4.0\n",
+        )
+        .unwrap();
+        assert_eq!(config.pow_accepted(), Ok(vec![PowAlgorithm::Sha256]));
+        // 8 s at 4/s is 32 attempts.
+        assert_eq!(config.pow_difficulty(), 5);
+
+        // SHA-256 deployments keep their 16 bits unless told otherwise.
+        assert_eq!(Config::parse("").unwrap().pow_difficulty(), 16);
+        assert!(Config::parse("[pow]\nalgorithm = \"scrypt\"\n")
+            .unwrap()
+            .pow_algorithm()
+            .is_err());
+    }
+
+    #[test]
+    fn argon2id_cost_parameters_are_checked() {
+        let mut report = Report::default();
+        Config::parse("[pow]\nalgorithm = \"argon2id\"\n")
+            .unwrap()
+            .check_pow(&mut report);
+        assert!(
+            errors(&report).iter().all(|e| !e.contains("argon2id")),
+            "{:?}",
+            errors(&report)
+        );
+
+        // Also while only accepted, during a switch back.
+        let mut report = Report::default();
+        Config::parse("[pow]\naccept = [\"argon2id\"]\nargon2_memory_kib = 1\n")
+            .unwrap()
+            .check_pow(&mut report);
+        assert!(
+            errors(&report)
+                .iter()
+                .any(|e| e.contains("invalid Argon2id parameters")),
+            "{:?}",
+            errors(&report)
+        );
+    }
+
+    /// A misspelled key must be an error, not a silently ignored setting that
+    /// leaves the default in force.
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(Config::parse("[rate_limit]\nglobal_invites_per_hr = 10\n").is_err());
+        assert!(Config::parse("[ratelimit]\nfile = \"x\"\n").is_err());
+    }
+
+    #[test]
+    fn supplied_flags_override_the_file_and_defaults_do_not() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("gkapi.toml");
+        fs::write(
+            &path,
+            "[notary]\ndir = \"/from/file\"\n[pow]\ndifficulty = 20\n[invite]\nroom_name = \"File Room\"\n",
+        )
+        .unwrap();
+
+        let matches = crate::cli()
+            .try_get_matches_from([
+                "gkapi",
+                "--config",
+                path.to_str().unwrap(),
+                "--notary-dir",
+                "/from/flag",
+                "--master-verifying-key",
+                "bWFzdGVy",
+            ])
+            .unwrap();
+        let config = Config::resolve(&matches).unwrap();
+
+        assert_eq!(config.notary.dir, Some(PathBuf::from("/from/flag")));
+        assert_eq!(
+            config.notary.master_verifying_key.as_deref(),
+            Some("bWFzdGVy")
+        );
+        // --invite-pow-difficulty and --room-name have clap defaults; those
+        // defaults must not clobber what the file says.
+        assert_eq!(config.pow_difficulty(), 20);
+        assert_eq!(config.room_name(), "File Room");
+    }
+
+    #[test]
+    fn check_reports_bad_tiers_and_accepts_good_ones() {
+        let (master, master_vk) = create_keypair(&mut OsRng).unwrap();
+        let (other_master, _) = create_keypair(&mut OsRng).unwrap();
+        let dir = tempdir().unwrap();
+
+        let (cert, key) = NotaryCertificateV1::new(&master, &"$20".to_string()).unwrap();
+        cert.to_file(&dir.path().join("notary_certificate_20.pem"))
+            .unwrap();
+        key.to_file(&dir.path().join("notary_signing_key_20.pem"))
+            .unwrap();
+        let (cert, key) = NotaryCertificateV1::new(&other_master, &"$50".to_string()).unwrap();
+        cert.to_file(&dir.path().join("notary_certificate_50.pem"))
+            .unwrap();
+        key.to_file(&dir.path().join("notary_signing_key_50.pem"))
+            .unwrap();
+        fs::write(dir.path().join("notary_certificate_100.pem"), "junk").unwrap();
+
+        let mut config = Config::default();
+        config.notary.dir = Some(dir.path().to_path_buf());
+        config.notary.master_verifying_key = Some(master_vk.to_base64().unwrap());
+        let mut report = Report::default();
+        config.check_notaries(&mut report);
+
+        let errors = errors(&report);
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert!(errors[0].contains("$50 tier") && errors[0].contains("master key"));
+        assert!(errors[1].contains("$100 tier"));
+        assert!(report
+            .findings
+            .iter()
+            .any(|f| f.severity == Severity::Ok && f.message.contains("$20")));
+    }
+
+    #[test]
+    fn signer_mode_needs_only_certificates() {
+        let (master, master_vk) = create_keypair(&mut OsRng).unwrap();
+        let dir = tempdir().unwrap();
+        let (cert, key) = NotaryCertificateV1::new(&master, &"$20".to_string()).unwrap();
+        cert.to_file(&dir.path().join("notary_certificate_20.pem"))
+            .unwrap();
+        let auth_key = dir.path().join("auth.key");
+        fs::write(&auth_key, [b'k'; 32]).unwrap();
+
+        let mut config = Config::default();
+        config.notary.dir = Some(dir.path().to_path_buf());
+        config.notary.master_verifying_key = Some(master_vk.to_base64().unwrap());
+        config.notary.signer_socket = Some(dir.path().join("gknotary.sock"));
+        let mut report = Report::default();
+        config.check_notaries(&mut report);
+        assert!(
+            errors(&report)[0].contains("must be given together"),
+            "a socket without an auth key is an error"
+        );
+
+        config.notary.signer_auth_key_file = Some(auth_key);
+        let mut report = Report::default();
+        config.check_notaries(&mut report);
+        assert!(!report.has_errors(), "{:?}", errors(&report));
+        assert!(report
+            .findings
+            .iter()
+            .any(|f| f.severity == Severity::Ok && f.message.contains("$20")));
+
+        key.to_file(&dir.path().join("notary_signing_key_20.pem"))
+            .unwrap();
+        let mut report = Report::default();
+        config.check_notaries(&mut report);
+        assert!(report
+            .findings
+            .iter()
+            .any(|f| f.severity == Severity::Warning && f.message.contains("signing keys")));
+    }
+
+    #[test]
+    fn trusted_proxies_are_checked() {
+        let mut config = Config::default();
+        config.server.proxy_protocol = true;
+        let mut report = Report::default();
+        config.check_server(&mut report);
+        assert!(report.has_errors(), "PROXY protocol without proxies");
+
+        config.server.trusted_proxies = vec!["10.0.0.0/8".to_string(), "::1".to_string()];
+        let mut report = Report::default();
+        config.check_server(&mut report);
+        assert!(!report.has_errors(), "{:?}", errors(&report));
+        assert!(config
+            .proxy_trust()
+            .unwrap()
+            .is_trusted("10.2.3.4".parse().unwrap()));
+
+        config.server.forwarded_header = Some("x-real-ip".to_string());
+        let mut report = Report::default();
+        config.check_server(&mut report);
+        assert!(report.has_errors());
+
+        config.server.forwarded_header = Some("Forwarded".to_string());
+        config.server.trusted_proxies.push("10.0.0.300".to_string());
+        let mut report = Report::default();
+        config.check_server(&mut report);
+        assert!(errors(&report)[0].contains("10.0.0.300"));
+    }
+
+    #[test]
+    fn operator_addresses_are_checked() {
+        let mut config = Config::default();
+        config.server.operator_addresses = vec!["192.0.2.0/24".to_string()];
+        let mut report = Report::default();
+        config.check_server(&mut report);
+        assert!(!report.has_errors(), "{:?}", errors(&report));
+        assert!(config
+            .operators()
+            .unwrap()
+            .allows("192.0.2.7".parse().unwrap()));
+
+        config
+            .server
+            .operator_addresses
+            .push("metrics.internal".to_string());
+        let mut report = Report::default();
+        config.check_server(&mut report);
+        assert!(errors(&report)[0].contains("metrics.internal"));
+    }
+
+    #[test]
+    fn rate_limit_files_default_by_backend() {
+        let mut config = Config::default();
+        assert_eq!(
+            config.rate_limit_file(),
+            PathBuf::from(DEFAULT_RATE_LIMIT_FILE)
+        );
+        config.rate_limit.backend = Some("redb".to_string());
+        assert_eq!(
+            config.rate_limit_file(),
+            PathBuf::from(DEFAULT_RATE_LIMIT_DB)
+        );
+        assert_eq!(
+            config.ghostkey_rate_limit_file(),
+            PathBuf::from(DEFAULT_GHOSTKEY_RATE_LIMIT_DB)
+        );
+        assert_eq!(config.pow_used_file(), PathBuf::from(DEFAULT_POW_USED_DB));
+        config.rate_limit.file = Some(PathBuf::from("/srv/limits.redb"));
+        assert_eq!(config.rate_limit_file(), PathBuf::from("/srv/limits.redb"));
+
+        config.rate_limit.backend = Some("sqlite".to_string());
+        config.invite.rooms.push(RoomConfig {
+            slug: "dev".to_string(),
+            name: "Dev".to_string(),
+            signing_key_file: PathBuf::from("/nonexistent"),
+            owner_vk: String::new(),
+            invites_per_ip: None,
+            invites_per_hour: None,
+            rate_limit_file: None,
+        });
+        let mut report = Report::default();
+        config.check_invite(&mut report);
+        assert!(errors(&report).iter().any(|e| e.contains("sqlite")));
+
+        config.rate_limit.backend = Some("memory".to_string());
+        let mut report = Report::default();
+        config.check_invite(&mut report);
+        assert!(!errors(&report).iter().any(|e| e.contains("backend")));
+        assert!(report
+            .findings
+            .iter()
+            .any(|f| f.severity == Severity::Warning && f.message.contains("forgotten")));
+    }
+
+    #[test]
+    fn network_quota_defaults_on_and_can_be_turned_off() {
+        let mut config = Config::default();
+        assert_eq!(config.prefix_limit(), Some(PrefixLimit::default()));
+        config.rate_limit.ipv6_prefix = Some(56);
+        assert_eq!(config.prefix_limit().unwrap().ipv6_prefix, 56);
+        config.rate_limit.invites_per_prefix = Some(0);
+        assert_eq!(config.prefix_limit(), None);
+    }
+
+    #[test]
+    fn tor_consensus_source_needs_both_documents() {
+        assert_eq!(Config::default().tor_source(), Ok(TorSource::default()));
+        let config = Config::parse(
+            "[tor]\nconsensus_url = \"http://128.31.0.39:9131/tor/status-vote/current/consensus\"\n\
+             certificates_file = \"/var/lib/gkapi/tor_keys\"\n",
+        )
+        .unwrap();
+        match config.tor_source().unwrap() {
+            TorSource::Consensus {
+                certificates,
+                authorities,
+                ..
+            } => {
+                assert_eq!(
+                    certificates,
+                    SourceLocation::File(PathBuf::from("/var/lib/gkapi/tor_keys"))
+                );
+                assert_eq!(authorities.len(), DIRECTORY_AUTHORITIES.len());
+            }
+            other => panic!("expected a consensus source, got {other:?}"),
+        }
+        assert!(Config::parse("[tor]\nconsensus_file = \"/x\"\n")
+            .unwrap()
+            .tor_source()
+            .is_err());
+        assert!(Config::parse(
+            "[tor]\nconsensus_file = \"/x\"\ncertificates_file = \"/y\"\nauthorities = [\"abc\"]\n"
+        )
+        .unwrap()
+        .tor_source()
+        .is_err());
+    }
+
+    #[test]
+    fn shutdown_grace_defaults_and_warns_at_zero() {
+        assert_eq!(
+            Config::parse("").unwrap().shutdown_grace(),
+            shutdown::DEFAULT_GRACE
+        );
+        let config = Config::parse("[server]\nshutdown_grace_seconds = 0").unwrap();
+        assert_eq!(config.shutdown_grace(), Duration::ZERO);
+        let mut report = Report::default();
+        config.check_server(&mut report);
+        assert!(report
+            .findings
+            .iter()
+            .any(|f| f.severity == Severity::Warning && f.message.contains("cuts off")));
+    }
+
+    #[test]
+    fn listeners_default_to_the_port_and_are_checked() {
+        let tcp = |s: &str| Listen::Tcp(s.parse().unwrap());
+        let config = Config::parse("").unwrap();
+        assert_eq!(config.listen().unwrap(), [tcp("0.0.0.0:8000")]);
+        assert_eq!(config.challenge_listen().unwrap(), [tcp("0.0.0.0:80")]);
+        // The port alone is given through challenge_listen.
+        assert!(Config::parse("[server]\nchallenge_port = 5002").is_err());
+        let config = Config::parse("[tls]\ncert = \"c.pem\"\nkey = \"k.pem\"").unwrap();
+        assert_eq!(config.listen().unwrap(), [tcp("0.0.0.0:443")]);
+
+        let config = Config::parse(
+            r#"
+            [server]
+            listen = ["[::]:443", "unix:/run/gkapi/gkapi.sock", "systemd:https"]
+            challenge_dir = "/var/www/acme"
+            challenge_listen = ["[::]:80"]
+            "#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.listen().unwrap(),
+            [
+                tcp("[::]:443"),
+                Listen::Unix(PathBuf::from("/run/gkapi/gkapi.sock")),
+                Listen::Systemd(Some("https".to_string())),
+            ]
+        );
+        assert_eq!(config.challenge_listen().unwrap(), [tcp("[::]:80")]);
+        let mut report = Report::default();
+        config.check_server(&mut report);
+        assert!(errors(&report).is_empty(), "{:?}", errors(&report));
+
+        for bad in [
+            "[server]\nlisten = [\"localhost:443\"]",
+            "[server]\nport = 443\nlisten = [\"[::]:443\"]",
+            "[server]\nlisten = [\"[::]:443\", \"[::]:443\"]",
+            "[server]\nchallenge_dir = \"/w\"\nlisten = [\"systemd\"]\nchallenge_listen = [\"systemd\"]",
+        ] {
+            let mut report = Report::default();
+            Config::parse(bad).unwrap().check_server(&mut report);
+            assert!(
+                errors(&report)
+                    .iter()
+                    .any(|e| e.starts_with("[error] server:")),
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn acme_settings_are_normalised_and_checked() {
+        let config = Config::parse(
+            r#"
+            [acme]
+            domains = ["GKAPI.example.org", "gkapi.example.org", "api.example.org"]
+            contact = ["ops@example.org", "mailto:oncall@example.org"]
+            "#,
+        )
+        .unwrap();
+        let settings = config.acme().unwrap().unwrap();
+        assert_eq!(settings.domains, ["gkapi.example.org", "api.example.org"]);
+        assert_eq!(
+            settings.contact,
+            ["mailto:ops@example.org", "mailto:oncall@example.org"]
+        );
+        assert_eq!(settings.directory, acme::LETS_ENCRYPT_DIRECTORY);
+        assert_eq!(settings.renew_before, chrono::Duration::days(30));
+        assert_eq!(Config::parse("").unwrap().acme(), Ok(None));
+
+        for bad in [
+            "[acme]\ndomains = [\"*.example.org\"]",
+            "[acme]\ndomains = [\"gkapi example.org\"]",
+            "[acme]\ndomains = [\"example.org\"]\ndirectory = \"http://ca.test/dir\"",
+            "[acme]\ndomains = [\"example.org\"]\ncontact = [\"ops\"]",
+            "[acme]\ndomains = [\"example.org\"]\nrenew_days_before = 90",
+            "[acme]\ndomains = [\"example.org\"]\n[tls]\ncert = \"c.pem\"\nkey = \"k.pem\"",
+        ] {
+            let config = Config::parse(bad).unwrap();
+            assert!(config.acme().is_err(), "{bad}");
+            assert!(
+                errors(&config.check())
+                    .iter()
+                    .any(|e| e.starts_with("[error] acme:")),
+                "{bad}"
+            );
+        }
+    }
+
+    #[test]
+    fn throttle_overrides_the_built_in_route_limits() {
+        let config = Config::parse(
+            r#"
+            [throttle]
+            max_body_bytes = 8192
+            [throttle.routes."/create-donation"]
+            per_minute = 2
+            [throttle.routes."/rooms"]
+            per_minute = 20
+            "#,
+        )
+        .unwrap();
+        let throttle = config.throttle().unwrap();
+        assert_eq!(
+            throttle.route_limit("/create-donation"),
+            Some(RouteLimit {
+                per_minute: 2,
+                burst: 3,
+                max_body_bytes: None,
+            })
+        );
+        assert_eq!(
+            throttle.route_limit("/rooms").map(|limit| limit.burst),
+            Some(20)
+        );
+        assert!(throttle.route_limit("/sign-certificate").is_some());
+        assert!(errors(&config.check())
+            .iter()
+            .all(|e| !e.contains("throttle")));
+
+        for bad in [
+            "[throttle]\nmax_body_bytes = 0\n",
+            "[throttle.routes.\"rooms\"]\nper_minute = 1\n",
+            "[throttle.routes.\"/create-donation\"]\nburst = 0\n",
+        ] {
+            assert!(Config::parse(bad).unwrap().throttle().is_err(), "{bad}");
+        }
+    }
+
+    #[test]
+    fn reputation_sources_are_parsed_and_checked() {
+        let config = Config::parse(
+            r#"
+            [[reputation.sources]]
+            name = "vpn"
+            url = "https://example.org/vpn.txt"
+            action = "extra-bits"
+            extra_bits = 4
+            min_entries = 100
+
+            [[reputation.sources]]
+            name = "hosting"
+            file = "/etc/gkapi/hosting.txt"
+            action = "quota"
+            invites_per_ip = 1
+            refresh_minutes = 5
+            "#,
+        )
+        .unwrap();
+        let sources = config.reputation_sources().unwrap();
+        assert_eq!(sources[0].action, ReputationAction::ExtraBits(4));
+        assert_eq!(
+            sources[0].cache.as_deref(),
+            Some(Path::new("/var/lib/gkapi/reputation/vpn.txt"))
+        );
+        assert_eq!(sources[0].min_entries, 100);
+        assert_eq!(sources[0].refresh, reputation::DEFAULT_REFRESH);
+        assert_eq!(sources[1].action, ReputationAction::Quota(1));
+        assert_eq!(sources[1].cache, None);
+        assert_eq!(sources[1].refresh, Duration::from_secs(300));
+
+        let broken = |extra: &str| {
+            let config = Config::parse(&format!(
+                "[[reputation.sources]]\nname = \"x\"\nfile = \"/x\"\n{extra}\n"
+            ))
+            .unwrap();
+            let mut report = Report::default();
+            config.check_reputation(&mut report);
+            errors(&report)
+        };
+        assert!(broken("action = \"block\"\nextra_bits = 2").len() == 1);
+        assert!(broken("action = \"extra-bits\"\nextra_bits = 31").len() == 1);
+        assert!(broken("action = \"quota\"").len() == 1);
+        assert!(broken("action = \"block\"\nurl = \"https://x\"").len() == 1);
+        assert!(broken("action = \"block\"\nrefresh_minutes = 0").len() == 1);
+        // A missing file only blocks startup for a fail-closed source.
+        assert!(broken("action = \"block\"").is_empty());
+        assert!(broken("action = \"block\"\nfail_closed = true").len() == 1);
+    }
+
+    #[test]
+    fn half_an_invite_configuration_is_an_error() {
+        let mut config = Config::default();
+        config.invite.owner_vk = Some("93XNNwmRLQ6nwUwi4dDmp3kpjMb5ekMRc2e22x5TAnUY".to_string());
+        let mut report = Report::default();
+        config.check_invite(&mut report);
+        assert!(report.has_errors());
+
+        let mut report = Report::default();
+        Config::default().check_invite(&mut report);
+        assert!(
+            !report.has_errors(),
+            "no invite settings means invites are off"
+        );
+    }
+
+    #[test]
+    fn room_keys_are_length_checked() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("room.key");
+        fs::write(&path, [7u8; 31]).unwrap();
+        assert!(load_room_signing_key(&path)
+            .unwrap_err()
+            .contains("32 bytes"));
+        fs::write(&path, [7u8; 32]).unwrap();
+        assert!(load_room_signing_key(&path).is_ok());
+
+        assert!(parse_room_owner_vk("93XNNwmRLQ6nwUwi4dDmp3kpjMb5ekMRc2e22x5TAnUY").is_ok());
+        assert!(parse_room_owner_vk("0OIl").is_err());
+    }
+
+    #[test]
+    fn rooms_join_the_flat_room() {
+        let config = Config::parse(
+            r#"
+            [invite]
+            signing_key_file = "/etc/gkapi/room.key"
+            owner_vk = "93XNNwmRLQ6nwUwi4dDmp3kpjMb5ekMRc2e22x5TAnUY"
+
+            [[invite.rooms]]
+            slug = "dev"
+            name = "Freenet Dev"
+            signing_key_file = "/etc/gkapi/dev.key"
+            owner_vk = "93XNNwmRLQ6nwUwi4dDmp3kpjMb5ekMRc2e22x5TAnUY"
+            invites_per_hour = 20
+
+            [rate_limit]
+            file = "/var/lib/gkapi/rl.json"
+            "#,
+        )
+        .unwrap();
+        let rooms = config.invite_rooms();
+        let slugs: Vec<_> = rooms.iter().map(|room| room.slug.as_str()).collect();
+        assert_eq!(slugs, [DEFAULT_ROOM_SLUG, "dev"]);
+        assert_eq!(rooms[0].name, DEFAULT_ROOM_NAME);
+        assert_eq!(rooms[1].invites_per_hour, Some(20));
+        assert_eq!(config.default_room().as_deref(), Some(DEFAULT_ROOM_SLUG));
+        // The flat room keeps its existing limiter state across the upgrade.
+        assert_eq!(
+            config.room_rate_limit_file(&rooms[0]),
+            PathBuf::from("/var/lib/gkapi/rl.json")
+        );
+        assert_eq!(
+            config.room_rate_limit_file(&rooms[1]),
+            PathBuf::from("/var/lib/gkapi/rl.dev.json")
+        );
+    }
+
+    #[test]
+    fn room_slugs_are_checked() {
+        let dir = tempdir().unwrap();
+        let key = dir.path().join("room.key");
+        fs::write(&key, [7u8; 32]).unwrap();
+        let room = |slug: &str| RoomConfig {
+            slug: slug.to_string(),
+            name: "Room".to_string(),
+            signing_key_file: key.clone(),
+            owner_vk: "93XNNwmRLQ6nwUwi4dDmp3kpjMb5ekMRc2e22x5TAnUY".to_string(),
+            invites_per_ip: None,
+            invites_per_hour: None,
+            rate_limit_file: Some(dir.path().join(format!("rl.{slug}.json"))),
+        };
+        let mut config = Config::default();
+        config.rate_limit.file = Some(dir.path().join("rl.json"));
+        config.rate_limit.ghostkey_file = Some(dir.path().join("gk.json"));
+        config.invite.ledger = Some(dir.path().join("ledger.jsonl"));
+        config.tor.exit_cache = Some(dir.path().join("tor.txt"));
+        config.pow.key_file = Some(dir.path().join("pow_key"));
+        config.pow.used_file = Some(dir.path().join("pow_used.json"));
+        config.invite.rooms = vec![room("main"), room("dev")];
+        let mut report = Report::default();
+        config.check_invite(&mut report);
+        assert!(errors(&report).is_empty(), "{:?}", errors(&report));
+        assert_eq!(config.default_room().as_deref(), Some("main"));
+
+        config.invite.rooms = vec![room("main"), room("main"), room("Bad Slug")];
+        config.invite.default_room = Some("elsewhere".to_string());
+        let mut report = Report::default();
+        config.check_invite(&mut report);
+        let errors = errors(&report);
+        assert!(errors.iter().any(|e| e.contains("more than once")));
+        assert!(errors.iter().any(|e| e.contains("lowercase")));
+        assert!(errors.iter().any(|e| e.contains("default_room")));
+    }
+}
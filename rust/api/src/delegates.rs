@@ -7,15 +7,17 @@
 //! kept for backward compatibility with existing deployments; `NOTARY_DIR`
 //! is the canonical name going forward.
 
-use std::path::{Path, PathBuf};
+use std::path::Path;
 
 use blind_rsa_signatures::{BlindSignature, BlindedMessage, Options, SecretKey as RSASigningKey};
+use ed25519_dalek::VerifyingKey;
 use rand_core::OsRng;
 
 use ghostkey_lib::armorable::*;
 use ghostkey_lib::notary_certificate::NotaryCertificateV1;
 
-use crate::handle_sign_cert::CertificateError;
+use crate::errors::CertificateError;
+use crate::notary_store::{Notary, NotaryKey};
 
 /// Which naming scheme the per-amount files on disk use.
 ///
@@ -46,25 +48,6 @@ impl NamingScheme {
     }
 }
 
-/// Resolve the notary directory from env, preferring `NOTARY_DIR` and
-/// falling back to the legacy `DELEGATE_DIR` with a deprecation warning.
-fn notary_dir() -> Result<PathBuf, CertificateError> {
-    if let Ok(dir) = std::env::var("NOTARY_DIR") {
-        return Ok(PathBuf::from(dir));
-    }
-    if let Ok(dir) = std::env::var("DELEGATE_DIR") {
-        log::warn!(
-            "reading legacy DELEGATE_DIR env var; rename to NOTARY_DIR — \
-             DELEGATE_DIR will be removed in a future release (freenet/web#24)"
-        );
-        return Ok(PathBuf::from(dir));
-    }
-    log::error!("neither NOTARY_DIR nor DELEGATE_DIR is set");
-    Err(CertificateError::KeyError(
-        "NOTARY_DIR environment variable not set".to_string(),
-    ))
-}
-
 /// Decide which naming scheme a directory uses for a given amount. Prefers
 /// the canonical `notary_*` pair; falls back to the legacy `delegate_*` pair
 /// only if BOTH legacy files exist AND neither canonical file does.
@@ -98,20 +81,14 @@ fn pick_scheme(dir: &Path, amount: u64) -> NamingScheme {
     NamingScheme::Notary
 }
 
-pub(crate) fn get_notary(
+/// Read the certificate and signing key for one tier from `dir`.
+pub fn load_notary(
+    dir: &Path,
     amount: u64,
 ) -> Result<(NotaryCertificateV1, RSASigningKey), CertificateError> {
-    let dir = notary_dir()?;
-    let scheme = pick_scheme(&dir, amount);
+    let scheme = pick_scheme(dir, amount);
 
-    let cert_path = dir.join(scheme.cert_filename(amount));
-    let cert = NotaryCertificateV1::from_file(&cert_path).map_err(|e| {
-        CertificateError::KeyError(format!(
-            "Unable to read notary certificate from {}: {}",
-            cert_path.display(),
-            e
-        ))
-    })?;
+    let cert = read_certificate(dir, scheme, amount)?;
 
     let signing_key_path = dir.join(scheme.signing_key_filename(amount));
     let signing_key = RSASigningKey::from_file(&signing_key_path).map_err(|e| {
@@ -124,24 +101,146 @@ pub(crate) fn get_notary(
     Ok((cert, signing_key))
 }
 
-pub(crate) fn sign_with_notary_key(
+/// Read only the certificate for one tier, for a process that signs through
+/// `gknotary` and deliberately has no signing keys on disk.
+///
+/// There is no key to pair it with, so the pairing rule in [`pick_scheme`]
+/// does not apply: the canonical name wins and the legacy one is a fallback.
+pub fn load_notary_certificate(
+    dir: &Path,
+    amount: u64,
+) -> Result<NotaryCertificateV1, CertificateError> {
+    let scheme = if !dir
+        .join(NamingScheme::Notary.cert_filename(amount))
+        .exists()
+        && dir
+            .join(NamingScheme::LegacyDelegate.cert_filename(amount))
+            .exists()
+    {
+        NamingScheme::LegacyDelegate
+    } else {
+        NamingScheme::Notary
+    };
+    read_certificate(dir, scheme, amount)
+}
+
+fn read_certificate(
+    dir: &Path,
+    scheme: NamingScheme,
+    amount: u64,
+) -> Result<NotaryCertificateV1, CertificateError> {
+    let cert_path = dir.join(scheme.cert_filename(amount));
+    NotaryCertificateV1::from_file(&cert_path).map_err(|e| {
+        CertificateError::KeyError(format!(
+            "Unable to read notary certificate from {}: {}",
+            cert_path.display(),
+            e
+        ))
+    })
+}
+
+/// Whether `dir` holds a signing key for any tier under either naming scheme.
+pub fn has_signing_keys(dir: &Path) -> std::io::Result<bool> {
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with("notary_signing_key_") || name.starts_with("delegate_signing_key_") {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Donation tiers (in dollars) that have a certificate on disk under either
+/// naming scheme, ascending.
+///
+/// Only certificates are scanned: a tier whose signing key is missing is
+/// still listed so that [`load_notary`] can report the missing key by name,
+/// rather than the tier silently disappearing.
+pub fn tier_amounts(dir: &Path) -> std::io::Result<Vec<u64>> {
+    let mut amounts = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let name = entry?.file_name();
+        let Some(name) = name.to_str() else {
+            continue;
+        };
+        let amount = name
+            .strip_suffix(".pem")
+            .and_then(|stem| {
+                stem.strip_prefix("notary_certificate_")
+                    .or_else(|| stem.strip_prefix("delegate_certificate_"))
+            })
+            .and_then(|amount| amount.parse::<u64>().ok());
+        if let Some(amount) = amount {
+            amounts.push(amount);
+        }
+    }
+    amounts.sort_unstable();
+    amounts.dedup();
+    Ok(amounts)
+}
+
+/// Check that a tier's certificate chains to `master_vk` and that the signing
+/// key on disk is the one the certificate vouches for.
+///
+/// Either failure means every ghost key issued from this tier would fail
+/// verification in the browser, after the donor has already paid.
+pub fn verify_notary(
+    cert: &NotaryCertificateV1,
+    signing_key: &RSASigningKey,
+    master_vk: &VerifyingKey,
+) -> Result<(), String> {
+    cert.verify(&Some(*master_vk))
+        .map_err(|e| format!("certificate does not chain to the master key: {e}"))?;
+
+    let public_key = signing_key
+        .public_key()
+        .and_then(|pk| pk.to_der())
+        .map_err(|e| format!("signing key is unusable: {e}"))?;
+    let certified_key = cert
+        .payload
+        .notary_verifying_key
+        .to_der()
+        .map_err(|e| format!("certificate key is unusable: {e}"))?;
+    if public_key != certified_key {
+        return Err("signing key does not match the key in the certificate".to_string());
+    }
+    Ok(())
+}
+
+/// Blind-sign with a key held in this process.
+pub fn blind_sign(
+    signing_key: &RSASigningKey,
     blinded_ghostkey: &BlindedMessage,
-    amount_dollars: u64,
 ) -> Result<BlindSignature, CertificateError> {
-    let (_, notary_signing_key) = get_notary(amount_dollars)?;
-
     let options = Options::default();
 
-    let blind_sig = notary_signing_key
+    let blind_sig = signing_key
         .blind_sign(&mut OsRng, blinded_ghostkey, &options)
         .map_err(|e| CertificateError::MiscError(format!("Failed to blind sign: {}", e)))?;
 
     Ok(blind_sig)
 }
 
+/// Blind-sign for `notary`'s tier, either with the key in memory or by asking
+/// the `gknotary` daemon that holds it.
+pub async fn sign_with_notary_key(
+    notary: &Notary,
+    blinded_ghostkey: &BlindedMessage,
+) -> Result<BlindSignature, CertificateError> {
+    match &notary.key {
+        NotaryKey::Local(signing_key) => blind_sign(signing_key, blinded_ghostkey),
+        NotaryKey::Remote(signer) => signer
+            .sign(notary.amount, blinded_ghostkey)
+            .await
+            .map_err(|e| CertificateError::KeyError(format!("Notary signer: {}", e))),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ghostkey_lib::util::create_keypair;
     use tempfile::tempdir;
 
     fn touch(path: &Path) {
@@ -195,6 +294,34 @@ mod tests {
         assert_eq!(pick_scheme(dir.path(), 20), NamingScheme::Notary);
     }
 
+    #[test]
+    fn tier_amounts_lists_both_schemes_once_in_order() {
+        let dir = tempdir().unwrap();
+        touch(&dir.path().join("notary_certificate_50.pem"));
+        touch(&dir.path().join("notary_certificate_5.pem"));
+        touch(&dir.path().join("delegate_certificate_5.pem"));
+        touch(&dir.path().join("delegate_certificate_100.pem"));
+        touch(&dir.path().join("notary_signing_key_20.pem"));
+        touch(&dir.path().join("notary_certificate_abc.pem"));
+        assert_eq!(tier_amounts(dir.path()).unwrap(), vec![5, 50, 100]);
+    }
+
+    #[test]
+    fn verify_notary_rejects_foreign_master_and_mismatched_key() {
+        let (master, master_vk) = create_keypair(&mut OsRng).unwrap();
+        let (_, other_vk) = create_keypair(&mut OsRng).unwrap();
+        let (cert, key) = NotaryCertificateV1::new(&master, &"$20".to_string()).unwrap();
+        let (_, other_key) = NotaryCertificateV1::new(&master, &"$50".to_string()).unwrap();
+
+        assert!(verify_notary(&cert, &key, &master_vk).is_ok());
+        assert!(verify_notary(&cert, &key, &other_vk)
+            .unwrap_err()
+            .contains("master key"));
+        assert!(verify_notary(&cert, &other_key, &master_vk)
+            .unwrap_err()
+            .contains("does not match"));
+    }
+
     #[test]
     fn naming_scheme_filenames_are_exactly_as_documented() {
         assert_eq!(
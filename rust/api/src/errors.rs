@@ -30,6 +30,23 @@ impl std::fmt::Display for CertificateError {
     }
 }
 
+impl CertificateError {
+    /// The variant, as a stable label for metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            CertificateError::StripeError(_) => "stripe",
+            CertificateError::PaymentNotSuccessful => "payment_not_successful",
+            CertificateError::PaymentMethodMissing => "payment_method_missing",
+            CertificateError::CertificateAlreadySigned => "certificate_already_signed",
+            CertificateError::Base64Error(_) => "base64",
+            CertificateError::KeyError(_) => "key",
+            CertificateError::ParseIdError(_) => "parse_id",
+            CertificateError::MiscError(_) => "misc",
+            CertificateError::GhostkeyError(_) => "ghostkey",
+        }
+    }
+}
+
 impl StdError for CertificateError {}
 
 impl From<stripe::StripeError> for CertificateError {
@@ -0,0 +1,150 @@
+//! Ghost key authentication for River invitations.
+//!
+//! Anonymous invite requests are held back by Tor blocking, proof of work and
+//! per-IP limits, all of which stand in for an identity the endpoint does not
+//! have. A ghost key is one: it costs a donation and chains to the master key
+//! through a notary. A request signed with one skips the IP-based checks and
+//! the work and is limited per ghost key instead. The global and per-room
+//! ceilings still apply.
+//!
+//! The request signs [`INVITE_SIGNATURE_DOMAIN`] followed by the challenge id
+//! exactly as `/invite-challenge` served it (the hex string), so a signature is
+//! only good for that one single-use challenge and cannot be lifted from, or
+//! into, another protocol.
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+use ghostkey_lib::armorable::Armorable;
+use ghostkey_lib::ghost_key_certificate::GhostkeyCertificateV1;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::rate_limit::RateLimiter;
+
+pub const INVITE_SIGNATURE_DOMAIN: &[u8] = b"freenet-river-invite-ghostkey-v1\n";
+
+/// Invitations per ghost key per 24 hours, across all rooms. Higher than the
+/// per-IP allowance: a ghost key was paid for and a shared address is not
+/// shared with it.
+pub const DEFAULT_INVITES_PER_GHOSTKEY: usize = 10;
+
+#[derive(Error, Debug)]
+pub enum GhostkeyAuthError {
+    #[error("ghost key certificate is malformed: {0}")]
+    Malformed(String),
+    #[error("ghost key certificate does not verify: {0}")]
+    Certificate(String),
+    #[error("signature over the challenge does not verify")]
+    Signature,
+}
+
+/// Everything the invite endpoint needs for the ghost key path.
+pub struct GhostkeyGate {
+    pub master_vk: VerifyingKey,
+    /// Keyed by [`fingerprint`].
+    pub quota: RateLimiter,
+}
+
+/// Check a ghost key certificate against the master key and the request's
+/// signature over `challenge_id`. Returns the ghost key's fingerprint.
+///
+/// The certificate may be base64 or the armored block the CLI writes.
+pub fn verify_invite_request(
+    certificate: &str,
+    signature_base64: &str,
+    challenge_id: &str,
+    master_vk: &VerifyingKey,
+) -> Result<String, GhostkeyAuthError> {
+    let certificate = if certificate.contains("-----BEGIN") {
+        GhostkeyCertificateV1::from_armored_string(certificate)
+    } else {
+        GhostkeyCertificateV1::from_base64(certificate.trim())
+    }
+    .map_err(|e| GhostkeyAuthError::Malformed(e.to_string()))?;
+    certificate
+        .verify(&Some(*master_vk))
+        .map_err(|e| GhostkeyAuthError::Certificate(e.to_string()))?;
+
+    let signature = BASE64
+        .decode(signature_base64.trim())
+        .ok()
+        .and_then(|bytes| Signature::from_slice(&bytes).ok())
+        .ok_or(GhostkeyAuthError::Signature)?;
+    let mut message = INVITE_SIGNATURE_DOMAIN.to_vec();
+    message.extend_from_slice(challenge_id.as_bytes());
+    certificate
+        .verifying_key
+        .verify(&message, &signature)
+        .map_err(|_| GhostkeyAuthError::Signature)?;
+
+    Ok(fingerprint(&certificate.verifying_key))
+}
+
+/// Hex SHA-256 of a ghost key's verifying key.
+pub fn fingerprint(verifying_key: &VerifyingKey) -> String {
+    hex::encode(Sha256::digest(verifying_key.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::{Signer, SigningKey};
+    use ghostkey_lib::notary_certificate::NotaryCertificateV1;
+    use ghostkey_lib::util::create_keypair;
+    use rand_core::OsRng;
+
+    fn sign(key: &SigningKey, challenge_id: &str) -> String {
+        let mut message = INVITE_SIGNATURE_DOMAIN.to_vec();
+        message.extend_from_slice(challenge_id.as_bytes());
+        BASE64.encode(key.sign(&message).to_bytes())
+    }
+
+    #[test]
+    fn request_must_chain_to_the_master_key_and_sign_the_challenge() {
+        let (master_sk, master_vk) = create_keypair(&mut OsRng).unwrap();
+        let (notary, notary_sk) = NotaryCertificateV1::new(&master_sk, &"$20".to_string()).unwrap();
+        let (certificate, ghost_sk) = GhostkeyCertificateV1::new(&notary, &notary_sk);
+        let base64 = certificate.to_base64().unwrap();
+        let armored = certificate.to_armored_string().unwrap();
+        let challenge = "00112233445566778899aabbccddeeff";
+
+        let fp = verify_invite_request(&base64, &sign(&ghost_sk, challenge), challenge, &master_vk)
+            .unwrap();
+        assert_eq!(fp, fingerprint(&certificate.verifying_key));
+        assert!(verify_invite_request(
+            &armored,
+            &sign(&ghost_sk, challenge),
+            challenge,
+            &master_vk
+        )
+        .is_ok());
+
+        // Signed for a different challenge.
+        assert!(matches!(
+            verify_invite_request(
+                &base64,
+                &sign(&ghost_sk, "ffeeddccbbaa99887766554433221100"),
+                challenge,
+                &master_vk
+            ),
+            Err(GhostkeyAuthError::Signature)
+        ));
+        // Signed by a key the certificate is not for.
+        let other = SigningKey::generate(&mut OsRng);
+        assert!(matches!(
+            verify_invite_request(&base64, &sign(&other, challenge), challenge, &master_vk),
+            Err(GhostkeyAuthError::Signature)
+        ));
+        // Certified under some other master key.
+        let (_, other_master_vk) = create_keypair(&mut OsRng).unwrap();
+        assert!(matches!(
+            verify_invite_request(
+                &base64,
+                &sign(&ghost_sk, challenge),
+                challenge,
+                &other_master_vk
+            ),
+            Err(GhostkeyAuthError::Certificate(_))
+        ));
+    }
+}
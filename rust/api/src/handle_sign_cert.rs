@@ -1,21 +1,77 @@
 use std::collections::HashMap;
 use std::str::FromStr;
+use std::sync::Arc;
 
 use blind_rsa_signatures::BlindedMessage;
 use serde::{Deserialize, Serialize};
-use stripe::{Client, PaymentIntent, PaymentIntentStatus};
+use sha2::{Digest, Sha256};
+use stripe::{Client, PaymentIntent, PaymentIntentId, PaymentIntentStatus, StripeError};
 
 use ghostkey_lib::armorable::Armorable;
 
 use crate::delegates::sign_with_notary_key;
 pub use crate::errors::CertificateError;
+use crate::issuance_log::IssuanceLog;
+use crate::notary_store::{Notary, NotaryStore};
+
+/// PaymentIntent metadata key holding the hex SHA-256 of the blinded message
+/// a PaymentIntent's certificate was signed for. Set together with
+/// `certificate_signed` and cleared with it.
+const BLINDED_SHA256_KEY: &str = "certificate_blinded_sha256";
+
+/// PaymentIntent metadata key holding the fingerprint of the notary key that
+/// signed. Set and cleared together with `certificate_signed`.
+const NOTARY_FINGERPRINT_KEY: &str = "certificate_notary";
+
+/// The two Stripe calls signing makes.
+trait PaymentIntents {
+    async fn retrieve(&self, id: &PaymentIntentId) -> Result<PaymentIntent, StripeError>;
+
+    /// Merge `metadata` into the PaymentIntent's; an empty value deletes a key.
+    async fn update_metadata(
+        &self,
+        id: &PaymentIntentId,
+        metadata: HashMap<String, String>,
+    ) -> Result<(), StripeError>;
+}
+
+impl PaymentIntents for Client {
+    async fn retrieve(&self, id: &PaymentIntentId) -> Result<PaymentIntent, StripeError> {
+        PaymentIntent::retrieve(self, id, &[]).await
+    }
+
+    async fn update_metadata(
+        &self,
+        id: &PaymentIntentId,
+        metadata: HashMap<String, String>,
+    ) -> Result<(), StripeError> {
+        let params = stripe::UpdatePaymentIntent {
+            metadata: Some(metadata),
+            ..Default::default()
+        };
+        PaymentIntent::update(self, id, params).await.map(drop)
+    }
+}
 
-#[derive(Debug, Deserialize)]
+#[derive(Deserialize)]
 pub struct SignCertificateRequest {
     payment_intent_id: String,
     blinded_ghost_key_base64: String,
 }
 
+/// Requests are logged; the blinded message is not.
+impl std::fmt::Debug for SignCertificateRequest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SignCertificateRequest")
+            .field("payment_intent_id", &self.payment_intent_id)
+            .field(
+                "blinded_ghost_key_base64",
+                &format_args!("<{} chars>", self.blinded_ghost_key_base64.len()),
+            )
+            .finish()
+    }
+}
+
 /// HTTP response for successful certificate signing.
 ///
 /// During the 0.2.0 rename transition the notary certificate is emitted in
@@ -34,26 +90,28 @@ pub struct SignCertificateResponse {
 
 pub async fn sign_certificate(
     request: SignCertificateRequest,
+    stripe: Option<&Client>,
+    notaries: &NotaryStore,
+    issuance: &IssuanceLog,
 ) -> Result<SignCertificateResponse, CertificateError> {
     log::info!(
-        "Starting sign_certificate function with request: {:?}",
-        request
+        "Signing certificate for PaymentIntent {}",
+        request.payment_intent_id
     );
-    log::debug!("Current working directory: {:?}", std::env::current_dir());
-    log::debug!("HOME environment variable: {:?}", std::env::var("HOME"));
 
-    let stripe_secret_key = std::env::var("STRIPE_SECRET_KEY").map_err(|e| {
-        log::error!("Environment variable STRIPE_SECRET_KEY not found: {}", e);
-        log::error!(
-            "Current environment variables: {:?}",
-            std::env::vars().collect::<Vec<_>>()
-        );
-        CertificateError::KeyError("STRIPE_SECRET_KEY environment variable not set".to_string())
+    let client = stripe.ok_or_else(|| {
+        log::error!("No Stripe secret key is configured");
+        CertificateError::KeyError("Stripe secret key is not configured".to_string())
     })?;
+    sign_payment(client, request, notaries, issuance).await
+}
 
-    log::info!("STRIPE_SECRET_KEY found");
-    let client = Client::new(stripe_secret_key);
-
+async fn sign_payment(
+    client: &impl PaymentIntents,
+    request: SignCertificateRequest,
+    notaries: &NotaryStore,
+    issuance: &IssuanceLog,
+) -> Result<SignCertificateResponse, CertificateError> {
     // Take an exclusive claim on this PaymentIntent and hold it for the rest of
     // the function. The `certificate_signed` check below and the update that
     // sets it are two separate Stripe calls with nothing atomic between them,
@@ -64,19 +122,16 @@ pub async fn sign_certificate(
     let _claim = crate::payment_claim::claim(&request.payment_intent_id).await;
 
     // Verify payment intent
-    let pi = PaymentIntent::retrieve(
-        &client,
-        &stripe::PaymentIntentId::from_str(&request.payment_intent_id)?,
-        &[],
-    )
-    .await
-    .map_err(|e| {
-        log::error!("Failed to retrieve PaymentIntent: {:?}", e);
-        CertificateError::StripeError(e)
-    })?;
+    let pi = client
+        .retrieve(&PaymentIntentId::from_str(&request.payment_intent_id)?)
+        .await
+        .map_err(|e| {
+            log::error!("Failed to retrieve PaymentIntent: {:?}", e);
+            CertificateError::StripeError(e)
+        })?;
 
-    log::info!("Retrieved PaymentIntent: {:?}", pi);
-    log::info!("PaymentIntent status: {:?}", pi.status);
+    // Not the whole PaymentIntent: it carries the client secret.
+    log::info!("PaymentIntent {} status: {:?}", pi.id, pi.status);
 
     match pi.status {
         PaymentIntentStatus::Succeeded => {
@@ -92,12 +147,6 @@ pub async fn sign_certificate(
         }
     }
 
-    // Check if the certificate has already been signed
-    if pi.metadata.get("certificate_signed").is_some() {
-        log::warn!("Certificate already signed for PaymentIntent: {}", pi.id);
-        return Err(CertificateError::CertificateAlreadySigned);
-    }
-
     // Parse the caller-supplied key BEFORE marking the PaymentIntent as spent.
     // A malformed request is the caller's mistake and must not consume the
     // donation; marking first would leave a donor charged with nothing to show
@@ -107,23 +156,67 @@ pub async fn sign_certificate(
             log::error!("Error in from_base64: {:?}", e);
             CertificateError::MiscError(e.to_string())
         })?;
+    let blinded_sha256 = hex::encode(Sha256::digest(&blinded_ghostkey.0));
+
+    let amount_cents = pi.amount as u64;
+    let amount_dollars = amount_cents / 100;
+    let notary = notary_for(notaries, amount_dollars)?;
 
-    // Mark the payment intent as used for certificate signing
+    // Check if the certificate has already been signed
+    if pi.metadata.contains_key("certificate_signed") {
+        // A retry with the very message that was signed means the browser lost
+        // the response. RSA blind signatures are deterministic, so signing it
+        // again hands back the signature the donor already paid for, and a
+        // party replaying someone else's request learns nothing it can unblind.
+        // It is not a new issuance and is not logged as one.
+        if pi.metadata.get(BLINDED_SHA256_KEY) == Some(&blinded_sha256) {
+            // Only the key that signed the first time gives back the same
+            // signature. A rotated tier key would mint a second, different
+            // certificate from one donation, so that retry is refused.
+            if pi.metadata.get(NOTARY_FINGERPRINT_KEY) != Some(&notary.fingerprint) {
+                log::warn!(
+                    "Not re-issuing for PaymentIntent {}: the ${} notary key has changed \
+                     since it was signed",
+                    pi.id,
+                    amount_dollars
+                );
+                return Err(CertificateError::CertificateAlreadySigned);
+            }
+            log::info!(
+                "Re-issuing the signature for PaymentIntent {} after a lost response",
+                pi.id
+            );
+            return signed_response(&notary, &blinded_ghostkey, amount_cents).await;
+        }
+        log::warn!("Certificate already signed for PaymentIntent: {}", pi.id);
+        return Err(CertificateError::CertificateAlreadySigned);
+    }
+
+    // Mark the payment intent as used for certificate signing, remembering
+    // which message it was used for and which key signs it, so a lost
+    // response can be recovered.
     let mut metadata = HashMap::new();
     metadata.insert("certificate_signed".to_string(), "true".to_string());
-    let params = stripe::UpdatePaymentIntent {
-        metadata: Some(metadata),
-        ..Default::default()
-    };
-    PaymentIntent::update(&client, &pi.id, params).await?;
+    metadata.insert(BLINDED_SHA256_KEY.to_string(), blinded_sha256);
+    metadata.insert(
+        NOTARY_FINGERPRINT_KEY.to_string(),
+        notary.fingerprint.clone(),
+    );
+    client.update_metadata(&pi.id, metadata).await?;
 
     // Sign the certificate
     log::info!("Payment intent verified successfully");
 
-    let amount_cents = pi.amount as u64;
-    let amount_dollars = amount_cents / 100;
-
-    match sign_marked_payment(&blinded_ghostkey, amount_dollars, amount_cents) {
+    match sign_marked_payment(
+        &notary,
+        issuance,
+        pi.id.as_str(),
+        &blinded_ghostkey,
+        amount_dollars,
+        amount_cents,
+    )
+    .await
+    {
         Ok(response) => Ok(response),
         Err(e) => {
             // The PaymentIntent is marked spent but no certificate came out of
@@ -131,7 +224,7 @@ pub async fn sign_certificate(
             // out of retrying. Releasing the mark is safe here specifically
             // because `_claim` is still held: no concurrent request can slip
             // into the window where the flag is briefly clear again.
-            release_certificate_mark(&client, &pi.id).await;
+            release_certificate_mark(client, &pi.id).await;
             Err(e)
         }
     }
@@ -142,21 +235,56 @@ pub async fn sign_certificate(
 ///
 /// Split out so the caller can tell "signing failed" apart from the earlier
 /// validation steps and undo the mark for exactly that case.
-fn sign_marked_payment(
+async fn sign_marked_payment(
+    notary: &Notary,
+    issuance: &IssuanceLog,
+    payment_intent_id: &str,
     blinded_ghostkey: &BlindedMessage,
     amount_dollars: u64,
     amount_cents: u64,
 ) -> Result<SignCertificateResponse, CertificateError> {
-    let blind_signature = sign_with_notary_key(blinded_ghostkey, amount_dollars).map_err(|e| {
-        log::error!("Error in sign_with_notary_key: {:?}", e);
-        e
-    })?;
+    let response = signed_response(notary, blinded_ghostkey, amount_cents).await?;
+
+    // Logged before the signature leaves the process: if the entry cannot be
+    // written, the signature is discarded and the mark released, so nothing
+    // is ever issued that the transparency counts do not include.
+    issuance
+        .record(amount_dollars, &notary.fingerprint, payment_intent_id)
+        .await
+        .map_err(|e| {
+            log::error!("Failed to record issuance, discarding signature: {}", e);
+            CertificateError::MiscError(e.to_string())
+        })?;
+    crate::metrics::get().certificate_signed(amount_dollars);
 
-    let (notary_certificate, _) = crate::delegates::get_notary(amount_dollars)?;
+    Ok(response)
+}
+
+fn notary_for(
+    notaries: &NotaryStore,
+    amount_dollars: u64,
+) -> Result<Arc<Notary>, CertificateError> {
+    notaries.get(amount_dollars).ok_or_else(|| {
+        CertificateError::KeyError(format!("No notary is loaded for ${}", amount_dollars))
+    })
+}
+
+/// Blind-sign and build the response. The certificate and the signing key
+/// come from the same `notary` snapshot, so a reload mid-request cannot pair a
+/// new certificate with an old key.
+async fn signed_response(
+    notary: &Notary,
+    blinded_ghostkey: &BlindedMessage,
+    amount_cents: u64,
+) -> Result<SignCertificateResponse, CertificateError> {
+    let blind_signature = sign_with_notary_key(notary, blinded_ghostkey)
+        .await
+        .map_err(|e| {
+            log::error!("Error in sign_with_notary_key: {:?}", e);
+            e
+        })?;
 
-    let cert_base64 = notary_certificate
-        .to_base64()
-        .map_err(|e| CertificateError::MiscError(e.to_string()))?;
+    let cert_base64 = notary.certificate_base64.clone();
 
     Ok(SignCertificateResponse {
         blind_signature_base64: blind_signature
@@ -170,22 +298,20 @@ fn sign_marked_payment(
     })
 }
 
-/// Clear `certificate_signed` after a failed signing attempt, so the donation
-/// can be retried.
+/// Clear `certificate_signed` (and the message and key it was for) after a
+/// failed signing attempt, so the donation can be retried.
 ///
 /// Stripe deletes a metadata key when it is set to an empty string. A failure
 /// here is logged rather than propagated: the caller is already returning the
 /// original signing error, which is the more useful one to surface, and the
 /// donation is recoverable by hand from the log line.
-async fn release_certificate_mark(client: &Client, pi_id: &stripe::PaymentIntentId) {
+async fn release_certificate_mark(client: &impl PaymentIntents, pi_id: &PaymentIntentId) {
     let mut metadata = HashMap::new();
     metadata.insert("certificate_signed".to_string(), String::new());
-    let params = stripe::UpdatePaymentIntent {
-        metadata: Some(metadata),
-        ..Default::default()
-    };
+    metadata.insert(BLINDED_SHA256_KEY.to_string(), String::new());
+    metadata.insert(NOTARY_FINGERPRINT_KEY.to_string(), String::new());
 
-    if let Err(e) = PaymentIntent::update(client, pi_id, params).await {
+    if let Err(e) = client.update_metadata(pi_id, metadata).await {
         log::error!(
             "Signing failed for PaymentIntent {} AND clearing certificate_signed \
              failed: {:?}. This donation is now marked spent with no certificate \
@@ -204,75 +330,263 @@ async fn release_certificate_mark(client: &Client, pi_id: &stripe::PaymentIntent
 
 #[cfg(test)]
 mod tests {
-    /// Strip all whitespace so the pins below survive rustfmt re-wrapping the
-    /// lines they match.
-    fn squeeze(s: &str) -> String {
-        s.chars().filter(|c| !c.is_whitespace()).collect()
+    use super::*;
+    use std::sync::Mutex;
+    use std::time::Duration;
+
+    use blind_rsa_signatures::{Options, PublicKey};
+    use ghostkey_lib::notary_certificate::NotaryCertificateV1;
+    use ghostkey_lib::util::create_keypair;
+    use rand_core::OsRng;
+    use tempfile::{tempdir, TempDir};
+
+    use crate::notary_store::NotaryKey;
+
+    /// One PaymentIntent held in memory in place of Stripe.
+    #[derive(Default)]
+    struct FakeStripe {
+        intent: Mutex<PaymentIntent>,
+        updates: Mutex<Vec<HashMap<String, String>>>,
     }
 
-    /// Production source only. Without this cut the needles match their own
-    /// text in this test module and every pin passes vacuously.
-    fn production_source() -> String {
-        let source = include_str!("handle_sign_cert.rs");
-        let production = source
-            .split_once("\nmod tests {")
-            .map(|(before, _)| before)
-            .expect("test module marker not found; the cut below is not working");
-        squeeze(production)
+    impl FakeStripe {
+        fn succeeded(id: &str, amount_cents: i64) -> Self {
+            let intent = PaymentIntent {
+                id: id.parse().unwrap(),
+                amount: amount_cents,
+                status: PaymentIntentStatus::Succeeded,
+                ..Default::default()
+            };
+            Self {
+                intent: Mutex::new(intent),
+                ..Default::default()
+            }
+        }
+
+        fn metadata(&self, key: &str) -> Option<String> {
+            self.intent.lock().unwrap().metadata.get(key).cloned()
+        }
     }
 
-    /// The claim has to be taken before the flag is read, not after. Taking it
-    /// afterwards leaves exactly the read-check-write window it exists to
-    /// close, and nothing else in the test suite would notice: the happy path
-    /// still returns a valid certificate.
-    #[test]
-    fn claim_is_taken_before_the_signed_flag_is_read() {
-        let source = production_source();
-
-        let claim_at = source
-            .find(&squeeze("payment_claim::claim(&request.payment_intent_id)"))
-            .expect("sign_certificate no longer claims the PaymentIntent at all");
-        let check_at = source
-            .find(&squeeze(r#"pi.metadata.get("certificate_signed")"#))
-            .expect("the certificate_signed check has moved or been renamed");
+    impl PaymentIntents for FakeStripe {
+        async fn retrieve(&self, _: &PaymentIntentId) -> Result<PaymentIntent, StripeError> {
+            let intent = self.intent.lock().unwrap().clone();
+            // Leave room for a concurrent request to interleave, as the
+            // round trip to Stripe does.
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok(intent)
+        }
 
-        assert!(
-            claim_at < check_at,
-            "the PaymentIntent claim must be taken BEFORE certificate_signed is \
-             read, otherwise concurrent requests can both observe an unset flag \
-             and one donation mints several Ghost Keys"
-        );
+        async fn update_metadata(
+            &self,
+            _: &PaymentIntentId,
+            metadata: HashMap<String, String>,
+        ) -> Result<(), StripeError> {
+            let mut intent = self.intent.lock().unwrap();
+            for (key, value) in &metadata {
+                if value.is_empty() {
+                    intent.metadata.remove(key);
+                } else {
+                    intent.metadata.insert(key.clone(), value.clone());
+                }
+            }
+            self.updates.lock().unwrap().push(metadata);
+            Ok(())
+        }
     }
 
-    /// `let _ = claim(..)` drops the guard immediately and `let _claim = ..`
-    /// holds it to end of scope. The two differ by one character and only the
-    /// second one actually excludes anything, so pin the binding shape.
-    #[test]
-    fn claim_guard_is_bound_and_not_dropped_immediately() {
-        let source = production_source();
+    struct Fixture {
+        _dir: TempDir,
+        notaries: NotaryStore,
+        issuance: IssuanceLog,
+        public_key: PublicKey,
+    }
 
-        assert!(
-            source.contains(&squeeze("let _claim = crate::payment_claim::claim(")),
-            "the claim guard must be bound to a named binding that lives to the \
-             end of sign_certificate"
+    fn fixture() -> Fixture {
+        let (master, master_vk) = create_keypair(&mut OsRng).unwrap();
+        let dir = tempdir().unwrap();
+        let notary_dir = dir.path().join("notaries");
+        std::fs::create_dir(&notary_dir).unwrap();
+        let (cert, key) = NotaryCertificateV1::new(&master, &"$20".to_string()).unwrap();
+        cert.to_file(&notary_dir.join("notary_certificate_20.pem"))
+            .unwrap();
+        key.to_file(&notary_dir.join("notary_signing_key_20.pem"))
+            .unwrap();
+        let notaries = NotaryStore::load(&notary_dir, master_vk).unwrap();
+        let NotaryKey::Local(key) = &notaries.get(20).unwrap().key else {
+            unreachable!()
+        };
+        let public_key = key.public_key().unwrap();
+        let issuance = IssuanceLog::open(&dir.path().join("issuance.jsonl")).unwrap();
+        Fixture {
+            _dir: dir,
+            notaries,
+            issuance,
+            public_key,
+        }
+    }
+
+    impl Fixture {
+        fn request(&self, payment_intent_id: &str, message: &[u8]) -> SignCertificateRequest {
+            let blinded = self
+                .public_key
+                .blind(&mut OsRng, message, true, &Options::default())
+                .unwrap();
+            SignCertificateRequest {
+                payment_intent_id: payment_intent_id.to_string(),
+                blinded_ghost_key_base64: blinded.blind_msg.to_base64().unwrap(),
+            }
+        }
+
+        async fn sign(
+            &self,
+            stripe: &FakeStripe,
+            request: SignCertificateRequest,
+        ) -> Result<SignCertificateResponse, CertificateError> {
+            sign_payment(stripe, request, &self.notaries, &self.issuance).await
+        }
+
+        fn issued(&self) -> u64 {
+            self.issuance.current().unwrap().entries
+        }
+    }
+
+    fn same_request(request: &SignCertificateRequest) -> SignCertificateRequest {
+        SignCertificateRequest {
+            payment_intent_id: request.payment_intent_id.clone(),
+            blinded_ghost_key_base64: request.blinded_ghost_key_base64.clone(),
+        }
+    }
+
+    /// The mark, the message hash and the signing key go out in one update,
+    /// so a crash between writes cannot leave a spent PaymentIntent whose
+    /// lost response is unrecoverable. A retry of that message gets the same
+    /// signature and no second issuance entry; any other message is refused.
+    #[tokio::test]
+    async fn a_lost_response_is_reissued_once_signed() {
+        let fixture = fixture();
+        let stripe = FakeStripe::succeeded("pi_lost", 2000);
+        let request = fixture.request("pi_lost", b"ghost key");
+
+        let first = fixture.sign(&stripe, same_request(&request)).await.unwrap();
+        assert_eq!(fixture.issued(), 1);
+        let updates = stripe.updates.lock().unwrap().clone();
+        assert_eq!(updates.len(), 1, "marked in a single Stripe update");
+        let notary = fixture.notaries.get(20).unwrap();
+        assert_eq!(updates[0]["certificate_signed"], "true");
+        assert_eq!(updates[0][NOTARY_FINGERPRINT_KEY], notary.fingerprint);
+        assert!(updates[0].contains_key(BLINDED_SHA256_KEY));
+
+        let again = fixture.sign(&stripe, request).await.unwrap();
+        assert_eq!(again.blind_signature_base64, first.blind_signature_base64);
+        assert_eq!(fixture.issued(), 1, "a re-issue is not a new issuance");
+
+        let other = fixture.request("pi_lost", b"another ghost key");
+        assert!(matches!(
+            fixture.sign(&stripe, other).await,
+            Err(CertificateError::CertificateAlreadySigned)
+        ));
+        assert_eq!(fixture.issued(), 1);
+    }
+
+    /// Another key would sign the same message differently, minting a second
+    /// certificate from one donation.
+    #[tokio::test]
+    async fn a_retry_after_the_tier_key_changed_is_refused() {
+        let fixture = fixture();
+        let stripe = FakeStripe::succeeded("pi_rotated", 2000);
+        let request = fixture.request("pi_rotated", b"ghost key");
+        fixture.sign(&stripe, same_request(&request)).await.unwrap();
+        stripe
+            .intent
+            .lock()
+            .unwrap()
+            .metadata
+            .insert(NOTARY_FINGERPRINT_KEY.to_string(), "00".repeat(32));
+
+        assert!(matches!(
+            fixture.sign(&stripe, request).await,
+            Err(CertificateError::CertificateAlreadySigned)
+        ));
+        assert_eq!(fixture.issued(), 1);
+    }
+
+    /// The claim makes the flag check and the update that sets it one step;
+    /// without it both requests read an unset flag and both sign.
+    #[tokio::test]
+    async fn concurrent_requests_for_one_payment_sign_once() {
+        let fixture = fixture();
+        let stripe = FakeStripe::succeeded("pi_race", 2000);
+        let (first, second) = tokio::join!(
+            fixture.sign(&stripe, fixture.request("pi_race", b"one")),
+            fixture.sign(&stripe, fixture.request("pi_race", b"two")),
         );
-        assert!(
-            !source.contains(&squeeze("let _ = crate::payment_claim::claim(")),
-            "`let _ = claim(..)` drops the guard on the spot, so the claim is \
-             released before the flag is even read and the race is fully open"
+
+        assert_eq!(
+            [&first, &second].iter().filter(|r| r.is_ok()).count(),
+            1,
+            "exactly one request signs"
         );
+        assert_eq!(fixture.issued(), 1);
     }
 
     /// A signing failure after the mark is set must clear it, or the donor is
     /// charged and permanently unable to retry.
-    #[test]
-    fn failed_signing_releases_the_mark() {
-        let source = production_source();
+    #[tokio::test]
+    async fn failed_signing_releases_the_mark() {
+        let fixture = fixture();
+        let stripe = FakeStripe::succeeded("pi_failed", 2000);
+        // Larger than the modulus, so blind signing rejects it.
+        let unsignable = SignCertificateRequest {
+            payment_intent_id: "pi_failed".to_string(),
+            blinded_ghost_key_base64: BlindedMessage(vec![0xff; 512]).to_base64().unwrap(),
+        };
+
+        assert!(fixture.sign(&stripe, unsignable).await.is_err());
+        assert_eq!(
+            stripe.updates.lock().unwrap().len(),
+            2,
+            "marked, then released"
+        );
+        assert_eq!(stripe.metadata("certificate_signed"), None);
+        assert_eq!(stripe.metadata(BLINDED_SHA256_KEY), None);
+        assert_eq!(stripe.metadata(NOTARY_FINGERPRINT_KEY), None);
+        assert_eq!(fixture.issued(), 0);
+
+        let retry = fixture.request("pi_failed", b"ghost key");
+        fixture.sign(&stripe, retry).await.unwrap();
+        assert_eq!(fixture.issued(), 1);
+    }
 
+    /// The environment holds the Stripe key and a PaymentIntent's Debug
+    /// output includes its client secret. Whatever the error path logs, none
+    /// of it, nor the blinded message, reaches the log.
+    #[tokio::test]
+    async fn secrets_are_not_dumped_into_the_log() {
+        let stripe_key = "opaque-stripe-key-5be1c9";
+        crate::logging::register_secret(stripe_key);
+        let fixture = fixture();
+        let stripe = FakeStripe::succeeded("pi_logged", 2000);
+        stripe.intent.lock().unwrap().client_secret = Some("pi_logged_secret_Zx81Kq".to_string());
+        let blinded = BlindedMessage(vec![0xff; 512]).to_base64().unwrap();
+        let unsignable = SignCertificateRequest {
+            payment_intent_id: "pi_logged".to_string(),
+            blinded_ghost_key_base64: blinded.clone(),
+        };
+
+        let (guard, log) = crate::logging::capture();
+        // As a `{:?}` added later would.
+        log::error!("{:?} with {stripe_key}", stripe.intent.lock().unwrap());
+        assert!(fixture.sign(&stripe, unsignable).await.is_err());
+        drop(guard);
+
+        let log = String::from_utf8(log.lock().unwrap().clone()).unwrap();
         assert!(
-            source.contains(&squeeze("release_certificate_mark(&client, &pi.id)")),
-            "signing failures must clear certificate_signed, otherwise a \
-             transient failure burns the donation"
+            log.contains("Signing failed for PaymentIntent pi_logged"),
+            "the error path was not captured:\n{log}"
         );
+        for secret in [stripe_key, "_secret_", "Zx81Kq", blinded.as_str()] {
+            assert!(!log.contains(secret), "{secret} reached the log:\n{log}");
+        }
     }
 }
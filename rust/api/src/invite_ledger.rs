@@ -0,0 +1,229 @@
+//! Record of every invitation handed out.
+//!
+//! When spam members turn up in a room, moderators need to know which
+//! invitations they came from and what else was issued alongside them. Each
+//! invitation appends one JSON line, written and synced before the invite is
+//! returned, so an invitation that cannot be traced is not handed out.
+//!
+//! The client's address is not stored. Its network (by the `rate_limit`
+//! prefixes) is stored as the SHA-256 of its CIDR string, the same form as a
+//! hashed access policy entry. This groups invitations from one network, but
+//! it is a pseudonym, not anonymity: IPv4 /24s are few enough to enumerate.
+//!
+//! [`read_range`] reads the records back for `ghostkey-api export-invites`.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::Mutex;
+
+use chrono::{DateTime, NaiveDate, Utc};
+use ipnet::IpNet;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct InviteRecord {
+    pub time: DateTime<Utc>,
+    pub room: String,
+    /// The invitee's 8-character id, as River shows it.
+    pub member_id: String,
+    /// Member id of the room key that signed the invitation.
+    pub inviter: String,
+    /// SHA-256 of the client's network, such as `203.0.113.0/24`.
+    pub network_sha256: String,
+    pub network_prefix: u8,
+    /// Proof-of-work difficulty solved; absent for ghost key invitations.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub difficulty: Option<u8>,
+    /// Fingerprint of the ghost key that asked, if one did.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ghostkey: Option<String>,
+}
+
+/// Append-only invite ledger.
+pub struct InviteLedger {
+    file: Mutex<File>,
+    ipv4_prefix: u8,
+    ipv6_prefix: u8,
+}
+
+impl InviteLedger {
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+        })
+    }
+
+    /// Group addresses into networks of these sizes.
+    pub fn with_prefixes(mut self, ipv4_prefix: u8, ipv6_prefix: u8) -> Self {
+        self.ipv4_prefix = ipv4_prefix;
+        self.ipv6_prefix = ipv6_prefix;
+        self
+    }
+
+    /// The hashed network and its prefix length for `ip`.
+    pub fn network(&self, ip: IpAddr) -> (String, u8) {
+        let prefix = match ip {
+            IpAddr::V4(_) => self.ipv4_prefix,
+            IpAddr::V6(_) => self.ipv6_prefix,
+        };
+        let net = IpNet::new(ip, prefix)
+            .map(|net| net.trunc())
+            .unwrap_or_else(|_| IpNet::from(ip));
+        // As a hashed policy entry does: a full-length prefix is the bare
+        // address.
+        let text = if net.prefix_len() == net.max_prefix_len() {
+            net.addr().to_string()
+        } else {
+            net.to_string()
+        };
+        (
+            hex::encode(Sha256::digest(text.as_bytes())),
+            net.prefix_len(),
+        )
+    }
+
+    /// Written and synced before the invitation is returned; a failure here
+    /// means the invitation is not handed out.
+    pub fn record(&self, record: &InviteRecord) -> io::Result<()> {
+        let line = serde_json::to_string(record)?;
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| io::Error::other("invite ledger lock poisoned"))?;
+        writeln!(file, "{line}")?;
+        file.sync_data()
+    }
+
+    /// Wait out a write in progress and sync the file, metadata included.
+    pub fn flush(&self) -> io::Result<()> {
+        self.file
+            .lock()
+            .map_err(|_| io::Error::other("invite ledger lock poisoned"))?
+            .sync_all()
+    }
+}
+
+/// Parse an export bound: RFC 3339, or a date meaning its start in UTC.
+pub fn parse_time(s: &str) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(s) {
+        return Ok(time.into());
+    }
+    NaiveDate::parse_from_str(s, "%Y-%m-%d")
+        .map(|date| date.and_hms_opt(0, 0, 0).unwrap().and_utc())
+        .map_err(|_| format!("{s:?} is neither an RFC 3339 time nor a YYYY-MM-DD date"))
+}
+
+/// Records with `since <= time < until`, optionally in one room, in file
+/// order. A line that does not parse is an error naming its line number,
+/// rather than a silent gap in an export used for bans.
+pub fn read_range(
+    path: &Path,
+    since: DateTime<Utc>,
+    until: Option<DateTime<Utc>>,
+    room: Option<&str>,
+) -> Result<Vec<InviteRecord>, String> {
+    let file = File::open(path).map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+    let mut records = Vec::new();
+    for (index, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: InviteRecord = serde_json::from_str(&line)
+            .map_err(|e| format!("{} line {}: {e}", path.display(), index + 1))?;
+        if record.time < since || until.is_some_and(|until| record.time >= until) {
+            continue;
+        }
+        if room.is_some_and(|room| room != record.room) {
+            continue;
+        }
+        records.push(record);
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    fn record(time: DateTime<Utc>, room: &str, member_id: &str) -> InviteRecord {
+        InviteRecord {
+            time,
+            room: room.to_string(),
+            member_id: member_id.to_string(),
+            inviter: "INVITER1".to_string(),
+            network_sha256: "00".repeat(32),
+            network_prefix: 24,
+            difficulty: Some(16),
+            ghostkey: None,
+        }
+    }
+
+    #[test]
+    fn records_are_read_back_by_time_and_room() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("invite_ledger.jsonl");
+        let ledger = InviteLedger::open(&path).unwrap();
+        let start = parse_time("2026-03-01").unwrap();
+        ledger
+            .record(&record(start, "default", "AAAAAAAA"))
+            .unwrap();
+        ledger
+            .record(&record(start + Duration::hours(1), "dev", "BBBBBBBB"))
+            .unwrap();
+        let mut ghost = record(start + Duration::hours(2), "default", "CCCCCCCC");
+        ghost.difficulty = None;
+        ghost.ghostkey = Some("ab".repeat(32));
+        ledger.record(&ghost).unwrap();
+
+        let all = read_range(&path, start, None, None).unwrap();
+        assert_eq!(all.len(), 3);
+        assert_eq!(all[2], ghost);
+        let ids = |records: Vec<InviteRecord>| -> Vec<String> {
+            records.into_iter().map(|r| r.member_id).collect()
+        };
+        assert_eq!(
+            ids(read_range(&path, start, Some(start + Duration::hours(2)), None).unwrap()),
+            ["AAAAAAAA", "BBBBBBBB"]
+        );
+        assert_eq!(
+            ids(read_range(&path, start + Duration::minutes(1), None, Some("default")).unwrap()),
+            ["CCCCCCCC"]
+        );
+
+        fs::write(&path, "{not json}\n").unwrap();
+        let err = read_range(&path, start, None, None).unwrap_err();
+        assert!(err.contains("line 1"), "{err}");
+    }
+
+    #[test]
+    fn networks_are_hashed_by_prefix() {
+        let dir = tempfile::tempdir().unwrap();
+        let ledger = InviteLedger::open(&dir.path().join("ledger.jsonl")).unwrap();
+        let (a, prefix) = ledger.network("203.0.113.7".parse().unwrap());
+        let (b, _) = ledger.network("203.0.113.200".parse().unwrap());
+        assert_eq!(a, b);
+        assert_eq!(prefix, 24);
+        assert_eq!(a, hex::encode(Sha256::digest(b"203.0.113.0/24")));
+        let (v6, prefix) = ledger.network("2001:db8::1".parse().unwrap());
+        assert_eq!(prefix, 64);
+        assert_eq!(v6, hex::encode(Sha256::digest(b"2001:db8::/64")));
+        let hosts = InviteLedger::open(&dir.path().join("hosts.jsonl"))
+            .unwrap()
+            .with_prefixes(32, 128);
+        let (host, _) = hosts.network("203.0.113.7".parse().unwrap());
+        assert_eq!(host, hex::encode(Sha256::digest(b"203.0.113.7")));
+        assert!(parse_time("2026-03-01T12:00:00Z").is_ok());
+        assert!(parse_time("yesterday").is_err());
+    }
+}
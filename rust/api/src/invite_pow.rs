@@ -1,14 +1,37 @@
 //! Stateless proof-of-work challenges for River invitation issuance.
 //!
-//! A challenge is authenticated with a process-local HMAC key, so clients
-//! cannot lower its difficulty or extend its lifetime. Successfully used
-//! challenge ids are retained until expiry to make each proof single-use.
+//! A challenge is authenticated with an HMAC key, so clients cannot lower its
+//! difficulty or extend its lifetime. Successfully used challenge ids are
+//! retained until expiry to make each proof single-use.
+//!
+//! By default the key is random per process and the used ids are held in
+//! memory. A deployment that must not void challenges on restart, or that runs
+//! several instances, loads [`PowKeys`] from a shared key file and records used
+//! ids in a shared [`RateLimitStore`]. The key file holds a current and a
+//! previous key, so a rotation does not void challenges already handed out.
+//!
+//! Two algorithms are supported. `sha256-leading-zero-bits-v1` is cheap to
+//! verify but runs orders of magnitude faster on GPUs than in a browser.
+//! `argon2id-leading-zero-bits-v1` makes every attempt fill a block of memory,
+//! which narrows that gap. The algorithm is part of the signed challenge, so a
+//! client cannot pick the cheaper one, and a deployment can accept both while
+//! clients move over.
 
-use std::collections::HashMap;
-use std::sync::Mutex;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
 
+use argon2::{Algorithm, Argon2, Params, Version};
 use chrono::Utc;
+use ghostkey_api::rate_limit::RateLimitError;
+use ghostkey_api::rate_limit_store::{MemoryStore, RateLimitStore};
 use hmac::{Hmac, Mac};
+use log::{error, info, warn};
 use rand::RngCore;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
@@ -20,12 +43,107 @@ const CHALLENGE_BYTES: usize = 16;
 const SIGNATURE_BYTES: usize = 32;
 const CHALLENGE_TTL_SECONDS: i64 = 5 * 60;
 const DOMAIN: &[u8] = b"freenet-river-invite-pow-v1";
+const ARGON2_DOMAIN: &[u8] = b"freenet-river-invite-pow-argon2id-v1";
+const GHOST_KEY_PURPOSE: &[u8] = b"/ghostkey";
+const KEY_POLL_INTERVAL: Duration = Duration::from_secs(30);
 
-/// Difficulty increases as successful invitation volume approaches the global
-/// emergency ceiling. Each additional bit doubles expected work.
-pub const MEDIUM_TRAFFIC_THRESHOLD: usize = 90;
-pub const HIGH_TRAFFIC_THRESHOLD: usize = 150;
+/// Base difficulty; [`crate::pow_difficulty`] adds to it under load. Each
+/// additional bit doubles expected work.
 pub const DEFAULT_POW_DIFFICULTY: u8 = 16;
+/// Expected browser solve time used to calibrate Argon2id when neither a
+/// difficulty nor a target is configured.
+pub const DEFAULT_POW_TARGET_MS: u64 = 2000;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum PowAlgorithm {
+    #[default]
+    #[serde(rename = "sha256-leading-zero-bits-v1")]
+    Sha256,
+    #[serde(rename = "argon2id-leading-zero-bits-v1")]
+    Argon2id,
+}
+
+impl PowAlgorithm {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha256 => "sha256-leading-zero-bits-v1",
+            Self::Argon2id => "argon2id-leading-zero-bits-v1",
+        }
+    }
+
+    /// Rough attempts per second in a current desktop browser: batched
+    /// WebCrypto digests for SHA-256, a WASM build with the default
+    /// [`Argon2Params`] for Argon2id.
+    pub fn browser_attempts_per_second(self) -> f64 {
+        match self {
+            Self::Sha256 => 50_000.0,
+            Self::Argon2id => 10.0,
+        }
+    }
+}
+
+impl fmt::Display for PowAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl FromStr for PowAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "sha256" | "sha256-leading-zero-bits-v1" => Ok(Self::Sha256),
+            "argon2id" | "argon2id-leading-zero-bits-v1" => Ok(Self::Argon2id),
+            other => Err(format!(
+                "unknown proof-of-work algorithm {other:?} (expected \"sha256\" or \"argon2id\")"
+            )),
+        }
+    }
+}
+
+/// Argon2id cost per attempt. The defaults are the OWASP minimum for
+/// password hashing: 19 MiB, two passes, one lane.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+pub struct Argon2Params {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Params {
+    fn default() -> Self {
+        Self {
+            memory_kib: 19 * 1024,
+            iterations: 2,
+            parallelism: 1,
+        }
+    }
+}
+
+impl Argon2Params {
+    pub fn validate(&self) -> Result<(), String> {
+        self.hasher().map(|_| ())
+    }
+
+    fn hasher(&self) -> Result<Argon2<'static>, String> {
+        let params = Params::new(self.memory_kib, self.iterations, self.parallelism, Some(32))
+            .map_err(|e| format!("invalid Argon2id parameters: {e}"))?;
+        Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+    }
+}
+
+/// Leading-zero bits whose expected work, `2^bits` attempts, takes about
+/// `target_ms` at `attempts_per_second`.
+pub fn difficulty_for(target_ms: u64, attempts_per_second: f64) -> u8 {
+    let attempts = (target_ms as f64 / 1000.0 * attempts_per_second).max(2.0);
+    attempts.log2().round().clamp(1.0, 30.0) as u8
+}
+
+/// Expected milliseconds to solve `difficulty` at `attempts_per_second`.
+pub fn expected_ms(difficulty: u8, attempts_per_second: f64) -> u64 {
+    (2f64.powi(i32::from(difficulty)) / attempts_per_second * 1000.0).round() as u64
+}
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct PowChallenge {
@@ -33,13 +151,20 @@ pub struct PowChallenge {
     pub issued_at: i64,
     pub difficulty: u8,
     pub signature: String,
+    /// Echoed back by the client. Clients that predate Argon2id omit it and
+    /// are read as SHA-256. Serialized by [`PowChallengeResponse`] instead.
+    #[serde(default, skip_serializing)]
+    pub algorithm: PowAlgorithm,
 }
 
 #[derive(Debug, Serialize)]
 pub struct PowChallengeResponse {
     #[serde(flatten)]
     pub challenge: PowChallenge,
-    pub algorithm: &'static str,
+    pub algorithm: PowAlgorithm,
+    /// Cost parameters, present for Argon2id challenges.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub argon2: Option<Argon2Params>,
     pub expires_in_seconds: i64,
 }
 
@@ -55,73 +180,296 @@ pub enum PowError {
     InvalidProof,
     #[error("challenge has already been used")]
     Reused,
+    #[error("proof-of-work algorithm is not accepted")]
+    UnsupportedAlgorithm,
     #[error("proof-of-work state is unavailable")]
     Lock,
 }
 
+/// HMAC keys for challenges. New challenges are signed with `current`; one
+/// signed with `previous` still verifies.
+#[derive(Clone)]
+pub struct PowKeys {
+    current: [u8; 32],
+    previous: Option<[u8; 32]>,
+}
+
+impl PowKeys {
+    pub fn generate() -> Self {
+        let mut current = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut current);
+        Self {
+            current,
+            previous: None,
+        }
+    }
+
+    /// Read a key file: the current key in hex on the first line, and
+    /// optionally the previous one on the second. Blank lines and `#`
+    /// comments are skipped.
+    pub fn read(path: &Path) -> Result<Self, String> {
+        let content =
+            fs::read_to_string(path).map_err(|e| format!("cannot read {}: {e}", path.display()))?;
+        let mut keys = content
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(|line| {
+                hex::decode(line)
+                    .ok()
+                    .and_then(|bytes| <[u8; 32]>::try_from(bytes).ok())
+                    .ok_or_else(|| format!("{}: keys must be 64 hex digits", path.display()))
+            });
+        let current = keys
+            .next()
+            .ok_or_else(|| format!("{} holds no key", path.display()))??;
+        let previous = keys.next().transpose()?;
+        if keys.next().is_some() {
+            return Err(format!(
+                "{} holds more than a current and a previous key",
+                path.display()
+            ));
+        }
+        Ok(Self { current, previous })
+    }
+
+    /// Read the key file, creating it with a fresh key if it does not exist.
+    pub fn load_or_create(path: &Path) -> Result<Self, String> {
+        if path.exists() {
+            return Self::read(path);
+        }
+        let keys = Self::generate();
+        keys.write(path)?;
+        info!("Created proof-of-work key file {}", path.display());
+        Ok(keys)
+    }
+
+    /// Make a new current key, keep the old current one as previous and drop
+    /// the one before it.
+    pub fn rotate(path: &Path) -> Result<Self, String> {
+        let old = Self::load_or_create(path)?;
+        let keys = Self {
+            previous: Some(old.current),
+            ..Self::generate()
+        };
+        keys.write(path)?;
+        Ok(keys)
+    }
+
+    /// Replace the file atomically. Only the owner can read it.
+    fn write(&self, path: &Path) -> Result<(), String> {
+        let write_error = |e: std::io::Error| format!("cannot write {}: {e}", path.display());
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent).map_err(write_error)?;
+            }
+        }
+        let mut content = format!("{}\n", hex::encode(self.current));
+        if let Some(previous) = self.previous {
+            content.push_str(&format!("{}\n", hex::encode(previous)));
+        }
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        let mut file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&temp)
+            .map_err(write_error)?;
+        file.write_all(content.as_bytes()).map_err(write_error)?;
+        file.sync_all().map_err(write_error)?;
+        fs::rename(&temp, path).map_err(write_error)
+    }
+
+    fn verifying(&self) -> impl Iterator<Item = &[u8; 32]> {
+        std::iter::once(&self.current).chain(self.previous.as_ref())
+    }
+}
+
+/// Fingerprint of the key file for change polling.
+type FileStamp = Option<(u64, Option<SystemTime>)>;
+
+fn stamp(path: &Path) -> FileStamp {
+    fs::metadata(path)
+        .ok()
+        .map(|metadata| (metadata.len(), metadata.modified().ok()))
+}
+
 pub struct PowManager {
-    secret: [u8; 32],
-    base_difficulty: u8,
-    /// challenge id -> wall-clock expiry. Entries exist only after a valid
-    /// proof is consumed, so challenge-request floods do not grow this map.
-    used: Mutex<HashMap<[u8; CHALLENGE_BYTES], i64>>,
+    keys: RwLock<PowKeys>,
+    /// Where `keys` came from, if they are reloaded from a file.
+    key_file: Option<PathBuf>,
+    key_stamp: Mutex<FileStamp>,
+    /// Algorithm of newly issued challenges.
+    algorithm: PowAlgorithm,
+    /// Algorithms whose proofs are verified, including `algorithm`.
+    accepted: Vec<PowAlgorithm>,
+    argon2: Argon2Params,
+    /// One hit per consumed challenge id, kept until the challenge expires.
+    /// Entries exist only after a valid proof is consumed, so
+    /// challenge-request floods do not grow it.
+    used: Arc<dyn RateLimitStore>,
+}
+
+impl Default for PowManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PowManager {
-    pub fn new(base_difficulty: u8) -> Self {
-        let mut secret = [0u8; 32];
-        rand::thread_rng().fill_bytes(&mut secret);
+    pub fn new() -> Self {
+        Self::with_keys(PowKeys::generate())
+    }
+
+    fn with_keys(keys: PowKeys) -> Self {
         Self {
-            secret,
-            base_difficulty,
-            used: Mutex::new(HashMap::new()),
+            keys: RwLock::new(keys),
+            key_file: None,
+            key_stamp: Mutex::new(None),
+            algorithm: PowAlgorithm::Sha256,
+            accepted: vec![PowAlgorithm::Sha256],
+            argon2: Argon2Params::default(),
+            used: Arc::new(MemoryStore::default()),
         }
     }
 
     #[cfg(test)]
-    fn with_secret(base_difficulty: u8, secret: [u8; 32]) -> Self {
-        Self {
-            secret,
-            base_difficulty,
-            used: Mutex::new(HashMap::new()),
+    fn with_secret(secret: [u8; 32]) -> Self {
+        Self::with_keys(PowKeys {
+            current: secret,
+            previous: None,
+        })
+    }
+
+    /// Sign and verify with the keys in `path`, creating it if needed, and
+    /// pick up rotations through [`spawn_key_reloader`].
+    pub fn with_key_file(mut self, path: &Path) -> Result<Self, String> {
+        let stamp = stamp(path);
+        self.keys = RwLock::new(PowKeys::load_or_create(path)?);
+        self.key_file = Some(path.to_path_buf());
+        self.key_stamp = Mutex::new(stamp);
+        Ok(self)
+    }
+
+    /// Record consumed challenges in `store`. Instances sharing the store
+    /// and the key file honour each other's consumptions.
+    pub fn with_used_store(mut self, store: Arc<dyn RateLimitStore>) -> Self {
+        self.used = store;
+        self
+    }
+
+    /// Issue `algorithm` challenges from now on. Proofs for other algorithms
+    /// are refused unless they are added with [`Self::also_accepting`].
+    pub fn with_algorithm(mut self, algorithm: PowAlgorithm, argon2: Argon2Params) -> Self {
+        self.algorithm = algorithm;
+        self.accepted = vec![algorithm];
+        self.argon2 = argon2;
+        self
+    }
+
+    pub fn algorithm(&self) -> PowAlgorithm {
+        self.algorithm
+    }
+
+    /// Keep verifying proofs for `algorithms`, for clients part way through
+    /// a switch of algorithm. Only for the switch itself: once challenges
+    /// issued before it have expired, the old algorithm serves no one.
+    pub fn also_accepting(mut self, algorithms: &[PowAlgorithm]) -> Self {
+        for algorithm in algorithms {
+            if !self.accepted.contains(algorithm) {
+                self.accepted.push(*algorithm);
+            }
         }
+        self
     }
 
-    pub fn difficulty(&self, recent_invites: usize) -> u8 {
-        let extra = if recent_invites >= HIGH_TRAFFIC_THRESHOLD {
-            4
-        } else if recent_invites >= MEDIUM_TRAFFIC_THRESHOLD {
-            2
-        } else {
-            0
-        };
-        self.base_difficulty.saturating_add(extra).min(30)
+    /// A fresh challenge at `difficulty` leading-zero bits, at most 30.
+    pub fn issue(&self, difficulty: u8) -> PowChallengeResponse {
+        self.issue_for(Purpose::Work, difficulty)
+    }
+
+    /// A fresh challenge to be signed with a ghost key instead of worked.
+    /// Only [`Self::consume_without_work`] accepts it.
+    pub fn issue_for_ghost_key(&self) -> PowChallengeResponse {
+        self.issue_for(Purpose::GhostKey, 0)
     }
 
-    pub fn issue(&self, recent_invites: usize) -> PowChallengeResponse {
+    fn issue_for(&self, purpose: Purpose, difficulty: u8) -> PowChallengeResponse {
         let mut id = [0u8; CHALLENGE_BYTES];
         rand::thread_rng().fill_bytes(&mut id);
         let issued_at = Utc::now().timestamp();
-        let difficulty = self.difficulty(recent_invites);
-        let signature = self.sign(&id, issued_at, difficulty);
+        let difficulty = difficulty.min(30);
+        let signature = {
+            let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+            mac(
+                &keys.current,
+                &id,
+                issued_at,
+                difficulty,
+                self.algorithm,
+                purpose,
+            )
+            .finalize()
+            .into_bytes()
+        };
         PowChallengeResponse {
             challenge: PowChallenge {
                 challenge: hex::encode(id),
                 issued_at,
                 difficulty,
                 signature: hex::encode(signature),
+                algorithm: self.algorithm,
             },
-            algorithm: "sha256-leading-zero-bits-v1",
+            algorithm: self.algorithm,
+            argon2: (self.algorithm == PowAlgorithm::Argon2id).then_some(self.argon2),
             expires_in_seconds: CHALLENGE_TTL_SECONDS,
         }
     }
 
     /// Validate and atomically consume a proof. The returned challenge id can
     /// be passed to [`Self::release`] if a downstream admission check fails.
+    ///
+    /// The challenge is consumed before the work is checked, so a wrong nonce
+    /// uses it up too: each challenge buys one hash, not one per guess. An
+    /// Argon2id proof costs one full hash to check, so callers on an async
+    /// runtime should run this on a blocking thread.
     pub fn verify_and_consume(
         &self,
         challenge: &PowChallenge,
         nonce: u64,
+    ) -> Result<[u8; CHALLENGE_BYTES], PowError> {
+        let id = self.consume(self.authenticate(challenge, Purpose::Work)?)?;
+        let valid = match challenge.algorithm {
+            PowAlgorithm::Sha256 => valid_proof(&id, nonce, challenge.difficulty),
+            PowAlgorithm::Argon2id => {
+                valid_argon2_proof(&id, nonce, challenge.difficulty, &self.argon2)
+            }
+        };
+        if !valid {
+            return Err(PowError::InvalidProof);
+        }
+        Ok(id)
+    }
+
+    /// Consume a challenge without checking any work, for requests that
+    /// prove themselves another way (a ghost key signature over the id). The
+    /// challenge still has to be genuine, fresh and unused, and issued by
+    /// [`Self::issue_for_ghost_key`].
+    pub fn consume_without_work(
+        &self,
+        challenge: &PowChallenge,
+    ) -> Result<[u8; CHALLENGE_BYTES], PowError> {
+        let id = self.authenticate(challenge, Purpose::GhostKey)?;
+        self.consume(id)
+    }
+
+    fn authenticate(
+        &self,
+        challenge: &PowChallenge,
+        purpose: Purpose,
     ) -> Result<[u8; CHALLENGE_BYTES], PowError> {
         let id: [u8; CHALLENGE_BYTES] = hex::decode(&challenge.challenge)
             .ok()
@@ -131,6 +479,9 @@ impl PowManager {
             .ok()
             .and_then(|v| v.try_into().ok())
             .ok_or(PowError::Malformed)?;
+        if !self.accepted.contains(&challenge.algorithm) {
+            return Err(PowError::UnsupportedAlgorithm);
+        }
 
         let now = Utc::now().timestamp();
         let age = now.saturating_sub(challenge.issued_at);
@@ -138,51 +489,161 @@ impl PowManager {
             return Err(PowError::Expired);
         }
 
-        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts 32-byte keys");
-        mac.update(DOMAIN);
-        mac.update(&id);
-        mac.update(&challenge.issued_at.to_be_bytes());
-        mac.update(&[challenge.difficulty]);
-        mac.verify_slice(&signature)
-            .map_err(|_| PowError::InvalidSignature)?;
-
-        if !valid_proof(&id, nonce, challenge.difficulty) {
-            return Err(PowError::InvalidProof);
+        let keys = self.keys.read().unwrap_or_else(|e| e.into_inner());
+        let genuine = keys.verifying().any(|key| {
+            mac(
+                key,
+                &id,
+                challenge.issued_at,
+                challenge.difficulty,
+                challenge.algorithm,
+                purpose,
+            )
+            .verify_slice(&signature)
+            .is_ok()
+        });
+        if !genuine {
+            return Err(PowError::InvalidSignature);
         }
+        Ok(id)
+    }
 
-        let mut used = self.used.lock().map_err(|_| PowError::Lock)?;
-        used.retain(|_, expiry| *expiry > now);
-        if used.contains_key(&id) {
-            return Err(PowError::Reused);
+    fn consume(&self, id: [u8; CHALLENGE_BYTES]) -> Result<[u8; CHALLENGE_BYTES], PowError> {
+        let now = Utc::now();
+        // A challenge is accepted only within its lifetime, so an earlier
+        // consumption of it cannot be older than that.
+        let since = now - chrono::Duration::seconds(CHALLENGE_TTL_SECONDS + 1);
+        match self
+            .used
+            .record_if_under(&[(hex::encode(id), 1)], since, now)
+        {
+            Ok(true) => Ok(id),
+            Ok(false) => Err(PowError::Reused),
+            Err(e) => {
+                error!("Cannot record consumed invite challenge: {e}");
+                Err(PowError::Lock)
+            }
         }
-        used.insert(id, challenge.issued_at + CHALLENGE_TTL_SECONDS);
-        Ok(id)
     }
 
     /// Make a consumed challenge reusable after a downstream refusal. This
     /// prevents a race at the global ceiling or a transient storage error from
     /// forcing a legitimate browser to repeat the expensive work.
     pub fn release(&self, id: &[u8; CHALLENGE_BYTES]) {
-        if let Ok(mut used) = self.used.lock() {
-            used.remove(id);
+        if let Err(e) = self.used.remove(&hex::encode(id)) {
+            warn!("Cannot release consumed invite challenge: {e}");
         }
     }
 
-    fn sign(
-        &self,
-        id: &[u8; CHALLENGE_BYTES],
-        issued_at: i64,
-        difficulty: u8,
-    ) -> [u8; SIGNATURE_BYTES] {
-        let mut mac = HmacSha256::new_from_slice(&self.secret).expect("HMAC accepts 32-byte keys");
-        mac.update(DOMAIN);
-        mac.update(id);
-        mac.update(&issued_at.to_be_bytes());
-        mac.update(&[difficulty]);
-        mac.finalize().into_bytes().into()
+    /// Sync the record of consumed challenges.
+    pub fn flush(&self) -> Result<(), RateLimitError> {
+        self.used.flush()
+    }
+
+    /// Where the keys in use came from, or why their file no longer reads.
+    pub fn key_source(&self) -> Result<String, String> {
+        match &self.key_file {
+            Some(path) => PowKeys::read(path).map(|_| format!("keys from {}", path.display())),
+            None => Ok("keys generated at startup, not shared with other instances".to_string()),
+        }
+    }
+
+    /// Re-read the key file; on error the current keys stay in force.
+    pub fn reload_keys(&self) -> Result<(), String> {
+        let Some(path) = &self.key_file else {
+            return Ok(());
+        };
+        let stamp = stamp(path);
+        let keys = PowKeys::read(path)?;
+        *self.keys.write().unwrap_or_else(|e| e.into_inner()) = keys;
+        *self.key_stamp.lock().unwrap_or_else(|e| e.into_inner()) = stamp;
+        Ok(())
+    }
+
+    fn reload_keys_if_changed(&self) -> Option<Result<(), String>> {
+        let path = self.key_file.as_ref()?;
+        let current = stamp(path);
+        if *self.key_stamp.lock().unwrap_or_else(|e| e.into_inner()) == current {
+            return None;
+        }
+        Some(self.reload_keys())
+    }
+}
+
+/// What a challenge may be spent on. Part of the signature, so a challenge
+/// handed out without any work behind it cannot stand in for a proof.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Purpose {
+    Work,
+    GhostKey,
+}
+
+fn mac(
+    key: &[u8; 32],
+    id: &[u8; CHALLENGE_BYTES],
+    issued_at: i64,
+    difficulty: u8,
+    algorithm: PowAlgorithm,
+    purpose: Purpose,
+) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts 32-byte keys");
+    mac.update(DOMAIN);
+    mac.update(id);
+    mac.update(&issued_at.to_be_bytes());
+    mac.update(&[difficulty]);
+    mac.update(algorithm.name().as_bytes());
+    // Proof-of-work challenges keep the signature they had before ghost key
+    // challenges existed, so none in flight are voided by an upgrade.
+    if purpose == Purpose::GhostKey {
+        mac.update(GHOST_KEY_PURPOSE);
+    }
+    mac
+}
+
+fn log_reload(trigger: &str, result: Result<(), String>) {
+    match result {
+        Ok(()) => info!("Reloaded proof-of-work keys ({trigger})"),
+        Err(e) => {
+            error!("Proof-of-work key reload ({trigger}) failed, keeping the previous keys: {e}")
+        }
     }
 }
 
+/// Reload the key file on SIGHUP and whenever it changes, so a rotation made
+/// for one instance reaches all of them.
+pub fn spawn_key_reloader(pow: Arc<PowManager>) {
+    if pow.key_file.is_none() {
+        return;
+    }
+    let polled = Arc::clone(&pow);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(KEY_POLL_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Some(result) = polled.reload_keys_if_changed() {
+                log_reload("file changed", result);
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                warn!(
+                    "Cannot listen for SIGHUP; proof-of-work keys reload on file change only: {e}"
+                );
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            log_reload("SIGHUP", pow.reload_keys());
+        }
+    });
+}
+
 pub(crate) fn valid_proof(id: &[u8; CHALLENGE_BYTES], nonce: u64, difficulty: u8) -> bool {
     let mut hasher = Sha256::new();
     hasher.update(DOMAIN);
@@ -191,6 +652,26 @@ pub(crate) fn valid_proof(id: &[u8; CHALLENGE_BYTES], nonce: u64, difficulty: u8
     has_leading_zero_bits(&hasher.finalize(), difficulty)
 }
 
+/// Argon2id over the domain, challenge id and big-endian nonce, salted with
+/// the challenge id, with a 32-byte output.
+pub(crate) fn valid_argon2_proof(
+    id: &[u8; CHALLENGE_BYTES],
+    nonce: u64,
+    difficulty: u8,
+    params: &Argon2Params,
+) -> bool {
+    let Ok(hasher) = params.hasher() else {
+        return false;
+    };
+    let mut input = Vec::with_capacity(ARGON2_DOMAIN.len() + CHALLENGE_BYTES + 8);
+    input.extend_from_slice(ARGON2_DOMAIN);
+    input.extend_from_slice(id);
+    input.extend_from_slice(&nonce.to_be_bytes());
+    let mut hash = [0u8; 32];
+    hasher.hash_password_into(&input, id, &mut hash).is_ok()
+        && has_leading_zero_bits(&hash, difficulty)
+}
+
 fn has_leading_zero_bits(hash: &[u8], difficulty: u8) -> bool {
     let full_bytes = usize::from(difficulty / 8);
     let remaining_bits = difficulty % 8;
@@ -206,6 +687,7 @@ fn has_leading_zero_bits(hash: &[u8], difficulty: u8) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use ghostkey_api::rate_limit_store::JsonStore;
 
     fn solve(challenge: &PowChallenge) -> u64 {
         (0..u64::MAX)
@@ -219,18 +701,10 @@ mod tests {
             .unwrap()
     }
 
-    #[test]
-    fn difficulty_is_adaptive() {
-        let manager = PowManager::with_secret(8, [7; 32]);
-        assert_eq!(manager.difficulty(0), 8);
-        assert_eq!(manager.difficulty(MEDIUM_TRAFFIC_THRESHOLD), 10);
-        assert_eq!(manager.difficulty(HIGH_TRAFFIC_THRESHOLD), 12);
-    }
-
     #[test]
     fn valid_proof_is_single_use() {
-        let manager = PowManager::with_secret(8, [7; 32]);
-        let response = manager.issue(0);
+        let manager = PowManager::with_secret([7; 32]);
+        let response = manager.issue(8);
         let nonce = solve(&response.challenge);
         let id = manager
             .verify_and_consume(&response.challenge, nonce)
@@ -247,8 +721,8 @@ mod tests {
 
     #[test]
     fn signed_fields_cannot_be_changed() {
-        let manager = PowManager::with_secret(8, [7; 32]);
-        let mut challenge = manager.issue(0).challenge;
+        let manager = PowManager::with_secret([7; 32]);
+        let mut challenge = manager.issue(8).challenge;
         challenge.difficulty = 1;
         assert_eq!(
             manager.verify_and_consume(&challenge, 0),
@@ -256,17 +730,231 @@ mod tests {
         );
     }
 
+    /// Otherwise one challenge buys an unlimited number of guesses, each a
+    /// full hash for the server to check.
     #[test]
-    fn incorrect_nonce_is_rejected() {
-        let manager = PowManager::with_secret(8, [7; 32]);
-        let challenge = manager.issue(0).challenge;
+    fn incorrect_nonce_is_rejected_and_uses_the_challenge_up() {
+        let manager = PowManager::with_secret([7; 32]);
+        let challenge = manager.issue(8).challenge;
+        let id: [u8; CHALLENGE_BYTES] = hex::decode(&challenge.challenge)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        let wrong = (0..u64::MAX)
+            .find(|nonce| !valid_proof(&id, *nonce, challenge.difficulty))
+            .unwrap();
+        assert_eq!(
+            manager.verify_and_consume(&challenge, wrong),
+            Err(PowError::InvalidProof)
+        );
+        assert_eq!(
+            manager.verify_and_consume(&challenge, solve(&challenge)),
+            Err(PowError::Reused)
+        );
+    }
+
+    #[test]
+    fn unworked_consumption_is_still_authenticated_and_single_use() {
+        let manager = PowManager::with_secret([7; 32]);
+        let challenge = manager.issue_for_ghost_key().challenge;
+        assert!(manager.consume_without_work(&challenge).is_ok());
+        assert_eq!(
+            manager.consume_without_work(&challenge),
+            Err(PowError::Reused)
+        );
+
+        let mut forged = manager.issue_for_ghost_key().challenge;
+        forged.issued_at -= 1;
+        assert_eq!(
+            manager.consume_without_work(&forged),
+            Err(PowError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn challenges_are_spent_only_on_their_purpose() {
+        let manager = PowManager::with_secret([7; 32]);
+        let unworked = manager.issue_for_ghost_key().challenge;
+        assert_eq!(
+            manager.verify_and_consume(&unworked, solve(&unworked)),
+            Err(PowError::InvalidSignature)
+        );
+        let worked = manager.issue(8).challenge;
+        assert_eq!(
+            manager.consume_without_work(&worked),
+            Err(PowError::InvalidSignature)
+        );
+        // Neither refusal used the challenge up.
+        assert!(manager.consume_without_work(&unworked).is_ok());
+        assert!(manager.verify_and_consume(&worked, solve(&worked)).is_ok());
+    }
+
+    const CHEAP_ARGON2: Argon2Params = Argon2Params {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    fn solve_argon2(challenge: &PowChallenge) -> u64 {
+        let id: [u8; CHALLENGE_BYTES] = hex::decode(&challenge.challenge)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        (0..u64::MAX)
+            .find(|nonce| valid_argon2_proof(&id, *nonce, challenge.difficulty, &CHEAP_ARGON2))
+            .unwrap()
+    }
+
+    #[test]
+    fn argon2id_proofs_match_the_browser_solver() {
+        // The same vector is checked against the solver in gkwasm.
+        let solved =
+            (0..64).find(|nonce| valid_argon2_proof(&[0x11; 16], *nonce, 4, &CHEAP_ARGON2));
+        assert_eq!(solved, Some(41));
+    }
+
+    #[test]
+    fn argon2id_challenges_advertise_and_check_their_algorithm() {
+        let manager =
+            PowManager::with_secret([7; 32]).with_algorithm(PowAlgorithm::Argon2id, CHEAP_ARGON2);
+        let response = manager.issue(3);
+        assert_eq!(response.argon2, Some(CHEAP_ARGON2));
+        let json = serde_json::to_value(&response).unwrap();
+        assert_eq!(json["algorithm"], "argon2id-leading-zero-bits-v1");
+        assert_eq!(json["argon2"]["memory_kib"], 64);
+
+        let nonce = solve_argon2(&response.challenge);
+        assert!(manager
+            .verify_and_consume(&response.challenge, nonce)
+            .is_ok());
+        assert_eq!(
+            manager.verify_and_consume(&response.challenge, nonce),
+            Err(PowError::Reused)
+        );
+
+        // A SHA-256 proof is not accepted for an Argon2id challenge, even if
+        // the client relabels it.
+        let mut relabeled = manager.issue(3).challenge;
+        relabeled.algorithm = PowAlgorithm::Sha256;
+        assert_eq!(
+            manager.verify_and_consume(&relabeled, solve(&relabeled)),
+            Err(PowError::UnsupportedAlgorithm)
+        );
+    }
+
+    #[test]
+    fn both_algorithms_verify_during_a_transition() {
+        let old = PowManager::with_secret([7; 32]);
+        let sha_challenge = old.issue(8).challenge;
+        let sha_nonce = solve(&sha_challenge);
+
+        let strict =
+            PowManager::with_secret([7; 32]).with_algorithm(PowAlgorithm::Argon2id, CHEAP_ARGON2);
+        assert_eq!(
+            strict.verify_and_consume(&sha_challenge, sha_nonce),
+            Err(PowError::UnsupportedAlgorithm)
+        );
+
+        let moving = PowManager::with_secret([7; 32])
+            .with_algorithm(PowAlgorithm::Argon2id, CHEAP_ARGON2)
+            .also_accepting(&[PowAlgorithm::Sha256]);
+        assert!(moving.verify_and_consume(&sha_challenge, sha_nonce).is_ok());
+
+        // The algorithm is signed: an Argon2id challenge cannot be solved
+        // with SHA-256 by changing the label.
+        let mut relabeled = moving.issue(3).challenge;
+        relabeled.algorithm = PowAlgorithm::Sha256;
+        assert_eq!(
+            moving.verify_and_consume(&relabeled, solve(&relabeled)),
+            Err(PowError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn difficulty_is_calibrated_from_expected_milliseconds() {
+        // 2 s at 50k/s is 100k attempts, about 2^17.
+        assert_eq!(difficulty_for(2000, 50_000.0), 17);
+        // 2 s at 10/s is 20 attempts, about 2^4.
+        assert_eq!(difficulty_for(2000, 10.0), 4);
+        assert_eq!(difficulty_for(0, 10.0), 1);
+        assert_eq!(expected_ms(4, 10.0), 1600);
+        assert_eq!(
+            "argon2id".parse::<PowAlgorithm>(),
+            Ok(PowAlgorithm::Argon2id)
+        );
+        assert!("scrypt".parse::<PowAlgorithm>().is_err());
+    }
+
+    #[test]
+    fn file_keys_survive_restarts_and_one_rotation() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pow_key");
+        let first = PowManager::new().with_key_file(&path).unwrap();
+        let before_restart = first.issue(8).challenge;
+        let before_rotation = first.issue(8).challenge;
+        let before_two_rotations = first.issue(8).challenge;
+
+        let restarted = PowManager::new().with_key_file(&path).unwrap();
+        assert!(restarted
+            .verify_and_consume(&before_restart, solve(&before_restart))
+            .is_ok());
+
+        PowKeys::rotate(&path).unwrap();
+        restarted.reload_keys().unwrap();
+        assert!(restarted
+            .verify_and_consume(&before_rotation, solve(&before_rotation))
+            .is_ok());
+        let after_rotation = restarted.issue(8).challenge;
+
+        PowKeys::rotate(&path).unwrap();
+        assert_eq!(restarted.reload_keys_if_changed(), Some(Ok(())));
+        assert_eq!(
+            restarted.verify_and_consume(&before_two_rotations, solve(&before_two_rotations)),
+            Err(PowError::InvalidSignature)
+        );
+        assert!(restarted
+            .verify_and_consume(&after_rotation, solve(&after_rotation))
+            .is_ok());
+    }
+
+    #[test]
+    fn instances_sharing_a_store_consume_each_challenge_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_file = dir.path().join("pow_key");
+        let used = dir.path().join("pow_used.json");
+        let instance = || {
+            PowManager::new()
+                .with_key_file(&key_file)
+                .unwrap()
+                .with_used_store(Arc::new(JsonStore::new(used.clone())))
+        };
+        let (a, b) = (instance(), instance());
+
+        let challenge = a.issue(8).challenge;
         let nonce = solve(&challenge);
-        let wrong = nonce.wrapping_add(1);
-        if wrong != nonce {
-            assert_eq!(
-                manager.verify_and_consume(&challenge, wrong),
-                Err(PowError::InvalidProof)
-            );
+        let id = a.verify_and_consume(&challenge, nonce).unwrap();
+        assert_eq!(
+            b.verify_and_consume(&challenge, nonce),
+            Err(PowError::Reused)
+        );
+        a.release(&id);
+        assert!(b.verify_and_consume(&challenge, nonce).is_ok());
+    }
+
+    #[test]
+    fn key_files_are_checked() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("pow_key");
+        let key = hex::encode([1u8; 32]);
+        for (content, ok) in [
+            (format!("# gkapi\n{key}\n"), true),
+            (format!("{key}\n{key}\n"), true),
+            (format!("{key}\n{key}\n{key}\n"), false),
+            ("abcd\n".to_string(), false),
+            ("\n".to_string(), false),
+        ] {
+            fs::write(&path, &content).unwrap();
+            assert_eq!(PowKeys::read(&path).is_ok(), ok, "{content:?}");
         }
     }
 }
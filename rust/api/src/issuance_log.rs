@@ -0,0 +1,554 @@
+//! Append-only, hash-chained log of blind signatures, and the master-signed
+//! checkpoints published at `/transparency`.
+//!
+//! A leaked or misused notary key mints Ghost Keys that are indistinguishable
+//! from paid-for ones; blind signatures are unlinkable by design. What can be
+//! observed is volume: every legitimate signature corresponds to one donation,
+//! so a notary whose issuance count runs ahead of the donation totals for its
+//! tier has been used outside this server.
+//!
+//! Each line records the tier, the notary key, the time and a hash of the
+//! PaymentIntent id, and commits to the previous line's hash, so rewriting or
+//! dropping history breaks the chain from that point on. The blinded message
+//! is never recorded: it is the one value that could help link a certificate
+//! back to a donation.
+//!
+//! The master key is kept offline and never touches this server, so
+//! checkpoints are signed elsewhere: an operator copies the log to the master
+//! key's machine, runs `sign-checkpoint`, and appends the output to the
+//! checkpoints file this server publishes. Anyone can then check the signed
+//! counts against the chain and against donation totals.
+
+use std::collections::BTreeMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::Utc;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Prefixed to a checkpoint before signing so a master signature over one
+/// can never be replayed as a signature over anything else.
+const CHECKPOINT_DOMAIN: &[u8] = b"freenet-ghostkey-issuance-checkpoint-v1\n";
+
+#[derive(Error, Debug)]
+pub enum IssuanceLogError {
+    #[error("issuance log {path}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("issuance log {path} line {line}: {reason}")]
+    Corrupt {
+        path: PathBuf,
+        line: usize,
+        reason: String,
+    },
+    #[error("issuance log is unavailable")]
+    Lock,
+}
+
+/// The hashed part of an entry. Field order is part of the format.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct EntryBody {
+    seq: u64,
+    time: String,
+    tier: u64,
+    notary: String,
+    payment_intent_sha256: String,
+    prev: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Entry {
+    #[serde(flatten)]
+    body: EntryBody,
+    hash: String,
+}
+
+fn entry_hash(body: &EntryBody) -> String {
+    let json = serde_json::to_vec(body).expect("entry bodies always serialize");
+    hex::encode(Sha256::digest(json))
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct NotaryCount {
+    pub notary: String,
+    pub tier: u64,
+    pub issued: u64,
+}
+
+/// State of the chain after its last entry.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Checkpoint {
+    pub entries: u64,
+    pub head: String,
+    pub time: String,
+    pub counts: Vec<NotaryCount>,
+}
+
+/// A checkpoint signed by the master key. `checkpoint` is kept as the exact
+/// JSON text that was signed, so verifiers in any language check the bytes
+/// they were given rather than a re-serialization.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedCheckpoint {
+    pub checkpoint: String,
+    pub signature: String,
+}
+
+impl SignedCheckpoint {
+    pub fn sign(checkpoint: &Checkpoint, master: &SigningKey) -> Self {
+        let checkpoint = serde_json::to_string(checkpoint).expect("checkpoints always serialize");
+        let signature = master.sign(&[CHECKPOINT_DOMAIN, checkpoint.as_bytes()].concat());
+        Self {
+            checkpoint,
+            signature: BASE64.encode(signature.to_bytes()),
+        }
+    }
+
+    pub fn verify(&self, master_vk: &VerifyingKey) -> Result<Checkpoint, String> {
+        let signature = BASE64
+            .decode(&self.signature)
+            .ok()
+            .and_then(|bytes| Signature::from_slice(&bytes).ok())
+            .ok_or("signature is malformed")?;
+        master_vk
+            .verify(
+                &[CHECKPOINT_DOMAIN, self.checkpoint.as_bytes()].concat(),
+                &signature,
+            )
+            .map_err(|_| "signature does not verify against the master key")?;
+        serde_json::from_str(&self.checkpoint).map_err(|e| format!("checkpoint is malformed: {e}"))
+    }
+}
+
+#[derive(Default)]
+struct Chain {
+    entries: u64,
+    head: String,
+    /// (notary, tier) -> signatures issued
+    counts: BTreeMap<(String, u64), u64>,
+    /// Bytes up to the end of the last complete line. Anything past it is
+    /// a line torn by a crash mid-append.
+    complete_len: u64,
+}
+
+impl Chain {
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            entries: self.entries,
+            head: self.head.clone(),
+            time: Utc::now().to_rfc3339(),
+            counts: self
+                .counts
+                .iter()
+                .map(|((notary, tier), issued)| NotaryCount {
+                    notary: notary.clone(),
+                    tier: *tier,
+                    issued: *issued,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Read and verify a whole log. A missing file is an empty chain.
+fn read_chain(path: &Path) -> Result<Chain, IssuanceLogError> {
+    let mut chain = Chain {
+        head: GENESIS_HASH.to_string(),
+        ..Chain::default()
+    };
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(chain),
+        Err(source) => {
+            return Err(IssuanceLogError::Io {
+                path: path.to_path_buf(),
+                source,
+            })
+        }
+    };
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+    for index in 0.. {
+        let corrupt = |reason: String| IssuanceLogError::Corrupt {
+            path: path.to_path_buf(),
+            line: index + 1,
+            reason,
+        };
+        line.clear();
+        let read = reader
+            .read_until(b'\n', &mut line)
+            .map_err(|e| corrupt(e.to_string()))?;
+        // An unterminated last line was never acknowledged: `record` returns
+        // only after the whole line, newline included, is on disk.
+        if line.last() != Some(&b'\n') {
+            break;
+        }
+        let entry: Entry = serde_json::from_slice(&line).map_err(|e| corrupt(e.to_string()))?;
+        if entry.body.seq != chain.entries + 1 {
+            return Err(corrupt(format!(
+                "sequence {} follows {}",
+                entry.body.seq, chain.entries
+            )));
+        }
+        if entry.body.prev != chain.head {
+            return Err(corrupt("does not chain to the previous entry".to_string()));
+        }
+        if entry_hash(&entry.body) != entry.hash {
+            return Err(corrupt("hash does not match its contents".to_string()));
+        }
+        chain.entries = entry.body.seq;
+        chain.head = entry.hash;
+        *chain
+            .counts
+            .entry((entry.body.notary, entry.body.tier))
+            .or_default() += 1;
+        chain.complete_len += read as u64;
+    }
+    Ok(chain)
+}
+
+/// Verify the log at `path` and describe its current state, for signing.
+pub fn checkpoint_of(path: &Path) -> Result<Checkpoint, IssuanceLogError> {
+    read_chain(path).map(|chain| chain.checkpoint())
+}
+
+pub struct IssuanceLog {
+    path: PathBuf,
+    state: Arc<Mutex<(File, Chain)>>,
+}
+
+impl IssuanceLog {
+    /// Open `path` for appending, verifying every existing entry first. A
+    /// broken chain is an error: appending to it would hide the break. A
+    /// torn last line, left by a crash mid-append, is cut off with a warning.
+    pub fn open(path: &Path) -> Result<Self, IssuanceLogError> {
+        let chain = read_chain(path)?;
+        let io_error = |source| IssuanceLogError::Io {
+            path: path.to_path_buf(),
+            source,
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(io_error)?;
+        let len = file.metadata().map_err(io_error)?.len();
+        if len > chain.complete_len {
+            warn!(
+                "Issuance log {} ends in a partial line ({} bytes), presumably from a crash \
+                 mid-append; truncating it",
+                path.display(),
+                len - chain.complete_len
+            );
+            file.set_len(chain.complete_len).map_err(io_error)?;
+            file.sync_all().map_err(io_error)?;
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            state: Arc::new(Mutex::new((file, chain))),
+        })
+    }
+
+    /// Append one issuance and flush it to disk before returning, so a
+    /// signature is never handed out for an entry that a crash could lose.
+    /// The write and sync run on the blocking pool.
+    pub async fn record(
+        &self,
+        tier: u64,
+        notary: &str,
+        payment_intent_id: &str,
+    ) -> Result<(), IssuanceLogError> {
+        let state = Arc::clone(&self.state);
+        let path = self.path.clone();
+        let notary = notary.to_string();
+        let payment_intent_sha256 = hex::encode(Sha256::digest(payment_intent_id.as_bytes()));
+        tokio::task::spawn_blocking(move || {
+            append(&state, &path, tier, notary, payment_intent_sha256)
+        })
+        .await
+        .map_err(|_| IssuanceLogError::Lock)?
+    }
+
+    /// The live, unsigned state of the chain.
+    pub fn current(&self) -> Result<Checkpoint, IssuanceLogError> {
+        let state = self.state.lock().map_err(|_| IssuanceLogError::Lock)?;
+        Ok(state.1.checkpoint())
+    }
+
+    /// Wait out a write in progress and sync the file, metadata included.
+    pub fn flush(&self) -> Result<(), IssuanceLogError> {
+        let state = self.state.lock().map_err(|_| IssuanceLogError::Lock)?;
+        state.0.sync_all().map_err(|source| IssuanceLogError::Io {
+            path: self.path.clone(),
+            source,
+        })
+    }
+}
+
+/// Append one entry under the lock, for [`IssuanceLog::record`].
+fn append(
+    state: &Mutex<(File, Chain)>,
+    path: &Path,
+    tier: u64,
+    notary: String,
+    payment_intent_sha256: String,
+) -> Result<(), IssuanceLogError> {
+    let mut state = state.lock().map_err(|_| IssuanceLogError::Lock)?;
+    let (file, chain) = &mut *state;
+    let body = EntryBody {
+        seq: chain.entries + 1,
+        time: Utc::now().to_rfc3339(),
+        tier,
+        notary,
+        payment_intent_sha256,
+        prev: chain.head.clone(),
+    };
+    let entry = Entry {
+        hash: entry_hash(&body),
+        body,
+    };
+    let mut line = serde_json::to_string(&entry).expect("entries always serialize");
+    line.push('\n');
+    let io_error = |source| IssuanceLogError::Io {
+        path: path.to_path_buf(),
+        source,
+    };
+    if let Err(e) = file
+        .write_all(line.as_bytes())
+        .and_then(|()| file.sync_data())
+    {
+        // Cut off whatever part of the line did land, so the next entry is
+        // not appended to a torn one.
+        let _ = file.set_len(chain.complete_len);
+        return Err(io_error(e));
+    }
+
+    chain.entries = entry.body.seq;
+    chain.head = entry.hash;
+    *chain
+        .counts
+        .entry((entry.body.notary, entry.body.tier))
+        .or_default() += 1;
+    chain.complete_len += line.len() as u64;
+    Ok(())
+}
+
+/// The published checkpoints file: one [`SignedCheckpoint`] JSON per line,
+/// appended by hand. Re-read only when its modification time changes.
+pub struct Checkpoints {
+    path: PathBuf,
+    master_vk: VerifyingKey,
+    cache: Mutex<Option<(SystemTime, Arc<Vec<SignedCheckpoint>>)>>,
+}
+
+impl Checkpoints {
+    pub fn new(path: PathBuf, master_vk: VerifyingKey) -> Self {
+        Self {
+            path,
+            master_vk,
+            cache: Mutex::new(None),
+        }
+    }
+
+    pub fn master_verifying_key(&self) -> &VerifyingKey {
+        &self.master_vk
+    }
+
+    /// Every checkpoint whose signature verifies. Lines that do not verify are
+    /// logged and left out rather than published with a bad signature.
+    pub fn load(&self) -> Arc<Vec<SignedCheckpoint>> {
+        let modified = fs::metadata(&self.path).and_then(|m| m.modified()).ok();
+        let mut cache = self.cache.lock().unwrap_or_else(|e| e.into_inner());
+        if let (Some(modified), Some((cached_at, checkpoints))) = (modified, cache.as_ref()) {
+            if modified == *cached_at {
+                return Arc::clone(checkpoints);
+            }
+        }
+        let Some(modified) = modified else {
+            return Arc::new(Vec::new());
+        };
+        let (checkpoints, rejected) = read_checkpoints(&self.path, &self.master_vk);
+        for reason in rejected {
+            warn!(
+                "Not publishing checkpoint from {}: {reason}",
+                self.path.display()
+            );
+        }
+        let checkpoints = Arc::new(checkpoints);
+        *cache = Some((modified, Arc::clone(&checkpoints)));
+        checkpoints
+    }
+}
+
+/// Split the file at `path` into verified checkpoints and reasons for the rest.
+pub fn read_checkpoints(
+    path: &Path,
+    master_vk: &VerifyingKey,
+) -> (Vec<SignedCheckpoint>, Vec<String>) {
+    let mut checkpoints = Vec::new();
+    let mut rejected = Vec::new();
+    let Ok(content) = fs::read_to_string(path) else {
+        return (checkpoints, rejected);
+    };
+    for (index, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let verified = serde_json::from_str::<SignedCheckpoint>(line)
+            .map_err(|e| e.to_string())
+            .and_then(|signed| signed.verify(master_vk).map(|_| signed));
+        match verified {
+            Ok(signed) => checkpoints.push(signed),
+            Err(e) => rejected.push(format!("line {}: {e}", index + 1)),
+        }
+    }
+    (checkpoints, rejected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand_core::OsRng;
+    use tempfile::tempdir;
+
+    #[tokio::test]
+    async fn entries_chain_and_survive_a_reopen() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("issuance.jsonl");
+
+        let log = IssuanceLog::open(&path).unwrap();
+        log.record(20, "aa", "pi_1").await.unwrap();
+        log.record(20, "aa", "pi_2").await.unwrap();
+        log.record(50, "bb", "pi_3").await.unwrap();
+        let before = log.current().unwrap();
+        drop(log);
+
+        let log = IssuanceLog::open(&path).unwrap();
+        let after = log.current().unwrap();
+        assert_eq!(after.entries, 3);
+        assert_eq!(after.head, before.head);
+        assert_eq!(
+            after.counts,
+            vec![
+                NotaryCount {
+                    notary: "aa".to_string(),
+                    tier: 20,
+                    issued: 2
+                },
+                NotaryCount {
+                    notary: "bb".to_string(),
+                    tier: 50,
+                    issued: 1
+                },
+            ]
+        );
+
+        let content = fs::read_to_string(&path).unwrap();
+        assert!(
+            !content.contains("pi_1"),
+            "only a hash of the PaymentIntent"
+        );
+        assert!(content.contains(&hex::encode(Sha256::digest(b"pi_1"))));
+    }
+
+    #[tokio::test]
+    async fn edited_or_dropped_entries_break_the_chain() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("issuance.jsonl");
+        let log = IssuanceLog::open(&path).unwrap();
+        for pi in ["pi_1", "pi_2", "pi_3"] {
+            log.record(20, "aa", pi).await.unwrap();
+        }
+        drop(log);
+        let lines: Vec<String> = fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect();
+
+        fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        assert!(matches!(
+            IssuanceLog::open(&path),
+            Err(IssuanceLogError::Corrupt { line: 2, .. })
+        ));
+
+        let edited = lines[1].replace("\"tier\":20", "\"tier\":5");
+        fs::write(&path, format!("{}\n{}\n{}\n", lines[0], edited, lines[2])).unwrap();
+        match IssuanceLog::open(&path) {
+            Err(IssuanceLogError::Corrupt {
+                line: 2, reason, ..
+            }) => {
+                assert!(reason.contains("hash"), "{reason}")
+            }
+            other => panic!("expected a hash mismatch, got {:?}", other.err()),
+        }
+    }
+
+    #[tokio::test]
+    async fn a_torn_last_line_is_cut_off_on_open() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("issuance.jsonl");
+        let log = IssuanceLog::open(&path).unwrap();
+        log.record(20, "aa", "pi_1").await.unwrap();
+        log.record(20, "aa", "pi_2").await.unwrap();
+        drop(log);
+        let complete = fs::read_to_string(&path).unwrap();
+        let second = complete.lines().nth(1).unwrap();
+        let first_len = complete.len() - second.len() - 1;
+        fs::write(&path, &complete[..first_len + second.len() / 2]).unwrap();
+
+        assert_eq!(checkpoint_of(&path).unwrap().entries, 1);
+        let log = IssuanceLog::open(&path).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            complete[..first_len],
+            "the partial line is truncated"
+        );
+        log.record(20, "aa", "pi_3").await.unwrap();
+        drop(log);
+        assert_eq!(
+            IssuanceLog::open(&path).unwrap().current().unwrap().entries,
+            2
+        );
+    }
+
+    #[tokio::test]
+    async fn checkpoints_verify_only_under_the_master_key() {
+        let dir = tempdir().unwrap();
+        let log_path = dir.path().join("issuance.jsonl");
+        let log = IssuanceLog::open(&log_path).unwrap();
+        log.record(20, "aa", "pi_1").await.unwrap();
+
+        let master = SigningKey::generate(&mut OsRng);
+        let impostor = SigningKey::generate(&mut OsRng);
+        let checkpoint = checkpoint_of(&log_path).unwrap();
+        let signed = SignedCheckpoint::sign(&checkpoint, &master);
+        assert_eq!(signed.verify(&master.verifying_key()).unwrap(), checkpoint);
+
+        let forged = SignedCheckpoint::sign(&checkpoint, &impostor);
+        let mut tampered = signed.clone();
+        tampered.checkpoint = tampered.checkpoint.replace("\"issued\":1", "\"issued\":0");
+        assert!(tampered.verify(&master.verifying_key()).is_err());
+
+        let path = dir.path().join("checkpoints.jsonl");
+        let lines: Vec<String> = [&signed, &forged, &tampered]
+            .iter()
+            .map(|c| serde_json::to_string(c).unwrap())
+            .collect();
+        fs::write(&path, lines.join("\n")).unwrap();
+        let published = Checkpoints::new(path, master.verifying_key()).load();
+        assert_eq!(published.len(), 1);
+        assert_eq!(published[0].checkpoint, signed.checkpoint);
+    }
+}
@@ -0,0 +1,12 @@
+//! Notary key handling shared by the `ghostkey-api` web server and the
+//! `gknotary` signing daemon.
+//!
+//! Everything else lives in the `ghostkey-api` binary. Only what both
+//! processes need is here, so `gknotary` links no HTTP or payment code.
+
+pub mod delegates;
+pub mod errors;
+pub mod notary_signer;
+pub mod notary_store;
+pub mod rate_limit;
+pub mod rate_limit_store;
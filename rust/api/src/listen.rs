@@ -0,0 +1,613 @@
+//! Where gkapi accepts connections.
+//!
+//! `server.listen` and `server.challenge_listen` each take a list of:
+//!
+//! - `ip:port`, TCP. `[::]:443` is dual-stack and takes IPv4 connections
+//!   too, unless an IPv4 address on the same port is also listed, in which
+//!   case it takes IPv6 only. IPv4 peers on a dual-stack socket arrive as
+//!   `::ffff:a.b.c.d`; [`ClientIp`](crate::client_ip::ClientIp) and the Tor
+//!   check both map them back to IPv4.
+//! - `unix:/path`, a Unix domain socket for a proxy on the same host. It
+//!   speaks plain HTTP without a PROXY header, and its peer is taken to be a
+//!   trusted proxy.
+//! - `systemd` or `systemd:<name>`, the sockets systemd passed in through
+//!   socket activation: those whose `FileDescriptorName=` is `<name>`, or
+//!   all that no named entry took.
+//!
+//! Everything is bound before any of it is served, so a taken port or a
+//! missing socket stops startup instead of leaving half the listeners up.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum::Router;
+use axum_server::accept::DefaultAcceptor;
+use axum_server::tls_rustls::{RustlsAcceptor, RustlsConfig};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use hyper_util::server::conn::auto::Builder;
+use hyper_util::service::TowerToHyperService;
+use log::warn;
+use socket2::{Domain, SockRef, Socket, Type};
+use tokio::task::JoinSet;
+use tower_http::add_extension::AddExtension;
+
+use crate::client_ip::{ProxyProtocolAcceptor, UnixPeer};
+use crate::shutdown::Drain;
+
+/// The first descriptor systemd passes (`SD_LISTEN_FDS_START`).
+const LISTEN_FDS_START: RawFd = 3;
+
+const BACKLOG: i32 = 1024;
+
+/// One `server.listen` entry.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Listen {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+    /// Sockets passed in by systemd, by `FileDescriptorName=`, or all the
+    /// rest when `None`.
+    Systemd(Option<String>),
+}
+
+impl FromStr for Listen {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err(format!("listen address {s:?} names no socket path"));
+            }
+            return Ok(Self::Unix(PathBuf::from(path)));
+        }
+        if s == "systemd" {
+            return Ok(Self::Systemd(None));
+        }
+        if let Some(name) = s.strip_prefix("systemd:") {
+            if name.is_empty() || name.contains(':') {
+                return Err(format!("listen address {s:?} has no usable socket name"));
+            }
+            return Ok(Self::Systemd(Some(name.to_string())));
+        }
+        s.parse().map(Self::Tcp).map_err(|_| {
+            format!("listen address {s:?} is not ip:port, unix:/path or systemd[:name]")
+        })
+    }
+}
+
+impl fmt::Display for Listen {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "{addr}"),
+            Self::Unix(path) => write!(f, "unix:{}", path.display()),
+            Self::Systemd(None) => write!(f, "systemd"),
+            Self::Systemd(Some(name)) => write!(f, "systemd:{name}"),
+        }
+    }
+}
+
+pub enum Listener {
+    Tcp(TcpListener),
+    /// `path` is removed again at shutdown; it is `None` for a socket systemd
+    /// owns.
+    Unix {
+        listener: UnixListener,
+        path: Option<PathBuf>,
+    },
+}
+
+/// A bound socket and how to name it in the log.
+pub struct Bound {
+    pub name: String,
+    pub listener: Listener,
+}
+
+/// The sockets systemd passed in, each handed out once.
+#[derive(Default)]
+pub struct Activated {
+    sockets: Vec<(String, OwnedFd)>,
+}
+
+impl Activated {
+    /// Take the sockets named in `LISTEN_FDS`, if they are meant for this
+    /// process. Only the first call takes them; later ones get none.
+    ///
+    /// The variables are left set: clearing them would race with other
+    /// threads reading the environment, and a child process sees a
+    /// `LISTEN_PID` other than its own and ignores them.
+    pub fn from_env() -> Result<Self, String> {
+        static TAKEN: AtomicBool = AtomicBool::new(false);
+        if TAKEN.swap(true, Ordering::SeqCst) {
+            return Ok(Self::default());
+        }
+        let pid = std::env::var("LISTEN_PID").ok();
+        let count = std::env::var("LISTEN_FDS").ok();
+        let names = std::env::var("LISTEN_FDNAMES").ok();
+        let (Some(pid), Some(count)) = (pid, count) else {
+            return Ok(Self::default());
+        };
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return Ok(Self::default());
+        }
+        let count: RawFd = count
+            .parse()
+            .map_err(|_| format!("LISTEN_FDS={count:?} is not a number"))?;
+        let names: Vec<String> = names
+            .map(|names| names.split(':').map(str::to_string).collect())
+            .unwrap_or_default();
+        let mut sockets = Vec::new();
+        for (i, raw) in (LISTEN_FDS_START..LISTEN_FDS_START + count).enumerate() {
+            // SAFETY: systemd passes `count` open descriptors from 3 up to
+            // the process in LISTEN_PID, and `TAKEN` lets only one call wrap
+            // them, so each is owned here and nowhere else. Close-on-exec
+            // keeps them out of child processes.
+            let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+            SockRef::from(&fd)
+                .set_cloexec(true)
+                .map_err(|e| format!("passed descriptor {raw} is not usable: {e}"))?;
+            let name = names
+                .get(i)
+                .cloned()
+                .unwrap_or_else(|| "unknown".to_string());
+            sockets.push((name, fd));
+        }
+        Ok(Self { sockets })
+    }
+
+    /// Sockets named `name`, or all that are left for `None`.
+    fn take(&mut self, name: Option<&str>) -> Vec<(String, OwnedFd)> {
+        let (taken, kept) = std::mem::take(&mut self.sockets)
+            .into_iter()
+            .partition(|(fd_name, _)| name.is_none_or(|name| fd_name == name));
+        self.sockets = kept;
+        taken
+    }
+
+    /// Names of passed sockets no listen entry took.
+    pub fn unclaimed(&self) -> Vec<&str> {
+        self.sockets.iter().map(|(name, _)| name.as_str()).collect()
+    }
+}
+
+/// Bind `main` and `challenge`. Named systemd entries are served before the
+/// bare `systemd` takes what is left.
+pub fn bind(
+    main: &[Listen],
+    challenge: &[Listen],
+    activated: &mut Activated,
+) -> Result<(Vec<Bound>, Vec<Bound>), String> {
+    let named = |entry: &&Listen| matches!(entry, Listen::Systemd(Some(_)));
+    let mut bound = (Vec::new(), Vec::new());
+    for (list, out) in [(main, &mut bound.0), (challenge, &mut bound.1)] {
+        for entry in list.iter().filter(named) {
+            out.extend(bind_one(entry, list, activated)?);
+        }
+    }
+    for (list, out) in [(main, &mut bound.0), (challenge, &mut bound.1)] {
+        for entry in list.iter().filter(|entry| !named(entry)) {
+            out.extend(bind_one(entry, list, activated)?);
+        }
+    }
+    Ok(bound)
+}
+
+fn bind_one(
+    entry: &Listen,
+    list: &[Listen],
+    activated: &mut Activated,
+) -> Result<Vec<Bound>, String> {
+    match entry {
+        Listen::Tcp(addr) => {
+            let listener = bind_tcp(*addr, dual_stack(*addr, list))
+                .map_err(|e| format!("cannot listen on {addr}: {e}"))?;
+            Ok(vec![Bound {
+                name: entry.to_string(),
+                listener: Listener::Tcp(listener),
+            }])
+        }
+        Listen::Unix(path) => {
+            let listener = bind_unix(path).map_err(|e| format!("cannot listen on {entry}: {e}"))?;
+            Ok(vec![Bound {
+                name: entry.to_string(),
+                listener: Listener::Unix {
+                    listener,
+                    path: Some(path.clone()),
+                },
+            }])
+        }
+        Listen::Systemd(name) => {
+            let sockets = activated.take(name.as_deref());
+            if sockets.is_empty() {
+                return Err(format!(
+                    "{entry} is listed but systemd passed no such socket; is gkapi started by its .socket unit?"
+                ));
+            }
+            sockets
+                .into_iter()
+                .map(|(name, fd)| adopt(name, fd))
+                .collect()
+        }
+    }
+}
+
+/// A wildcard IPv6 address also takes IPv4, unless IPv4 on the same port is
+/// bound separately.
+fn dual_stack(addr: SocketAddr, list: &[Listen]) -> bool {
+    addr.is_ipv6()
+        && addr.ip().is_unspecified()
+        && !list.iter().any(|entry| {
+            matches!(entry, Listen::Tcp(other) if other.is_ipv4() && other.port() == addr.port())
+        })
+}
+
+fn bind_tcp(addr: SocketAddr, dual_stack: bool) -> io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    // As tokio's own bind does, so a restart is not refused while old
+    // connections sit in TIME_WAIT.
+    socket.set_reuse_address(true)?;
+    if addr.is_ipv6() {
+        // Set either way: the kernel default follows a sysctl.
+        socket.set_only_v6(!dual_stack)?;
+    }
+    socket.bind(&addr.into())?;
+    socket.listen(BACKLOG)?;
+    Ok(socket.into())
+}
+
+/// Bind `path`, replacing a socket left behind by a process that did not
+/// get to remove it. Anything else at `path` is left alone.
+fn bind_unix(path: &Path) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "a file that is not a socket is in the way",
+            ))
+        }
+        Err(_) => {}
+    }
+    UnixListener::bind(path)
+}
+
+/// Wrap a socket systemd passed in, TCP or Unix.
+fn adopt(name: String, fd: OwnedFd) -> Result<Bound, String> {
+    let socket = Socket::from(fd);
+    let unusable = |e: io::Error| format!("systemd socket {name} is not usable: {e}");
+    if socket.r#type().map_err(unusable)? != Type::STREAM
+        || !socket.is_listener().map_err(unusable)?
+    {
+        return Err(format!(
+            "systemd socket {name} is not a listening stream socket; use ListenStream= with Accept=no"
+        ));
+    }
+    let local = socket.local_addr().map_err(unusable)?;
+    if let Some(addr) = local.as_socket() {
+        return Ok(Bound {
+            name: format!("{addr} (systemd socket {name})"),
+            listener: Listener::Tcp(socket.into()),
+        });
+    }
+    if local.is_unix() {
+        let path = local
+            .as_pathname()
+            .map(|path| format!("unix:{}", path.display()))
+            .unwrap_or_else(|| "unix".to_string());
+        return Ok(Bound {
+            name: format!("{path} (systemd socket {name})"),
+            listener: Listener::Unix {
+                listener: OwnedFd::from(socket).into(),
+                path: None,
+            },
+        });
+    }
+    Err(format!(
+        "systemd socket {name} is neither TCP nor a Unix socket"
+    ))
+}
+
+/// Serve `app` on `bound` until `drain` says stop. TCP listeners terminate
+/// TLS when `tls` is given and read PROXY headers as `proxy` says; Unix
+/// sockets always speak plain HTTP.
+pub async fn serve(
+    bound: Bound,
+    app: Router,
+    tls: Option<RustlsConfig>,
+    proxy: ProxyProtocolAcceptor<DefaultAcceptor>,
+    drain: Drain,
+) -> Result<(), String> {
+    let served = match (bound.listener, tls) {
+        // The PROXY header, if any, precedes the TLS handshake.
+        (Listener::Tcp(listener), Some(tls)) => {
+            axum_server::from_tcp(listener)
+                .handle(drain.handle())
+                .acceptor(RustlsAcceptor::new(tls).acceptor(proxy))
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+        }
+        (Listener::Tcp(listener), None) => {
+            axum_server::from_tcp(listener)
+                .handle(drain.handle())
+                .acceptor(proxy)
+                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+                .await
+        }
+        (Listener::Unix { listener, path }, _) => {
+            let served = serve_unix(listener, app, drain).await;
+            if let Some(path) = path {
+                let _ = fs::remove_file(path);
+            }
+            served
+        }
+    };
+    served.map_err(|e| format!("listener on {} failed: {e}", bound.name))
+}
+
+/// axum-server only accepts TCP, so Unix sockets get the same loop by hand:
+/// stop accepting when draining starts, let connections finish until
+/// closing, then return once the last one is gone.
+async fn serve_unix(listener: UnixListener, app: Router, drain: Drain) -> io::Result<()> {
+    listener.set_nonblocking(true)?;
+    let listener = tokio::net::UnixListener::from_std(listener)?;
+    let builder = Arc::new(Builder::new(TokioExecutor::new()));
+    let mut connections = JoinSet::new();
+    loop {
+        let stream = tokio::select! {
+            biased;
+            _ = drain.draining() => break,
+            accepted = listener.accept() => match accepted {
+                Ok((stream, _)) => stream,
+                Err(e) => {
+                    // Out of descriptors and the like; as axum-server does,
+                    // wait and try again rather than give up.
+                    warn!("Accepting on a Unix socket failed: {e}");
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    continue;
+                }
+            },
+        };
+        let service = TowerToHyperService::new(AddExtension::new(app.clone(), UnixPeer));
+        let builder = Arc::clone(&builder);
+        let drain = drain.clone();
+        connections.spawn(async move {
+            let connection = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            tokio::pin!(connection);
+            tokio::select! {
+                biased;
+                _ = drain.draining() => {
+                    connection.as_mut().graceful_shutdown();
+                    tokio::select! {
+                        biased;
+                        _ = drain.closing() => {}
+                        _ = &mut connection => {}
+                    }
+                }
+                _ = &mut connection => {}
+            }
+        });
+        while connections.try_join_next().is_some() {}
+    }
+    drop(listener);
+    while connections.join_next().await.is_some() {}
+    Ok(())
+}
+
+/// Bare `systemd` entries in both lists would race for the same sockets.
+pub fn check_lists(main: &[Listen], challenge: &[Listen]) -> Result<(), String> {
+    let bare = |list: &[Listen]| list.contains(&Listen::Systemd(None));
+    if bare(main) && bare(challenge) {
+        return Err(
+            "server.listen and server.challenge_listen both take every systemd socket; \
+             name them with systemd:<FileDescriptorName>"
+                .to_string(),
+        );
+    }
+    let mut seen = HashSet::new();
+    for entry in main.iter().chain(challenge) {
+        if !seen.insert(entry) {
+            return Err(format!("{entry} is listed twice"));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use axum::extract::ConnectInfo;
+    use axum::routing::get;
+    use axum::Extension;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::{TcpStream, UnixStream};
+
+    use super::*;
+    use crate::client_ip::{ClientIp, ProxyTrust};
+    use crate::tor::{TorExitList, TorSource};
+
+    fn plain() -> ProxyProtocolAcceptor<DefaultAcceptor> {
+        ProxyProtocolAcceptor::new(DefaultAcceptor, Arc::default(), false)
+    }
+
+    async fn get_over<S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin>(
+        mut stream: S,
+        path: &str,
+        extra: &str,
+    ) -> String {
+        stream
+            .write_all(
+                format!("GET {path} HTTP/1.1\r\nhost: gkapi\r\nconnection: close\r\n{extra}\r\n")
+                    .as_bytes(),
+            )
+            .await
+            .unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        response
+    }
+
+    #[test]
+    fn listen_entries_parse() {
+        assert_eq!(
+            "[::]:443".parse::<Listen>().unwrap(),
+            Listen::Tcp("[::]:443".parse().unwrap())
+        );
+        assert_eq!(
+            "unix:/run/gkapi/gkapi.sock".parse::<Listen>().unwrap(),
+            Listen::Unix(PathBuf::from("/run/gkapi/gkapi.sock"))
+        );
+        assert_eq!("systemd".parse::<Listen>().unwrap(), Listen::Systemd(None));
+        assert_eq!(
+            "systemd:https".parse::<Listen>().unwrap(),
+            Listen::Systemd(Some("https".to_string()))
+        );
+        for bad in ["localhost:80", "443", "unix:", "systemd:"] {
+            assert!(bad.parse::<Listen>().is_err(), "{bad}");
+        }
+
+        let v4 = Listen::Tcp("0.0.0.0:443".parse().unwrap());
+        let v6: SocketAddr = "[::]:443".parse().unwrap();
+        assert!(dual_stack(v6, &[Listen::Tcp(v6)]));
+        let both = [v4, Listen::Tcp(v6)];
+        assert!(!dual_stack(v6, &both));
+        assert!(check_lists(&both[..1], &both[..1]).is_err());
+        assert!(check_lists(&[Listen::Systemd(None)], &[Listen::Systemd(None)]).is_err());
+        assert!(check_lists(
+            &[Listen::Systemd(Some("https".into()))],
+            &[Listen::Systemd(None)]
+        )
+        .is_ok());
+    }
+
+    #[test]
+    fn systemd_entries_take_their_named_sockets() {
+        let (a, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let (b, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        let mut activated = Activated {
+            sockets: vec![
+                ("https".to_string(), a.into()),
+                ("acme".to_string(), b.into()),
+            ],
+        };
+        assert_eq!(activated.take(Some("acme")).len(), 1);
+        assert!(activated.take(Some("acme")).is_empty());
+        assert_eq!(activated.unclaimed(), vec!["https"]);
+        assert_eq!(activated.take(None).len(), 1);
+        assert!(activated.unclaimed().is_empty());
+
+        let tcp = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = tcp.local_addr().unwrap();
+        let bound = adopt("https".to_string(), tcp.into()).unwrap();
+        assert_eq!(bound.name, format!("{addr} (systemd socket https)"));
+        assert!(matches!(bound.listener, Listener::Tcp(_)));
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gkapi.sock");
+        let unix = UnixListener::bind(&path).unwrap();
+        let bound = adopt("local".to_string(), unix.into()).unwrap();
+        assert!(bound.name.starts_with("unix:"), "{}", bound.name);
+        assert!(matches!(bound.listener, Listener::Unix { path: None, .. }));
+
+        let (stream, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        assert!(adopt("pair".to_string(), stream.into()).is_err());
+    }
+
+    /// A dual-stack listener hands IPv4 peers over as `::ffff:a.b.c.d`. The
+    /// client address and the Tor check must still see them as IPv4.
+    #[tokio::test]
+    async fn a_mapped_ipv4_peer_is_seen_as_ipv4() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("tor_exits.txt");
+        fs::write(&cache, "127.0.0.1\n").unwrap();
+        let exits = Arc::new(TorExitList::new(Some(cache), TorSource::default()));
+
+        let listen = Listen::Tcp("[::]:0".parse().unwrap());
+        let (mut bound, _) = bind(&[listen], &[], &mut Activated::default()).unwrap();
+        let bound = bound.remove(0);
+        let Listener::Tcp(listener) = &bound.listener else {
+            panic!("bound a TCP address as {}", bound.name);
+        };
+        let port = listener.local_addr().unwrap().port();
+
+        let app = Router::new().route(
+            "/peer",
+            get(
+                |ConnectInfo(peer): ConnectInfo<SocketAddr>, ClientIp(client): ClientIp| async move {
+                    format!("{} {} {}", peer.ip(), client, exits.is_exit(&peer.ip()))
+                },
+            ),
+        );
+        let drain = Drain::default();
+        let server = tokio::spawn(serve(bound, app, None, plain(), drain.clone()));
+
+        let stream = TcpStream::connect((Ipv4Addr::LOCALHOST, port))
+            .await
+            .unwrap();
+        let response = get_over(stream, "/peer", "").await;
+        assert!(
+            response.ends_with("::ffff:127.0.0.1 127.0.0.1 true"),
+            "{response}"
+        );
+
+        drain.graceful(Duration::from_secs(5));
+        server.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn a_unix_socket_serves_and_drains() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("gkapi.sock");
+        // Left behind by a crash; binding replaces it.
+        drop(UnixListener::bind(&path).unwrap());
+        let (mut bound, _) = bind(
+            &[Listen::Unix(path.clone())],
+            &[],
+            &mut Activated::default(),
+        )
+        .unwrap();
+
+        let trust = Arc::new(ProxyTrust::default());
+        let app = Router::new()
+            .route(
+                "/client",
+                get(|ClientIp(client): ClientIp| async move { client.to_string() }),
+            )
+            .route(
+                "/slow",
+                get(|| async {
+                    tokio::time::sleep(Duration::from_millis(300)).await;
+                    "done"
+                }),
+            )
+            .layer(Extension(trust));
+        let drain = Drain::default();
+        let server = tokio::spawn(serve(bound.remove(0), app, None, plain(), drain.clone()));
+
+        let stream = UnixStream::connect(&path).await.unwrap();
+        let response = get_over(stream, "/client", "x-forwarded-for: 203.0.113.5\r\n").await;
+        assert!(response.ends_with("203.0.113.5"), "{response}");
+
+        let slow = tokio::spawn(get_over(
+            UnixStream::connect(&path).await.unwrap(),
+            "/slow",
+            "",
+        ));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        drain.graceful(Duration::from_secs(5));
+        let response = slow.await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("done"), "{response}");
+        server.await.unwrap().unwrap();
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,328 @@
+//! Structured logging, request ids and secret redaction.
+//!
+//! Everything is logged through `tracing`; the `log` macros used across the
+//! crate are bridged into it, so they pick up the span of the request they
+//! run in. [`request_span`] opens that span with an id, echoed back in
+//! `x-request-id` so a user's report can be matched to the log.
+//!
+//! Output goes through [`redact`] on its way to stderr, whatever wrote it and
+//! in either format. It masks:
+//!
+//! - Stripe secret, restricted and webhook keys (`sk_`, `rk_`, `whsec_`);
+//! - PaymentIntent client secrets (`pi_..._secret_...`);
+//! - any run of base64 or base58 of [`LONG_TOKEN`] characters or more, which
+//!   covers blinded messages, blind signatures and invite codes;
+//! - values registered with [`register_secret`], such as the configured
+//!   Stripe key, whatever their shape.
+//!
+//! Call sites should still not log these. This is the guarantee that a
+//! `{:?}` added later does not undo it.
+
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::net::SocketAddr;
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
+
+use axum::{
+    extract::{ConnectInfo, MatchedPath, Request},
+    http::{HeaderName, HeaderValue},
+    middleware::Next,
+    response::Response,
+};
+use tracing::Instrument;
+use tracing_subscriber::{fmt::MakeWriter, EnvFilter};
+
+use crate::client_ip::{ProxyTrust, UnixPeer};
+
+/// Tokens at least this long are masked. Longer than a hex SHA-256, which is
+/// logged as a fingerprint, and shorter than any blinded message or invite.
+pub const LONG_TOKEN: usize = 100;
+
+const REDACTED: &str = "[redacted]";
+
+const SECRET_PREFIXES: [&str; 5] = ["sk_live_", "sk_test_", "rk_live_", "rk_test_", "whsec_"];
+
+pub const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    /// One JSON object per line, with the request span's fields.
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            other => Err(format!(
+                "unknown log format {other:?}; use \"text\" or \"json\""
+            )),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LogSettings {
+    pub format: LogFormat,
+    /// `tracing` filter directives, such as `info` or
+    /// `info,ghostkey_api=debug`. `RUST_LOG` overrides it when set.
+    pub level: String,
+}
+
+impl Default for LogSettings {
+    fn default() -> Self {
+        Self {
+            format: LogFormat::Text,
+            level: "info".to_string(),
+        }
+    }
+}
+
+/// Check filter directives without installing them.
+pub fn parse_level(level: &str) -> Result<EnvFilter, String> {
+    EnvFilter::try_new(level).map_err(|e| format!("invalid log level {level:?}: {e}"))
+}
+
+/// Install the global subscriber. Only the first call in a process has any
+/// effect.
+pub fn init(settings: &LogSettings) {
+    let filter = match std::env::var("RUST_LOG") {
+        Ok(directives) => parse_level(&directives),
+        Err(_) => parse_level(&settings.level),
+    }
+    .unwrap_or_else(|e| {
+        eprintln!("{e}; logging at info");
+        EnvFilter::new("info")
+    });
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(RedactingStderr)
+        // Escape codes run into the tokens next to them and would hide a
+        // key's prefix from the redaction.
+        .with_ansi(false)
+        .with_target(false);
+    let result = match settings.format {
+        LogFormat::Text => builder.try_init(),
+        LogFormat::Json => builder.json().with_current_span(true).try_init(),
+    };
+    if let Err(e) = result {
+        eprintln!("logging already initialised: {e}");
+    }
+}
+
+fn secrets() -> &'static RwLock<Vec<String>> {
+    static SECRETS: RwLock<Vec<String>> = RwLock::new(Vec::new());
+    &SECRETS
+}
+
+/// Mask `secret` wherever it appears in the log from now on. Values too
+/// short to be a credential are ignored, since masking them would garble
+/// unrelated text.
+pub fn register_secret(secret: &str) {
+    let secret = secret.trim();
+    if secret.len() < 8 {
+        return;
+    }
+    let mut secrets = secrets().write().unwrap_or_else(|e| e.into_inner());
+    if !secrets.iter().any(|known| known == secret) {
+        secrets.push(secret.to_string());
+    }
+}
+
+fn is_token_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '+' | '/')
+}
+
+fn is_secret_token(token: &str) -> bool {
+    token.len() >= LONG_TOKEN
+        || token.contains("_secret_")
+        || SECRET_PREFIXES.iter().any(|prefix| token.contains(prefix))
+}
+
+/// `line` with every secret in it masked.
+pub fn redact(line: &str) -> Cow<'_, str> {
+    let mut out = String::new();
+    let mut copied = 0;
+    let mut start = None;
+    for (i, c) in line.char_indices().chain([(line.len(), ' ')]) {
+        match (start, is_token_char(c) && i < line.len()) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                if is_secret_token(&line[s..i]) {
+                    out.push_str(&line[copied..s]);
+                    out.push_str(REDACTED);
+                    copied = i;
+                }
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    let mut line = if copied == 0 {
+        Cow::Borrowed(line)
+    } else {
+        out.push_str(&line[copied..]);
+        Cow::Owned(out)
+    };
+    for secret in secrets().read().unwrap_or_else(|e| e.into_inner()).iter() {
+        if line.contains(secret.as_str()) {
+            line = Cow::Owned(line.replace(secret.as_str(), REDACTED));
+        }
+    }
+    line
+}
+
+/// Stderr, through [`redact`]. The formatter hands over each event in a
+/// single write, so no token is split between two calls.
+struct RedactingStderr;
+
+struct RedactingWriter<W>(W);
+
+impl<'a> MakeWriter<'a> for RedactingStderr {
+    type Writer = RedactingWriter<io::StderrLock<'static>>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        RedactingWriter(io::stderr().lock())
+    }
+}
+
+impl<W: Write> Write for RedactingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let text = String::from_utf8_lossy(buf);
+        self.0.write_all(redact(&text).as_bytes())?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Log on this thread into a buffer, formatted as [`init`] formats text and
+/// through the same redaction, until the guard is dropped.
+#[cfg(test)]
+pub(crate) fn capture() -> (
+    tracing::subscriber::DefaultGuard,
+    Arc<std::sync::Mutex<Vec<u8>>>,
+) {
+    use tracing_subscriber::util::SubscriberInitExt;
+
+    #[derive(Clone)]
+    struct Buffer(Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl Write for Buffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    let buffer = Buffer(Arc::default());
+    let captured = Arc::clone(&buffer.0);
+    // Also routes the `log` macros to this thread's subscriber.
+    let guard = tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::new("debug"))
+        .with_writer(move || RedactingWriter(buffer.clone()))
+        .with_ansi(false)
+        .with_target(false)
+        .set_default();
+    (guard, captured)
+}
+
+/// A caller-supplied id is kept only from a trusted proxy, and only if it is
+/// short and plain; anyone else could fill the log with ids of their choosing.
+fn forwarded_id(request: &Request) -> Option<String> {
+    // A Unix socket peer is on this host; see `ProxyTrust::resolve_local`.
+    if request.extensions().get::<UnixPeer>().is_none() {
+        let ConnectInfo(peer) = request.extensions().get::<ConnectInfo<SocketAddr>>()?;
+        let trust = request.extensions().get::<Arc<ProxyTrust>>()?;
+        if !trust.is_trusted(peer.ip()) {
+            return None;
+        }
+    }
+    let id = request.headers().get(&REQUEST_ID)?.to_str().ok()?;
+    let plain = !id.is_empty()
+        && id.len() <= 64
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    plain.then(|| id.to_string())
+}
+
+/// Middleware: run the request in a span carrying its id, log its outcome,
+/// and return the id in `x-request-id`. Must sit inside the proxy trust layer.
+pub async fn request_span(route: Option<MatchedPath>, request: Request, next: Next) -> Response {
+    let id = forwarded_id(&request).unwrap_or_else(|| format!("{:016x}", rand::random::<u64>()));
+    // The route as routed, not the URI: paths carry PaymentIntent ids.
+    let route = route.as_ref().map_or("-", MatchedPath::as_str).to_string();
+    let span = tracing::info_span!(
+        "request",
+        id = %id,
+        method = %request.method(),
+        route = %route,
+    );
+    let start = Instant::now();
+    let mut response = next.run(request).instrument(span.clone()).await;
+    span.in_scope(|| {
+        tracing::info!(
+            status = response.status().as_u16(),
+            latency_ms = start.elapsed().as_millis() as u64,
+            "finished"
+        )
+    });
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(REQUEST_ID, value);
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn secrets_are_masked_and_the_rest_kept() {
+        let blinded = "A".repeat(344);
+        let line = format!(
+            "key sk_live_51Habc, client pi_3Mxyz_secret_QWERTY, \
+             blinded={blinded}; fingerprint {} at /var/lib/gkapi",
+            "ab".repeat(32)
+        );
+        let redacted = redact(&line);
+        assert_eq!(
+            redacted,
+            format!(
+                "key [redacted], client [redacted], blinded=[redacted]; \
+                 fingerprint {} at /var/lib/gkapi",
+                "ab".repeat(32)
+            )
+        );
+        assert!(matches!(redact("nothing to see"), Cow::Borrowed(_)));
+
+        register_secret("opaque-key-7d2f");
+        register_secret("short");
+        assert_eq!(
+            redact("{\"key\":\"opaque-key-7d2f\",\"short\":1}"),
+            "{\"key\":\"[redacted]\",\"short\":1}"
+        );
+    }
+
+    #[test]
+    fn settings_are_parsed() {
+        assert_eq!("JSON".parse::<LogFormat>(), Ok(LogFormat::Json));
+        assert!("yaml".parse::<LogFormat>().is_err());
+        assert!(parse_level("info,ghostkey_api=debug").is_ok());
+        assert!(parse_level("info,ghostkey_api=loud").is_err());
+    }
+}
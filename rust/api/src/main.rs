@@ -1,41 +1,81 @@
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
-use std::{env, fs, path::PathBuf, sync::Arc};
+use std::path::{Path, PathBuf};
+use std::{env, sync::Arc};
 
-use axum::{http::StatusCode, response::IntoResponse, routing::get, Router};
+use axum::{http::StatusCode, middleware, response::IntoResponse, routing::get, Extension, Router};
+use axum_server::accept::DefaultAcceptor;
 use axum_server::tls_rustls::RustlsConfig;
-use clap::{value_parser, Arg, Command};
+use clap::{value_parser, Arg, ArgAction, ArgMatches, Command};
 use dotenv::dotenv;
-use ed25519_dalek::{SigningKey, VerifyingKey};
-use log::{error, info, warn, LevelFilter};
+use ed25519_dalek::SigningKey;
+use ghostkey_lib::armorable::Armorable;
+use log::{error, info, warn};
 use tokio::sync::Mutex;
-use tower_http::trace::TraceLayer;
+use tokio::task::JoinSet;
 
-use crate::invite_pow::DEFAULT_POW_DIFFICULTY;
-use crate::routes::InviteState;
+use ghostkey_api::{delegates, errors, notary_signer, notary_store, rate_limit, rate_limit_store};
 
-mod delegates;
-mod errors;
+use crate::access_policy::{PolicyEntry, PolicyFile, PolicyStore};
+use crate::acme::{AcmeManager, Challenges};
+use crate::client_ip::ProxyProtocolAcceptor;
+use crate::config::{Config, Severity};
+use crate::ghostkey_auth::GhostkeyGate;
+use crate::invite_ledger::InviteLedger;
+use crate::invite_pow::{PowKeys, PowManager};
+use crate::issuance_log::{Checkpoints, IssuanceLog, SignedCheckpoint};
+use crate::logging::LogSettings;
+use crate::notary_store::NotaryStore;
+use crate::pow_difficulty::DifficultyController;
+use crate::rate_limit::RateLimiter;
+use crate::rate_limit_store::{RedbStore, StoreBackend};
+use crate::readiness::Readiness;
+use crate::reputation::NetworkReputation;
+use crate::routes::{DonationState, InviteState, RoomSettings};
+use crate::shutdown::Drain;
+use crate::tor::TorExitList;
+
+mod access_policy;
+mod acme;
+mod client_ip;
+mod config;
+mod ghostkey_auth;
 mod handle_sign_cert;
 mod invite;
+mod invite_ledger;
 mod invite_pow;
+mod issuance_log;
+mod listen;
+mod logging;
+mod metrics;
 mod payment_claim;
-mod rate_limit;
+mod pow_difficulty;
+mod readiness;
+mod reputation;
 mod routes;
+mod shutdown;
+mod throttle;
+mod tls_reload;
 mod tor;
+mod tor_consensus;
+mod x509;
 
 /// Canonical env var for the notary key directory. The legacy name
-/// `DELEGATE_DIR` is also read (in `delegates::notary_dir`) for backward
-/// compatibility with existing deployments. See freenet/web#24.
+/// `DELEGATE_DIR` is hydrated into it at startup for backward compatibility
+/// with existing deployments. See freenet/web#24.
 pub static NOTARY_DIR: &str = "NOTARY_DIR";
 
 async fn serve_http01_challenge(
     challenge_dir: Arc<Mutex<Option<PathBuf>>>,
+    challenges: Arc<Challenges>,
     uri: axum::http::Uri,
 ) -> impl IntoResponse {
     let path = uri
         .path()
         .trim_start_matches('/')
         .trim_start_matches(".well-known/acme-challenge/");
+    // Challenges gkapi's own ACME client is answering.
+    if let Some(key_authorization) = challenges.get(path) {
+        return (StatusCode::OK, key_authorization);
+    }
     let challenge_dir = challenge_dir.lock().await;
 
     if let Some(dir) = &*challenge_dir {
@@ -69,160 +109,356 @@ async fn health() -> &'static str {
     "OK"
 }
 
-/// Load invite configuration from CLI arguments
-/// Returns None if not all required configuration is present
-fn load_invite_config(matches: &clap::ArgMatches) -> Option<InviteState> {
-    let signing_key_path = matches.get_one::<String>("room-signing-key")?;
-    let owner_vk_str = matches.get_one::<String>("room-owner-vk")?;
-    let room_name = matches.get_one::<String>("room-name")?.clone();
-    let rate_limit_file = PathBuf::from(
-        matches
-            .get_one::<String>("rate-limit-file")
-            .map(|s| s.as_str())
-            .unwrap_or("/var/lib/gkapi/invite_rate_limits.json"),
-    );
-    let tor_exit_cache = Some(PathBuf::from(
-        matches
-            .get_one::<String>("tor-exit-cache")
-            .map(|s| s.as_str())
-            .unwrap_or("/var/lib/gkapi/tor_exit_list.txt"),
-    ));
-    let global_invites_per_hour = matches.get_one::<usize>("global-invites-per-hour").copied();
-    let pow_base_difficulty = matches
-        .get_one::<u8>("invite-pow-difficulty")
-        .copied()
-        .unwrap_or(DEFAULT_POW_DIFFICULTY);
-
-    // Load signing key from file (32 bytes raw)
-    let signing_key_bytes = match fs::read(signing_key_path) {
-        Ok(bytes) => bytes,
+/// Build the invite state from the resolved configuration.
+/// Returns None if invites are not configured or a room's keys are unusable.
+fn load_invite_config(config: &Config) -> Option<InviteState> {
+    let backend = match config.rate_limit_backend() {
+        Ok(backend) => backend,
         Err(e) => {
-            error!(
-                "Failed to read room signing key from {}: {}",
-                signing_key_path, e
-            );
+            error!("{e}");
             return None;
         }
     };
-
-    if signing_key_bytes.len() != 32 {
-        error!(
-            "Room signing key must be exactly 32 bytes, got {}",
-            signing_key_bytes.len()
-        );
-        return None;
+    let open_store = |path: PathBuf| match rate_limit_store::open_store(backend, &path) {
+        Ok(store) => Some(store),
+        Err(e) => {
+            error!("Cannot open rate limit store {}: {e}", path.display());
+            None
+        }
+    };
+    let mut rooms = Vec::new();
+    for room in config.invite_rooms() {
+        let inviter_signing_key = match config::load_room_signing_key(&room.signing_key_file) {
+            Ok(key) => key,
+            Err(e) => {
+                error!("room {}: {e}", room.slug);
+                return None;
+            }
+        };
+        let room_owner_vk = match config::parse_room_owner_vk(&room.owner_vk) {
+            Ok(vk) => vk,
+            Err(e) => {
+                error!("room {}: {e}", room.slug);
+                return None;
+            }
+        };
+        rooms.push(RoomSettings {
+            rate_limit_store: open_store(config.room_rate_limit_file(&room))?,
+            slug: room.slug,
+            name: room.name,
+            room_owner_vk,
+            inviter_signing_key,
+            invites_per_ip: room.invites_per_ip,
+            invites_per_hour: room.invites_per_hour,
+            prefix_limit: config.prefix_limit(),
+        });
     }
+    let default_room = config.default_room()?;
 
-    let mut key_array = [0u8; 32];
-    key_array.copy_from_slice(&signing_key_bytes);
-    let inviter_signing_key = SigningKey::from_bytes(&key_array);
+    let master_vk = match config.master_verifying_key() {
+        Ok(vk) => vk,
+        Err(e) => {
+            error!("{e}");
+            return None;
+        }
+    };
+    let ghostkeys = GhostkeyGate {
+        master_vk,
+        quota: RateLimiter::with_store(
+            open_store(config.ghostkey_rate_limit_file())?,
+            24,
+            config.invites_per_ghostkey(),
+        ),
+    };
 
-    // Parse owner verifying key from base58
-    let owner_vk_bytes = match bs58::decode(owner_vk_str).into_vec() {
-        Ok(bytes) => bytes,
+    let policy_path = config.access_policy_file();
+    let policy = match PolicyStore::load(&policy_path) {
+        Ok(policy) => policy,
         Err(e) => {
-            error!(
-                "Failed to decode room owner verifying key from base58: {}",
-                e
-            );
+            error!("{e}");
             return None;
         }
     };
 
-    if owner_vk_bytes.len() != 32 {
-        error!(
-            "Room owner verifying key must be exactly 32 bytes, got {}",
-            owner_vk_bytes.len()
-        );
-        return None;
+    let tor_source = match config.tor_source() {
+        Ok(source) => source,
+        Err(e) => {
+            error!("{e}");
+            return None;
+        }
+    };
+    let mut state = InviteState::new(
+        rooms,
+        default_room,
+        TorExitList::new(Some(config.tor_exit_cache()), tor_source),
+        config.rate_limit.global_invites_per_hour,
+        config.pow_difficulty(),
+        ghostkeys,
+    );
+    state.policy = Arc::new(policy);
+    match config.reputation_sources() {
+        Ok(sources) => state.reputation = Arc::new(NetworkReputation::new(sources)),
+        Err(e) => {
+            error!("{e}");
+            return None;
+        }
     }
-
-    let mut vk_array = [0u8; 32];
-    vk_array.copy_from_slice(&owner_vk_bytes);
-    let room_owner_vk = match VerifyingKey::from_bytes(&vk_array) {
-        Ok(vk) => vk,
+    let ledger_path = config.invite_ledger();
+    let settings = config.difficulty_settings();
+    match InviteLedger::open(&ledger_path) {
+        Ok(ledger) => {
+            state.ledger = Some(Arc::new(
+                ledger.with_prefixes(settings.ipv4_prefix, settings.ipv6_prefix),
+            ))
+        }
         Err(e) => {
-            error!("Failed to parse room owner verifying key: {}", e);
+            error!("Cannot open {}: {e}", ledger_path.display());
+            return None;
+        }
+    }
+    let (algorithm, accepted) = match (config.pow_algorithm(), config.pow_accepted()) {
+        (Ok(algorithm), Ok(accepted)) => (algorithm, accepted),
+        (Err(e), _) | (_, Err(e)) => {
+            error!("{e}");
             return None;
         }
     };
-
-    Some(InviteState::new(
-        rate_limit_file,
-        tor_exit_cache,
-        global_invites_per_hour,
-        pow_base_difficulty,
-        room_owner_vk,
-        inviter_signing_key,
-        room_name,
-    ))
+    let pow = match PowManager::new().with_key_file(&config.pow_key_file()) {
+        Ok(pow) => pow,
+        Err(e) => {
+            error!("{e}");
+            return None;
+        }
+    };
+    state.pow = Arc::new(
+        pow.with_algorithm(algorithm, config.argon2_params())
+            .also_accepting(&accepted)
+            .with_used_store(open_store(config.pow_used_file())?),
+    );
+    state.difficulty = Arc::new(DifficultyController::new(settings));
+    info!(
+        "Invite proof of work: {} at {} bits{}",
+        algorithm,
+        config.pow_difficulty(),
+        if accepted.is_empty() {
+            String::new()
+        } else {
+            let names: Vec<&str> = accepted.iter().map(|a| a.name()).collect();
+            format!(", also accepting {}", names.join(", "))
+        }
+    );
+    Some(state)
 }
 
-/// Install the process-wide rustls crypto provider.
-///
-/// REQUIRED, and load-bearing: this crate ends up with BOTH `rustls/aws-lc-rs`
-/// (via axum-server's `tls-rustls`) and `rustls/ring` (via reqwest's
-/// `rustls-tls`) enabled. When both provider features are on, rustls 0.23's
-/// `CryptoProvider::from_crate_features()` returns `None` on purpose, and the
-/// first TLS construction panics with:
-///
-///   no process-level CryptoProvider available -- call
-///   CryptoProvider::install_default() before this point
-///
-/// That panic is on the main task, so gkapi dies at startup in HTTPS mode and
-/// takes the donation and cert-signing endpoints down with it -- not just
-/// invites. It is invisible to `cargo test`: nothing in the test suite builds
-/// an `axum-server` `RustlsConfig`, and `cargo run` without `--tls-cert`
-/// takes the plain-HTTP branch. Only an actual HTTPS start reaches it.
-///
-/// aws-lc-rs is chosen to preserve the provider axum-server used before
-/// reqwest was introduced. Pinned by
-/// `https_mode_starts_without_crypto_provider_panic` in tests/https_startup.rs.
-fn install_crypto_provider() {
-    if rustls::crypto::aws_lc_rs::default_provider()
-        .install_default()
-        .is_err()
-    {
-        // Already installed (e.g. a second call in a test process). Not fatal:
-        // the invariant we need is "a provider exists", not "we installed it".
-        warn!("rustls crypto provider was already installed");
+/// `ghostkey-api policy ...`: edit or show the access policy file.
+fn policy_command(config: &Config, matches: &ArgMatches) -> Result<String, String> {
+    let path = config.access_policy_file();
+    let mut file = PolicyFile::read_or_new(&path).map_err(|e| e.to_string())?;
+    let reload = "gkapi picks the change up within 30 seconds, or at once on SIGHUP";
+    match matches.subcommand() {
+        Some(("list", _)) => {
+            let mut out = format!("{}\n", path.display());
+            for (list, entries) in [("deny", &file.deny), ("allow", &file.allow)] {
+                for entry in entries {
+                    out.push_str(&format!("{list}  {}\n", entry.describe()));
+                }
+            }
+            Ok(out.trim_end().to_string())
+        }
+        Some((list @ ("allow" | "deny"), sub)) => {
+            let target = sub.get_one::<String>("target").unwrap();
+            let entry = PolicyEntry::new(
+                target,
+                sub.get_flag("hashed"),
+                sub.get_one::<String>("note").cloned(),
+            )?;
+            if list == "allow" {
+                file.allow.push(entry);
+            } else {
+                file.deny.push(entry);
+            }
+            file.write(&path).map_err(|e| e.to_string())?;
+            Ok(format!("added {target} to the {list} list; {reload}"))
+        }
+        Some(("remove", sub)) => {
+            let target = sub.get_one::<String>("target").unwrap();
+            match file.remove(target) {
+                0 => Err(format!("no entry for {target} in {}", path.display())),
+                n => {
+                    file.write(&path).map_err(|e| e.to_string())?;
+                    Ok(format!(
+                        "removed {n} entr{} for {target}; {reload}",
+                        if n == 1 { "y" } else { "ies" }
+                    ))
+                }
+            }
+        }
+        _ => unreachable!("clap requires a policy subcommand"),
     }
 }
 
-#[tokio::main]
-async fn main() {
-    // MUST run before anything constructs a TLS config. See the function docs.
-    install_crypto_provider();
+/// `ghostkey-api export-invites`: the members invited in a time range, one
+/// id per line for River's ban tooling, or the full records with `--json`.
+fn export_invites(config: &Config, matches: &ArgMatches) -> Result<String, String> {
+    let since = invite_ledger::parse_time(matches.get_one::<String>("since").unwrap())?;
+    let until = matches
+        .get_one::<String>("until")
+        .map(|s| invite_ledger::parse_time(s))
+        .transpose()?;
+    let room = matches.get_one::<String>("room").map(String::as_str);
+    let records = invite_ledger::read_range(&config.invite_ledger(), since, until, room)?;
+    let lines: Vec<String> = if matches.get_flag("json") {
+        records
+            .iter()
+            .map(serde_json::to_string)
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?
+    } else {
+        records.into_iter().map(|record| record.member_id).collect()
+    };
+    Ok(lines.join("\n"))
+}
 
-    // Pre-scan argv for the legacy --delegate-dir spelling so we can emit a
-    // deprecation warning before clap normalizes it to the canonical name.
-    // (See the same pattern in rust/cli/src/bin/ghostkey.rs.)
-    for arg in std::env::args().skip(1) {
-        if arg == "--delegate-dir" || arg.starts_with("--delegate-dir=") {
-            eprintln!(
-                "warning: --delegate-dir is deprecated and will be removed in a future release. \
-                 Use --notary-dir instead. See freenet/web#24."
-            );
-            break;
-        }
+/// Sign the current state of the issuance log with the master key.
+fn sign_checkpoint(config: &Config, master_key_file: &Path) -> Result<String, String> {
+    let master = SigningKey::from_file(master_key_file)
+        .map_err(|e| format!("cannot read {}: {e}", master_key_file.display()))?;
+    let master_vk = config.master_verifying_key()?;
+    if master.verifying_key() != master_vk {
+        return Err(format!(
+            "{} is not the master key this server publishes checkpoints for",
+            master_key_file.display()
+        ));
     }
+    let checkpoint =
+        issuance_log::checkpoint_of(&config.issuance_log()).map_err(|e| e.to_string())?;
+    let signed = SignedCheckpoint::sign(&checkpoint, &master);
+    serde_json::to_string(&signed).map_err(|e| e.to_string())
+}
 
-    // If the operator is running with only the legacy DELEGATE_DIR env var
-    // set (e.g. via systemd unit or .env), hydrate NOTARY_DIR from it so
-    // clap's .env("NOTARY_DIR") binding below can satisfy the required
-    // flag without forcing an immediate migration. Warn loudly so the
-    // signal is not buried.
-    if env::var_os("NOTARY_DIR").is_none() {
-        if let Some(legacy) = env::var_os("DELEGATE_DIR") {
-            eprintln!(
-                "warning: DELEGATE_DIR env var is deprecated and will be removed in a \
-                 future release. Rename to NOTARY_DIR. See freenet/web#24."
-            );
-            env::set_var("NOTARY_DIR", legacy);
-        }
-    }
+fn policy_edit(name: &'static str, about: &'static str) -> Command {
+    Command::new(name)
+        .about(about)
+        .arg(Arg::new("target").required(true).value_name("ADDRESS|CIDR"))
+        .arg(
+            Arg::new("hashed")
+                .long("hashed")
+                .action(ArgAction::SetTrue)
+                .help("Store only the SHA-256 of the address or network"),
+        )
+        .arg(
+            Arg::new("note")
+                .long("note")
+                .value_name("TEXT")
+                .help("Why the entry was added"),
+        )
+}
 
-    let matches = Command::new("Freenet Certified Donation API")
+/// Command-line interface. Every flag also has a config-file equivalent; see
+/// the `config` module for precedence.
+pub(crate) fn cli() -> Command {
+    Command::new("Freenet Certified Donation API")
+        .arg(
+            Arg::new("config")
+                .long("config")
+                .env("GKAPI_CONFIG")
+                .value_name("FILE")
+                .global(true)
+                .help(
+                    "TOML configuration file. Flags and env vars given explicitly take \
+                 precedence over it.",
+                ),
+        )
+        .subcommand(
+            Command::new("check-config")
+                .about("Validate the configuration, notary keys and TLS material, then exit"),
+        )
+        .subcommand(
+            Command::new("sign-checkpoint")
+                .about(
+                    "Verify the issuance log and print a master-signed checkpoint of it, \
+                     to be appended to the published checkpoints file",
+                )
+                .arg(
+                    Arg::new("master-signing-key")
+                        .long("master-signing-key")
+                        .value_name("FILE")
+                        .required(true)
+                        .help(
+                            "Master signing key PEM, as written by `ghostkey generate-master-key`",
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new("policy")
+                .about("Show or edit the invite access policy (allow and deny lists)")
+                .subcommand_required(true)
+                .subcommand(Command::new("list").about("Print every entry"))
+                .subcommand(policy_edit("allow", "Exempt an address or network from the Tor block and per-IP limits"))
+                .subcommand(policy_edit("deny", "Refuse invitations to an address or network"))
+                .subcommand(
+                    Command::new("remove")
+                        .about("Remove the entries for an address, network or hash")
+                        .arg(Arg::new("target").required(true).value_name("ADDRESS|CIDR|SHA256")),
+                ),
+        )
+        .subcommand(Command::new("rotate-pow-key").about(
+            "Replace the proof-of-work key, keeping the old one to verify challenges \
+             already issued. Running instances pick it up within 30 seconds or on SIGHUP.",
+        ))
+        .subcommand(
+            Command::new("export-invites")
+                .about(
+                    "Print the member ids invited in a time range, one per line, for \
+                     banning them with River's moderation tools",
+                )
+                .arg(
+                    Arg::new("since")
+                        .long("since")
+                        .value_name("TIME")
+                        .required(true)
+                        .help("Start of the range, inclusive: RFC 3339 or YYYY-MM-DD (UTC)"),
+                )
+                .arg(
+                    Arg::new("until")
+                        .long("until")
+                        .value_name("TIME")
+                        .help("End of the range, exclusive. Defaults to now"),
+                )
+                .arg(
+                    Arg::new("room")
+                        .long("room")
+                        .value_name("SLUG")
+                        .help("Only invitations to this room"),
+                )
+                .arg(
+                    Arg::new("json")
+                        .long("json")
+                        .action(ArgAction::SetTrue)
+                        .help("Print each full record as a JSON line instead"),
+                ),
+        )
+        .subcommand(
+            Command::new("migrate-rate-limits")
+                .about(
+                    "Import a JSON rate limit file into a redb database, for switching \
+                     rate_limit.backend to redb. Run it with gkapi stopped.",
+                )
+                .arg(
+                    Arg::new("from")
+                        .long("from")
+                        .value_name("FILE")
+                        .required(true)
+                        .help("Existing JSON file, such as invite_rate_limits.json"),
+                )
+                .arg(
+                    Arg::new("to")
+                        .long("to")
+                        .value_name("FILE")
+                        .required(true)
+                        .help("redb database to import into; created if missing"),
+                ),
+        )
         .arg(
             Arg::new("notary-dir")
                 .long("notary-dir")
@@ -231,10 +467,20 @@ async fn main() {
                 .value_name("DIR")
                 .help(
                     "Directory containing per-amount notary certificates and signing keys. \
-                     Falls back to the NOTARY_DIR env var and then to the legacy \
-                     DELEGATE_DIR env var for backward compatibility.",
-                )
-                .required(true),
+                 Falls back to the NOTARY_DIR env var and then to the legacy \
+                 DELEGATE_DIR env var for backward compatibility. Required here or \
+                 as notary.dir in the config file.",
+                ),
+        )
+        .arg(
+            Arg::new("master-verifying-key")
+                .long("master-verifying-key")
+                .env("MASTER_VERIFYING_KEY")
+                .value_name("KEY")
+                .help(
+                    "Base64 master verifying key the notary certificates must chain to. \
+                 Defaults to the Freenet master key; only tests and staging set this.",
+                ),
         )
         .arg(
             Arg::new("tls-cert")
@@ -255,6 +501,22 @@ async fn main() {
                 .help("Sets the port to listen on")
                 .value_parser(value_parser!(u16)),
         )
+        .arg(
+            Arg::new("log-format")
+                .long("log-format")
+                .env("GKAPI_LOG_FORMAT")
+                .value_name("FORMAT")
+                .global(true)
+                .help("Log as \"text\" (the default) or \"json\""),
+        )
+        .arg(
+            Arg::new("log-level")
+                .long("log-level")
+                .env("GKAPI_LOG_LEVEL")
+                .value_name("FILTER")
+                .global(true)
+                .help("Log filter such as \"info\" or \"info,ghostkey_api=debug\"; RUST_LOG overrides it"),
+        )
         .arg(
             Arg::new("challenge-dir")
                 .long("challenge-dir")
@@ -281,7 +543,7 @@ async fn main() {
                 .long("room-name")
                 .value_name("NAME")
                 .env("ROOM_NAME")
-                .default_value("Freenet Chat")
+                .default_value(config::DEFAULT_ROOM_NAME)
                 .help("Display name of the room"),
         )
         .arg(
@@ -289,17 +551,25 @@ async fn main() {
                 .long("rate-limit-file")
                 .value_name("FILE")
                 .env("RATE_LIMIT_FILE")
-                .default_value("/var/lib/gkapi/invite_rate_limits.json")
-                .help("Path to rate limit JSON file"),
+                .default_value(config::DEFAULT_RATE_LIMIT_FILE)
+                .help("Path to the rate limit file (a redb database with rate_limit.backend = \"redb\")"),
         )
         .arg(
             Arg::new("tor-exit-cache")
                 .long("tor-exit-cache")
                 .value_name("FILE")
                 .env("TOR_EXIT_CACHE")
-                .default_value("/var/lib/gkapi/tor_exit_list.txt")
+                .default_value(config::DEFAULT_TOR_EXIT_CACHE)
                 .help("Path to the cached Tor exit-node list (refreshed hourly)"),
         )
+        .arg(
+            Arg::new("issuance-log")
+                .long("issuance-log")
+                .value_name("FILE")
+                .env("ISSUANCE_LOG")
+                .default_value(config::DEFAULT_ISSUANCE_LOG)
+                .help("Hash-chained log of every blind signature issued"),
+        )
         .arg(
             Arg::new("global-invites-per-hour")
                 .long("global-invites-per-hour")
@@ -317,120 +587,471 @@ async fn main() {
                 .default_value("16")
                 .help("Base leading-zero-bit difficulty for invite proof of work"),
         )
-        .get_matches();
-
-    let notary_dir = matches.get_one::<String>("notary-dir").unwrap();
-    let user_port = matches.get_one::<u16>("port");
-    let challenge_dir = matches
-        .get_one::<String>("challenge-dir")
-        .map(PathBuf::from);
-    // Canonical env var for downstream consumers (e.g. `delegates::notary_dir`).
-    // The legacy `DELEGATE_DIR` hydration path above already fired before
-    // clap parsed, so by this point NOTARY_DIR is either already set by
-    // the operator or we set it from DELEGATE_DIR ourselves.
-    env::set_var(NOTARY_DIR, notary_dir);
-
-    env_logger::builder()
-        .format_timestamp(Some(env_logger::TimestampPrecision::Millis))
-        .format_module_path(false)
-        .format_target(false)
-        .filter_level(LevelFilter::Debug)
-        .init();
+}
 
-    info!("Starting Freenet Certified Donation API");
-    match dotenv() {
+/// Install the process-wide rustls crypto provider.
+///
+/// REQUIRED, and load-bearing: this crate ends up with BOTH `rustls/aws-lc-rs`
+/// (via axum-server's `tls-rustls`) and `rustls/ring` (via reqwest's
+/// `rustls-tls`) enabled. When both provider features are on, rustls 0.23's
+/// `CryptoProvider::from_crate_features()` returns `None` on purpose, and the
+/// first TLS construction panics with:
+///
+///   no process-level CryptoProvider available -- call
+///   CryptoProvider::install_default() before this point
+///
+/// That panic is on the main task, so gkapi dies at startup in HTTPS mode and
+/// takes the donation and cert-signing endpoints down with it -- not just
+/// invites. It is invisible to `cargo test`: nothing in the test suite builds
+/// an `axum-server` `RustlsConfig`, and `cargo run` without `--tls-cert`
+/// takes the plain-HTTP branch. Only an actual HTTPS start reaches it.
+///
+/// aws-lc-rs is chosen to preserve the provider axum-server used before
+/// reqwest was introduced. Pinned by
+/// `https_mode_starts_without_crypto_provider_panic` in tests/https_startup.rs.
+fn install_crypto_provider() {
+    if rustls::crypto::aws_lc_rs::default_provider()
+        .install_default()
+        .is_err()
+    {
+        // Already installed (e.g. a second call in a test process). Not fatal:
+        // the invariant we need is "a provider exists", not "we installed it".
+        warn!("rustls crypto provider was already installed");
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    // MUST run before anything constructs a TLS config. See the function docs.
+    install_crypto_provider();
+
+    // Pre-scan argv for the legacy --delegate-dir spelling so we can emit a
+    // deprecation warning before clap normalizes it to the canonical name.
+    // (See the same pattern in rust/cli/src/bin/ghostkey.rs.)
+    for arg in std::env::args().skip(1) {
+        if arg == "--delegate-dir" || arg.starts_with("--delegate-dir=") {
+            eprintln!(
+                "warning: --delegate-dir is deprecated and will be removed in a future release. \
+                 Use --notary-dir instead. See freenet/web#24."
+            );
+            break;
+        }
+    }
+
+    // If the operator is running with only the legacy DELEGATE_DIR env var
+    // set (e.g. via systemd unit or .env), hydrate NOTARY_DIR from it so
+    // clap's .env("NOTARY_DIR") binding below can satisfy the required
+    // flag without forcing an immediate migration. Warn loudly so the
+    // signal is not buried.
+    if env::var_os(NOTARY_DIR).is_none() {
+        if let Some(legacy) = env::var_os("DELEGATE_DIR") {
+            eprintln!(
+                "warning: DELEGATE_DIR env var is deprecated and will be removed in a \
+                 future release. Rename to NOTARY_DIR. See freenet/web#24."
+            );
+            env::set_var(NOTARY_DIR, legacy);
+        }
+    }
+
+    let matches = cli().get_matches();
+
+    let dotenv = dotenv();
+
+    let config = match Config::resolve(&matches) {
+        Ok(config) => config,
+        Err(e) => {
+            logging::init(&LogSettings::default());
+            error!("{e}");
+            std::process::exit(2);
+        }
+    };
+    // A bad setting is reported by the config check below.
+    logging::init(&config.log_settings().unwrap_or_default());
+    if let Ok(key) = config.stripe_secret_key() {
+        logging::register_secret(&key);
+    }
+
+    match dotenv {
         Ok(path) => info!(".env file loaded successfully from: {:?}", path),
         Err(e) => error!("Failed to load .env file: {}", e),
     }
 
-    env::var("NOTARY_DIR").expect("NOTARY_DIR environment variable not set");
+    // Runs where the master key is, which need not have notary keys or TLS
+    // material, so before the full check.
+    if let Some(sub) = matches.subcommand_matches("sign-checkpoint") {
+        let key_file = sub.get_one::<String>("master-signing-key").unwrap();
+        match sign_checkpoint(&config, Path::new(key_file)) {
+            Ok(line) => println!("{line}"),
+            Err(e) => {
+                error!("{e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(sub) = matches.subcommand_matches("policy") {
+        match policy_command(&config, sub) {
+            Ok(out) => println!("{out}"),
+            Err(e) => {
+                error!("{e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if matches.subcommand_matches("rotate-pow-key").is_some() {
+        let path = config.pow_key_file();
+        match PowKeys::rotate(&path) {
+            Ok(_) => println!(
+                "rotated {}; challenges signed with the previous key still verify",
+                path.display()
+            ),
+            Err(e) => {
+                error!("{e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(sub) = matches.subcommand_matches("export-invites") {
+        match export_invites(&config, sub) {
+            Ok(out) if out.is_empty() => {}
+            Ok(out) => println!("{out}"),
+            Err(e) => {
+                error!("{e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    if let Some(sub) = matches.subcommand_matches("migrate-rate-limits") {
+        let from = Path::new(sub.get_one::<String>("from").unwrap());
+        let to = Path::new(sub.get_one::<String>("to").unwrap());
+        let imported =
+            RedbStore::open(to).and_then(|store| rate_limit_store::migrate_json(from, &store));
+        match imported {
+            Ok(count) => println!(
+                "imported {count} entries from {} into {}",
+                from.display(),
+                to.display()
+            ),
+            Err(e) => {
+                error!("{e}");
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let report = config.check();
+    if matches.subcommand_matches("check-config").is_some() {
+        for finding in &report.findings {
+            println!("{finding}");
+        }
+        if report.has_errors() {
+            println!("configuration has errors");
+            std::process::exit(1);
+        }
+        println!("configuration ok");
+        return;
+    }
+
+    info!("Starting Freenet Certified Donation API");
+    for finding in &report.findings {
+        match finding.severity {
+            Severity::Ok => info!("{finding}"),
+            Severity::Warning => warn!("{finding}"),
+            Severity::Error => error!("{finding}"),
+        }
+    }
+    if report.has_errors() {
+        error!("Refusing to start: fix the errors above (run `check-config` to re-check)");
+        std::process::exit(1);
+    }
+
+    // `check` has already refused a configuration without a notary directory
+    // or master key, or with a tier that does not verify, so a failure here
+    // means the directory changed in the last few milliseconds.
+    let signer = match config.remote_signer() {
+        Ok(signer) => signer.map(Arc::new),
+        Err(e) => {
+            error!("{e}");
+            std::process::exit(1);
+        }
+    };
+    if let Some(signer) = &signer {
+        info!("Signing through gknotary at {}", signer.socket().display());
+    }
+    let notaries = match (&config.notary.dir, config.master_verifying_key()) {
+        (Some(dir), Ok(master_vk)) => match NotaryStore::load_with_signer(dir, master_vk, signer) {
+            Ok(store) => Arc::new(store),
+            Err(e) => {
+                error!("Failed to load notaries: {e}");
+                std::process::exit(1);
+            }
+        },
+        _ => {
+            error!("Refusing to start without a notary directory and master key");
+            std::process::exit(1);
+        }
+    };
+    info!(
+        "Loaded notary tiers {:?} from {}",
+        notaries.tiers(),
+        notaries.dir().display()
+    );
+    notary_store::spawn_reloader(Arc::clone(&notaries));
+
+    let issuance = match IssuanceLog::open(&config.issuance_log()) {
+        Ok(log) => Arc::new(log),
+        Err(e) => {
+            error!("Refusing to start: {e}");
+            std::process::exit(1);
+        }
+    };
+    let master_vk = config
+        .master_verifying_key()
+        .expect("checked before the notary store was loaded");
+    // Built here, once: handlers never read the key from the environment.
+    let stripe = config.stripe_secret_key().ok().map(stripe::Client::new);
+    let donation_state = DonationState {
+        notaries,
+        stripe: stripe.clone(),
+        issuance: Arc::clone(&issuance),
+        checkpoints: Arc::new(Checkpoints::new(config.checkpoints_file(), master_vk)),
+    };
+
+    let challenge_dir = config.server.challenge_dir.clone();
 
     let challenge_dir = Arc::new(Mutex::new(challenge_dir));
 
     // Load invite configuration (optional)
-    let invite_state = load_invite_config(&matches);
+    let invite_state = load_invite_config(&config);
+
+    let mut readiness = Readiness::new(
+        Arc::clone(&donation_state.notaries),
+        stripe,
+        invite_state.clone(),
+        config.invite_requested(),
+    )
+    .with_storage("issuance-log", config.issuance_log(), true);
+    if invite_state.is_some() {
+        // A memory backend writes none of its files.
+        if config
+            .rate_limit_backend()
+            .is_ok_and(StoreBackend::uses_files)
+        {
+            for room in config.invite_rooms() {
+                readiness = readiness.with_storage(
+                    format!("rate-limits:{}", room.slug),
+                    config.room_rate_limit_file(&room),
+                    true,
+                );
+            }
+            readiness = readiness
+                .with_storage("ghostkey-limits", config.ghostkey_rate_limit_file(), true)
+                .with_storage("pow-used", config.pow_used_file(), true);
+        }
+        readiness = readiness
+            .with_storage("invite-ledger", config.invite_ledger(), true)
+            .with_storage("tor-cache", config.tor_exit_cache(), false);
+    }
+    if let Ok(Some(acme)) = config.acme() {
+        // Only renewals write here, and the certificate served keeps working.
+        readiness = readiness.with_storage("acme-cache", acme.cache_dir.join("account.key"), false);
+    }
 
     let mut app = Router::new()
         .route("/health", get(health))
-        .merge(routes::get_routes());
+        .route(
+            "/ready",
+            get(readiness::serve).with_state(Arc::new(readiness)),
+        )
+        .route(
+            "/metrics",
+            get(metrics::serve).with_state(invite_state.clone()),
+        )
+        .merge(routes::get_routes(donation_state));
+
+    // Kept to flush at shutdown.
+    let invite_stores = invite_state.clone();
 
     // Add invite routes if configured
     if let Some(state) = invite_state {
+        let slugs: Vec<&str> = state.rooms.iter().map(|room| room.slug.as_str()).collect();
         info!(
-            "River room invite endpoint enabled for room: {}",
-            state.room_name
+            "River room invite endpoint enabled for rooms {:?} (default {})",
+            slugs, state.default_room
         );
         // Keep the Tor exit list current. Invite issuance fails closed while
         // the list is unavailable so Tor blocking cannot silently degrade.
         tor::spawn_refresher(Arc::clone(&state.tor_exits));
+        reputation::spawn_refreshers(&state.reputation);
+        access_policy::spawn_reloader(Arc::clone(&state.policy));
+        invite_pow::spawn_key_reloader(Arc::clone(&state.pow));
         app = app.merge(routes::get_invite_routes(state));
     } else {
-        warn!("River room invite endpoint not configured. Set ROOM_SIGNING_KEY_FILE and ROOM_OWNER_VK to enable.");
+        warn!("River room invite endpoint not configured. Set ROOM_SIGNING_KEY_FILE and ROOM_OWNER_VK, or add [[invite.rooms]], to enable.");
     }
 
-    let app = app.layer(TraceLayer::new_for_http()).fallback(not_found);
+    // Checked by `config.check()` above, so this cannot fail here.
+    let proxy_trust = Arc::new(config.proxy_trust().unwrap_or_default());
+    // Checked by `config.check()` above as well.
+    let operators = Arc::new(config.operators().unwrap_or_default());
+    // Checked by `config.check()` above as well.
+    let throttle = Arc::new(config.throttle().unwrap_or_default());
+    // The proxy trust layer goes outside the throttle, which resolves the
+    // client address with it.
+    let app = app
+        .layer(middleware::from_fn_with_state(throttle, throttle::limit))
+        // Outside the throttle, so its refusals are timed and counted too.
+        .layer(middleware::from_fn(metrics::track))
+        .layer(middleware::from_fn(logging::request_span))
+        .layer(Extension(Arc::clone(&proxy_trust)))
+        .layer(Extension(operators))
+        .fallback(not_found);
+    let proxy_acceptor =
+        ProxyProtocolAcceptor::new(DefaultAcceptor, proxy_trust, config.server.proxy_protocol);
+
+    // Checked by `config.check()` above; only reading `acme.ca_file` can
+    // fail here.
+    let acme = match config.acme().unwrap_or_default().map(AcmeManager::new) {
+        Some(Ok(manager)) => Some(Arc::new(manager)),
+        Some(Err(e)) => {
+            error!("Refusing to start: {e}");
+            std::process::exit(1);
+        }
+        None => None,
+    };
 
     let challenge_dir_clone = challenge_dir.clone();
-    let challenge_app =
-        Router::new().fallback(move |uri| serve_http01_challenge(challenge_dir_clone.clone(), uri));
+    let challenges = acme
+        .as_ref()
+        .map(|acme| acme.challenges())
+        .unwrap_or_default();
+    let challenge_app = Router::new().fallback(move |uri| {
+        serve_http01_challenge(challenge_dir_clone.clone(), Arc::clone(&challenges), uri)
+    });
 
-    let (is_https, default_port) = if matches.get_one::<String>("tls-cert").is_some()
-        && matches.get_one::<String>("tls-key").is_some()
-    {
-        (true, 443)
-    } else {
-        (false, 8000)
+    let tls = match (&config.tls.cert, &config.tls.key) {
+        (Some(cert), Some(key)) => Some((cert.clone(), key.clone())),
+        _ => None,
     };
 
-    let port = user_port.copied().unwrap_or(default_port);
-    let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port);
-    info!("Listening on {}", addr);
-
-    let main_server = async {
-        if is_https {
-            info!("TLS certificate and key provided. Starting in HTTPS mode.");
-            let tls_cert = matches.get_one::<String>("tls-cert").unwrap();
-            let tls_key = matches.get_one::<String>("tls-key").unwrap();
-            let tls_config =
-                RustlsConfig::from_pem_file(PathBuf::from(tls_cert), PathBuf::from(tls_key))
-                    .await
-                    .unwrap();
-            axum_server::bind_rustls(addr, tls_config)
-                .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-                .await
-                .unwrap();
-        } else {
-            info!("No TLS certificate and key provided. Starting in HTTP mode.");
-            let listener = tokio::net::TcpListener::bind(addr).await.unwrap();
-            axum::serve(
-                listener,
-                app.into_make_service_with_connect_info::<SocketAddr>(),
-            )
-            .await
-            .unwrap();
+    // Both lists were checked by `config.check()` above.
+    let listen = config.listen().unwrap_or_default();
+    let challenge_listen = if config.serves_challenges() {
+        config.challenge_listen().unwrap_or_default()
+    } else {
+        Vec::new()
+    };
+    let bound = listen::Activated::from_env().and_then(|mut activated| {
+        let bound = listen::bind(&listen, &challenge_listen, &mut activated)?;
+        for name in activated.unclaimed() {
+            warn!("systemd passed socket {name}, but no server.listen entry takes it");
+        }
+        Ok(bound)
+    });
+    let (main_listeners, challenge_listeners) = match bound {
+        Ok(bound) => bound,
+        Err(e) => {
+            error!("Refusing to start: {e}");
+            std::process::exit(1);
         }
     };
 
-    if challenge_dir.lock().await.is_some() {
-        let http_challenge_addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 80);
-        info!(
-            "Starting HTTP-01 challenge server on {}",
-            http_challenge_addr
-        );
-        let challenge_listener = tokio::net::TcpListener::bind(http_challenge_addr)
-            .await
-            .unwrap();
-        let challenge_server = tokio::task::spawn(async move {
-            axum::serve(challenge_listener, challenge_app)
-                .await
-                .unwrap();
-        });
+    // One drain stops every listener.
+    let drain = Drain::default();
+    shutdown::drain_on_signal(drain.clone(), config.shutdown_grace());
 
+    let mut servers = JoinSet::new();
+    // The challenge listeners answer from the start: ACME needs them before
+    // the main listeners have a certificate.
+    let no_proxy = ProxyProtocolAcceptor::new(DefaultAcceptor, Arc::default(), false);
+    for bound in challenge_listeners {
+        info!("Starting HTTP-01 challenge server on {}", bound.name);
+        servers.spawn(listen::serve(
+            bound,
+            challenge_app.clone(),
+            None,
+            no_proxy.clone(),
+            drain.clone(),
+        ));
+    }
+
+    let mut served_ok = true;
+    // `None` when the main listeners are not to be served after all.
+    let tls_config = if let Some((tls_cert, tls_key)) = tls {
+        info!("TLS certificate and key provided. Starting in HTTPS mode.");
+        match RustlsConfig::from_pem_file(&tls_cert, &tls_key).await {
+            Ok(tls_config) => {
+                tls_reload::spawn_reloader(Arc::new(tls_reload::TlsFiles::new(
+                    tls_cert,
+                    tls_key,
+                    tls_config.clone(),
+                )));
+                Some(Some(tls_config))
+            }
+            Err(e) => {
+                error!(
+                    "Cannot load TLS certificate {} and key {}: {e}",
+                    tls_cert.display(),
+                    tls_key.display()
+                );
+                served_ok = false;
+                None
+            }
+        }
+    } else if let Some(acme) = &acme {
+        info!("Certificates come from ACME. Starting in HTTPS mode.");
         tokio::select! {
-            _ = main_server => {},
-            _ = challenge_server => {},
+            (tls_config, not_after) = acme.initial_config() => {
+                acme::spawn_renewer(Arc::clone(acme), tls_config.clone(), not_after);
+                Some(Some(tls_config))
+            }
+            // Nothing is being served on them yet, so there is nothing to drain.
+            _ = drain.draining() => None,
         }
     } else {
-        main_server.await;
+        info!("No TLS certificate and key provided. Starting in HTTP mode.");
+        Some(None)
+    };
+    match tls_config {
+        Some(tls_config) => {
+            for bound in main_listeners {
+                info!("Listening on {}", bound.name);
+                servers.spawn(listen::serve(
+                    bound,
+                    app.clone(),
+                    tls_config.clone(),
+                    proxy_acceptor.clone(),
+                    drain.clone(),
+                ));
+            }
+        }
+        None => drain.graceful(config.shutdown_grace()),
+    }
+
+    // Each listener returns Ok only once the drain has begun, so the first
+    // failure drains the rest.
+    while let Some(joined) = servers.join_next().await {
+        let served = joined
+            .map_err(|e| format!("a listener task failed: {e}"))
+            .and_then(|served| served);
+        if let Err(e) = served {
+            error!("{e}; shutting down");
+            drain.graceful(config.shutdown_grace());
+            served_ok = false;
+        }
+    }
+
+    let flushed = shutdown::flush_stores(&issuance, invite_stores.as_ref());
+    if !served_ok || !flushed {
+        std::process::exit(1);
     }
+    info!("Shut down cleanly");
 }
@@ -0,0 +1,309 @@
+//! Prometheus metrics, served at `/metrics`.
+//!
+//! Events are counted where they happen, through [`get`]. What is already
+//! held in shared state, such as the Tor list and the invite ceilings, is
+//! read when the endpoint is scraped instead of being mirrored on every
+//! change.
+//!
+//! Only loopback and `server.operator_addresses` may scrape; see
+//! [`Operators`]. Everyone else gets a 404, as for any unknown path.
+//!
+//! Labels are drawn from fixed sets (routes as routed, room slugs, tiers,
+//! error kinds), never from anything a client sends, so a client cannot grow
+//! the number of series.
+
+use std::sync::atomic::AtomicU64;
+use std::sync::{Arc, OnceLock};
+use std::time::Instant;
+
+use axum::{
+    extract::{MatchedPath, Request, State},
+    http::{header::CONTENT_TYPE, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Extension,
+};
+use ghostkey_api::errors::CertificateError;
+use log::error;
+use prometheus_client::{
+    encoding::text::encode,
+    metrics::{
+        counter::Counter,
+        family::Family,
+        gauge::Gauge,
+        histogram::{exponential_buckets, Histogram},
+    },
+    registry::{Registry, Unit},
+};
+
+use crate::client_ip::{ClientIp, Operators};
+use crate::routes::InviteState;
+
+type Labels = Vec<(&'static str, String)>;
+
+pub struct Metrics {
+    registry: Registry,
+    donations_created: Counter,
+    certificates_signed: Family<Labels, Counter>,
+    signing_failures: Family<Labels, Counter>,
+    invites_issued: Family<Labels, Counter>,
+    invites_rejected: Family<Labels, Counter>,
+    challenge_difficulty: Histogram,
+    request_duration: Family<Labels, Histogram, fn() -> Histogram>,
+    pow_difficulty: Gauge,
+    pow_base_difficulty: Gauge,
+    bucket_used: Family<Labels, Gauge>,
+    bucket_limit: Family<Labels, Gauge>,
+    tor_exits: Gauge,
+    tor_age: Gauge<f64, AtomicU64>,
+}
+
+/// The process-wide metrics.
+pub fn get() -> &'static Metrics {
+    static METRICS: OnceLock<Metrics> = OnceLock::new();
+    METRICS.get_or_init(Metrics::new)
+}
+
+fn request_histogram() -> Histogram {
+    // 5 ms to about 20 s. Signing waits on Stripe twice.
+    Histogram::new(exponential_buckets(0.005, 2.0, 13))
+}
+
+impl Metrics {
+    fn new() -> Self {
+        let mut metrics = Self {
+            registry: Registry::with_prefix("gkapi"),
+            donations_created: Counter::default(),
+            certificates_signed: Family::default(),
+            signing_failures: Family::default(),
+            invites_issued: Family::default(),
+            invites_rejected: Family::default(),
+            challenge_difficulty: Histogram::new((8..=30).step_by(2).map(f64::from)),
+            request_duration: Family::new_with_constructor(request_histogram),
+            pow_difficulty: Gauge::default(),
+            pow_base_difficulty: Gauge::default(),
+            bucket_used: Family::default(),
+            bucket_limit: Family::default(),
+            tor_exits: Gauge::default(),
+            tor_age: Gauge::default(),
+        };
+        let registry = &mut metrics.registry;
+        registry.register(
+            "donations_created",
+            "Stripe PaymentIntents created for donations",
+            metrics.donations_created.clone(),
+        );
+        registry.register(
+            "certificates_signed",
+            "Ghost key certificates signed, by notary tier in dollars",
+            metrics.certificates_signed.clone(),
+        );
+        registry.register(
+            "certificate_signing_failures",
+            "Refused or failed /sign-certificate requests, by error",
+            metrics.signing_failures.clone(),
+        );
+        registry.register(
+            "invites_issued",
+            "Room invitations issued, by room and kind (pow or ghostkey)",
+            metrics.invites_issued.clone(),
+        );
+        registry.register(
+            "invites_rejected",
+            "Invite endpoint refusals, challenges included, by reason",
+            metrics.invites_rejected.clone(),
+        );
+        registry.register(
+            "pow_challenge_difficulty_bits",
+            "Difficulty of proof-of-work challenges issued",
+            metrics.challenge_difficulty.clone(),
+        );
+        registry.register_with_unit(
+            "request_duration",
+            "Request latency, by route, method and status",
+            Unit::Seconds,
+            metrics.request_duration.clone(),
+        );
+        registry.register(
+            "pow_difficulty_bits",
+            "Difficulty a network with no recent requests is asked for now",
+            metrics.pow_difficulty.clone(),
+        );
+        registry.register(
+            "pow_base_difficulty_bits",
+            "Configured base proof-of-work difficulty",
+            metrics.pow_base_difficulty.clone(),
+        );
+        registry.register(
+            "invite_bucket_used",
+            "Invitations in the current window of each ceiling, global or per room",
+            metrics.bucket_used.clone(),
+        );
+        registry.register(
+            "invite_bucket_limit",
+            "Size of each invitation ceiling; 0 when it is off",
+            metrics.bucket_limit.clone(),
+        );
+        registry.register(
+            "tor_exit_list_entries",
+            "Addresses on the Tor exit list",
+            metrics.tor_exits.clone(),
+        );
+        registry.register_with_unit(
+            "tor_exit_list_age",
+            "Time since the Tor exit list was last loaded; -1 before it ever has been",
+            Unit::Seconds,
+            metrics.tor_age.clone(),
+        );
+        metrics
+    }
+
+    pub fn donation_created(&self) {
+        self.donations_created.inc();
+    }
+
+    pub fn certificate_signed(&self, tier_dollars: u64) {
+        self.certificates_signed
+            .get_or_create(&vec![("tier", tier_dollars.to_string())])
+            .inc();
+    }
+
+    pub fn signing_failed(&self, error: &CertificateError) {
+        self.signing_failures
+            .get_or_create(&vec![("error", error.kind().to_string())])
+            .inc();
+    }
+
+    pub fn invite_issued(&self, room: &str, kind: &'static str) {
+        self.invites_issued
+            .get_or_create(&vec![
+                ("room", room.to_string()),
+                ("kind", kind.to_string()),
+            ])
+            .inc();
+    }
+
+    pub fn invite_rejected(&self, reason: &'static str) {
+        self.invites_rejected
+            .get_or_create(&vec![("reason", reason.to_string())])
+            .inc();
+    }
+
+    pub fn challenge_issued(&self, difficulty: u8) {
+        self.challenge_difficulty.observe(f64::from(difficulty));
+    }
+
+    /// Read the invite state's current values into the gauges.
+    fn sample(&self, state: &InviteState) {
+        let global = &state.global_bucket;
+        let status = state.difficulty.status(global.current(), global.limit());
+        self.pow_difficulty.set(i64::from(status.difficulty));
+        self.pow_base_difficulty
+            .set(i64::from(status.base_difficulty));
+
+        let buckets = std::iter::once(("global".to_string(), global)).chain(
+            state
+                .rooms
+                .iter()
+                .map(|room| (room.slug.clone(), &room.bucket)),
+        );
+        for (name, bucket) in buckets {
+            let labels = vec![("bucket", name)];
+            self.bucket_used
+                .get_or_create(&labels)
+                .set(bucket.current() as i64);
+            self.bucket_limit
+                .get_or_create(&labels)
+                .set(bucket.limit() as i64);
+        }
+
+        self.tor_exits.set(state.tor_exits.len() as i64);
+        let age = state
+            .tor_exits
+            .last_updated()
+            .map(|updated| (chrono::Utc::now() - updated).num_milliseconds() as f64 / 1000.0)
+            .unwrap_or(-1.0);
+        self.tor_age.set(age);
+    }
+
+    fn encode(&self) -> Result<String, std::fmt::Error> {
+        let mut body = String::new();
+        encode(&mut body, &self.registry)?;
+        Ok(body)
+    }
+}
+
+/// `/metrics`. The invite gauges are only present when invites are served.
+pub async fn serve(
+    State(invites): State<Option<InviteState>>,
+    Extension(operators): Extension<Arc<Operators>>,
+    ClientIp(client): ClientIp,
+) -> Response {
+    if !operators.allows(client) {
+        return StatusCode::NOT_FOUND.into_response();
+    }
+    let metrics = get();
+    if let Some(state) = &invites {
+        metrics.sample(state);
+    }
+    match metrics.encode() {
+        Ok(body) => (
+            [(
+                CONTENT_TYPE,
+                "application/openmetrics-text; version=1.0.0; charset=utf-8",
+            )],
+            body,
+        )
+            .into_response(),
+        Err(e) => {
+            error!("Failed to encode metrics: {e}");
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Middleware timing every routed request.
+pub async fn track(route: Option<MatchedPath>, request: Request, next: Next) -> Response {
+    let method = request.method().to_string();
+    let start = Instant::now();
+    let response = next.run(request).await;
+    if let Some(route) = route {
+        get()
+            .request_duration
+            .get_or_create(&vec![
+                ("route", route.as_str().to_string()),
+                ("method", method),
+                ("status", response.status().as_u16().to_string()),
+            ])
+            .observe(start.elapsed().as_secs_f64());
+    }
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_appear_in_the_exposition() {
+        let metrics = get();
+        metrics.donation_created();
+        metrics.certificate_signed(20);
+        metrics.signing_failed(&CertificateError::CertificateAlreadySigned);
+        metrics.invite_issued("default", "pow");
+        metrics.invite_rejected("tor");
+        metrics.challenge_issued(16);
+        let body = metrics.encode().unwrap();
+        for line in [
+            "gkapi_certificates_signed_total{tier=\"20\"}",
+            "gkapi_certificate_signing_failures_total{error=\"certificate_already_signed\"}",
+            "gkapi_invites_issued_total{room=\"default\",kind=\"pow\"}",
+            "gkapi_invites_rejected_total{reason=\"tor\"}",
+            "gkapi_pow_challenge_difficulty_bits_bucket{le=\"16.0\"}",
+        ] {
+            assert!(body.contains(line), "{line} missing from:\n{body}");
+        }
+        assert!(body.contains("# TYPE gkapi_donations_created counter"));
+        assert!(body.ends_with("# EOF\n"));
+    }
+}
@@ -0,0 +1,685 @@
+//! Out-of-process notary signing over a local Unix socket.
+//!
+//! `gkapi` faces the internet and, until this module, held every notary RSA
+//! private key in its own memory: one remote code execution in the HTTP stack
+//! and an attacker could mint Ghost Keys for any tier, forever, with no
+//! donation behind them. `gknotary` owns the keys instead and `gkapi` asks it
+//! for each blind signature. A compromised `gkapi` can still request
+//! signatures while it is compromised, but only at the configured per-tier
+//! rate, only while it can reach the socket, and every request lands in the
+//! audit log. The keys themselves never leave `gknotary`.
+//!
+//! # Protocol
+//!
+//! Each message is a 4-byte big-endian length followed by that many bytes of
+//! JSON. A connection carries any number of request/response pairs; the
+//! client in this module opens one per signature, which keeps it trivially
+//! correct across daemon restarts.
+//!
+//! Requests are authenticated with HMAC-SHA256 under a key shared through a
+//! file that only the two service users can read. The MAC covers the tier, the
+//! blinded message, a timestamp and a random nonce, so a captured request can
+//! be neither altered nor replayed. On top of that the daemon can restrict
+//! callers by Unix uid via the socket's peer credentials.
+
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use blind_rsa_signatures::{BlindSignature, BlindedMessage};
+use chrono::Utc;
+use ghostkey_lib::armorable::Armorable;
+use hmac::{Hmac, Mac};
+use log::{error, info, warn};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::delegates;
+use crate::notary_store::{NotaryKey, NotaryStore};
+use crate::rate_limit::AggregateBucket;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const DOMAIN: &[u8] = b"freenet-gknotary-sign-v1";
+
+/// A blinded 2048-bit message is ~350 bytes of base64; this leaves ample room
+/// while keeping a hostile peer from making the daemon allocate much.
+const MAX_FRAME_BYTES: u32 = 16 * 1024;
+
+/// How far a request's timestamp may be from the daemon's clock. Both ends run
+/// on the same host, so this only needs to absorb scheduling delay.
+const MAX_CLOCK_SKEW_SECONDS: i64 = 30;
+
+const NONCE_BYTES: usize = 16;
+
+/// Shorter auth keys are refused: the MAC is the whole access control when no
+/// uid restriction is configured.
+pub const MIN_AUTH_KEY_BYTES: usize = 32;
+
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Signatures per tier per hour when no explicit quota is given. Organic
+/// traffic is a few donations a day; this is generous for that and still caps
+/// what a compromised `gkapi` can extract.
+pub const DEFAULT_TIER_QUOTA_PER_HOUR: usize = 60;
+
+const QUOTA_WINDOW_MINUTES: i64 = 60;
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct SignRequest {
+    pub tier: u64,
+    pub blinded_message: String,
+    pub timestamp: i64,
+    pub nonce: String,
+    pub mac: String,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(tag = "status", rename_all = "snake_case")]
+pub enum SignResponse {
+    Signed { blind_signature: String },
+    Refused { reason: Refusal, message: String },
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Refusal {
+    Unauthorized,
+    UnknownTier,
+    QuotaExceeded,
+    BadRequest,
+    SigningFailed,
+}
+
+#[derive(Error, Debug)]
+pub enum SignerError {
+    #[error("cannot reach gknotary: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("gknotary did not answer within {0:?}")]
+    Timeout(Duration),
+    #[error("malformed message: {0}")]
+    Protocol(String),
+    #[error("gknotary refused ({reason:?}): {message}")]
+    Refused { reason: Refusal, message: String },
+}
+
+/// Read the shared request-authentication key. Surrounding whitespace is
+/// ignored so a key written with `echo` works.
+pub fn load_auth_key(path: &Path) -> Result<Vec<u8>, String> {
+    let bytes = std::fs::read(path).map_err(|e| format!("{}: {e}", path.display()))?;
+    let key = bytes.trim_ascii().to_vec();
+    if key.len() < MIN_AUTH_KEY_BYTES {
+        return Err(format!(
+            "{}: auth key is {} bytes, need at least {MIN_AUTH_KEY_BYTES}",
+            path.display(),
+            key.len()
+        ));
+    }
+    Ok(key)
+}
+
+fn request_mac(
+    key: &[u8],
+    tier: u64,
+    blinded_message: &str,
+    timestamp: i64,
+    nonce: &str,
+) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(DOMAIN);
+    mac.update(&tier.to_be_bytes());
+    mac.update(&timestamp.to_be_bytes());
+    mac.update(nonce.as_bytes());
+    mac.update(&[0]);
+    mac.update(blinded_message.as_bytes());
+    mac
+}
+
+async fn read_frame<T, R>(reader: &mut R) -> Result<Option<T>, SignerError>
+where
+    T: for<'de> Deserialize<'de>,
+    R: AsyncRead + Unpin,
+{
+    let len = match reader.read_u32().await {
+        Ok(len) => len,
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into()),
+    };
+    if len > MAX_FRAME_BYTES {
+        return Err(SignerError::Protocol(format!(
+            "frame of {len} bytes exceeds {MAX_FRAME_BYTES}"
+        )));
+    }
+    let mut body = vec![0; len as usize];
+    reader.read_exact(&mut body).await?;
+    serde_json::from_slice(&body)
+        .map(Some)
+        .map_err(|e| SignerError::Protocol(e.to_string()))
+}
+
+async fn write_frame<T, W>(writer: &mut W, message: &T) -> Result<(), SignerError>
+where
+    T: Serialize,
+    W: AsyncWrite + Unpin,
+{
+    let body = serde_json::to_vec(message).map_err(|e| SignerError::Protocol(e.to_string()))?;
+    writer.write_u32(body.len() as u32).await?;
+    writer.write_all(&body).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Client side, used by `gkapi` in place of a local key.
+pub struct RemoteSigner {
+    socket: PathBuf,
+    auth_key: Vec<u8>,
+}
+
+impl RemoteSigner {
+    pub fn new(socket: PathBuf, auth_key: Vec<u8>) -> Self {
+        Self { socket, auth_key }
+    }
+
+    pub fn socket(&self) -> &Path {
+        &self.socket
+    }
+
+    fn request(&self, tier: u64, blinded_message: String) -> SignRequest {
+        let mut nonce = [0u8; NONCE_BYTES];
+        rand::thread_rng().fill_bytes(&mut nonce);
+        let nonce = hex::encode(nonce);
+        let timestamp = Utc::now().timestamp();
+        let mac = request_mac(&self.auth_key, tier, &blinded_message, timestamp, &nonce);
+        SignRequest {
+            tier,
+            blinded_message,
+            timestamp,
+            nonce,
+            mac: hex::encode(mac.finalize().into_bytes()),
+        }
+    }
+
+    pub async fn sign(
+        &self,
+        tier: u64,
+        blinded: &BlindedMessage,
+    ) -> Result<BlindSignature, SignerError> {
+        let blinded_message = blinded
+            .to_base64()
+            .map_err(|e| SignerError::Protocol(e.to_string()))?;
+        let request = self.request(tier, blinded_message);
+
+        let exchange = async {
+            let mut stream = UnixStream::connect(&self.socket).await?;
+            write_frame(&mut stream, &request).await?;
+            read_frame::<SignResponse, _>(&mut stream)
+                .await?
+                .ok_or_else(|| SignerError::Protocol("connection closed".to_string()))
+        };
+        let response = tokio::time::timeout(CLIENT_TIMEOUT, exchange)
+            .await
+            .map_err(|_| SignerError::Timeout(CLIENT_TIMEOUT))??;
+
+        match response {
+            SignResponse::Signed { blind_signature } => {
+                BlindSignature::from_base64(&blind_signature)
+                    .map_err(|e| SignerError::Protocol(format!("bad signature encoding: {e}")))
+            }
+            SignResponse::Refused { reason, message } => {
+                Err(SignerError::Refused { reason, message })
+            }
+        }
+    }
+}
+
+/// Per-tier signing quotas, in signatures per rolling hour. 0 disables a
+/// tier's quota.
+pub struct Quotas {
+    default_per_hour: usize,
+    overrides: HashMap<u64, usize>,
+    buckets: Mutex<HashMap<u64, Arc<AggregateBucket>>>,
+}
+
+impl Quotas {
+    pub fn new(default_per_hour: usize, overrides: HashMap<u64, usize>) -> Self {
+        Self {
+            default_per_hour,
+            overrides,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn bucket(&self, tier: u64) -> Option<Arc<AggregateBucket>> {
+        let mut buckets = self.buckets.lock().ok()?;
+        let bucket = buckets.entry(tier).or_insert_with(|| {
+            let limit = self
+                .overrides
+                .get(&tier)
+                .copied()
+                .unwrap_or(self.default_per_hour);
+            Arc::new(AggregateBucket::new(limit, QUOTA_WINDOW_MINUTES))
+        });
+        Some(Arc::clone(bucket))
+    }
+}
+
+/// Parse a `TIER=N` quota override, as given on the `gknotary` command line.
+pub fn parse_quota(spec: &str) -> Result<(u64, usize), String> {
+    let (tier, limit) = spec
+        .split_once('=')
+        .ok_or_else(|| format!("quota `{spec}` is not TIER=N"))?;
+    let tier = tier
+        .trim()
+        .trim_start_matches('$')
+        .parse()
+        .map_err(|_| format!("quota `{spec}`: `{tier}` is not a tier amount"))?;
+    let limit = limit
+        .trim()
+        .parse()
+        .map_err(|_| format!("quota `{spec}`: `{limit}` is not a number"))?;
+    Ok((tier, limit))
+}
+
+#[derive(Serialize)]
+struct AuditRecord<'a> {
+    time: String,
+    peer_uid: Option<u32>,
+    tier: u64,
+    /// Hash rather than the message itself: enough to correlate with gkapi's
+    /// logs without turning the audit log into a list of blinded keys.
+    blinded_sha256: String,
+    outcome: &'a str,
+    detail: &'a str,
+}
+
+/// Server side, run by the `gknotary` binary.
+pub struct SignerServer {
+    store: Arc<NotaryStore>,
+    auth_key: Vec<u8>,
+    quotas: Quotas,
+    allowed_uids: Vec<u32>,
+    audit_log: Option<Mutex<File>>,
+    /// nonce -> request timestamp, kept for the skew window so a request
+    /// cannot be replayed while its timestamp would still be accepted.
+    seen_nonces: Mutex<HashMap<String, i64>>,
+}
+
+impl SignerServer {
+    /// `allowed_uids` empty means any peer that can open the socket and
+    /// knows the auth key.
+    pub fn new(
+        store: Arc<NotaryStore>,
+        auth_key: Vec<u8>,
+        quotas: Quotas,
+        allowed_uids: Vec<u32>,
+        audit_log: Option<&Path>,
+    ) -> std::io::Result<Self> {
+        let audit_log = match audit_log {
+            Some(path) => Some(Mutex::new(
+                OpenOptions::new().create(true).append(true).open(path)?,
+            )),
+            None => None,
+        };
+        Ok(Self {
+            store,
+            auth_key,
+            quotas,
+            allowed_uids,
+            audit_log,
+            seen_nonces: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Accept connections until the listener fails.
+    pub async fn serve(self: Arc<Self>, listener: UnixListener) -> std::io::Result<()> {
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let server = Arc::clone(&self);
+            tokio::spawn(async move {
+                if let Err(e) = server.connection(stream).await {
+                    warn!("gknotary connection ended with an error: {e}");
+                }
+            });
+        }
+    }
+
+    async fn connection(self: Arc<Self>, mut stream: UnixStream) -> Result<(), SignerError> {
+        let peer_uid = stream.peer_cred().ok().map(|cred| cred.uid());
+        while let Some(request) = read_frame::<SignRequest, _>(&mut stream).await? {
+            // Signing and the audit write block; keep them off the runtime.
+            let server = Arc::clone(&self);
+            let response = tokio::task::spawn_blocking(move || server.handle(&request, peer_uid))
+                .await
+                .map_err(|e| SignerError::Io(std::io::Error::other(e)))?;
+            write_frame(&mut stream, &response).await?;
+        }
+        Ok(())
+    }
+
+    /// Authenticate, check quota, sign and audit one request.
+    pub fn handle(&self, request: &SignRequest, peer_uid: Option<u32>) -> SignResponse {
+        let response = self.decide(request, peer_uid);
+        let (outcome, detail) = match &response {
+            SignResponse::Signed { .. } => ("signed", ""),
+            SignResponse::Refused { message, .. } => ("refused", message.as_str()),
+        };
+        self.audit(request, peer_uid, outcome, detail);
+        response
+    }
+
+    fn decide(&self, request: &SignRequest, peer_uid: Option<u32>) -> SignResponse {
+        let refuse = |reason: Refusal, message: &str| SignResponse::Refused {
+            reason,
+            message: message.to_string(),
+        };
+
+        if !self.allowed_uids.is_empty()
+            && !peer_uid.is_some_and(|uid| self.allowed_uids.contains(&uid))
+        {
+            return refuse(Refusal::Unauthorized, "peer uid is not allowed");
+        }
+        if let Err(message) = self.authenticate(request) {
+            return refuse(Refusal::Unauthorized, message);
+        }
+
+        let Some(notary) = self.store.get(request.tier) else {
+            return refuse(Refusal::UnknownTier, "no notary is loaded for this tier");
+        };
+        let NotaryKey::Local(signing_key) = &notary.key else {
+            // The daemon always loads keys locally; this is a configuration
+            // loop (gknotary pointed at another gknotary) rather than a
+            // client error.
+            return refuse(
+                Refusal::SigningFailed,
+                "gknotary holds no key for this tier",
+            );
+        };
+        let Ok(blinded) = BlindedMessage::from_base64(&request.blinded_message) else {
+            return refuse(Refusal::BadRequest, "blinded message is not valid");
+        };
+
+        let Some(bucket) = self.quotas.bucket(request.tier) else {
+            return refuse(Refusal::QuotaExceeded, "quota state is unavailable");
+        };
+        if !bucket.try_acquire() {
+            return refuse(
+                Refusal::QuotaExceeded,
+                "hourly quota for this tier is spent",
+            );
+        }
+
+        let signature = delegates::blind_sign(signing_key, &blinded)
+            .map_err(|e| e.to_string())
+            .and_then(|signature| signature.to_base64().map_err(|e| e.to_string()));
+        match signature {
+            Ok(blind_signature) => SignResponse::Signed { blind_signature },
+            Err(e) => {
+                bucket.release();
+                error!("Blind signing failed for ${} tier: {e}", request.tier);
+                refuse(Refusal::SigningFailed, "signing failed")
+            }
+        }
+    }
+
+    fn authenticate(&self, request: &SignRequest) -> Result<(), &'static str> {
+        let now = Utc::now().timestamp();
+        if (now - request.timestamp).abs() > MAX_CLOCK_SKEW_SECONDS {
+            return Err("request timestamp is outside the accepted window");
+        }
+        let mac = hex::decode(&request.mac).map_err(|_| "request MAC is malformed")?;
+        request_mac(
+            &self.auth_key,
+            request.tier,
+            &request.blinded_message,
+            request.timestamp,
+            &request.nonce,
+        )
+        .verify_slice(&mac)
+        .map_err(|_| "request MAC is invalid")?;
+
+        let mut seen = self
+            .seen_nonces
+            .lock()
+            .map_err(|_| "replay state is unavailable")?;
+        seen.retain(|_, timestamp| (now - *timestamp).abs() <= MAX_CLOCK_SKEW_SECONDS);
+        if seen
+            .insert(request.nonce.clone(), request.timestamp)
+            .is_some()
+        {
+            return Err("request nonce has already been used");
+        }
+        Ok(())
+    }
+
+    fn audit(&self, request: &SignRequest, peer_uid: Option<u32>, outcome: &str, detail: &str) {
+        let record = AuditRecord {
+            time: Utc::now().to_rfc3339(),
+            peer_uid,
+            tier: request.tier,
+            blinded_sha256: hex::encode(Sha256::digest(request.blinded_message.as_bytes())),
+            outcome,
+            detail,
+        };
+        let Ok(line) = serde_json::to_string(&record) else {
+            return;
+        };
+        match &self.audit_log {
+            Some(file) => {
+                let written = file
+                    .lock()
+                    .map_err(|_| std::io::Error::other("audit log lock poisoned"))
+                    .and_then(|mut file| writeln!(file, "{line}"));
+                if let Err(e) = written {
+                    // Keep signing: refusing donors because a log disk filled
+                    // up would be worse than a gap in the log, and the gap is
+                    // itself visible here.
+                    error!("Failed to write audit record {line}: {e}");
+                }
+            }
+            None => info!("audit {line}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use blind_rsa_signatures::Options;
+    use ghostkey_lib::notary_certificate::NotaryCertificateV1;
+    use ghostkey_lib::util::create_keypair;
+    use rand_core::OsRng;
+    use tempfile::{tempdir, TempDir};
+
+    const AUTH_KEY: &[u8] = b"0123456789abcdef0123456789abcdef";
+
+    fn store_with_tier(amount: u64) -> (TempDir, Arc<NotaryStore>) {
+        let (master, master_vk) = create_keypair(&mut OsRng).unwrap();
+        let dir = tempdir().unwrap();
+        let (cert, key) = NotaryCertificateV1::new(&master, &format!("${amount}")).unwrap();
+        cert.to_file(&dir.path().join(format!("notary_certificate_{amount}.pem")))
+            .unwrap();
+        key.to_file(&dir.path().join(format!("notary_signing_key_{amount}.pem")))
+            .unwrap();
+        let store = NotaryStore::load(dir.path(), master_vk).unwrap();
+        (dir, Arc::new(store))
+    }
+
+    fn server(store: Arc<NotaryStore>, quota: usize, audit: Option<&Path>) -> SignerServer {
+        SignerServer::new(
+            store,
+            AUTH_KEY.to_vec(),
+            Quotas::new(quota, HashMap::new()),
+            Vec::new(),
+            audit,
+        )
+        .unwrap()
+    }
+
+    fn refusal(response: SignResponse) -> Refusal {
+        match response {
+            SignResponse::Refused { reason, .. } => reason,
+            SignResponse::Signed { .. } => panic!("expected a refusal"),
+        }
+    }
+
+    /// One test for the whole round trip, so the 2048-bit key is generated
+    /// once: sign over a real socket, then check the signature finalizes
+    /// against the public key in the certificate.
+    #[tokio::test]
+    async fn remote_signature_verifies_against_the_certificate() {
+        let (_dir, store) = store_with_tier(20);
+        let socket_dir = tempdir().unwrap();
+        let socket = socket_dir.path().join("gknotary.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let audit = socket_dir.path().join("audit.jsonl");
+        let server = Arc::new(server(Arc::clone(&store), 10, Some(&audit)));
+        tokio::spawn(Arc::clone(&server).serve(listener));
+
+        let NotaryKey::Local(key) = &store.get(20).unwrap().key else {
+            unreachable!()
+        };
+        let public_key = key.public_key().unwrap();
+        let options = Options::default();
+        let message = b"ghost key";
+        let blinding = public_key
+            .blind(&mut OsRng, message, true, &options)
+            .unwrap();
+
+        let client = RemoteSigner::new(socket.clone(), AUTH_KEY.to_vec());
+        let blind_signature = client.sign(20, &blinding.blind_msg).await.unwrap();
+        public_key
+            .finalize(
+                &blind_signature,
+                &blinding.secret,
+                blinding.msg_randomizer,
+                message,
+                &options,
+            )
+            .expect("signature from gknotary must verify");
+
+        let wrong_key = RemoteSigner::new(socket, b"x".repeat(32));
+        match wrong_key.sign(20, &blinding.blind_msg).await {
+            Err(SignerError::Refused {
+                reason: Refusal::Unauthorized,
+                ..
+            }) => {}
+            other => panic!("expected an auth refusal, got {other:?}"),
+        }
+
+        let log = std::fs::read_to_string(&audit).unwrap();
+        let lines: Vec<_> = log.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].contains("\"outcome\":\"signed\""));
+        assert!(lines[1].contains("\"outcome\":\"refused\""));
+        assert!(!log.contains(&blinding.blind_msg.to_base64().unwrap()));
+    }
+
+    #[tokio::test]
+    async fn replays_tampering_unknown_tiers_and_spent_quotas_are_refused() {
+        let (_dir, store) = store_with_tier(20);
+        let server = server(store, 1, None);
+        let client = RemoteSigner::new(PathBuf::new(), AUTH_KEY.to_vec());
+        // Not a real blinded message; every refusal until the bad-request
+        // case happens before it would be parsed.
+        let blinded = "bm90IGJsaW5kZWQ=".to_string();
+
+        let request = client.request(50, blinded.clone());
+        assert_eq!(refusal(server.handle(&request, None)), Refusal::UnknownTier);
+        assert_eq!(
+            refusal(server.handle(&request, None)),
+            Refusal::Unauthorized,
+            "a nonce is single-use"
+        );
+
+        let mut tampered = client.request(50, blinded.clone());
+        tampered.tier = 20;
+        assert_eq!(
+            refusal(server.handle(&tampered, None)),
+            Refusal::Unauthorized
+        );
+
+        let mut stale = client.request(20, blinded.clone());
+        stale.timestamp -= 10 * MAX_CLOCK_SKEW_SECONDS;
+        assert_eq!(refusal(server.handle(&stale, None)), Refusal::Unauthorized);
+
+        let request = client.request(20, blinded);
+        assert_eq!(refusal(server.handle(&request, None)), Refusal::BadRequest);
+
+        let NotaryKey::Local(key) = &server.store.get(20).unwrap().key else {
+            unreachable!()
+        };
+        let blinded = key
+            .public_key()
+            .unwrap()
+            .blind(&mut OsRng, b"m", true, &Options::default())
+            .unwrap()
+            .blind_msg
+            .to_base64()
+            .unwrap();
+        let request = client.request(20, blinded.clone());
+        assert!(matches!(
+            server.handle(&request, None),
+            SignResponse::Signed { .. }
+        ));
+        let request = client.request(20, blinded);
+        assert_eq!(
+            refusal(server.handle(&request, None)),
+            Refusal::QuotaExceeded,
+            "the quota is one signature per hour"
+        );
+    }
+
+    #[test]
+    fn peer_uid_allow_list_is_enforced() {
+        let dir = tempdir().unwrap();
+        let (_, master_vk) = create_keypair(&mut OsRng).unwrap();
+        let store = Arc::new(NotaryStore::load(dir.path(), master_vk).unwrap());
+        let server = SignerServer::new(
+            store,
+            AUTH_KEY.to_vec(),
+            Quotas::new(1, HashMap::new()),
+            vec![1000],
+            None,
+        )
+        .unwrap();
+        let client = RemoteSigner::new(PathBuf::new(), AUTH_KEY.to_vec());
+
+        let request = client.request(20, String::new());
+        assert_eq!(
+            refusal(server.handle(&request, Some(1001))),
+            Refusal::Unauthorized
+        );
+        assert_eq!(
+            refusal(server.handle(&request, None)),
+            Refusal::Unauthorized
+        );
+        let request = client.request(20, String::new());
+        assert_eq!(
+            refusal(server.handle(&request, Some(1000))),
+            Refusal::UnknownTier
+        );
+    }
+
+    #[test]
+    fn quota_specs_and_auth_keys_are_validated() {
+        assert_eq!(parse_quota("20=5"), Ok((20, 5)));
+        assert_eq!(parse_quota("$100 = 0"), Ok((100, 0)));
+        assert!(parse_quota("20").is_err());
+        assert!(parse_quota("twenty=5").is_err());
+
+        let dir = tempdir().unwrap();
+        let short = dir.path().join("short");
+        std::fs::write(&short, "too short\n").unwrap();
+        assert!(load_auth_key(&short).unwrap_err().contains("need at least"));
+        let good = dir.path().join("good");
+        std::fs::write(&good, [AUTH_KEY, b"\n"].concat()).unwrap();
+        assert_eq!(load_auth_key(&good).unwrap(), AUTH_KEY);
+    }
+}
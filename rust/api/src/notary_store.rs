@@ -0,0 +1,328 @@
+//! In-memory notary certificates and signing keys.
+//!
+//! Every donation and signing request used to re-read and re-parse the
+//! certificate and the 2048-bit RSA key for its tier from disk. The store loads
+//! them once, verifies every tier against the master key (the same check
+//! `check-config` runs), and serves lookups from memory.
+//!
+//! Reloads (SIGHUP, or the directory changing on disk) build a complete new
+//! set and swap it in only if every tier verifies. A half-copied key during a
+//! rotation therefore leaves the previous set serving rather than taking a
+//! tier down or, worse, pairing a new certificate with an old key.
+//!
+//! When signing is delegated to `gknotary` (see [`crate::notary_signer`]) the
+//! store holds certificates only; the keys never enter this process.
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+use blind_rsa_signatures::SecretKey as RSASigningKey;
+use ed25519_dalek::VerifyingKey;
+use ghostkey_lib::armorable::Armorable;
+use log::{error, info, warn};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+use crate::delegates;
+use crate::notary_signer::RemoteSigner;
+
+/// How often the directory is checked for changes. Key rotation is rare and
+/// SIGHUP is the prompt path; this only catches a forgotten signal.
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Error, Debug)]
+pub enum NotaryStoreError {
+    #[error("cannot read notary directory {dir}: {source}")]
+    Dir {
+        dir: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("${amount} tier: {reason}")]
+    Tier { amount: u64, reason: String },
+}
+
+/// One donation tier, fully loaded and verified.
+pub struct Notary {
+    pub amount: u64,
+    /// Hex SHA-256 of the notary's public key (DER). Identifies the key, not
+    /// the tier, so issuance counts survive a tier's key being rotated.
+    pub fingerprint: String,
+    /// Pre-encoded once; every donation response carries it.
+    pub certificate_base64: String,
+    pub key: NotaryKey,
+}
+
+/// Where a tier's signing key lives.
+pub enum NotaryKey {
+    Local(Box<RSASigningKey>),
+    /// Held by a `gknotary` daemon; only its certificate is loaded here.
+    Remote(Arc<RemoteSigner>),
+}
+
+type Tiers = BTreeMap<u64, Arc<Notary>>;
+
+/// Names, sizes and mtimes of the directory's entries; cheap to compute and
+/// changes whenever a file is replaced, renamed or touched.
+type DirFingerprint = Vec<(std::ffi::OsString, u64, Option<SystemTime>)>;
+
+pub struct NotaryStore {
+    dir: PathBuf,
+    master_vk: VerifyingKey,
+    signer: Option<Arc<RemoteSigner>>,
+    tiers: RwLock<Arc<Tiers>>,
+    fingerprint: Mutex<Option<DirFingerprint>>,
+}
+
+impl NotaryStore {
+    /// Load and verify every tier in `dir`. Any bad tier fails the whole load.
+    pub fn load(dir: &Path, master_vk: VerifyingKey) -> Result<Self, NotaryStoreError> {
+        Self::load_with_signer(dir, master_vk, None)
+    }
+
+    /// Like [`Self::load`], but with `signer` set only the certificates are
+    /// read and every tier signs through it.
+    pub fn load_with_signer(
+        dir: &Path,
+        master_vk: VerifyingKey,
+        signer: Option<Arc<RemoteSigner>>,
+    ) -> Result<Self, NotaryStoreError> {
+        let fingerprint = fingerprint(dir).ok();
+        let tiers = load_tiers(dir, &master_vk, signer.as_ref())?;
+        Ok(Self {
+            dir: dir.to_path_buf(),
+            master_vk,
+            signer,
+            tiers: RwLock::new(Arc::new(tiers)),
+            fingerprint: Mutex::new(fingerprint),
+        })
+    }
+
+    /// Re-read the directory and swap the new set in atomically. On error the
+    /// current set is left untouched.
+    pub fn reload(&self) -> Result<usize, NotaryStoreError> {
+        let fingerprint = fingerprint(&self.dir).ok();
+        let tiers = load_tiers(&self.dir, &self.master_vk, self.signer.as_ref())?;
+        let count = tiers.len();
+        *self.tiers.write().unwrap_or_else(|e| e.into_inner()) = Arc::new(tiers);
+        *self.fingerprint.lock().unwrap_or_else(|e| e.into_inner()) = fingerprint;
+        Ok(count)
+    }
+
+    /// Reload only if the directory looks different from the last load.
+    fn reload_if_changed(&self) -> Option<Result<usize, NotaryStoreError>> {
+        let current = fingerprint(&self.dir).ok();
+        let last = self.fingerprint.lock().unwrap_or_else(|e| e.into_inner());
+        if *last == current {
+            return None;
+        }
+        drop(last);
+        Some(self.reload())
+    }
+
+    fn snapshot(&self) -> Arc<Tiers> {
+        Arc::clone(&self.tiers.read().unwrap_or_else(|e| e.into_inner()))
+    }
+
+    pub fn get(&self, amount: u64) -> Option<Arc<Notary>> {
+        self.snapshot().get(&amount).cloned()
+    }
+
+    /// Donation tiers (in dollars) currently available, ascending.
+    pub fn tiers(&self) -> Vec<u64> {
+        self.snapshot().keys().copied().collect()
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+fn load_tiers(
+    dir: &Path,
+    master_vk: &VerifyingKey,
+    signer: Option<&Arc<RemoteSigner>>,
+) -> Result<Tiers, NotaryStoreError> {
+    let amounts = delegates::tier_amounts(dir).map_err(|source| NotaryStoreError::Dir {
+        dir: dir.to_path_buf(),
+        source,
+    })?;
+    let mut tiers = Tiers::new();
+    for amount in amounts {
+        let tier_error = |reason: String| NotaryStoreError::Tier { amount, reason };
+        let (certificate, key) = match signer {
+            Some(signer) => {
+                let certificate = delegates::load_notary_certificate(dir, amount)
+                    .map_err(|e| tier_error(e.to_string()))?;
+                // The key is out of reach, so only the chain can be checked
+                // here; gknotary checks the pairing when it loads the key.
+                certificate.verify(&Some(*master_vk)).map_err(|e| {
+                    tier_error(format!("certificate does not chain to the master key: {e}"))
+                })?;
+                (certificate, NotaryKey::Remote(Arc::clone(signer)))
+            }
+            None => {
+                let (certificate, signing_key) =
+                    delegates::load_notary(dir, amount).map_err(|e| tier_error(e.to_string()))?;
+                delegates::verify_notary(&certificate, &signing_key, master_vk)
+                    .map_err(tier_error)?;
+                (certificate, NotaryKey::Local(Box::new(signing_key)))
+            }
+        };
+        let certificate_base64 = certificate
+            .to_base64()
+            .map_err(|e| tier_error(e.to_string()))?;
+        let fingerprint = certificate
+            .payload
+            .notary_verifying_key
+            .to_der()
+            .map(|der| hex::encode(Sha256::digest(der)))
+            .map_err(|e| tier_error(e.to_string()))?;
+        tiers.insert(
+            amount,
+            Arc::new(Notary {
+                amount,
+                fingerprint,
+                certificate_base64,
+                key,
+            }),
+        );
+    }
+    Ok(tiers)
+}
+
+fn fingerprint(dir: &Path) -> std::io::Result<DirFingerprint> {
+    let mut entries = Vec::new();
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let metadata = entry.metadata()?;
+        entries.push((entry.file_name(), metadata.len(), metadata.modified().ok()));
+    }
+    entries.sort();
+    Ok(entries)
+}
+
+fn log_reload(trigger: &str, result: Result<usize, NotaryStoreError>, store: &NotaryStore) {
+    match result {
+        Ok(n) => info!(
+            "Reloaded {n} notary tier(s) from {} ({trigger})",
+            store.dir.display()
+        ),
+        Err(e) => error!(
+            "Notary reload ({trigger}) failed, still serving the previous {} tier(s): {e}",
+            store.tiers().len()
+        ),
+    }
+}
+
+/// Reload `store` on SIGHUP and whenever the directory changes.
+pub fn spawn_reloader(store: Arc<NotaryStore>) {
+    let polled = Arc::clone(&store);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let store = Arc::clone(&polled);
+            // Reading and verifying every tier is file I/O; keep it off the
+            // runtime's worker threads.
+            match tokio::task::spawn_blocking(move || store.reload_if_changed()).await {
+                Ok(Some(result)) => log_reload("directory changed", result, &polled),
+                Ok(None) => {}
+                Err(e) => error!("Notary key reload task failed: {e}"),
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                warn!("Cannot listen for SIGHUP; notary keys reload on file change only: {e}");
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            let reloading = Arc::clone(&store);
+            match tokio::task::spawn_blocking(move || reloading.reload()).await {
+                Ok(result) => log_reload("SIGHUP", result, &store),
+                Err(e) => error!("Notary key reload task failed: {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+    use ghostkey_lib::notary_certificate::NotaryCertificateV1;
+    use ghostkey_lib::util::create_keypair;
+    use rand_core::OsRng;
+    use tempfile::tempdir;
+
+    fn write_tier(dir: &Path, master: &SigningKey, amount: u64) {
+        let (cert, key) = NotaryCertificateV1::new(master, &format!("${amount}")).unwrap();
+        cert.to_file(&dir.join(format!("notary_certificate_{amount}.pem")))
+            .unwrap();
+        key.to_file(&dir.join(format!("notary_signing_key_{amount}.pem")))
+            .unwrap();
+    }
+
+    #[test]
+    fn loads_tiers_and_serves_them_from_memory() {
+        let (master, master_vk) = create_keypair(&mut OsRng).unwrap();
+        let dir = tempdir().unwrap();
+        write_tier(dir.path(), &master, 20);
+
+        let store = NotaryStore::load(dir.path(), master_vk).unwrap();
+        assert_eq!(store.tiers(), vec![20]);
+
+        // Deleting the files must not affect lookups: nothing is read per call.
+        std::fs::remove_file(dir.path().join("notary_signing_key_20.pem")).unwrap();
+        let notary = store.get(20).expect("tier 20 is loaded");
+        assert!(!notary.certificate_base64.is_empty());
+        assert!(store.get(50).is_none());
+    }
+
+    #[test]
+    fn a_tier_from_another_master_fails_the_load() {
+        let (_, master_vk) = create_keypair(&mut OsRng).unwrap();
+        let (other_master, _) = create_keypair(&mut OsRng).unwrap();
+        let dir = tempdir().unwrap();
+        write_tier(dir.path(), &other_master, 20);
+
+        match NotaryStore::load(dir.path(), master_vk) {
+            Err(NotaryStoreError::Tier { amount: 20, .. }) => {}
+            other => panic!(
+                "expected the $20 tier to be rejected, got {:?}",
+                other.err()
+            ),
+        }
+    }
+
+    /// A broken reload must leave the previous set serving.
+    #[test]
+    fn failed_reload_keeps_the_previous_set() {
+        let (master, master_vk) = create_keypair(&mut OsRng).unwrap();
+        let dir = tempdir().unwrap();
+        write_tier(dir.path(), &master, 20);
+        let store = NotaryStore::load(dir.path(), master_vk).unwrap();
+
+        std::fs::write(dir.path().join("notary_certificate_50.pem"), "half copied").unwrap();
+        assert!(store.reload_if_changed().unwrap().is_err());
+        assert_eq!(store.tiers(), vec![20]);
+        assert!(store.get(20).is_some());
+
+        std::fs::remove_file(dir.path().join("notary_certificate_50.pem")).unwrap();
+        write_tier(dir.path(), &master, 50);
+        assert_eq!(store.reload_if_changed().unwrap().unwrap(), 2);
+        assert_eq!(store.tiers(), vec![20, 50]);
+        assert!(
+            store.reload_if_changed().is_none(),
+            "an unchanged directory is not reloaded"
+        );
+    }
+}
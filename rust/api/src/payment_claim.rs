@@ -29,50 +29,75 @@ use tokio::sync::{Mutex as AsyncMutex, OwnedMutexGuard};
 
 /// Live locks, keyed by PaymentIntent id.
 ///
-/// Entries are removed when the last guard for a key is dropped (see
-/// `ClaimGuard::drop`), so the map is bounded by the number of in-flight
+/// Entries are removed when the last holder or waiter for a key goes away (see
+/// `Registration::drop`), so the map is bounded by the number of in-flight
 /// requests rather than by the number of PaymentIntents ever seen. That
 /// bound is the point: the lock is taken before the PaymentIntent is known to
 /// exist, so without cleanup an unauthenticated caller could grow this map
 /// without limit by posting garbage ids.
-static CLAIM_LOCKS: LazyLock<Mutex<HashMap<String, Arc<AsyncMutex<()>>>>> =
+static CLAIM_LOCKS: LazyLock<Mutex<HashMap<String, ClaimEntry>>> =
     LazyLock::new(|| Mutex::new(HashMap::new()));
 
-/// Exclusive claim on one PaymentIntent, held for as long as the guard lives.
-pub(crate) struct ClaimGuard {
+struct ClaimEntry {
+    lock: Arc<AsyncMutex<()>>,
+    /// Requests holding or queued on `lock`, counted under the map lock.
+    ///
+    /// This used to be inferred from `Arc::strong_count`, which is not safe:
+    /// `OwnedMutexGuard` wakes the next waiter before it drops its own `Arc`,
+    /// so on a multi-threaded runtime the waiter can finish and observe a
+    /// count that still includes the previous holder, and the entry leaks.
+    users: usize,
+}
+
+/// One request's interest in a key, from before it queues until after it
+/// has released the mutex. Also covers a claim future that is dropped while
+/// still waiting.
+struct Registration {
     payment_intent_id: String,
-    // Dropped after `Drop::drop` runs, which is what makes the strong_count
-    // arithmetic there work out.
-    _guard: OwnedMutexGuard<()>,
 }
 
-impl Drop for ClaimGuard {
+impl Drop for Registration {
     fn drop(&mut self) {
         // A poisoned map lock only means some other thread panicked while
         // holding it; the map itself is still structurally sound, and refusing
         // to clean up would leak. Recover rather than propagate.
         let mut map = CLAIM_LOCKS.lock().unwrap_or_else(|e| e.into_inner());
 
-        if let Some(lock) = map.get(&self.payment_intent_id) {
-            // Two references means the map and this guard, so nobody else is
-            // holding or waiting and the entry can go. Three or more means a
-            // waiter has already cloned the Arc and must keep contending on
-            // this same mutex, so leave it in place.
-            if Arc::strong_count(lock) == 2 {
+        if let Some(entry) = map.get_mut(&self.payment_intent_id) {
+            entry.users -= 1;
+            if entry.users == 0 {
                 map.remove(&self.payment_intent_id);
             }
         }
     }
 }
 
+/// Exclusive claim on one PaymentIntent, held for as long as the guard lives.
+pub(crate) struct ClaimGuard {
+    // Field order is load-bearing: the mutex must be released BEFORE the
+    // registration can remove the entry. The other way round, a new request
+    // arriving in between would find no entry, create a fresh mutex, and hold
+    // it while this guard still holds the old one.
+    _guard: OwnedMutexGuard<()>,
+    _registration: Registration,
+}
+
 /// Wait until no other in-process request is signing against this
 /// PaymentIntent, then take the claim.
 pub(crate) async fn claim(payment_intent_id: &str) -> ClaimGuard {
     let lock = {
         let mut map = CLAIM_LOCKS.lock().unwrap_or_else(|e| e.into_inner());
-        map.entry(payment_intent_id.to_string())
-            .or_insert_with(|| Arc::new(AsyncMutex::new(())))
-            .clone()
+        let entry = map
+            .entry(payment_intent_id.to_string())
+            .or_insert_with(|| ClaimEntry {
+                lock: Arc::new(AsyncMutex::new(())),
+                users: 0,
+            });
+        entry.users += 1;
+        entry.lock.clone()
+    };
+    let registration = Registration {
+        payment_intent_id: payment_intent_id.to_string(),
     };
 
     // Awaited with the map lock released, so a slow claim on one PaymentIntent
@@ -80,8 +105,8 @@ pub(crate) async fn claim(payment_intent_id: &str) -> ClaimGuard {
     let guard = lock.lock_owned().await;
 
     ClaimGuard {
-        payment_intent_id: payment_intent_id.to_string(),
         _guard: guard,
+        _registration: registration,
     }
 }
 
@@ -191,6 +216,27 @@ mod tests {
         );
     }
 
+    /// Handing a claim to a waiter is where the cleanup has to count right:
+    /// the holder's guard wakes the waiter before it lets go of the mutex, so
+    /// on several threads the waiter may finish first. Repeated because it
+    /// is a race; either order must leave nothing behind.
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn handed_off_claims_are_reclaimed() {
+        for i in 0..200 {
+            let key = format!("pi_handed_off_{i}");
+            let held = claim(&key).await;
+            let waiter = {
+                let key = key.clone();
+                tokio::spawn(async move { drop(claim(&key).await) })
+            };
+            // Let the waiter queue behind the held claim.
+            tokio::time::sleep(std::time::Duration::from_millis(1)).await;
+            drop(held);
+            waiter.await.unwrap();
+            assert!(!is_tracked(&key), "hand-off {i} leaked its entry");
+        }
+    }
+
     /// A waiter must keep contending on the same mutex the holder is using; if
     /// cleanup dropped the entry out from under it, the two would end up on
     /// different mutexes and the exclusion would silently stop working.
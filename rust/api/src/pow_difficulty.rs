@@ -0,0 +1,427 @@
+//! Adaptive difficulty for invite proof of work.
+//!
+//! Each extra bit doubles the expected work. Three terms are added to the
+//! base difficulty:
+//!
+//! - **rate**: invitations issued over the last hour against a target rate.
+//!   Nothing at or below the target, then one bit per doubling above it, so
+//!   an attacker with fixed compute is pushed back towards the target.
+//! - **fill**: how close the global ceiling is, from nothing at half full to
+//!   [`CEILING_EXTRA_BITS`] at the ceiling, so legitimate users are not
+//!   locked out by an attacker who can afford the rate term.
+//! - **network**: challenge requests from the requester's network over the
+//!   last minute. One bit per doubling above a free allowance, for that
+//!   network only.
+//!
+//! The first two follow a rise at once and decay with a time constant of
+//! [`DECAY`], so one burst does not swing difficulty back and forth and it
+//! settles back to the base once an attack stops. The total is rounded to
+//! whole bits and capped at `max_extra_bits`.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use ipnet::IpNet;
+use serde::Serialize;
+
+use crate::invite_pow::DEFAULT_POW_DIFFICULTY;
+
+pub const DEFAULT_TARGET_INVITES_PER_HOUR: usize = 90;
+pub const DEFAULT_MAX_EXTRA_BITS: u8 = 8;
+pub const DEFAULT_NETWORK_REQUESTS_PER_MINUTE: usize = 10;
+/// Bits the fill term reaches at the global ceiling.
+pub const CEILING_EXTRA_BITS: f64 = 4.0;
+const DECAY: Duration = Duration::from_secs(10 * 60);
+const NETWORK_WINDOW: Duration = Duration::from_secs(60);
+/// Most leading-zero bits a challenge can ask for.
+const MAX_DIFFICULTY: u8 = 30;
+
+#[derive(Clone, Copy, Debug)]
+pub struct DifficultySettings {
+    pub base: u8,
+    /// Hourly issuance the rate term holds difficulty at the base for.
+    pub target_per_hour: usize,
+    pub max_extra_bits: u8,
+    /// Challenge requests a network can make in a minute before its own
+    /// difficulty rises. 0 turns the network term off.
+    pub network_requests_per_minute: usize,
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+}
+
+impl Default for DifficultySettings {
+    fn default() -> Self {
+        Self {
+            base: DEFAULT_POW_DIFFICULTY,
+            target_per_hour: DEFAULT_TARGET_INVITES_PER_HOUR,
+            max_extra_bits: DEFAULT_MAX_EXTRA_BITS,
+            network_requests_per_minute: DEFAULT_NETWORK_REQUESTS_PER_MINUTE,
+            ipv4_prefix: 24,
+            ipv6_prefix: 64,
+        }
+    }
+}
+
+/// The controller's view at one moment, for `/pow-status`.
+#[derive(Debug, Serialize)]
+pub struct DifficultyStatus {
+    pub base_difficulty: u8,
+    /// What a network with no recent requests is asked for now.
+    pub difficulty: u8,
+    pub global_extra_bits: f64,
+    pub target_invites_per_hour: usize,
+    pub invites_last_hour: usize,
+    /// 0 when the ceiling is off.
+    pub invites_per_hour_ceiling: usize,
+    /// Networks whose own difficulty is currently raised.
+    pub busy_networks: usize,
+}
+
+struct ControllerState {
+    /// Smoothed sum of the rate and fill terms.
+    global: f64,
+    updated: Option<Instant>,
+    /// Challenge requests per network over the last [`NETWORK_WINDOW`].
+    networks: HashMap<IpNet, VecDeque<Instant>>,
+    swept: Option<Instant>,
+}
+
+pub struct DifficultyController {
+    settings: DifficultySettings,
+    state: Mutex<ControllerState>,
+}
+
+impl DifficultyController {
+    pub fn new(settings: DifficultySettings) -> Self {
+        Self {
+            settings,
+            state: Mutex::new(ControllerState {
+                global: 0.0,
+                updated: None,
+                networks: HashMap::new(),
+                swept: None,
+            }),
+        }
+    }
+
+    /// Count a challenge request from `ip`, if given, and return the
+    /// difficulty to issue it at. `issued` and `ceiling` describe the global
+    /// bucket; pass `None` for requests that are not counted against their
+    /// network, such as allow-listed addresses.
+    pub fn challenge_difficulty(&self, ip: Option<IpAddr>, issued: usize, ceiling: usize) -> u8 {
+        self.difficulty_at(ip, issued, ceiling, Instant::now())
+    }
+
+    fn difficulty_at(&self, ip: Option<IpAddr>, issued: usize, ceiling: usize, now: Instant) -> u8 {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        self.update_global(&mut state, issued, ceiling, now);
+        let network = match ip.and_then(|ip| self.network(ip)) {
+            Some(net) => self.count_request(&mut state, net, now),
+            None => 0.0,
+        };
+        self.bits(state.global + network)
+    }
+
+    pub fn status(&self, issued: usize, ceiling: usize) -> DifficultyStatus {
+        self.status_at(issued, ceiling, Instant::now())
+    }
+
+    fn status_at(&self, issued: usize, ceiling: usize, now: Instant) -> DifficultyStatus {
+        let mut state = self.state.lock().unwrap_or_else(|e| e.into_inner());
+        self.update_global(&mut state, issued, ceiling, now);
+        self.sweep(&mut state, now, true);
+        let busy_networks = state
+            .networks
+            .values()
+            .filter(|requests| self.network_bits(requests.len()) > 0.0)
+            .count();
+        DifficultyStatus {
+            base_difficulty: self.settings.base,
+            difficulty: self.bits(state.global),
+            global_extra_bits: (state.global * 100.0).round() / 100.0,
+            target_invites_per_hour: self.settings.target_per_hour,
+            invites_last_hour: issued,
+            invites_per_hour_ceiling: ceiling,
+            busy_networks,
+        }
+    }
+
+    /// The rate and fill terms for the bucket as it is now, before smoothing.
+    fn global_target(&self, issued: usize, ceiling: usize) -> f64 {
+        let target = self.settings.target_per_hour.max(1) as f64;
+        let rate = (issued as f64 / target).log2().max(0.0);
+        let fill = if ceiling == 0 {
+            0.0
+        } else {
+            let fill = issued as f64 / ceiling as f64;
+            ((fill - 0.5) / 0.5).clamp(0.0, 1.0) * CEILING_EXTRA_BITS
+        };
+        rate + fill
+    }
+
+    fn update_global(
+        &self,
+        state: &mut ControllerState,
+        issued: usize,
+        ceiling: usize,
+        now: Instant,
+    ) {
+        let target = self.global_target(issued, ceiling);
+        let elapsed = state
+            .updated
+            .map_or(Duration::ZERO, |t| now.saturating_duration_since(t));
+        state.global = if target >= state.global {
+            target
+        } else {
+            let kept = (-elapsed.as_secs_f64() / DECAY.as_secs_f64()).exp();
+            target + (state.global - target) * kept
+        };
+        state.updated = Some(now);
+    }
+
+    fn network(&self, ip: IpAddr) -> Option<IpNet> {
+        if self.settings.network_requests_per_minute == 0 {
+            return None;
+        }
+        let prefix = match ip {
+            IpAddr::V4(_) => self.settings.ipv4_prefix,
+            IpAddr::V6(_) => self.settings.ipv6_prefix,
+        };
+        IpNet::new(ip, prefix).ok().map(|net| net.trunc())
+    }
+
+    fn count_request(&self, state: &mut ControllerState, net: IpNet, now: Instant) -> f64 {
+        self.sweep(state, now, false);
+        let requests = state.networks.entry(net).or_default();
+        while requests
+            .front()
+            .is_some_and(|t| now.saturating_duration_since(*t) >= NETWORK_WINDOW)
+        {
+            requests.pop_front();
+        }
+        requests.push_back(now);
+        self.network_bits(requests.len())
+    }
+
+    fn network_bits(&self, requests: usize) -> f64 {
+        let free = self.settings.network_requests_per_minute;
+        if free == 0 {
+            return 0.0;
+        }
+        (requests as f64 / free as f64).log2().max(0.0)
+    }
+
+    /// Forget networks with no request in the window. A full pass at most
+    /// once a second unless `force`, so a flood from many networks does not
+    /// make every request walk the whole map.
+    fn sweep(&self, state: &mut ControllerState, now: Instant, force: bool) {
+        let due = state
+            .swept
+            .is_none_or(|t| now.saturating_duration_since(t) >= Duration::from_secs(1));
+        if !(force || due) {
+            return;
+        }
+        state.networks.retain(|_, requests| {
+            requests.retain(|t| now.saturating_duration_since(*t) < NETWORK_WINDOW);
+            !requests.is_empty()
+        });
+        state.swept = Some(now);
+    }
+
+    fn bits(&self, extra: f64) -> u8 {
+        let extra = extra
+            .round()
+            .clamp(0.0, f64::from(self.settings.max_extra_bits)) as u8;
+        self.settings.base.saturating_add(extra).min(MAX_DIFFICULTY)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    /// A simulated hour-long window of issued invitations, capped like the
+    /// global bucket.
+    struct Bucket {
+        ceiling: usize,
+        issued: VecDeque<Instant>,
+    }
+
+    impl Bucket {
+        fn new(ceiling: usize) -> Self {
+            Self {
+                ceiling,
+                issued: VecDeque::new(),
+            }
+        }
+
+        fn current(&mut self, now: Instant) -> usize {
+            while self
+                .issued
+                .front()
+                .is_some_and(|t| now.duration_since(*t) >= Duration::from_secs(3600))
+            {
+                self.issued.pop_front();
+            }
+            self.issued.len()
+        }
+
+        fn try_acquire(&mut self, now: Instant) -> bool {
+            if self.current(now) >= self.ceiling {
+                return false;
+            }
+            self.issued.push_back(now);
+            true
+        }
+    }
+
+    /// Replays `seconds` of traffic one second at a time. Each second
+    /// `legit` is called for the requests of ordinary visitors, and a
+    /// botnet with `attacker_attempts` hashes a second spread over many
+    /// networks solves as many challenges as the current difficulty allows.
+    /// Returns the difficulties legitimate visitors were given.
+    fn simulate(
+        controller: &DifficultyController,
+        bucket: &mut Bucket,
+        start: Instant,
+        seconds: u64,
+        attacker_attempts: f64,
+        legit_every: u64,
+    ) -> Vec<u8> {
+        let mut seen = Vec::new();
+        let mut attacker_work = 0.0;
+        for second in 0..seconds {
+            let now = start + Duration::from_secs(second);
+            if second % legit_every == 0 {
+                let visitor = IpAddr::from([198, 51, (second / 256 % 256) as u8, second as u8]);
+                let issued = bucket.current(now);
+                seen.push(controller.difficulty_at(Some(visitor), issued, bucket.ceiling, now));
+                bucket.try_acquire(now);
+            }
+            if attacker_attempts > 0.0 {
+                attacker_work += attacker_attempts;
+                loop {
+                    let issued = bucket.current(now);
+                    let bot = IpAddr::from([
+                        10,
+                        (second % 200) as u8,
+                        (attacker_work as u64 % 250) as u8,
+                        1,
+                    ]);
+                    let difficulty =
+                        controller.difficulty_at(Some(bot), issued, bucket.ceiling, now);
+                    let cost = 2f64.powi(i32::from(difficulty));
+                    if attacker_work < cost {
+                        break;
+                    }
+                    attacker_work -= cost;
+                    bucket.try_acquire(now);
+                }
+            }
+        }
+        seen
+    }
+
+    fn settings() -> DifficultySettings {
+        DifficultySettings {
+            base: 12,
+            ..DifficultySettings::default()
+        }
+    }
+
+    #[test]
+    fn quiet_traffic_stays_at_the_base() {
+        let controller = DifficultyController::new(settings());
+        let mut bucket = Bucket::new(200);
+        // One visitor a minute is 60 an hour, under the target of 90.
+        let seen = simulate(&controller, &mut bucket, Instant::now(), 3 * 3600, 0.0, 60);
+        assert!(seen.iter().all(|d| *d == 12), "{seen:?}");
+    }
+
+    #[test]
+    fn distributed_attack_is_held_under_the_ceiling_and_decays_after() {
+        let controller = DifficultyController::new(settings());
+        let mut bucket = Bucket::new(200);
+        let start = Instant::now();
+        // Enough hashing for 20 invitations a minute at the base, about 13
+        // times the target rate.
+        let attempts = 20.0 * 4096.0 / 60.0;
+        let during = simulate(&controller, &mut bucket, start, 3 * 3600, attempts, 120);
+
+        let issued = bucket.current(start + Duration::from_secs(3 * 3600));
+        assert!(issued < 200, "the ceiling was reached: {issued}");
+        assert!(
+            issued <= 2 * 90,
+            "issuance not held near the target: {issued}"
+        );
+        let peak = *during.iter().max().unwrap();
+        assert!((14..=12 + DEFAULT_MAX_EXTRA_BITS).contains(&peak), "{peak}");
+        // Difficulty moves a bit at a time, not in jumps.
+        assert!(
+            during.windows(2).all(|w| w[0].abs_diff(w[1]) <= 1),
+            "{during:?}"
+        );
+
+        // After the attack, the hour-long window empties and the smoothed
+        // terms decay back to the base.
+        let after = simulate(
+            &controller,
+            &mut bucket,
+            start + Duration::from_secs(3 * 3600),
+            3 * 3600,
+            0.0,
+            120,
+        );
+        assert_eq!(*after.last().unwrap(), 12);
+    }
+
+    #[test]
+    fn a_flooding_network_pays_for_itself() {
+        let controller = DifficultyController::new(settings());
+        let start = Instant::now();
+        let mut flood = 0;
+        for i in 0..640u32 {
+            let now = start + Duration::from_millis(u64::from(i) * 90);
+            let host = ip(&format!("203.0.113.{}", i % 250));
+            flood = controller.difficulty_at(Some(host), 0, 200, now);
+        }
+        // 640 requests in a minute against 10 free is 6 doublings.
+        assert_eq!(flood, 18);
+        let later = start + Duration::from_secs(58);
+        assert_eq!(
+            controller.difficulty_at(Some(ip("192.0.2.9")), 0, 200, later),
+            12
+        );
+        assert_eq!(controller.difficulty_at(None, 0, 200, later), 12);
+        assert_eq!(controller.status_at(0, 200, later).busy_networks, 1);
+
+        // A minute after it stops, the network is forgotten.
+        let quiet = later + NETWORK_WINDOW;
+        assert_eq!(controller.status_at(0, 200, quiet).busy_networks, 0);
+        assert_eq!(
+            controller.difficulty_at(Some(ip("203.0.113.1")), 0, 200, quiet),
+            12
+        );
+    }
+
+    #[test]
+    fn network_term_can_be_turned_off() {
+        let controller = DifficultyController::new(DifficultySettings {
+            network_requests_per_minute: 0,
+            ..settings()
+        });
+        let now = Instant::now();
+        for _ in 0..100 {
+            assert_eq!(
+                controller.difficulty_at(Some(ip("203.0.113.1")), 0, 200, now),
+                12
+            );
+        }
+    }
+}
@@ -1,18 +1,25 @@
 //! IP-based rate limiting for invite generation
 //!
-//! Stores rate limit data in a JSON file, allowing persistence across restarts.
+//! Stores rate limit data in a [`RateLimitStore`], by default a JSON file,
+//! allowing persistence across restarts.
+//!
+//! A single address is cheap to come by over IPv6, where a subscriber is
+//! routinely handed a whole /64 or /56. [`PrefixLimit`] adds a second quota
+//! on the network an address belongs to, recorded in the same file under the
+//! network's CIDR (`2001:db8:1:2::/64`). A request must fit both.
 
-use chrono::{DateTime, Duration, Utc};
+use chrono::{Duration, Utc};
+use ipnet::IpNet;
 use serde::{Deserialize, Serialize};
-use sha2::{Digest, Sha256};
 use std::collections::{HashMap, VecDeque};
-use std::fs;
 use std::net::IpAddr;
 use std::path::PathBuf;
-use std::sync::Mutex;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration as StdDuration, Instant};
 use thiserror::Error;
 
+use crate::rate_limit_store::{JsonStore, RateLimitStore};
+
 /// Maximum number of invites allowed per IP within the time window.
 ///
 /// Kept deliberately low as an anti-spam measure for the freenet.org/quickstart
@@ -34,16 +41,46 @@ pub const DEFAULT_GLOBAL_INVITES_PER_HOUR: usize = 200;
 
 pub const GLOBAL_WINDOW_MINUTES: i64 = 60;
 
-/// SHA256 hashes of IPs exempt from rate limiting (for testing)
-const EXEMPT_IP_HASHES: &[&str] =
-    &["0cf75236cce089f9c592bb2b50925c48cbbb4d0f83094b2cd091dda4b53e1a4c"];
+/// Prefix length IPv4 addresses are grouped by for [`PrefixLimit`].
+pub const DEFAULT_IPV4_PREFIX: u8 = 24;
+
+/// Prefix length IPv6 addresses are grouped by. A /64 is the smallest network
+/// a subscriber is normally given, so anything longer is no bound at all.
+pub const DEFAULT_IPV6_PREFIX: u8 = 64;
+
+/// Invites per network per window. Several times the per-address limit, so a
+/// household, office or carrier-grade NAT sharing a /24 is not starved, while
+/// an IPv6 /64 no longer amounts to unlimited addresses.
+pub const DEFAULT_INVITES_PER_PREFIX: usize = 16;
+
+/// A quota shared by every address in the same network.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PrefixLimit {
+    pub ipv4_prefix: u8,
+    pub ipv6_prefix: u8,
+    pub max_per_window: usize,
+}
+
+impl Default for PrefixLimit {
+    fn default() -> Self {
+        Self {
+            ipv4_prefix: DEFAULT_IPV4_PREFIX,
+            ipv6_prefix: DEFAULT_IPV6_PREFIX,
+            max_per_window: DEFAULT_INVITES_PER_PREFIX,
+        }
+    }
+}
 
-/// Check if an IP is exempt from rate limiting
-fn is_exempt(ip: &IpAddr) -> bool {
-    let mut hasher = Sha256::new();
-    hasher.update(ip.to_string().as_bytes());
-    let hash = format!("{:x}", hasher.finalize());
-    EXEMPT_IP_HASHES.contains(&hash.as_str())
+impl PrefixLimit {
+    /// The network `ip` is counted against. Fails only for a prefix longer
+    /// than the address, which the config check rejects.
+    pub fn network(&self, ip: IpAddr) -> Option<IpNet> {
+        let prefix = match ip {
+            IpAddr::V4(_) => self.ipv4_prefix,
+            IpAddr::V6(_) => self.ipv6_prefix,
+        };
+        IpNet::new(ip, prefix).ok().map(|net| net.trunc())
+    }
 }
 
 #[derive(Error, Debug)]
@@ -54,21 +91,24 @@ pub enum RateLimitError {
     Json(#[from] serde_json::Error),
     #[error("Lock error")]
     Lock,
+    #[error("database error: {0}")]
+    Database(Box<redb::Error>),
 }
 
-/// Stored rate limit data
+/// Stored rate limit data, as [`JsonStore`] keeps it
 #[derive(Serialize, Deserialize, Default, Debug)]
 pub struct RateLimitData {
-    /// Map of IP address string to list of invite timestamps (RFC 3339)
+    /// Map of IP address string, network CIDR (or other limiter key) to list
+    /// of invite timestamps (RFC 3339)
     pub invites: HashMap<String, Vec<String>>,
 }
 
-/// Rate limiter with file-based persistence
+/// Rate limiter with persistent state
 pub struct RateLimiter {
-    data_path: PathBuf,
+    store: Arc<dyn RateLimitStore>,
     window_hours: i64,
-    /// Mutex for thread-safe access to the file
-    lock: Mutex<()>,
+    max_per_window: usize,
+    prefix_limit: Option<PrefixLimit>,
 }
 
 impl RateLimiter {
@@ -78,90 +118,148 @@ impl RateLimiter {
     /// * `data_path` - Path to the JSON file for persistence
     /// * `window_hours` - Time window in hours (e.g., 24 for once per day)
     pub fn new(data_path: PathBuf, window_hours: i64) -> Self {
+        Self::with_limit(data_path, window_hours, MAX_INVITES_PER_WINDOW)
+    }
+
+    /// Like [`Self::new`] with a per-IP limit other than
+    /// [`MAX_INVITES_PER_WINDOW`], for rooms configured with their own.
+    pub fn with_limit(data_path: PathBuf, window_hours: i64, max_per_window: usize) -> Self {
+        Self::with_store(
+            Arc::new(JsonStore::new(data_path)),
+            window_hours,
+            max_per_window,
+        )
+    }
+
+    /// A limiter over any [`RateLimitStore`].
+    pub fn with_store(
+        store: Arc<dyn RateLimitStore>,
+        window_hours: i64,
+        max_per_window: usize,
+    ) -> Self {
         Self {
-            data_path,
+            store,
             window_hours,
-            lock: Mutex::new(()),
+            max_per_window,
+            prefix_limit: None,
         }
     }
 
+    /// Also limit the network each address belongs to.
+    pub fn with_prefix_limit(mut self, prefix_limit: PrefixLimit) -> Self {
+        self.prefix_limit = Some(prefix_limit);
+        self
+    }
+
+    pub fn max_per_window(&self) -> usize {
+        self.max_per_window
+    }
+
+    /// The keys an IP request is counted against, each with its quota. `max`
+    /// lowers the address's own quota, never raises it.
+    fn ip_keys(&self, ip: IpAddr, max: Option<usize>) -> Vec<(String, usize)> {
+        let own = max.map_or(self.max_per_window, |max| max.min(self.max_per_window));
+        let mut keys = vec![(ip.to_string(), own)];
+        if let Some(limit) = &self.prefix_limit {
+            if let Some(network) = limit.network(ip) {
+                keys.push((network.to_string(), limit.max_per_window));
+            }
+        }
+        keys
+    }
+
     /// Check if an IP is rate limited, and record the access if allowed
     ///
+    /// With a [`PrefixLimit`] the access must also fit its network's quota,
+    /// and is recorded against both or neither.
+    ///
     /// Returns Ok(true) if the request is allowed, Ok(false) if rate limited
     pub fn check_and_record(&self, ip: IpAddr) -> Result<bool, RateLimitError> {
-        // Check exemption first (before acquiring lock)
-        if is_exempt(&ip) {
-            return Ok(true);
-        }
-
-        let _guard = self.lock.lock().map_err(|_| RateLimitError::Lock)?;
-
-        let mut data = self.load()?;
-        let ip_str = ip.to_string();
-        let now = Utc::now();
-        let window = Duration::hours(self.window_hours);
+        self.check_and_record_keys(&self.ip_keys(ip, None))
+    }
 
-        // Clean up old entries for all IPs
-        for timestamps in data.invites.values_mut() {
-            timestamps.retain(|ts| {
-                if let Ok(t) = DateTime::parse_from_rfc3339(ts) {
-                    let t_utc: DateTime<Utc> = t.into();
-                    now - t_utc < window
-                } else {
-                    false
-                }
-            });
-        }
-        // Remove IPs with no remaining timestamps
-        data.invites.retain(|_, v| !v.is_empty());
+    /// [`Self::check_and_record`] with the address allowed at most `max`,
+    /// for networks the operator trusts less than most.
+    pub fn check_and_record_within(
+        &self,
+        ip: IpAddr,
+        max: Option<usize>,
+    ) -> Result<bool, RateLimitError> {
+        self.check_and_record_keys(&self.ip_keys(ip, max))
+    }
 
-        // Check if IP has reached the limit
-        let timestamps = data.invites.entry(ip_str).or_default();
-        if timestamps.len() >= MAX_INVITES_PER_WINDOW {
-            return Ok(false); // Rate limited
-        }
+    /// Record an access by an address exempt from the limit, so it still
+    /// counts towards the history the global ceiling is seeded from. Only the
+    /// address itself is recorded; its network's quota is left alone.
+    pub fn record_exempt(&self, ip: IpAddr) -> Result<(), RateLimitError> {
+        self.check_and_record_keys(&[(ip.to_string(), usize::MAX)])
+            .map(drop)
+    }
 
-        // Record new invite
-        timestamps.push(now.to_rfc3339());
-        self.save(&data)?;
+    /// [`Self::check_and_record`] for limiters keyed by something other than
+    /// an IP, such as a ghost key fingerprint.
+    pub fn check_and_record_key(&self, key: &str) -> Result<bool, RateLimitError> {
+        self.check_and_record_keys(&[(key.to_string(), self.max_per_window)])
+    }
 
-        Ok(true)
+    fn check_and_record_keys(&self, keys: &[(String, usize)]) -> Result<bool, RateLimitError> {
+        let now = Utc::now();
+        self.store
+            .record_if_under(keys, now - Duration::hours(self.window_hours), now)
     }
 
     /// Get the remaining time until an IP can request again
     ///
-    /// Returns None if the IP is not rate limited, Some(seconds) otherwise
+    /// Returns None if the IP is not rate limited, Some(seconds) otherwise.
+    /// When both the address and its network are at their limit, the later
+    /// of the two is reported, since both have to clear.
     pub fn get_retry_after(&self, ip: IpAddr) -> Result<Option<i64>, RateLimitError> {
-        let _guard = self.lock.lock().map_err(|_| RateLimitError::Lock)?;
+        self.get_retry_after_keys(&self.ip_keys(ip, None))
+    }
+
+    /// [`Self::get_retry_after`] under the quota of
+    /// [`Self::check_and_record_within`].
+    pub fn get_retry_after_within(
+        &self,
+        ip: IpAddr,
+        max: Option<usize>,
+    ) -> Result<Option<i64>, RateLimitError> {
+        self.get_retry_after_keys(&self.ip_keys(ip, max))
+    }
+
+    /// [`Self::get_retry_after`] for limiters keyed by something other than
+    /// an IP.
+    pub fn get_retry_after_key(&self, key: &str) -> Result<Option<i64>, RateLimitError> {
+        self.get_retry_after_keys(&[(key.to_string(), self.max_per_window)])
+    }
 
-        let data = self.load()?;
-        let ip_str = ip.to_string();
+    fn get_retry_after_keys(
+        &self,
+        keys: &[(String, usize)],
+    ) -> Result<Option<i64>, RateLimitError> {
         let now = Utc::now();
         let window = Duration::hours(self.window_hours);
 
-        if let Some(timestamps) = data.invites.get(&ip_str) {
-            // Filter to only valid timestamps within window
-            let valid_timestamps: Vec<_> = timestamps
-                .iter()
-                .filter_map(|ts| DateTime::parse_from_rfc3339(ts).ok())
-                .map(|t| -> DateTime<Utc> { t.into() })
-                .filter(|t| now - *t < window)
-                .collect();
-
-            // If at limit, return time until oldest expires
-            if valid_timestamps.len() >= MAX_INVITES_PER_WINDOW {
-                if let Some(oldest) = valid_timestamps.iter().min() {
-                    let expires_at = *oldest + window;
-                    let remaining = expires_at - now;
-                    return Ok(Some(remaining.num_seconds()));
+        let mut retry_after = None;
+        for (key, max) in keys {
+            let recent = self.store.hits_since(key, now - window)?;
+            // If at limit, the wait is until enough of them expire to drop
+            // below it: the oldest for an exact fit, later ones past it.
+            if recent.len() >= *max {
+                let freeing = recent.len().checked_sub((*max).max(1));
+                if let Some(freeing) = freeing.and_then(|i| recent.get(i)) {
+                    let remaining = (*freeing + window - now).num_seconds();
+                    retry_after = retry_after.max(Some(remaining));
                 }
             }
         }
 
-        Ok(None)
+        Ok(retry_after)
     }
 
     /// IPs and ages of recorded invitations still inside `window_minutes`.
+    /// Network entries are skipped; each invitation is already counted under
+    /// its address.
     ///
     /// Used once at startup to seed the in-memory global ceiling from the
     /// persistent per-IP store. A deploy therefore cannot reset the emergency
@@ -170,45 +268,26 @@ impl RateLimiter {
         &self,
         window_minutes: i64,
     ) -> Result<Vec<(IpAddr, StdDuration)>, RateLimitError> {
-        let _guard = self.lock.lock().map_err(|_| RateLimitError::Lock)?;
-        let data = self.load()?;
         let now = Utc::now();
         let window = Duration::minutes(window_minutes);
-        Ok(data
-            .invites
-            .iter()
-            .filter_map(|(ip, timestamps)| ip.parse::<IpAddr>().ok().map(|ip| (ip, timestamps)))
-            .flat_map(|(ip, timestamps)| {
-                timestamps.iter().filter_map(move |ts| {
-                    let t: DateTime<Utc> = DateTime::parse_from_rfc3339(ts).ok()?.into();
-                    let age = now.signed_duration_since(t);
-                    if age >= Duration::zero() && age < window {
-                        age.to_std().ok().map(|age| (ip, age))
-                    } else {
-                        None
-                    }
-                })
+        Ok(self
+            .store
+            .all_since(now - window)?
+            .into_iter()
+            .filter_map(|(key, t)| {
+                let ip = key.parse::<IpAddr>().ok()?;
+                let age = now.signed_duration_since(t);
+                (age >= Duration::zero())
+                    .then(|| age.to_std().ok())
+                    .flatten()
+                    .map(|age| (ip, age))
             })
             .collect())
     }
 
-    fn load(&self) -> Result<RateLimitData, RateLimitError> {
-        if self.data_path.exists() {
-            let content = fs::read_to_string(&self.data_path)?;
-            Ok(serde_json::from_str(&content)?)
-        } else {
-            Ok(RateLimitData::default())
-        }
-    }
-
-    fn save(&self, data: &RateLimitData) -> Result<(), RateLimitError> {
-        // Ensure parent directory exists
-        if let Some(parent) = self.data_path.parent() {
-            fs::create_dir_all(parent)?;
-        }
-        let content = serde_json::to_string_pretty(data)?;
-        fs::write(&self.data_path, content)?;
-        Ok(())
+    /// Sync the store; see [`RateLimitStore::flush`].
+    pub fn flush(&self) -> Result<(), RateLimitError> {
+        self.store.flush()
     }
 }
 
@@ -231,7 +310,6 @@ pub struct AggregateBucket {
 }
 
 impl AggregateBucket {
-    #[cfg(test)]
     pub fn new(limit: usize, window_minutes: i64) -> Self {
         Self {
             limit,
@@ -378,6 +456,7 @@ impl AggregateBucket {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::rate_limit_store::MemoryStore;
     use std::net::Ipv4Addr;
     use tempfile::tempdir;
 
@@ -628,4 +707,85 @@ mod tests {
         assert!(retry.is_some());
         assert!(retry.unwrap() > 0);
     }
+
+    #[test]
+    fn ipv6_addresses_share_their_network_quota() {
+        let dir = tempdir().unwrap();
+        let limiter = RateLimiter::with_limit(dir.path().join("rate_limits.json"), 24, 2)
+            .with_prefix_limit(PrefixLimit {
+                max_per_window: 3,
+                ..PrefixLimit::default()
+            });
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+
+        // Rotating through one /64 gains nothing past the network quota.
+        assert!(limiter.check_and_record(ip("2001:db8:1:2::1")).unwrap());
+        assert!(limiter.check_and_record(ip("2001:db8:1:2::2")).unwrap());
+        assert!(limiter
+            .check_and_record(ip("2001:db8:1:2:ffff::3"))
+            .unwrap());
+        assert!(!limiter.check_and_record(ip("2001:db8:1:2::4")).unwrap());
+        assert!(limiter
+            .get_retry_after(ip("2001:db8:1:2::5"))
+            .unwrap()
+            .is_some());
+        // The neighbouring /64 is untouched.
+        assert!(limiter.check_and_record(ip("2001:db8:1:3::1")).unwrap());
+        assert!(limiter
+            .get_retry_after(ip("2001:db8:1:3::1"))
+            .unwrap()
+            .is_none());
+
+        // The per-address limit still bites first for a single address.
+        assert!(limiter.check_and_record(ip("192.0.2.1")).unwrap());
+        assert!(limiter.check_and_record(ip("192.0.2.1")).unwrap());
+        assert!(!limiter.check_and_record(ip("192.0.2.1")).unwrap());
+        assert!(limiter.get_retry_after(ip("192.0.2.1")).unwrap().is_some());
+        // A refused request is recorded against neither key: the /24 has
+        // seen two invitations, so a neighbour still gets the third.
+        assert!(limiter.get_retry_after(ip("192.0.2.2")).unwrap().is_none());
+        assert!(limiter.check_and_record(ip("192.0.2.2")).unwrap());
+        assert!(!limiter.check_and_record(ip("192.0.2.3")).unwrap());
+    }
+
+    #[test]
+    fn network_entries_do_not_count_twice_when_seeding() {
+        let store = Arc::new(MemoryStore::default());
+        let limiter = RateLimiter::with_store(store.clone(), 24, MAX_INVITES_PER_WINDOW)
+            .with_prefix_limit(PrefixLimit::default());
+        limiter
+            .check_and_record(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)))
+            .unwrap();
+        let since = Utc::now() - Duration::hours(1);
+        assert_eq!(store.hits_since("192.0.2.0/24", since).unwrap().len(), 1);
+        assert_eq!(limiter.recent_events(60).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_rate_limiter_with_custom_limit() {
+        let dir = tempdir().unwrap();
+        let limiter = RateLimiter::with_limit(dir.path().join("rate_limits.json"), 24, 1);
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
+
+        assert!(limiter.check_and_record(ip).unwrap());
+        assert!(!limiter.check_and_record(ip).unwrap());
+        assert!(limiter.get_retry_after(ip).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_lower_quota_only_lowers() {
+        let dir = tempdir().unwrap();
+        let limiter = RateLimiter::with_limit(dir.path().join("rate_limits.json"), 24, 2);
+        let ip = IpAddr::V4(Ipv4Addr::new(192, 168, 1, 1));
+
+        assert!(limiter.check_and_record_within(ip, Some(1)).unwrap());
+        assert!(!limiter.check_and_record_within(ip, Some(1)).unwrap());
+        assert!(limiter
+            .get_retry_after_within(ip, Some(1))
+            .unwrap()
+            .is_some());
+        assert!(limiter.get_retry_after(ip).unwrap().is_none());
+        assert!(limiter.check_and_record_within(ip, Some(10)).unwrap());
+        assert!(!limiter.check_and_record_within(ip, Some(10)).unwrap());
+    }
 }
@@ -0,0 +1,704 @@
+//! Where [`RateLimiter`](crate::rate_limit::RateLimiter) keeps its hits.
+//!
+//! The limiter only ever asks three things: record a hit against some keys if
+//! none of them is full, list one key's recent hits, and list every recent hit
+//! when seeding the ceilings at startup. [`RateLimitStore`] is that contract,
+//! plus taking a hit back, which the invite proof of work uses to remember
+//! consumed challenges.
+//!
+//! - [`JsonStore`] is the original `invite_rate_limits.json` format. It reads
+//!   and rewrites the whole file on every admitted request, so it suits low
+//!   traffic and existing deployments; writes now go through a temporary file
+//!   and a rename, so a crash leaves the old file or the new one. Writers
+//!   also take an advisory lock on a `.lock` file beside it, so several
+//!   processes can share one file.
+//! - [`RedbStore`] keeps hits in an embedded transactional database. Lookups
+//!   and expiry are range scans over two indexes, so the cost of a request does
+//!   not grow with the number of addresses seen. A database is opened by one
+//!   process at a time.
+//! - [`MemoryStore`] keeps nothing across a restart. It is for tests and for
+//!   throwaway instances.
+//!
+//! [`migrate_json`] imports a JSON file into any other store.
+
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+use chrono::{DateTime, Utc};
+use redb::{Database, ReadableTable, TableDefinition};
+
+use crate::rate_limit::{RateLimitData, RateLimitError};
+
+/// Hits keyed by (limiter key, time in microseconds).
+const HITS: TableDefinition<(&str, i64), ()> = TableDefinition::new("hits");
+/// The same hits ordered by time, so expiry never scans live entries.
+const EXPIRY: TableDefinition<(i64, &str), ()> = TableDefinition::new("expiry");
+
+/// Persistence for a rate limiter's hits.
+///
+/// Every method is atomic with respect to the others. "Recent" always means
+/// strictly after `since`.
+pub trait RateLimitStore: Send + Sync {
+    /// Record a hit at `now` against every key, unless one of them already
+    /// has its maximum of recent hits. All or nothing. Hits at or before
+    /// `since` may be discarded.
+    fn record_if_under(
+        &self,
+        keys: &[(String, usize)],
+        since: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<bool, RateLimitError>;
+
+    /// Recent hits against `key`, oldest first.
+    fn hits_since(
+        &self,
+        key: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<DateTime<Utc>>, RateLimitError>;
+
+    /// Every recent hit against every key.
+    fn all_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<(String, DateTime<Utc>)>, RateLimitError>;
+
+    /// Add hits unconditionally, for [`migrate_json`].
+    fn import(&self, hits: &[(String, DateTime<Utc>)]) -> Result<(), RateLimitError>;
+
+    /// Drop every hit against `key`, to take back a hit whose request was
+    /// refused further on.
+    fn remove(&self, key: &str) -> Result<(), RateLimitError>;
+
+    /// Make every hit recorded so far survive a power cut. Called at
+    /// shutdown, once the last request has finished.
+    fn flush(&self) -> Result<(), RateLimitError> {
+        Ok(())
+    }
+}
+
+/// Which [`RateLimitStore`] the server's limiters use.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum StoreBackend {
+    #[default]
+    Json,
+    Redb,
+    Memory,
+}
+
+impl StoreBackend {
+    /// Whether the store lives in the configured files.
+    pub fn uses_files(self) -> bool {
+        self != Self::Memory
+    }
+}
+
+impl FromStr for StoreBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(Self::Json),
+            "redb" => Ok(Self::Redb),
+            "memory" => Ok(Self::Memory),
+            other => Err(format!(
+                "unknown rate limit backend {other:?}; use \"json\", \"redb\" or \"memory\""
+            )),
+        }
+    }
+}
+
+/// Open the store for one limiter.
+pub fn open_store(
+    backend: StoreBackend,
+    path: &Path,
+) -> Result<Arc<dyn RateLimitStore>, RateLimitError> {
+    Ok(match backend {
+        StoreBackend::Json => Arc::new(JsonStore::new(path.to_path_buf())),
+        StoreBackend::Redb => Arc::new(RedbStore::open(path)?),
+        StoreBackend::Memory => Arc::new(MemoryStore::default()),
+    })
+}
+
+/// Copy every hit in the JSON file at `from` into `to`. Returns how many.
+///
+/// Expired hits are copied too; the destination drops them as it would its
+/// own. Importing the same file twice counts its hits twice.
+pub fn migrate_json(from: &Path, to: &dyn RateLimitStore) -> Result<usize, RateLimitError> {
+    let content = fs::read_to_string(from)?;
+    let data: RateLimitData = serde_json::from_str(&content)?;
+    let hits: Vec<_> = data
+        .invites
+        .into_iter()
+        .flat_map(|(key, timestamps)| {
+            timestamps
+                .into_iter()
+                .filter_map(|ts| parse_rfc3339(&ts))
+                .map(move |t| (key.clone(), t))
+        })
+        .collect();
+    to.import(&hits)?;
+    Ok(hits.len())
+}
+
+fn parse_rfc3339(ts: &str) -> Option<DateTime<Utc>> {
+    DateTime::parse_from_rfc3339(ts).ok().map(Into::into)
+}
+
+/// The original single-file format.
+pub struct JsonStore {
+    data_path: PathBuf,
+    /// Serializes the read-modify-write of the file.
+    lock: Mutex<()>,
+}
+
+impl JsonStore {
+    pub fn new(data_path: PathBuf) -> Self {
+        Self {
+            data_path,
+            lock: Mutex::new(()),
+        }
+    }
+
+    /// Take the in-process lock and then an advisory lock on `<file>.lock`,
+    /// held until the returned file is dropped, so that another process
+    /// sharing the file cannot interleave its read-modify-write with ours.
+    fn exclusive(&self) -> Result<(MutexGuard<'_, ()>, File), RateLimitError> {
+        let guard = self.lock.lock().map_err(|_| RateLimitError::Lock)?;
+        let mut path = self.data_path.clone().into_os_string();
+        path.push(".lock");
+        let path = PathBuf::from(path);
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&path)?;
+        file.lock()?;
+        Ok((guard, file))
+    }
+
+    fn load(&self) -> Result<RateLimitData, RateLimitError> {
+        if self.data_path.exists() {
+            let content = fs::read_to_string(&self.data_path)?;
+            Ok(serde_json::from_str(&content)?)
+        } else {
+            Ok(RateLimitData::default())
+        }
+    }
+
+    /// Write beside the file and rename over it, so a crash mid-write cannot
+    /// leave a truncated file that fails every later load.
+    fn save(&self, data: &RateLimitData) -> Result<(), RateLimitError> {
+        fs::create_dir_all(self.dir())?;
+        let mut temp = self.data_path.clone().into_os_string();
+        temp.push(".tmp");
+        let temp = PathBuf::from(temp);
+        let mut file = File::create(&temp)?;
+        file.write_all(&serde_json::to_vec(data)?)?;
+        file.sync_all()?;
+        fs::rename(&temp, &self.data_path)?;
+        Ok(())
+    }
+
+    fn dir(&self) -> &Path {
+        match self.data_path.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        }
+    }
+
+    fn recent(timestamps: &[String], since: DateTime<Utc>) -> Vec<DateTime<Utc>> {
+        let mut recent: Vec<_> = timestamps
+            .iter()
+            .filter_map(|ts| parse_rfc3339(ts))
+            .filter(|t| *t > since)
+            .collect();
+        recent.sort_unstable();
+        recent
+    }
+}
+
+impl RateLimitStore for JsonStore {
+    fn record_if_under(
+        &self,
+        keys: &[(String, usize)],
+        since: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<bool, RateLimitError> {
+        let _guard = self.exclusive()?;
+        let mut data = self.load()?;
+
+        // Clean up old entries for all keys
+        for timestamps in data.invites.values_mut() {
+            timestamps.retain(|ts| parse_rfc3339(ts).is_some_and(|t| t > since));
+        }
+        data.invites.retain(|_, v| !v.is_empty());
+
+        let full = keys.iter().any(|(key, max)| {
+            data.invites
+                .get(key)
+                .is_some_and(|timestamps| timestamps.len() >= *max)
+        });
+        if full {
+            return Ok(false);
+        }
+        for (key, _) in keys {
+            data.invites
+                .entry(key.clone())
+                .or_default()
+                .push(now.to_rfc3339());
+        }
+        self.save(&data)?;
+        Ok(true)
+    }
+
+    fn hits_since(
+        &self,
+        key: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<DateTime<Utc>>, RateLimitError> {
+        let _guard = self.lock.lock().map_err(|_| RateLimitError::Lock)?;
+        let data = self.load()?;
+        Ok(data
+            .invites
+            .get(key)
+            .map(|timestamps| Self::recent(timestamps, since))
+            .unwrap_or_default())
+    }
+
+    fn all_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<(String, DateTime<Utc>)>, RateLimitError> {
+        let _guard = self.lock.lock().map_err(|_| RateLimitError::Lock)?;
+        let data = self.load()?;
+        Ok(data
+            .invites
+            .iter()
+            .flat_map(|(key, timestamps)| {
+                Self::recent(timestamps, since)
+                    .into_iter()
+                    .map(move |t| (key.clone(), t))
+            })
+            .collect())
+    }
+
+    fn import(&self, hits: &[(String, DateTime<Utc>)]) -> Result<(), RateLimitError> {
+        let _guard = self.exclusive()?;
+        let mut data = self.load()?;
+        for (key, t) in hits {
+            data.invites
+                .entry(key.clone())
+                .or_default()
+                .push(t.to_rfc3339());
+        }
+        self.save(&data)
+    }
+
+    fn remove(&self, key: &str) -> Result<(), RateLimitError> {
+        let _guard = self.exclusive()?;
+        let mut data = self.load()?;
+        if data.invites.remove(key).is_some() {
+            self.save(&data)?;
+        }
+        Ok(())
+    }
+
+    /// Each save syncs the file before renaming it into place; syncing the
+    /// directory makes the last rename durable too.
+    fn flush(&self) -> Result<(), RateLimitError> {
+        let _guard = self.exclusive()?;
+        File::open(self.dir())?.sync_all()?;
+        Ok(())
+    }
+}
+
+/// Hits held in memory only.
+#[derive(Default)]
+pub struct MemoryStore {
+    hits: Mutex<HashMap<String, Vec<DateTime<Utc>>>>,
+}
+
+impl RateLimitStore for MemoryStore {
+    fn record_if_under(
+        &self,
+        keys: &[(String, usize)],
+        since: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<bool, RateLimitError> {
+        let mut hits = self.hits.lock().map_err(|_| RateLimitError::Lock)?;
+        for timestamps in hits.values_mut() {
+            timestamps.retain(|t| *t > since);
+        }
+        hits.retain(|_, v| !v.is_empty());
+        let full = keys
+            .iter()
+            .any(|(key, max)| hits.get(key).is_some_and(|t| t.len() >= *max));
+        if full {
+            return Ok(false);
+        }
+        for (key, _) in keys {
+            hits.entry(key.clone()).or_default().push(now);
+        }
+        Ok(true)
+    }
+
+    fn hits_since(
+        &self,
+        key: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<DateTime<Utc>>, RateLimitError> {
+        let hits = self.hits.lock().map_err(|_| RateLimitError::Lock)?;
+        let mut recent: Vec<_> = hits
+            .get(key)
+            .into_iter()
+            .flatten()
+            .copied()
+            .filter(|t| *t > since)
+            .collect();
+        recent.sort_unstable();
+        Ok(recent)
+    }
+
+    fn all_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<(String, DateTime<Utc>)>, RateLimitError> {
+        let hits = self.hits.lock().map_err(|_| RateLimitError::Lock)?;
+        Ok(hits
+            .iter()
+            .flat_map(|(key, timestamps)| {
+                timestamps
+                    .iter()
+                    .filter(|t| **t > since)
+                    .map(move |t| (key.clone(), *t))
+            })
+            .collect())
+    }
+
+    fn import(&self, imported: &[(String, DateTime<Utc>)]) -> Result<(), RateLimitError> {
+        let mut hits = self.hits.lock().map_err(|_| RateLimitError::Lock)?;
+        for (key, t) in imported {
+            hits.entry(key.clone()).or_default().push(*t);
+        }
+        Ok(())
+    }
+
+    fn remove(&self, key: &str) -> Result<(), RateLimitError> {
+        let mut hits = self.hits.lock().map_err(|_| RateLimitError::Lock)?;
+        hits.remove(key);
+        Ok(())
+    }
+}
+
+/// Hits in an embedded redb database.
+///
+/// Each hit is two rows: one in [`HITS`] for counting a key's recent hits
+/// with a range scan, and one in [`EXPIRY`] so that pruning walks only the
+/// rows that have expired. Every write commits durably before it returns.
+pub struct RedbStore {
+    db: Database,
+}
+
+fn db_error(e: impl Into<redb::Error>) -> RateLimitError {
+    RateLimitError::Database(Box::new(e.into()))
+}
+
+fn micros(t: DateTime<Utc>) -> i64 {
+    t.timestamp_micros()
+}
+
+fn from_micros(us: i64) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp_micros(us)
+}
+
+impl RedbStore {
+    pub fn open(path: &Path) -> Result<Self, RateLimitError> {
+        if let Some(parent) = path.parent() {
+            if !parent.as_os_str().is_empty() {
+                fs::create_dir_all(parent)?;
+            }
+        }
+        let db = Database::create(path).map_err(db_error)?;
+        // Create both tables up front so readers never see them missing.
+        let txn = db.begin_write().map_err(db_error)?;
+        txn.open_table(HITS).map_err(db_error)?;
+        txn.open_table(EXPIRY).map_err(db_error)?;
+        txn.commit().map_err(db_error)?;
+        Ok(Self { db })
+    }
+
+    /// Insert one hit, moving it forward a microsecond at a time if the key
+    /// already has one at that instant.
+    fn insert(
+        hits: &mut redb::Table<(&str, i64), ()>,
+        expiry: &mut redb::Table<(i64, &str), ()>,
+        key: &str,
+        t: DateTime<Utc>,
+    ) -> Result<(), RateLimitError> {
+        let mut at = micros(t);
+        while hits.get((key, at)).map_err(db_error)?.is_some() {
+            at += 1;
+        }
+        hits.insert((key, at), ()).map_err(db_error)?;
+        expiry.insert((at, key), ()).map_err(db_error)?;
+        Ok(())
+    }
+
+    fn count_since(
+        hits: &impl ReadableTable<(&'static str, i64), ()>,
+        key: &str,
+        since: DateTime<Utc>,
+    ) -> Result<usize, RateLimitError> {
+        let mut count = 0;
+        for row in hits
+            .range((key, micros(since) + 1)..=(key, i64::MAX))
+            .map_err(db_error)?
+        {
+            row.map_err(db_error)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+impl RateLimitStore for RedbStore {
+    fn record_if_under(
+        &self,
+        keys: &[(String, usize)],
+        since: DateTime<Utc>,
+        now: DateTime<Utc>,
+    ) -> Result<bool, RateLimitError> {
+        let txn = self.db.begin_write().map_err(db_error)?;
+        let admitted = {
+            let mut hits = txn.open_table(HITS).map_err(db_error)?;
+            let mut expiry = txn.open_table(EXPIRY).map_err(db_error)?;
+
+            let mut expired = Vec::new();
+            for row in expiry.range(..(micros(since) + 1, "")).map_err(db_error)? {
+                let (entry, _) = row.map_err(db_error)?;
+                let (at, key) = entry.value();
+                expired.push((at, key.to_string()));
+            }
+            for (at, key) in &expired {
+                expiry.remove((*at, key.as_str())).map_err(db_error)?;
+                hits.remove((key.as_str(), *at)).map_err(db_error)?;
+            }
+
+            let mut full = false;
+            for (key, max) in keys {
+                if Self::count_since(&hits, key, since)? >= *max {
+                    full = true;
+                    break;
+                }
+            }
+            if !full {
+                for (key, _) in keys {
+                    Self::insert(&mut hits, &mut expiry, key, now)?;
+                }
+            }
+            !full
+        };
+        txn.commit().map_err(db_error)?;
+        Ok(admitted)
+    }
+
+    fn hits_since(
+        &self,
+        key: &str,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<DateTime<Utc>>, RateLimitError> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let hits = txn.open_table(HITS).map_err(db_error)?;
+        let mut recent = Vec::new();
+        for row in hits
+            .range((key, micros(since) + 1)..=(key, i64::MAX))
+            .map_err(db_error)?
+        {
+            let (entry, _) = row.map_err(db_error)?;
+            recent.extend(from_micros(entry.value().1));
+        }
+        Ok(recent)
+    }
+
+    fn all_since(
+        &self,
+        since: DateTime<Utc>,
+    ) -> Result<Vec<(String, DateTime<Utc>)>, RateLimitError> {
+        let txn = self.db.begin_read().map_err(db_error)?;
+        let expiry = txn.open_table(EXPIRY).map_err(db_error)?;
+        let mut recent = Vec::new();
+        for row in expiry.range((micros(since) + 1, "")..).map_err(db_error)? {
+            let (entry, _) = row.map_err(db_error)?;
+            let (at, key) = entry.value();
+            recent.extend(from_micros(at).map(|t| (key.to_string(), t)));
+        }
+        Ok(recent)
+    }
+
+    fn import(&self, imported: &[(String, DateTime<Utc>)]) -> Result<(), RateLimitError> {
+        let txn = self.db.begin_write().map_err(db_error)?;
+        {
+            let mut hits = txn.open_table(HITS).map_err(db_error)?;
+            let mut expiry = txn.open_table(EXPIRY).map_err(db_error)?;
+            for (key, t) in imported {
+                Self::insert(&mut hits, &mut expiry, key, *t)?;
+            }
+        }
+        txn.commit().map_err(db_error)
+    }
+
+    fn remove(&self, key: &str) -> Result<(), RateLimitError> {
+        let txn = self.db.begin_write().map_err(db_error)?;
+        {
+            let mut hits = txn.open_table(HITS).map_err(db_error)?;
+            let mut expiry = txn.open_table(EXPIRY).map_err(db_error)?;
+            let mut times = Vec::new();
+            for row in hits
+                .range((key, i64::MIN)..=(key, i64::MAX))
+                .map_err(db_error)?
+            {
+                let (entry, _) = row.map_err(db_error)?;
+                times.push(entry.value().1);
+            }
+            for at in times {
+                hits.remove((key, at)).map_err(db_error)?;
+                expiry.remove((at, key)).map_err(db_error)?;
+            }
+        }
+        txn.commit().map_err(db_error)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+    use tempfile::tempdir;
+
+    fn key(k: &str, max: usize) -> (String, usize) {
+        (k.to_string(), max)
+    }
+
+    /// The same contract, run against every backend.
+    fn exercise(store: &dyn RateLimitStore) {
+        let start = Utc::now() - Duration::hours(2);
+        let at = |minutes: i64| start + Duration::minutes(minutes);
+        let window = Duration::hours(1);
+
+        let a = [key("192.0.2.1", 2), key("192.0.2.0/24", 3)];
+        let b = [key("192.0.2.2", 2), key("192.0.2.0/24", 3)];
+        assert!(store.record_if_under(&a, at(0) - window, at(0)).unwrap());
+        assert!(store.record_if_under(&a, at(0) - window, at(0)).unwrap());
+        assert!(!store.record_if_under(&a, at(1) - window, at(1)).unwrap());
+        assert!(store.record_if_under(&b, at(2) - window, at(2)).unwrap());
+        // The network is full now; the refusal records nothing for b.
+        assert!(!store.record_if_under(&b, at(3) - window, at(3)).unwrap());
+        assert_eq!(
+            store.hits_since("192.0.2.2", at(3) - window).unwrap().len(),
+            1
+        );
+        let hits = store.hits_since("192.0.2.1", at(3) - window).unwrap();
+        assert_eq!(hits.len(), 2);
+        assert!(
+            hits.windows(2).all(|pair| pair[0] <= pair[1]),
+            "oldest first"
+        );
+        assert_eq!(store.all_since(at(3) - window).unwrap().len(), 6);
+
+        // An hour on, the first hits have expired.
+        let later = at(61);
+        assert_eq!(
+            store.hits_since("192.0.2.1", later - window).unwrap().len(),
+            0
+        );
+        assert!(store.record_if_under(&a, later - window, later).unwrap());
+        assert_eq!(store.all_since(later - window).unwrap().len(), 4);
+
+        // Removing a key leaves the keys it was recorded alongside.
+        store.remove("192.0.2.1").unwrap();
+        assert!(store
+            .hits_since("192.0.2.1", later - window)
+            .unwrap()
+            .is_empty());
+        assert_eq!(store.all_since(later - window).unwrap().len(), 3);
+        store.remove("never seen").unwrap();
+    }
+
+    #[test]
+    fn every_backend_keeps_the_same_contract() {
+        let dir = tempdir().unwrap();
+        exercise(&MemoryStore::default());
+        exercise(&JsonStore::new(dir.path().join("limits.json")));
+        exercise(&RedbStore::open(&dir.path().join("limits.redb")).unwrap());
+    }
+
+    #[test]
+    fn redb_hits_survive_reopening() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("limits.redb");
+        let now = Utc::now();
+        {
+            let store = RedbStore::open(&path).unwrap();
+            assert!(store
+                .record_if_under(&[key("k", 1)], now - Duration::hours(1), now)
+                .unwrap());
+        }
+        let store = RedbStore::open(&path).unwrap();
+        assert!(!store
+            .record_if_under(&[key("k", 1)], now - Duration::hours(1), now)
+            .unwrap());
+    }
+
+    #[test]
+    fn json_files_migrate_into_redb() {
+        let dir = tempdir().unwrap();
+        let json = dir.path().join("invite_rate_limits.json");
+        let now = Utc::now();
+        let recent = (now - Duration::minutes(5)).to_rfc3339();
+        let old = (now - Duration::days(3)).to_rfc3339();
+        fs::write(
+            &json,
+            serde_json::json!({
+                "invites": {
+                    "203.0.113.7": [recent.clone(), recent.clone(), old],
+                    "fingerprint": [recent],
+                }
+            })
+            .to_string(),
+        )
+        .unwrap();
+
+        let store = RedbStore::open(&dir.path().join("limits.redb")).unwrap();
+        assert_eq!(migrate_json(&json, &store).unwrap(), 4);
+        let since = now - Duration::hours(24);
+        assert_eq!(store.hits_since("203.0.113.7", since).unwrap().len(), 2);
+        assert_eq!(store.all_since(since).unwrap().len(), 3);
+        assert!(!store
+            .record_if_under(&[key("203.0.113.7", 2)], since, now)
+            .unwrap());
+    }
+
+    #[test]
+    fn json_writes_replace_the_file_whole() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("limits.json");
+        let store = JsonStore::new(path.clone());
+        let now = Utc::now();
+        store
+            .record_if_under(&[key("k", 4)], now - Duration::hours(1), now)
+            .unwrap();
+        assert!(!dir.path().join("limits.json.tmp").exists());
+        let data: RateLimitData =
+            serde_json::from_str(&fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(data.invites["k"].len(), 1);
+    }
+}
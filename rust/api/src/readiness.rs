@@ -0,0 +1,359 @@
+//! `/ready`: whether this instance can do its job right now.
+//!
+//! `/health` only says the process answers. A load balancer or deploy script
+//! should route to an instance only when `/ready` is 200. Each check below is
+//! listed in the response. A failed hard check makes it 503; a failed soft
+//! check is reported but still 200, since requests still succeed:
+//!
+//! - **notaries** (hard): at least one tier loaded and verified.
+//! - **payment** (hard): Stripe answered with the configured key. Probed at
+//!   most once a minute, so polling `/ready` does not spend the API quota.
+//! - **invites** (hard when configured): the invite state loaded at startup.
+//! - **tor** (hard with invites): an empty list refuses every invitation;
+//!   a stale one is soft.
+//! - **pow** (soft): the key file still reads. The keys in memory stay in
+//!   force if it does not, but a rotation will not be picked up.
+//! - **storage** (hard, the Tor and ACME caches soft): each file's
+//!   directory accepts a write, probed with a scratch file that is removed
+//!   again. Probed at most every ten seconds, so polling does not turn into
+//!   a stream of disk writes.
+//!
+//! The details name paths and carry OS errors, so only loopback and
+//! `server.operator_addresses` get them; anyone else gets `ready` alone.
+
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use axum::{extract::State, http::StatusCode, response::Json, Extension};
+use serde::Serialize;
+use tokio::sync::Mutex;
+
+use crate::client_ip::{ClientIp, Operators};
+use crate::notary_store::NotaryStore;
+use crate::routes::InviteState;
+use crate::tor::TorExitList;
+
+/// How long a Stripe probe result is reused.
+const PAYMENT_PROBE_TTL: Duration = Duration::from_secs(60);
+const PAYMENT_PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the storage probe results are reused.
+const STORAGE_PROBE_TTL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    /// A failed hard check makes the instance not ready.
+    pub hard: bool,
+    pub detail: String,
+}
+
+impl Check {
+    fn new(name: impl Into<String>, hard: bool, result: Result<String, String>) -> Self {
+        let (ok, detail) = match result {
+            Ok(detail) => (true, detail),
+            Err(detail) => (false, detail),
+        };
+        Self {
+            name: name.into(),
+            ok,
+            hard,
+            detail,
+        }
+    }
+}
+
+struct StoragePath {
+    name: String,
+    path: PathBuf,
+    hard: bool,
+}
+
+pub struct Readiness {
+    notaries: Arc<NotaryStore>,
+    stripe: Option<stripe::Client>,
+    invites: Option<InviteState>,
+    invites_requested: bool,
+    storage: Vec<StoragePath>,
+    payment: Mutex<Option<(Instant, Result<String, String>)>>,
+    storage_probe: Mutex<Option<(Instant, Vec<Check>)>>,
+}
+
+impl Readiness {
+    /// `invites_requested` with no `invites` means the invite configuration
+    /// failed to load.
+    pub fn new(
+        notaries: Arc<NotaryStore>,
+        stripe: Option<stripe::Client>,
+        invites: Option<InviteState>,
+        invites_requested: bool,
+    ) -> Self {
+        Self {
+            notaries,
+            stripe,
+            invites,
+            invites_requested,
+            storage: Vec::new(),
+            payment: Mutex::new(None),
+            storage_probe: Mutex::new(None),
+        }
+    }
+
+    /// Probe that the directory holding `path` can be written.
+    pub fn with_storage(mut self, name: impl Into<String>, path: PathBuf, hard: bool) -> Self {
+        self.storage.push(StoragePath {
+            name: name.into(),
+            path,
+            hard,
+        });
+        self
+    }
+
+    pub async fn checks(&self) -> Vec<Check> {
+        let mut checks = vec![
+            self.notary_check(),
+            Check::new("payment", true, self.payment_probe().await),
+        ];
+        match &self.invites {
+            Some(state) => {
+                checks.push(Check::new(
+                    "invites",
+                    true,
+                    Ok(format!("{} rooms", state.rooms.len())),
+                ));
+                checks.push(tor_check(&state.tor_exits));
+                checks.push(Check::new("pow", false, state.pow.key_source()));
+            }
+            None if self.invites_requested => checks.push(Check::new(
+                "invites",
+                true,
+                Err("configured but failed to load; see the startup log".to_string()),
+            )),
+            None => {}
+        }
+        checks.extend(self.storage_checks().await);
+        checks
+    }
+
+    async fn storage_checks(&self) -> Vec<Check> {
+        let mut cached = self.storage_probe.lock().await;
+        if let Some((at, checks)) = cached.as_ref() {
+            if at.elapsed() < STORAGE_PROBE_TTL {
+                return checks.clone();
+            }
+        }
+        let storage: Vec<_> = self
+            .storage
+            .iter()
+            .map(|entry| (entry.name.clone(), entry.path.clone(), entry.hard))
+            .collect();
+        let probed = tokio::task::spawn_blocking(move || {
+            storage
+                .into_iter()
+                .map(|(name, path, hard)| {
+                    Check::new(format!("storage:{name}"), hard, probe_writable(&path))
+                })
+                .collect::<Vec<_>>()
+        })
+        .await
+        .unwrap_or_else(|e| {
+            vec![Check::new(
+                "storage",
+                true,
+                Err(format!("probe failed: {e}")),
+            )]
+        });
+        *cached = Some((Instant::now(), probed.clone()));
+        probed
+    }
+
+    fn notary_check(&self) -> Check {
+        let tiers = self.notaries.tiers();
+        let result = if tiers.is_empty() {
+            Err(format!(
+                "no tiers loaded from {}",
+                self.notaries.dir().display()
+            ))
+        } else {
+            let tiers: Vec<String> = tiers.iter().map(|amount| format!("${amount}")).collect();
+            Ok(format!("verified tiers {}", tiers.join(", ")))
+        };
+        Check::new("notaries", true, result)
+    }
+
+    /// Reach Stripe with the client the handlers use. An API error is still an
+    /// answer: only a rejected key or no answer at all counts as down.
+    async fn payment_probe(&self) -> Result<String, String> {
+        let mut cached = self.payment.lock().await;
+        if let Some((at, result)) = cached.as_ref() {
+            if at.elapsed() < PAYMENT_PROBE_TTL {
+                return result.clone();
+            }
+        }
+        let result = match &self.stripe {
+            None => Err("no Stripe secret key is configured".to_string()),
+            Some(client) => {
+                match tokio::time::timeout(
+                    PAYMENT_PROBE_TIMEOUT,
+                    stripe::Balance::retrieve(client, None),
+                )
+                .await
+                {
+                    Err(_) => Err("Stripe did not answer in time".to_string()),
+                    Ok(Ok(_)) => Ok("Stripe reachable".to_string()),
+                    Ok(Err(stripe::StripeError::Stripe(e))) if e.http_status == 401 => {
+                        Err("Stripe rejected the secret key".to_string())
+                    }
+                    Ok(Err(stripe::StripeError::Stripe(_))) => Ok("Stripe reachable".to_string()),
+                    Ok(Err(e)) => Err(format!("Stripe unreachable: {e}")),
+                }
+            }
+        };
+        *cached = Some((Instant::now(), result.clone()));
+        result
+    }
+}
+
+fn tor_check(tor_exits: &TorExitList) -> Check {
+    if tor_exits.is_empty() {
+        return Check::new(
+            "tor",
+            true,
+            Err("exit list is empty; invitations are refused until it loads".to_string()),
+        );
+    }
+    let age = tor_exits
+        .last_updated()
+        .map(|updated| format!(", updated {}", updated.to_rfc3339()))
+        .unwrap_or_default();
+    let detail = format!("{} exits{age}", tor_exits.len());
+    if tor_exits.is_stale() {
+        Check::new("tor", false, Err(format!("stale: {detail}")))
+    } else {
+        Check::new("tor", true, Ok(detail))
+    }
+}
+
+/// Write and remove a scratch file next to `path`. Permission bits alone miss
+/// a full disk, a read-only mount and an unwritable directory.
+fn probe_writable(path: &Path) -> Result<String, String> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let probe = dir.join(format!(".gkapi-ready-{:016x}", rand::random::<u64>()));
+    let written = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&probe)
+        .and_then(|mut file| file.write_all(b"ready"));
+    let removed = fs::remove_file(&probe);
+    written
+        .and(removed)
+        .map(|_| format!("{} writable", dir.display()))
+        .map_err(|e| format!("{} is not writable: {e}", dir.display()))
+}
+
+/// `/ready`: 200 when every hard check passes, 503 otherwise. The checks
+/// themselves are listed for operators only.
+pub async fn serve(
+    State(readiness): State<Arc<Readiness>>,
+    Extension(operators): Extension<Arc<Operators>>,
+    ClientIp(client): ClientIp,
+) -> (StatusCode, Json<serde_json::Value>) {
+    let checks = readiness.checks().await;
+    let ready = checks.iter().all(|check| check.ok || !check.hard);
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    let body = if operators.allows(client) {
+        serde_json::json!({ "ready": ready, "checks": checks })
+    } else {
+        serde_json::json!({ "ready": ready })
+    };
+    (status, Json(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tor::TorSource;
+    use ghostkey_lib::util::create_keypair;
+    use rand_core::OsRng;
+
+    /// No notary tiers and no invites: not ready.
+    fn readiness(notary_dir: &Path) -> Readiness {
+        let (_, master_vk) = create_keypair(&mut OsRng).unwrap();
+        let notaries = NotaryStore::load(notary_dir, master_vk).unwrap();
+        Readiness::new(Arc::new(notaries), None, None, false)
+    }
+
+    #[test]
+    fn storage_probe_writes_and_cleans_up() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(probe_writable(&dir.path().join("ledger.jsonl")).is_ok());
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+        let missing = dir.path().join("missing").join("ledger.jsonl");
+        let err = probe_writable(&missing).unwrap_err();
+        assert!(err.contains("not writable"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn storage_probes_are_reused_for_a_while() {
+        let dir = tempfile::tempdir().unwrap();
+        let data = dir.path().join("data");
+        fs::create_dir(&data).unwrap();
+        let readiness =
+            readiness(dir.path()).with_storage("ledger", data.join("ledger.jsonl"), true);
+        assert!(readiness.storage_checks().await[0].ok);
+
+        fs::remove_dir(&data).unwrap();
+        assert!(
+            readiness.storage_checks().await[0].ok,
+            "probed again too soon"
+        );
+        if let Some((at, _)) = readiness.storage_probe.lock().await.as_mut() {
+            *at -= STORAGE_PROBE_TTL;
+        }
+        assert!(!readiness.storage_checks().await[0].ok);
+    }
+
+    #[tokio::test]
+    async fn only_operators_see_the_checks() {
+        let dir = tempfile::tempdir().unwrap();
+        let readiness = Arc::new(readiness(dir.path()));
+        let operators = Arc::new(Operators::default());
+        let ask = |client: &str| {
+            serve(
+                State(Arc::clone(&readiness)),
+                Extension(Arc::clone(&operators)),
+                ClientIp(client.parse().unwrap()),
+            )
+        };
+
+        let (status, Json(body)) = ask("198.51.100.7").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(body, serde_json::json!({ "ready": false }));
+
+        let (status, Json(body)) = ask("127.0.0.1").await;
+        assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+        let notaries = &body["checks"][0];
+        assert_eq!(notaries["name"], "notaries");
+        assert!(notaries["detail"]
+            .as_str()
+            .unwrap()
+            .contains(&dir.path().display().to_string()));
+    }
+
+    #[test]
+    fn an_empty_tor_list_is_a_hard_failure() {
+        let check = tor_check(&TorExitList::new(None, TorSource::default()));
+        assert!(!check.ok);
+        assert!(check.hard);
+    }
+}
@@ -0,0 +1,756 @@
+//! Operator-chosen network lists, alongside the Tor exit list.
+//!
+//! [`crate::tor`] answers one question from one URL. Spam also arrives from
+//! VPN providers and datacenter ranges, which are published as CIDR lists
+//! rather than single addresses and which do not all deserve an outright
+//! block. Each `[[reputation.sources]]` entry names a list, fetched from a URL
+//! or read from a local file, and what happens to addresses on it:
+//!
+//! - `block`: 403 before any proof of work, as for Tor exits.
+//! - `extra-bits`: challenges are this many bits harder.
+//! - `quota`: the per-address allowance in each room drops to this.
+//!
+//! An address on several lists gets the strictest of each: any block, the
+//! largest extra bits and the smallest quota. Allow-listed addresses skip
+//! every source, as they skip the Tor block; ghost key requests skip them too.
+//!
+//! Lists are held in a [`NetworkSet`], a binary trie per address family, so a
+//! lookup costs at most 32 or 128 steps however many ranges are loaded.
+//!
+//! # Safeguards
+//!
+//! These are the Tor refresher's, per source: a fetched body is capped while
+//! it streams, a list below `min_entries` or under half its previous size is
+//! refused and the previous one kept, and a URL source's last good copy is
+//! cached so a restart does not start blind. A failed refresh never empties a
+//! list. A source marked `fail_closed` refuses invitations with 503 while it
+//! has never loaded; other sources are simply inactive until they do.
+
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use ipnet::IpNet;
+use log::{error, info, warn};
+use thiserror::Error;
+
+/// How often a source is reread when it does not set `refresh_minutes`.
+pub const DEFAULT_REFRESH: Duration = Duration::from_secs(60 * 60);
+
+/// Refuse a response larger than this. Datacenter lists run to a few MB.
+const MAX_RESPONSE_BYTES: usize = 32 * 1024 * 1024;
+
+/// Hard cap on ranges retained from one source.
+const MAX_ENTRIES: usize = 2_000_000;
+
+/// Network timeout for a single fetch.
+const FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+#[derive(Error, Debug)]
+pub enum ReputationError {
+    #[error("HTTP error: {0}")]
+    Http(#[from] reqwest::Error),
+    #[error("IO error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("response too large: {0} bytes exceeds cap of {MAX_RESPONSE_BYTES}")]
+    TooLarge(usize),
+    #[error("implausibly small list: {got} entries (minimum {min}); refusing to replace it")]
+    Implausible { got: usize, min: usize },
+    #[error(
+        "list shrank implausibly: {got} entries vs {previous} previously; refusing to replace"
+    )]
+    Shrank { got: usize, previous: usize },
+    #[error("lock poisoned")]
+    Lock,
+}
+
+/// What happens to an address on a source's list.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReputationAction {
+    Block,
+    ExtraBits(u8),
+    Quota(usize),
+}
+
+impl std::fmt::Display for ReputationAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ReputationAction::Block => f.write_str("block"),
+            ReputationAction::ExtraBits(bits) => write!(f, "{bits} extra bits"),
+            ReputationAction::Quota(max) => write!(f, "{max} invites per address"),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SourceLocation {
+    Url(String),
+    File(PathBuf),
+}
+
+/// One configured source; see `Config::reputation_sources`.
+#[derive(Clone, Debug)]
+pub struct SourceSettings {
+    pub name: String,
+    pub location: SourceLocation,
+    pub action: ReputationAction,
+    /// Last good copy of a URL source.
+    pub cache: Option<PathBuf>,
+    pub min_entries: usize,
+    pub fail_closed: bool,
+    pub refresh: Duration,
+}
+
+#[derive(Clone, Copy, Default)]
+struct TrieNode {
+    /// The prefix this node stands for, masked to `len` bits.
+    key: u128,
+    len: u8,
+    /// Child for a 0 and a 1 as the bit after `len`. 0 is "none": the root
+    /// is never a child.
+    children: [u32; 2],
+    /// Every address below this node is in the set.
+    end: bool,
+}
+
+/// Path-compressed binary trie of prefixes of one address family, keyed by
+/// the address bits left-aligned in a `u128`. A node is kept only where a
+/// prefix ends or two branch, so a list of single IPv6 addresses costs at
+/// most two nodes each rather than one per bit.
+struct PrefixTrie {
+    nodes: Vec<TrieNode>,
+}
+
+impl Default for PrefixTrie {
+    fn default() -> Self {
+        Self {
+            nodes: vec![TrieNode::default()],
+        }
+    }
+}
+
+impl PrefixTrie {
+    fn bit(key: u128, depth: u8) -> usize {
+        ((key >> (127 - depth)) & 1) as usize
+    }
+
+    fn mask(key: u128, len: u8) -> u128 {
+        match len {
+            0 => 0,
+            len => key & (u128::MAX << (128 - u32::from(len))),
+        }
+    }
+
+    fn push(&mut self, node: TrieNode) -> u32 {
+        self.nodes.push(node);
+        (self.nodes.len() - 1) as u32
+    }
+
+    fn insert(&mut self, key: u128, len: u8) {
+        let key = Self::mask(key, len);
+        let mut node = 0;
+        loop {
+            let current = self.nodes[node];
+            if current.end {
+                return;
+            }
+            if current.len == len {
+                // Narrower ranges below are now covered; lookups stop here.
+                self.nodes[node].end = true;
+                self.nodes[node].children = [0, 0];
+                return;
+            }
+            let bit = Self::bit(key, current.len);
+            let leaf = TrieNode {
+                key,
+                len,
+                children: [0, 0],
+                end: true,
+            };
+            let child = current.children[bit];
+            if child == 0 {
+                self.nodes[node].children[bit] = self.push(leaf);
+                return;
+            }
+            let existing = self.nodes[child as usize];
+            let common = ((key ^ existing.key).leading_zeros() as u8)
+                .min(len)
+                .min(existing.len);
+            if common == existing.len {
+                node = child as usize;
+                continue;
+            }
+            self.nodes[node].children[bit] = if common == len {
+                // Covers the existing branch entirely.
+                self.push(leaf)
+            } else {
+                let mut children = [0, 0];
+                children[Self::bit(existing.key, common)] = child;
+                children[Self::bit(key, common)] = self.push(leaf);
+                self.push(TrieNode {
+                    key: Self::mask(key, common),
+                    len: common,
+                    children,
+                    end: false,
+                })
+            };
+            return;
+        }
+    }
+
+    fn contains(&self, key: u128, len: u8) -> bool {
+        let mut node = &self.nodes[0];
+        loop {
+            if node.end {
+                return true;
+            }
+            if node.len >= len {
+                return false;
+            }
+            node = match node.children[Self::bit(key, node.len)] {
+                0 => return false,
+                child => &self.nodes[child as usize],
+            };
+            if Self::mask(key, node.len) != node.key {
+                return false;
+            }
+        }
+    }
+}
+
+/// A set of IPv4 and IPv6 networks with longest-path membership tests.
+#[derive(Default)]
+pub struct NetworkSet {
+    v4: PrefixTrie,
+    v6: PrefixTrie,
+    entries: usize,
+}
+
+impl NetworkSet {
+    pub fn insert(&mut self, net: IpNet) {
+        match net.trunc() {
+            IpNet::V4(net) => self
+                .v4
+                .insert(u128::from(u32::from(net.addr())) << 96, net.prefix_len()),
+            IpNet::V6(net) => self.v6.insert(u128::from(net.addr()), net.prefix_len()),
+        }
+        self.entries += 1;
+    }
+
+    /// Whether `ip` is in any of the networks. IPv4-mapped IPv6 addresses
+    /// match their IPv4 form, as in [`crate::tor`].
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match ip.to_canonical() {
+            IpAddr::V4(v4) => self.v4.contains(u128::from(u32::from(v4)) << 96, 32),
+            IpAddr::V6(v6) => self.v6.contains(u128::from(v6), 128),
+        }
+    }
+
+    /// Entries inserted, counting overlapping ones.
+    pub fn len(&self) -> usize {
+        self.entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries == 0
+    }
+
+    /// One address or CIDR per line. `#` and `;` start comments, anything
+    /// after the first field is ignored, and unparseable lines are skipped,
+    /// which covers plain lists and the Spamhaus DROP format alike.
+    pub fn parse(body: &str) -> Self {
+        let mut set = Self::default();
+        for line in body.lines() {
+            let line = line.split(['#', ';']).next().unwrap_or("");
+            let Some(field) = line.split_whitespace().next() else {
+                continue;
+            };
+            let net = field
+                .parse::<IpNet>()
+                .or_else(|_| field.parse::<IpAddr>().map(IpNet::from));
+            if let Ok(net) = net {
+                set.insert(net);
+                if set.len() >= MAX_ENTRIES {
+                    warn!("Reputation list hit the {MAX_ENTRIES} entry cap; truncating");
+                    break;
+                }
+            }
+        }
+        set
+    }
+}
+
+struct Snapshot {
+    set: Arc<NetworkSet>,
+    updated: Option<DateTime<Utc>>,
+}
+
+/// One named list and its policy.
+pub struct ReputationSource {
+    settings: SourceSettings,
+    inner: RwLock<Snapshot>,
+}
+
+impl ReputationSource {
+    /// A source seeded from its cache, or for a file source from the file.
+    /// Never fails: a source that cannot be read starts empty, which is
+    /// logged.
+    pub fn new(settings: SourceSettings) -> Self {
+        let seed = match &settings.location {
+            SourceLocation::Url(_) => settings.cache.as_deref(),
+            SourceLocation::File(path) => Some(path.as_path()),
+        };
+        let mut snapshot = Snapshot {
+            set: Arc::new(NetworkSet::default()),
+            updated: None,
+        };
+        if let Some(path) = seed {
+            match read_list(path) {
+                Ok(Some((set, updated))) => {
+                    info!(
+                        "Loaded {} entries for reputation source {} from {}",
+                        set.len(),
+                        settings.name,
+                        path.display()
+                    );
+                    snapshot = Snapshot {
+                        set: Arc::new(set),
+                        updated: Some(updated),
+                    };
+                }
+                Ok(None) => info!(
+                    "Reputation source {} has nothing at {} yet; starting empty",
+                    settings.name,
+                    path.display()
+                ),
+                Err(e) => warn!(
+                    "Could not read {} for reputation source {}: {e}; starting empty",
+                    path.display(),
+                    settings.name
+                ),
+            }
+        }
+        Self {
+            settings,
+            inner: RwLock::new(snapshot),
+        }
+    }
+
+    pub fn name(&self) -> &str {
+        &self.settings.name
+    }
+
+    pub fn action(&self) -> ReputationAction {
+        self.settings.action
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match self.inner.read() {
+            Ok(snapshot) => snapshot.set.contains(ip),
+            Err(_) => {
+                warn!(
+                    "Reputation source {} lock poisoned; treating as unlisted",
+                    self.settings.name
+                );
+                false
+            }
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.read().map(|s| s.set.len()).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// When the list was last loaded.
+    pub fn last_updated(&self) -> Option<DateTime<Utc>> {
+        self.inner.read().ok().and_then(|s| s.updated)
+    }
+
+    /// Whether invitations must wait for this source to load.
+    pub fn is_unavailable(&self) -> bool {
+        self.settings.fail_closed && self.is_empty()
+    }
+
+    /// Fetch or reread the list and replace the current one. On any error
+    /// the current list is kept.
+    pub async fn refresh(&self) -> Result<usize, ReputationError> {
+        let body = match &self.settings.location {
+            SourceLocation::Url(url) => fetch(url).await?,
+            SourceLocation::File(path) => tokio::fs::read_to_string(path).await?,
+        };
+        let set = NetworkSet::parse(&body);
+        if set.len() < self.settings.min_entries {
+            return Err(ReputationError::Implausible {
+                got: set.len(),
+                min: self.settings.min_entries,
+            });
+        }
+        // An operator shrinking their own file is deliberate; a published
+        // list halving overnight is a truncated response.
+        let previous = self.len();
+        if matches!(self.settings.location, SourceLocation::Url(_))
+            && previous > 0
+            && set.len() * 2 < previous
+        {
+            return Err(ReputationError::Shrank {
+                got: set.len(),
+                previous,
+            });
+        }
+        if let (SourceLocation::Url(_), Some(path)) =
+            (&self.settings.location, self.settings.cache.as_deref())
+        {
+            if let Err(e) = write_cache(path, &body) {
+                warn!(
+                    "Could not persist reputation source {} to {}: {e}",
+                    self.settings.name,
+                    path.display()
+                );
+            }
+        }
+        let count = set.len();
+        let mut snapshot = self.inner.write().map_err(|_| ReputationError::Lock)?;
+        *snapshot = Snapshot {
+            set: Arc::new(set),
+            updated: Some(Utc::now()),
+        };
+        Ok(count)
+    }
+}
+
+/// What the sources say about one address.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Assessment {
+    /// Name of a fail-closed source that has not loaded.
+    pub unavailable: Option<String>,
+    /// Name of a blocking source listing the address.
+    pub blocked_by: Option<String>,
+    pub extra_bits: u8,
+    pub invites_per_ip: Option<usize>,
+}
+
+/// Every configured source.
+#[derive(Default)]
+pub struct NetworkReputation {
+    sources: Vec<Arc<ReputationSource>>,
+}
+
+impl NetworkReputation {
+    pub fn new(settings: Vec<SourceSettings>) -> Self {
+        Self {
+            sources: settings
+                .into_iter()
+                .map(|settings| Arc::new(ReputationSource::new(settings)))
+                .collect(),
+        }
+    }
+
+    pub fn sources(&self) -> &[Arc<ReputationSource>] {
+        &self.sources
+    }
+
+    pub fn assess(&self, ip: IpAddr) -> Assessment {
+        let mut assessment = Assessment::default();
+        for source in &self.sources {
+            if source.is_unavailable() {
+                assessment
+                    .unavailable
+                    .get_or_insert_with(|| source.name().to_string());
+                continue;
+            }
+            if !source.contains(ip) {
+                continue;
+            }
+            match source.action() {
+                ReputationAction::Block => {
+                    assessment
+                        .blocked_by
+                        .get_or_insert_with(|| source.name().to_string());
+                }
+                ReputationAction::ExtraBits(bits) => {
+                    assessment.extra_bits = assessment.extra_bits.max(bits);
+                }
+                ReputationAction::Quota(max) => {
+                    assessment.invites_per_ip =
+                        Some(assessment.invites_per_ip.map_or(max, |cur| cur.min(max)));
+                }
+            }
+        }
+        assessment
+    }
+}
+
+async fn fetch(url: &str) -> Result<String, ReputationError> {
+    let client = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+    let mut response = client.get(url).send().await?.error_for_status()?;
+    if let Some(len) = response.content_length() {
+        if len > MAX_RESPONSE_BYTES as u64 {
+            return Err(ReputationError::TooLarge(
+                len.min(usize::MAX as u64) as usize
+            ));
+        }
+    }
+    // Capped while streaming; see `TorExitList::refresh` for why.
+    let mut buf: Vec<u8> = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if buf.len() + chunk.len() > MAX_RESPONSE_BYTES {
+            return Err(ReputationError::TooLarge(buf.len() + chunk.len()));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+fn read_list(path: &Path) -> Result<Option<(NetworkSet, DateTime<Utc>)>, ReputationError> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let set = NetworkSet::parse(&std::fs::read_to_string(path)?);
+    if set.is_empty() {
+        return Ok(None);
+    }
+    let updated = std::fs::metadata(path)?
+        .modified()
+        .map(DateTime::<Utc>::from)
+        .unwrap_or_else(|_| Utc::now());
+    Ok(Some((set, updated)))
+}
+
+fn write_cache(path: &Path, body: &str) -> Result<(), ReputationError> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    std::fs::write(&tmp, body)?;
+    std::fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Refresh every source now and then on its own interval, forever. As with
+/// the Tor list, a source with nothing loaded retries on a short backoff.
+pub fn spawn_refreshers(reputation: &NetworkReputation) {
+    for source in reputation.sources() {
+        let source = Arc::clone(source);
+        let name = source.name().to_string();
+        let handle = tokio::spawn(async move {
+            const EMPTY_RETRY_SECS: &[u64] = &[30, 60, 300, 900];
+            let mut empty_attempt = 0usize;
+            loop {
+                match source.refresh().await {
+                    Ok(n) => {
+                        info!("Refreshed reputation source {}: {n} entries", source.name());
+                        empty_attempt = 0;
+                    }
+                    Err(e) if source.is_empty() => warn!(
+                        "Reputation source {} refresh failed with nothing loaded: {e}{}",
+                        source.name(),
+                        if source.settings.fail_closed {
+                            "; invitations are refused until it loads"
+                        } else {
+                            "; it is inactive until it loads"
+                        }
+                    ),
+                    Err(e) => warn!(
+                        "Reputation source {} refresh failed: {e} (still using {} entries, \
+                         last updated {})",
+                        source.name(),
+                        source.len(),
+                        source
+                            .last_updated()
+                            .map(|t| t.to_rfc3339())
+                            .unwrap_or_else(|| "never".to_string())
+                    ),
+                }
+                let delay = if source.is_empty() {
+                    let d = EMPTY_RETRY_SECS[empty_attempt.min(EMPTY_RETRY_SECS.len() - 1)];
+                    empty_attempt = empty_attempt.saturating_add(1);
+                    Duration::from_secs(d)
+                } else {
+                    source.settings.refresh
+                };
+                tokio::time::sleep(delay).await;
+            }
+        });
+        tokio::spawn(async move {
+            if let Err(e) = handle.await {
+                error!("Reputation source {name} refresher panicked: {e}; the list is now frozen");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    fn file_source(path: &Path, action: ReputationAction, min_entries: usize) -> SourceSettings {
+        SourceSettings {
+            name: "test".to_string(),
+            location: SourceLocation::File(path.to_path_buf()),
+            action,
+            cache: None,
+            min_entries,
+            fail_closed: false,
+            refresh: DEFAULT_REFRESH,
+        }
+    }
+
+    #[test]
+    fn ranges_match_every_address_inside_them() {
+        let set = NetworkSet::parse(
+            "\
+# datacenters
+203.0.113.0/24 ; SBL123
+198.51.100.7
+2001:db8:1000::/36   hosting
+192.0.2.128/25
+192.0.2.0/24
+not-a-range
+",
+        );
+        assert_eq!(set.len(), 5);
+        assert!(set.contains(ip("203.0.113.0")));
+        assert!(set.contains(ip("203.0.113.255")));
+        assert!(!set.contains(ip("203.0.114.1")));
+        assert!(set.contains(ip("198.51.100.7")));
+        assert!(!set.contains(ip("198.51.100.8")));
+        assert!(set.contains(ip("2001:db8:1fff::1")));
+        assert!(!set.contains(ip("2001:db8:2000::1")));
+        // The wider range inserted after a narrower one covers all of it.
+        assert!(set.contains(ip("192.0.2.1")));
+        assert!(set.contains(ip("::ffff:203.0.113.9")));
+        assert!(!NetworkSet::default().contains(ip("203.0.113.1")));
+
+        let everything = NetworkSet::parse("0.0.0.0/0\n");
+        assert!(everything.contains(ip("8.8.8.8")));
+        assert!(!everything.contains(ip("2001:db8::1")));
+    }
+
+    #[test]
+    fn sources_combine_to_the_strictest_policy() {
+        let dir = tempfile::tempdir().unwrap();
+        let write = |name: &str, body: &str| {
+            let path = dir.path().join(name);
+            std::fs::write(&path, body).unwrap();
+            path
+        };
+        let vpn = write("vpn.txt", "203.0.113.0/24\n198.51.100.0/24\n");
+        let hosting = write("hosting.txt", "203.0.113.0/25\n");
+        let bad = write("bad.txt", "198.51.100.66\n");
+        let reputation = NetworkReputation::new(vec![
+            file_source(&vpn, ReputationAction::ExtraBits(2), 1),
+            file_source(&hosting, ReputationAction::ExtraBits(4), 1),
+            SourceSettings {
+                name: "quota".to_string(),
+                ..file_source(&vpn, ReputationAction::Quota(1), 1)
+            },
+            SourceSettings {
+                name: "bad".to_string(),
+                ..file_source(&bad, ReputationAction::Block, 1)
+            },
+        ]);
+
+        let inside = reputation.assess(ip("203.0.113.9"));
+        assert_eq!(inside.extra_bits, 4);
+        assert_eq!(inside.invites_per_ip, Some(1));
+        assert_eq!(inside.blocked_by, None);
+        assert_eq!(reputation.assess(ip("203.0.113.200")).extra_bits, 2);
+        assert_eq!(
+            reputation.assess(ip("198.51.100.66")).blocked_by.as_deref(),
+            Some("bad")
+        );
+        assert_eq!(reputation.assess(ip("192.0.2.1")), Assessment::default());
+    }
+
+    #[test]
+    fn long_prefixes_share_compressed_paths() {
+        let body: String = (0..1000u32)
+            .map(|i| format!("2001:db8::{:x}:{:x}\n", i >> 8, i & 0xff))
+            .collect();
+        let set = NetworkSet::parse(&body);
+        assert_eq!(set.len(), 1000);
+        assert!(
+            set.v6.nodes.len() <= 2 * 1000,
+            "{} nodes",
+            set.v6.nodes.len()
+        );
+        assert!(set.contains(ip("2001:db8::3:e7")));
+        assert!(!set.contains(ip("2001:db8::3:e8")));
+        assert!(!set.contains(ip("2001:db8::1:0:0")));
+
+        // A narrower range after a wider one, a wider one splitting a branch
+        // part way along its path, and one covering a whole branch.
+        let set = NetworkSet::parse(
+            "10.0.0.0/8\n10.1.0.0/16\n192.168.1.0/24\n192.168.2.0/24\n192.168.0.0/22\n",
+        );
+        assert!(set.contains(ip("10.200.0.1")));
+        assert!(set.contains(ip("192.168.3.1")));
+        assert!(!set.contains(ip("192.168.4.1")));
+        assert!(!set.contains(ip("11.0.0.1")));
+        let set = NetworkSet::parse("192.168.1.0/24\n192.168.2.0/24\n");
+        assert!(set.contains(ip("192.168.2.9")));
+        assert!(!set.contains(ip("192.168.0.9")));
+        assert!(!set.contains(ip("192.168.3.9")));
+    }
+
+    #[tokio::test]
+    async fn refresh_keeps_the_last_good_list_and_fail_closed_waits_for_one() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("list.txt");
+        let source = ReputationSource::new(SourceSettings {
+            fail_closed: true,
+            ..file_source(&path, ReputationAction::Block, 2)
+        });
+        assert!(source.is_unavailable());
+        let reputation = NetworkReputation {
+            sources: vec![Arc::new(source)],
+        };
+        assert_eq!(
+            reputation.assess(ip("192.0.2.1")).unavailable.as_deref(),
+            Some("test")
+        );
+        let source = &reputation.sources()[0];
+
+        std::fs::write(&path, "192.0.2.1\n").unwrap();
+        assert!(matches!(
+            source.refresh().await,
+            Err(ReputationError::Implausible { got: 1, min: 2 })
+        ));
+        std::fs::write(&path, "192.0.2.1\n192.0.2.2\n").unwrap();
+        assert_eq!(source.refresh().await.unwrap(), 2);
+        assert!(!source.is_unavailable());
+        assert!(source.last_updated().is_some());
+        std::fs::remove_file(&path).unwrap();
+        assert!(source.refresh().await.is_err());
+        assert!(source.contains(ip("192.0.2.2")));
+        assert_eq!(
+            reputation.assess(ip("192.0.2.2")).blocked_by.as_deref(),
+            Some("test")
+        );
+    }
+
+    #[test]
+    fn url_sources_seed_from_their_cache() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = dir.path().join("nested").join("vpn.txt");
+        write_cache(&cache, "203.0.113.0/24\n").unwrap();
+        assert!(!cache.with_extension("tmp").exists());
+        let source = ReputationSource::new(SourceSettings {
+            location: SourceLocation::Url("http://127.0.0.1:9/unused".to_string()),
+            cache: Some(cache),
+            ..file_source(dir.path(), ReputationAction::ExtraBits(3), 1)
+        });
+        assert_eq!(source.len(), 1);
+        assert!(source.contains(ip("203.0.113.50")));
+    }
+}
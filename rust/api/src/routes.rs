@@ -1,11 +1,10 @@
 use std::collections::HashMap;
-use std::net::{IpAddr, SocketAddr};
-use std::path::PathBuf;
+use std::net::IpAddr;
 use std::str::FromStr;
 use std::sync::Arc;
 
 use axum::{
-    extract::{ConnectInfo, Path, State},
+    extract::{FromRef, Path, Query, State},
     http::{header::CONTENT_TYPE, HeaderValue, Method, StatusCode},
     response::{IntoResponse, Json},
     routing::{get, post},
@@ -16,76 +15,202 @@ use ghostkey_lib::armorable::Armorable;
 use log::{error, info, warn};
 use serde::{Deserialize, Serialize};
 use stripe::{Client, Currency, PaymentIntent, PaymentIntentId};
+use tokio::sync::Semaphore;
 
-use crate::delegates::get_notary;
+use crate::access_policy::{Decision, PolicyStore};
+use crate::client_ip::ClientIp;
+use crate::ghostkey_auth::{self, GhostkeyGate};
 use crate::handle_sign_cert::{
     sign_certificate, CertificateError, SignCertificateRequest, SignCertificateResponse,
 };
-use crate::invite;
+use crate::invite::{self, MemberId};
+use crate::invite_ledger::{InviteLedger, InviteRecord};
 use crate::invite_pow::{PowChallenge, PowChallengeResponse, PowError, PowManager};
+use crate::issuance_log::{Checkpoints, IssuanceLog};
+use crate::metrics;
+use crate::notary_store::NotaryStore;
+use crate::pow_difficulty::{DifficultyController, DifficultySettings};
 use crate::rate_limit::{
-    AggregateBucket, RateLimiter, DEFAULT_GLOBAL_INVITES_PER_HOUR, GLOBAL_WINDOW_MINUTES,
-    MAX_INVITES_PER_WINDOW,
+    AggregateBucket, PrefixLimit, RateLimiter, DEFAULT_GLOBAL_INVITES_PER_HOUR,
+    GLOBAL_WINDOW_MINUTES, MAX_INVITES_PER_WINDOW,
 };
+use crate::rate_limit_store::RateLimitStore;
+use crate::reputation::NetworkReputation;
 use crate::tor::TorExitList;
 use tower_http::cors::CorsLayer;
 
+/// Shared state for the donation, certificate and transparency routes.
+#[derive(Clone)]
+pub struct DonationState {
+    pub notaries: Arc<NotaryStore>,
+    /// Built once from the configured secret key; `None` without one.
+    pub stripe: Option<Client>,
+    pub issuance: Arc<IssuanceLog>,
+    pub checkpoints: Arc<Checkpoints>,
+}
+
+impl FromRef<DonationState> for Option<Client> {
+    fn from_ref(state: &DonationState) -> Self {
+        state.stripe.clone()
+    }
+}
+
+impl FromRef<DonationState> for Arc<NotaryStore> {
+    fn from_ref(state: &DonationState) -> Self {
+        Arc::clone(&state.notaries)
+    }
+}
+
+/// One room the invite endpoint can mint invitations for.
+pub struct InviteRoom {
+    pub slug: String,
+    pub name: String,
+    pub room_owner_vk: VerifyingKey,
+    pub inviter_signing_key: SigningKey,
+    /// Per-IP allowance in this room, persisted separately for each room.
+    pub rate_limiter: Arc<RateLimiter>,
+    /// Ceiling for this room alone; [`InviteState::global_bucket`] still
+    /// bounds all rooms together. A limit of 0 leaves only the global one.
+    pub bucket: Arc<AggregateBucket>,
+}
+
+/// What [`InviteState::new`] needs to know about each configured room.
+pub struct RoomSettings {
+    pub slug: String,
+    pub name: String,
+    pub room_owner_vk: VerifyingKey,
+    pub inviter_signing_key: SigningKey,
+    pub rate_limit_store: Arc<dyn RateLimitStore>,
+    pub invites_per_ip: Option<usize>,
+    pub invites_per_hour: Option<usize>,
+    /// Network quota applied on top of `invites_per_ip`.
+    pub prefix_limit: Option<PrefixLimit>,
+}
+
 /// Shared application state for invite generation
 #[derive(Clone)]
 pub struct InviteState {
-    pub rate_limiter: Arc<RateLimiter>,
+    /// In configuration order, which is also the order `/rooms` lists them.
+    pub rooms: Arc<Vec<Arc<InviteRoom>>>,
+    /// Slug served when a request does not name a room.
+    pub default_room: String,
     /// Emergency ceiling across all successful invitation issuance.
     pub global_bucket: Arc<AggregateBucket>,
     pub pow: Arc<PowManager>,
+    /// Difficulty of each new challenge, from load and the requester's
+    /// network.
+    pub difficulty: Arc<DifficultyController>,
+    /// Bounds proofs checked at once. An Argon2id check holds its memory
+    /// cost for the length of a hash, and a wrong nonce costs the client
+    /// nothing to send.
+    pub pow_slots: Arc<Semaphore>,
     /// Membership test for "is this IP a Tor exit". An empty list makes the
     /// invite endpoint fail closed until the first refresh succeeds.
     pub tor_exits: Arc<TorExitList>,
-    pub room_owner_vk: VerifyingKey,
-    pub inviter_signing_key: SigningKey,
-    pub room_name: String,
+    /// Further network lists and what each does; see `reputation`. None
+    /// configured until replaced.
+    pub reputation: Arc<NetworkReputation>,
+    /// Verification, quota and record for requests signed with a ghost key.
+    pub ghostkeys: Arc<GhostkeyGate>,
+    /// Operator allow and deny lists. The built-in policy until replaced.
+    pub policy: Arc<PolicyStore>,
+    /// Record of every invitation issued, for moderation. None records
+    /// nothing.
+    pub ledger: Option<Arc<InviteLedger>>,
 }
 
 impl InviteState {
     pub fn new(
-        rate_limit_file: PathBuf,
-        tor_exit_cache: Option<PathBuf>,
+        rooms: Vec<RoomSettings>,
+        default_room: String,
+        tor_exits: TorExitList,
         global_invites_per_hour: Option<usize>,
         pow_base_difficulty: u8,
-        room_owner_vk: VerifyingKey,
-        inviter_signing_key: SigningKey,
-        room_name: String,
+        ghostkeys: GhostkeyGate,
     ) -> Self {
-        let rate_limiter = Arc::new(RateLimiter::new(rate_limit_file, 24));
-        let tor_exits = Arc::new(TorExitList::new(tor_exit_cache));
-        let recent_ages = match rate_limiter.recent_events(GLOBAL_WINDOW_MINUTES) {
-            // Seed only traffic the new policy would have admitted. Otherwise
-            // a pre-deploy Tor wave consumes legitimate global headroom even
-            // though every equivalent request is blocked after the restart.
-            Ok(events) => events
-                .into_iter()
-                .filter_map(|(ip, age)| (!tor_exits.is_exit(&ip)).then_some(age))
-                .collect(),
-            Err(e) => {
-                warn!("Could not seed global invite ceiling from persistent state: {e}");
-                Vec::new()
-            }
-        };
+        let tor_exits = Arc::new(tor_exits);
+        let mut all_ages = Vec::new();
+        let rooms = rooms
+            .into_iter()
+            .map(|room| {
+                let mut rate_limiter = RateLimiter::with_store(
+                    room.rate_limit_store,
+                    24,
+                    room.invites_per_ip.unwrap_or(MAX_INVITES_PER_WINDOW),
+                );
+                if let Some(limit) = room.prefix_limit {
+                    rate_limiter = rate_limiter.with_prefix_limit(limit);
+                }
+                let rate_limiter = Arc::new(rate_limiter);
+                let ages = recent_admitted(&rate_limiter, &tor_exits, &room.slug);
+                all_ages.extend(ages.iter().copied());
+                Arc::new(InviteRoom {
+                    bucket: Arc::new(AggregateBucket::new_seeded(
+                        room.invites_per_hour.unwrap_or(0),
+                        GLOBAL_WINDOW_MINUTES,
+                        ages,
+                    )),
+                    slug: room.slug,
+                    name: room.name,
+                    room_owner_vk: room.room_owner_vk,
+                    inviter_signing_key: room.inviter_signing_key,
+                    rate_limiter,
+                })
+            })
+            .collect();
         info!(
             "Seeding global invite ceiling with {} invitation(s) from the last hour",
-            recent_ages.len()
+            all_ages.len()
         );
         Self {
-            rate_limiter,
+            rooms: Arc::new(rooms),
+            default_room,
             global_bucket: Arc::new(AggregateBucket::new_seeded(
                 global_invites_per_hour.unwrap_or(DEFAULT_GLOBAL_INVITES_PER_HOUR),
                 GLOBAL_WINDOW_MINUTES,
-                recent_ages,
+                all_ages,
+            )),
+            pow: Arc::new(PowManager::new()),
+            difficulty: Arc::new(DifficultyController::new(DifficultySettings {
+                base: pow_base_difficulty,
+                ..DifficultySettings::default()
+            })),
+            pow_slots: Arc::new(Semaphore::new(
+                std::thread::available_parallelism().map_or(1, |n| n.get()),
             )),
-            pow: Arc::new(PowManager::new(pow_base_difficulty)),
             tor_exits,
-            room_owner_vk,
-            inviter_signing_key,
-            room_name,
+            reputation: Arc::new(NetworkReputation::default()),
+            ghostkeys: Arc::new(ghostkeys),
+            policy: Arc::new(PolicyStore::builtin()),
+            ledger: None,
+        }
+    }
+
+    /// The room a request asked for, or the default room if it named none.
+    pub fn room(&self, slug: Option<&str>) -> Option<Arc<InviteRoom>> {
+        let slug = slug.unwrap_or(&self.default_room);
+        self.rooms.iter().find(|room| room.slug == slug).cloned()
+    }
+}
+
+/// Ages of a room's invitations from the last hour, for seeding the ceilings
+/// so a deploy does not reset them.
+fn recent_admitted(
+    rate_limiter: &RateLimiter,
+    tor_exits: &TorExitList,
+    slug: &str,
+) -> Vec<std::time::Duration> {
+    match rate_limiter.recent_events(GLOBAL_WINDOW_MINUTES) {
+        // Seed only traffic the new policy would have admitted. Otherwise
+        // a pre-deploy Tor wave consumes legitimate global headroom even
+        // though every equivalent request is blocked after the restart.
+        Ok(events) => events
+            .into_iter()
+            .filter_map(|(ip, age)| (!tor_exits.is_exit(&ip)).then_some(age))
+            .collect(),
+        Err(e) => {
+            warn!("Could not seed invite ceilings from room {slug}'s persistent state: {e}");
+            Vec::new()
         }
     }
 }
@@ -133,16 +258,29 @@ async fn get_message() -> impl IntoResponse {
 }
 
 async fn sign_certificate_route(
+    State(state): State<DonationState>,
+    ClientIp(client_ip): ClientIp,
     Json(request): Json<SignCertificateRequest>,
 ) -> Result<Json<SignCertificateResponse>, (StatusCode, Json<ErrorResponse>)> {
-    info!("Received sign-certificate request: {:?}", request);
-    match sign_certificate(request).await {
+    info!(
+        "Received sign-certificate request from {}: {:?}",
+        client_ip, request
+    );
+    match sign_certificate(
+        request,
+        state.stripe.as_ref(),
+        &state.notaries,
+        &state.issuance,
+    )
+    .await
+    {
         Ok(response) => {
             info!("Certificate signed successfully");
             Ok(Json(response))
         }
         Err(e) => {
             error!("Error signing certificate: {:?}", e);
+            metrics::get().signing_failed(&e);
             match e {
                 CertificateError::PaymentNotSuccessful => {
                     Err((StatusCode::BAD_REQUEST, Json(ErrorResponse {
@@ -176,8 +314,12 @@ async fn sign_certificate_route(
 #[derive(Debug)]
 pub enum DonationError {
     InvalidCurrency,
+    /// No notary is loaded for the requested amount, so a donation of it
+    /// could never be turned into a certificate.
+    UnavailableTier(i64),
     StripeError(stripe::StripeError),
-    EnvError(std::env::VarError),
+    /// No Stripe secret key is configured.
+    NoStripeKey,
     OtherError(String),
 }
 
@@ -185,15 +327,22 @@ impl IntoResponse for DonationError {
     fn into_response(self) -> axum::response::Response {
         let (status, error_message) = match self {
             DonationError::InvalidCurrency => (StatusCode::BAD_REQUEST, "Invalid currency"),
+            DonationError::UnavailableTier(amount) => {
+                warn!("Donation requested for unavailable tier: {} cents", amount);
+                (
+                    StatusCode::BAD_REQUEST,
+                    "No certificate is available for this donation amount",
+                )
+            }
             DonationError::StripeError(e) => {
                 error!("Stripe error: {:?}", e);
                 (StatusCode::INTERNAL_SERVER_ERROR, "Stripe error occurred")
             }
-            DonationError::EnvError(e) => {
-                error!("Environment variable error: {:?}", e);
+            DonationError::NoStripeKey => {
+                error!("No Stripe secret key is configured");
                 (
                     StatusCode::INTERNAL_SERVER_ERROR,
-                    "Environment variable error",
+                    "Payments are not configured",
                 )
             }
             DonationError::OtherError(e) => {
@@ -214,13 +363,34 @@ impl IntoResponse for DonationError {
     }
 }
 
+/// The certificate for the tier `amount_cents` falls into, if one is loaded.
+fn notary_certificate_for(
+    notaries: &NotaryStore,
+    amount_cents: i64,
+) -> Result<String, DonationError> {
+    u64::try_from(amount_cents / 100)
+        .ok()
+        .and_then(|amount_dollars| notaries.get(amount_dollars))
+        .map(|notary| notary.certificate_base64.clone())
+        .ok_or(DonationError::UnavailableTier(amount_cents))
+}
+
 async fn create_donation(
+    State(notaries): State<Arc<NotaryStore>>,
+    State(stripe): State<Option<Client>>,
+    ClientIp(client_ip): ClientIp,
     Json(request): Json<DonationRequest>,
 ) -> Result<Json<DonationResponse>, DonationError> {
-    info!("Received create-donation request: {:?}", request);
+    info!(
+        "Received create-donation request from {}: {:?}",
+        client_ip, request
+    );
+
+    // Checked before Stripe is involved so an amount we cannot certify never
+    // becomes a PaymentIntent the donor could pay.
+    let cert_base64 = notary_certificate_for(&notaries, request.amount)?;
 
-    let secret_key = std::env::var("STRIPE_SECRET_KEY").map_err(DonationError::EnvError)?;
-    let client = Client::new(&secret_key);
+    let client = stripe.ok_or(DonationError::NoStripeKey)?;
 
     let currency = Currency::USD;
 
@@ -273,22 +443,16 @@ async fn create_donation(
 
     info!("Payment intent created successfully");
 
-    let amount_dollars = request.amount / 100;
-
-    let (notary_certificate, _) = get_notary(amount_dollars as u64).map_err(|e| {
-        error!("Error getting notary: {:?}", e);
-        DonationError::OtherError("Error getting notary".to_string())
-    })?;
-
-    let cert_base64 = notary_certificate.to_base64().unwrap();
-
     match intent.client_secret {
-        Some(secret) => Ok(Json(DonationResponse {
-            client_secret: secret,
-            payment_intent_id: intent.id.to_string(),
-            delegate_certificate_base64: cert_base64.clone(),
-            notary_certificate_base64: cert_base64,
-        })),
+        Some(secret) => {
+            metrics::get().donation_created();
+            Ok(Json(DonationResponse {
+                client_secret: secret,
+                payment_intent_id: intent.id.to_string(),
+                delegate_certificate_base64: cert_base64.clone(),
+                notary_certificate_base64: cert_base64,
+            }))
+        }
         None => {
             error!("Client secret is missing from the PaymentIntent");
             Err(DonationError::OtherError(
@@ -305,12 +469,19 @@ pub struct UpdateDonationRequest {
 }
 
 async fn update_donation(
+    State(notaries): State<Arc<NotaryStore>>,
+    State(stripe): State<Option<Client>>,
+    ClientIp(client_ip): ClientIp,
     Json(request): Json<UpdateDonationRequest>,
 ) -> Result<Json<DonationResponse>, DonationError> {
-    info!("Received update-donation request: {:?}", request);
+    info!(
+        "Received update-donation request from {}: {:?}",
+        client_ip, request
+    );
+
+    let cert_base64 = notary_certificate_for(&notaries, request.amount)?;
 
-    let secret_key = std::env::var("STRIPE_SECRET_KEY").map_err(DonationError::EnvError)?;
-    let client = Client::new(&secret_key);
+    let client = stripe.ok_or(DonationError::NoStripeKey)?;
 
     let payment_intent_id = PaymentIntentId::from_str(&request.payment_intent_id)
         .map_err(|_| DonationError::InvalidCurrency)?;
@@ -325,15 +496,6 @@ async fn update_donation(
 
     info!("Payment intent updated successfully");
 
-    let amount_dollars = request.amount / 100;
-
-    let (notary_certificate, _) = get_notary(amount_dollars as u64).map_err(|e| {
-        error!("Error getting notary: {:?}", e);
-        DonationError::OtherError("Error getting notary".to_string())
-    })?;
-
-    let cert_base64 = notary_certificate.to_base64().unwrap();
-
     Ok(Json(DonationResponse {
         client_secret: updated_intent.client_secret.unwrap_or_default(),
         payment_intent_id: updated_intent.id.to_string(),
@@ -343,6 +505,7 @@ async fn update_donation(
 }
 
 async fn check_payment_status_route(
+    State(stripe): State<Option<Client>>,
     Path(payment_intent_id): Path<String>,
 ) -> Result<StatusCode, DonationError> {
     info!(
@@ -350,8 +513,7 @@ async fn check_payment_status_route(
         payment_intent_id
     );
 
-    let secret_key = std::env::var("STRIPE_SECRET_KEY").map_err(DonationError::EnvError)?;
-    let client = Client::new(&secret_key);
+    let client = stripe.ok_or(DonationError::NoStripeKey)?;
 
     let payment_intent_id = PaymentIntentId::from_str(&payment_intent_id)
         .map_err(|_| DonationError::InvalidCurrency)?;
@@ -378,6 +540,8 @@ async fn check_payment_status_route(
 #[derive(Serialize)]
 pub struct CreateInviteResponse {
     pub invite_code: String,
+    /// Slug of the room the invite is for.
+    pub room: String,
     pub room_name: String,
 }
 
@@ -387,28 +551,35 @@ pub struct InviteErrorResponse {
     pub retry_after_seconds: Option<i64>,
 }
 
+/// Query string of the invite endpoints.
+#[derive(Deserialize, Default)]
+struct InviteQuery {
+    /// Room slug; absent means the default room.
+    room: Option<String>,
+    /// `/invite-challenge` only: the challenge will be redeemed at
+    /// `/create-invite/ghostkey`, so the per-IP checks do not apply.
+    #[serde(default)]
+    ghostkey: bool,
+}
+
+/// `/create-invite/ghostkey`: the challenge stands in for a nonce, signed by
+/// the ghost key instead of solved.
 #[derive(Deserialize)]
-struct CreateInviteRequest {
+struct GhostkeyInviteRequest {
     #[serde(flatten)]
     challenge: PowChallenge,
-    nonce: u64,
+    /// `GhostkeyCertificateV1`, base64 or armored.
+    ghostkey_certificate: String,
+    /// Base64 Ed25519 signature over `INVITE_SIGNATURE_DOMAIN` followed by
+    /// the challenge id.
+    signature: String,
 }
 
-/// Extract the client IP used to key the invite rate limiter.
-///
-/// We deliberately key on the TCP connection's peer address (`addr.ip()`) and
-/// do NOT trust `X-Forwarded-For` / `X-Real-IP`. gkapi is directly
-/// internet-facing (it binds :80/:443 and terminates its own TLS; there is no
-/// reverse proxy in front), so those headers are fully client-controlled and
-/// trusting them would let a spammer bypass the limit by rotating a spoofed
-/// header. `addr.ip()` returns the bare `IpAddr` (no port), so both IPv4 and
-/// IPv6 peers key correctly.
-///
-/// If a trusted reverse proxy / Cloudflare is ever placed in front of gkapi,
-/// revisit HERE to trust `X-Forwarded-For` ONLY when the peer is that proxy's
-/// IP. Do not re-add blanket `X-Forwarded-For` trust.
-fn get_client_ip(addr: SocketAddr) -> IpAddr {
-    addr.ip()
+#[derive(Deserialize)]
+struct CreateInviteRequest {
+    #[serde(flatten)]
+    challenge: PowChallenge,
+    nonce: u64,
 }
 
 fn invite_error(
@@ -425,66 +596,202 @@ fn invite_error(
     )
 }
 
+/// Refuse addresses on the operator's deny list. Returns whether the address
+/// is on the allow list instead.
+fn check_invite_policy(
+    state: &InviteState,
+    client_ip: IpAddr,
+) -> Result<bool, (StatusCode, Json<InviteErrorResponse>)> {
+    match state.policy.decide(client_ip) {
+        Decision::Deny => {
+            warn!("Invite request from denied address: {}", client_ip);
+            metrics::get().invite_rejected("denied");
+            Err(invite_error(
+                StatusCode::FORBIDDEN,
+                "Invitations are not available from this network.",
+                None,
+            ))
+        }
+        Decision::Allow => Ok(true),
+        Decision::Unlisted => Ok(false),
+    }
+}
+
+/// How [`check_invite_network`] treats an address it admitted.
+#[derive(Debug, Default)]
+struct Admission {
+    /// On the operator's allow list: no per-IP limits or network counting.
+    allowed: bool,
+    /// Added to the challenge difficulty by reputation sources.
+    extra_bits: u8,
+    /// Per-address quota lowered by a reputation source.
+    invites_per_ip: Option<usize>,
+}
+
 /// Enforce the network-level admission policy before issuing a challenge or
 /// accepting proof of work. Tor is intentionally blocked for this public room:
 /// rotating exits defeated IP rate limiting during the July 2026 spam waves.
+/// Reputation sources then block, add bits or lower the quota.
+///
+/// Addresses the operator has allowed skip the Tor block and the reputation
+/// sources here and the per-IP limits after it.
 fn check_invite_network(
     state: &InviteState,
     client_ip: IpAddr,
-) -> Result<(), (StatusCode, Json<InviteErrorResponse>)> {
+) -> Result<Admission, (StatusCode, Json<InviteErrorResponse>)> {
+    if check_invite_policy(state, client_ip)? {
+        return Ok(Admission {
+            allowed: true,
+            ..Admission::default()
+        });
+    }
+    let unavailable = |reason| {
+        metrics::get().invite_rejected(reason);
+        invite_error(
+            StatusCode::SERVICE_UNAVAILABLE,
+            "Invitations are temporarily unavailable. Please try again shortly.",
+            Some(30),
+        )
+    };
+    let blocked = |reason| {
+        metrics::get().invite_rejected(reason);
+        invite_error(
+            StatusCode::FORBIDDEN,
+            "Invitations are not available from this network.",
+            None,
+        )
+    };
     if state.tor_exits.is_empty() {
         error!(
             "Invite request from {} refused: Tor exit list is unavailable",
             client_ip
         );
-        return Err(invite_error(
-            StatusCode::SERVICE_UNAVAILABLE,
-            "Invitations are temporarily unavailable. Please try again shortly.",
-            Some(30),
-        ));
+        return Err(unavailable("tor_list_unavailable"));
     }
     if state.tor_exits.is_exit(&client_ip) {
         warn!("Invite request blocked from Tor exit: {}", client_ip);
-        return Err(invite_error(
-            StatusCode::FORBIDDEN,
-            "Invitations are not available from this network.",
-            None,
-        ));
+        return Err(blocked("tor"));
     }
-    Ok(())
+    let assessment = state.reputation.assess(client_ip);
+    if let Some(source) = assessment.unavailable {
+        error!(
+            "Invite request from {} refused: reputation source {} is unavailable",
+            client_ip, source
+        );
+        return Err(unavailable("reputation_unavailable"));
+    }
+    if let Some(source) = assessment.blocked_by {
+        warn!(
+            "Invite request blocked from {} by reputation source {}",
+            client_ip, source
+        );
+        return Err(blocked("reputation"));
+    }
+    Ok(Admission {
+        allowed: false,
+        extra_bits: assessment.extra_bits,
+        invites_per_ip: assessment.invites_per_ip,
+    })
+}
+
+/// Look up the room a request selected with `?room=`.
+fn select_room(
+    state: &InviteState,
+    query: &InviteQuery,
+) -> Result<Arc<InviteRoom>, (StatusCode, Json<InviteErrorResponse>)> {
+    state.room(query.room.as_deref()).ok_or_else(|| {
+        metrics::get().invite_rejected("unknown_room");
+        invite_error(
+            StatusCode::NOT_FOUND,
+            "No such room. See /rooms for the rooms invitations are offered for.",
+            None,
+        )
+    })
+}
+
+fn per_ip_limited(
+    room: &InviteRoom,
+    admission: &Admission,
+    retry_after: Option<i64>,
+) -> (StatusCode, Json<InviteErrorResponse>) {
+    metrics::get().invite_rejected("per_ip");
+    let max = room.rate_limiter.max_per_window();
+    invite_error(
+        StatusCode::TOO_MANY_REQUESTS,
+        format!(
+            "Rate limited. You can request up to {} invites per 24 hours.",
+            admission.invites_per_ip.map_or(max, |lower| lower.min(max))
+        ),
+        retry_after,
+    )
+}
+
+/// Refusal for a full ceiling, either the room's or the global one.
+fn ceiling_reached(
+    bucket: &AggregateBucket,
+    what: &str,
+    client_ip: IpAddr,
+) -> (StatusCode, Json<InviteErrorResponse>) {
+    warn!(
+        "Invite refused: {} ceiling reached ({}/{}), IP: {}",
+        what,
+        bucket.current(),
+        bucket.limit(),
+        client_ip
+    );
+    metrics::get().invite_rejected("ceiling");
+    invite_error(
+        StatusCode::TOO_MANY_REQUESTS,
+        "Too many invite requests right now. Please try again shortly.",
+        bucket.retry_after_seconds(),
+    )
 }
 
 async fn get_invite_challenge(
     State(state): State<InviteState>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ClientIp(client_ip): ClientIp,
+    Query(query): Query<InviteQuery>,
 ) -> Result<Json<PowChallengeResponse>, (StatusCode, Json<InviteErrorResponse>)> {
-    let client_ip = get_client_ip(addr);
-    check_invite_network(&state, client_ip)?;
+    // Ghost key holders skip the per-IP limits, not the Tor and reputation
+    // checks: a ghost key says who is asking, not where from.
+    let admission = check_invite_network(&state, client_ip)?;
+    let room = select_room(&state, &query)?;
 
     if !state.global_bucket.has_capacity() {
-        let retry_after = state.global_bucket.retry_after_seconds();
-        warn!(
-            "Invite challenge refused: global ceiling reached ({}/{}), IP: {}",
+        return Err(ceiling_reached(&state.global_bucket, "global", client_ip));
+    }
+    if !room.bucket.has_capacity() {
+        return Err(ceiling_reached(&room.bucket, &room.slug, client_ip));
+    }
+
+    if query.ghostkey {
+        // Nothing about the caller is known yet; the quota is checked when
+        // the signed challenge comes back. The challenge is bound to that
+        // use, so it cannot be spent at /create-invite without the work.
+        return Ok(Json(state.pow.issue_for_ghost_key()));
+    }
+
+    // Allow-listed addresses are not counted against their network.
+    let counted = !admission.allowed;
+    let difficulty = state
+        .difficulty
+        .challenge_difficulty(
+            counted.then_some(client_ip),
             state.global_bucket.current(),
             state.global_bucket.limit(),
-            client_ip
-        );
-        return Err(invite_error(
-            StatusCode::TOO_MANY_REQUESTS,
-            "Too many invite requests right now. Please try again shortly.",
-            retry_after,
-        ));
+        )
+        .saturating_add(admission.extra_bits);
+
+    if !counted {
+        return Ok(Json(state.pow.issue(difficulty)));
     }
 
-    match state.rate_limiter.get_retry_after(client_ip) {
+    match room
+        .rate_limiter
+        .get_retry_after_within(client_ip, admission.invites_per_ip)
+    {
         Ok(Some(retry_after)) => {
-            return Err(invite_error(
-                StatusCode::TOO_MANY_REQUESTS,
-                format!(
-                    "Rate limited. You can request up to {MAX_INVITES_PER_WINDOW} invites per 24 hours."
-                ),
-                Some(retry_after),
-            ));
+            return Err(per_ip_limited(&room, &admission, Some(retry_after)));
         }
         Ok(None) => {}
         Err(e) => {
@@ -497,31 +804,52 @@ async fn get_invite_challenge(
         }
     }
 
-    Ok(Json(state.pow.issue(state.global_bucket.current())))
+    metrics::get().challenge_issued(difficulty);
+    Ok(Json(state.pow.issue(difficulty)))
+}
+
+/// Current proof-of-work difficulty and what is driving it.
+async fn pow_status(State(state): State<InviteState>) -> impl IntoResponse {
+    let status = state
+        .difficulty
+        .status(state.global_bucket.current(), state.global_bucket.limit());
+    let mut body = serde_json::to_value(status).unwrap_or_default();
+    body["algorithm"] = serde_json::json!(state.pow.algorithm());
+    Json(body)
 }
 
 async fn create_room_invite(
     State(state): State<InviteState>,
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    ClientIp(client_ip): ClientIp,
+    Query(query): Query<InviteQuery>,
     Json(request): Json<CreateInviteRequest>,
 ) -> Result<Json<CreateInviteResponse>, (StatusCode, Json<InviteErrorResponse>)> {
-    let client_ip = get_client_ip(addr);
-    check_invite_network(&state, client_ip)?;
-    info!("Received create-invite request from IP: {}", client_ip);
+    let admission = check_invite_network(&state, client_ip)?;
+    // Before the proof is consumed, so a mistyped room does not burn it.
+    let room = select_room(&state, &query)?;
+    info!(
+        "Received create-invite request for room {} from IP: {}",
+        room.slug, client_ip
+    );
 
-    let proof_id = match state
-        .pow
-        .verify_and_consume(&request.challenge, request.nonce)
-    {
+    // An address already at its limit would be refused after the proof is
+    // checked; refuse it before spending a hash on it.
+    if !admission.allowed {
+        if let Ok(Some(retry_after)) = room
+            .rate_limiter
+            .get_retry_after_within(client_ip, admission.invites_per_ip)
+        {
+            return Err(per_ip_limited(&room, &admission, Some(retry_after)));
+        }
+    }
+
+    let difficulty = request.challenge.difficulty;
+    let proof_id = match verify_proof(&state, request.challenge, request.nonce).await {
         Ok(id) => id,
         Err(e) => {
-            let status = match e {
-                PowError::Expired => StatusCode::GONE,
-                PowError::Reused => StatusCode::CONFLICT,
-                PowError::Lock => StatusCode::INTERNAL_SERVER_ERROR,
-                _ => StatusCode::BAD_REQUEST,
-            };
+            let status = pow_refusal(&e);
             warn!("Invalid invite proof from {}: {}", client_ip, e);
+            metrics::get().invite_rejected("invalid_proof");
             return Err(invite_error(
                 status,
                 "The invite verification could not be completed. Please try again.",
@@ -530,45 +858,47 @@ async fn create_room_invite(
         }
     };
 
-    // The global bucket is the final safety valve. Acquire it atomically before
-    // recording the per-IP allowance, and refund it on all downstream failures.
+    // The global bucket is the final safety valve. Acquire it and then the
+    // room's atomically before recording the per-IP allowance, and refund
+    // both on all downstream failures.
     if !state.global_bucket.try_acquire() {
         state.pow.release(&proof_id);
-        let retry_after = state.global_bucket.retry_after_seconds();
-        warn!(
-            "Invite refused at acquire: global ceiling reached ({}/{}), IP: {}",
-            state.global_bucket.current(),
-            state.global_bucket.limit(),
-            client_ip
-        );
-        return Err(invite_error(
-            StatusCode::TOO_MANY_REQUESTS,
-            "Too many invite requests right now. Please try again shortly.",
-            retry_after,
-        ));
+        return Err(ceiling_reached(&state.global_bucket, "global", client_ip));
+    }
+    if !room.bucket.try_acquire() {
+        state.global_bucket.release();
+        state.pow.release(&proof_id);
+        return Err(ceiling_reached(&room.bucket, &room.slug, client_ip));
     }
+    let refund = || {
+        room.bucket.release();
+        state.global_bucket.release();
+        state.pow.release(&proof_id);
+    };
 
-    match state.rate_limiter.check_and_record(client_ip) {
+    let recorded = if admission.allowed {
+        room.rate_limiter.record_exempt(client_ip).map(|()| true)
+    } else {
+        room.rate_limiter
+            .check_and_record_within(client_ip, admission.invites_per_ip)
+    };
+    match recorded {
         Ok(true) => {}
         Ok(false) => {
-            state.global_bucket.release();
-            state.pow.release(&proof_id);
-            let retry_after = state.rate_limiter.get_retry_after(client_ip).ok().flatten();
+            refund();
+            let retry_after = room
+                .rate_limiter
+                .get_retry_after_within(client_ip, admission.invites_per_ip)
+                .ok()
+                .flatten();
             info!(
-                "Rate limited IP: {}, retry_after: {:?}",
-                client_ip, retry_after
+                "Rate limited IP: {} in room {}, retry_after: {:?}",
+                client_ip, room.slug, retry_after
             );
-            return Err(invite_error(
-                StatusCode::TOO_MANY_REQUESTS,
-                format!(
-                    "Rate limited. You can request up to {MAX_INVITES_PER_WINDOW} invites per 24 hours."
-                ),
-                retry_after,
-            ));
+            return Err(per_ip_limited(&room, &admission, retry_after));
         }
         Err(e) => {
-            state.global_bucket.release();
-            state.pow.release(&proof_id);
+            refund();
             error!("Rate limiter error: {:?}", e);
             return Err(invite_error(
                 StatusCode::INTERNAL_SERVER_ERROR,
@@ -578,33 +908,269 @@ async fn create_room_invite(
         }
     }
 
-    match invite::create_invitation(&state.room_owner_vk, &state.inviter_signing_key) {
-        Ok(created) => {
-            info!(
-                "Generated invite for IP: {} member_id={}",
-                client_ip, created.member_id
+    let internal_error = || {
+        refund();
+        invite_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to generate invite. Please try again later.",
+            None,
+        )
+    };
+    let created = invite::create_invitation(&room.room_owner_vk, &room.inviter_signing_key)
+        .map_err(|e| {
+            error!("Failed to generate invite: {:?}", e);
+            internal_error()
+        })?;
+    if let Err(e) = record_invite(
+        &state,
+        &room,
+        client_ip,
+        &created.member_id,
+        Some(difficulty),
+        None,
+    ) {
+        error!(
+            "Failed to record invite in the ledger, discarding it: {}",
+            e
+        );
+        return Err(internal_error());
+    }
+    info!(
+        "Generated invite for IP: {} room={} member_id={}",
+        client_ip, room.slug, created.member_id
+    );
+    metrics::get().invite_issued(&room.slug, "pow");
+    Ok(Json(CreateInviteResponse {
+        invite_code: created.code,
+        room: room.slug.clone(),
+        room_name: room.name.clone(),
+    }))
+}
+
+/// Append an issued invitation to the ledger, if one is configured.
+fn record_invite(
+    state: &InviteState,
+    room: &InviteRoom,
+    client_ip: IpAddr,
+    member_id: &str,
+    difficulty: Option<u8>,
+    ghostkey: Option<String>,
+) -> std::io::Result<()> {
+    let Some(ledger) = &state.ledger else {
+        return Ok(());
+    };
+    let (network_sha256, network_prefix) = ledger.network(client_ip);
+    ledger.record(&InviteRecord {
+        time: chrono::Utc::now(),
+        room: room.slug.clone(),
+        member_id: member_id.to_string(),
+        inviter: MemberId::from(room.inviter_signing_key.verifying_key()).short(),
+        network_sha256,
+        network_prefix,
+        difficulty,
+        ghostkey,
+    })
+}
+
+/// Check a proof on a blocking thread, at most one per core at a time.
+async fn verify_proof(
+    state: &InviteState,
+    challenge: PowChallenge,
+    nonce: u64,
+) -> Result<[u8; 16], PowError> {
+    let _slot = state
+        .pow_slots
+        .acquire()
+        .await
+        .map_err(|_| PowError::Lock)?;
+    let pow = Arc::clone(&state.pow);
+    tokio::task::spawn_blocking(move || pow.verify_and_consume(&challenge, nonce))
+        .await
+        .map_err(|_| PowError::Lock)?
+}
+
+fn pow_refusal(e: &PowError) -> StatusCode {
+    match e {
+        PowError::Expired => StatusCode::GONE,
+        PowError::Reused => StatusCode::CONFLICT,
+        PowError::Lock => StatusCode::INTERNAL_SERVER_ERROR,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+/// Invitation for a caller identified by a ghost key. No per-IP or
+/// proof-of-work checks; the Tor and reputation checks still apply. The
+/// ghost key's own quota takes the place of the per-IP one, the ceilings
+/// still apply, and the ghost key is recorded against the member.
+async fn create_ghostkey_invite(
+    State(state): State<InviteState>,
+    ClientIp(client_ip): ClientIp,
+    Query(query): Query<InviteQuery>,
+    Json(request): Json<GhostkeyInviteRequest>,
+) -> Result<Json<CreateInviteResponse>, (StatusCode, Json<InviteErrorResponse>)> {
+    check_invite_network(&state, client_ip)?;
+    let room = select_room(&state, &query)?;
+    let gate = &state.ghostkeys;
+
+    let ghostkey = ghostkey_auth::verify_invite_request(
+        &request.ghostkey_certificate,
+        &request.signature,
+        &request.challenge.challenge,
+        &gate.master_vk,
+    )
+    .map_err(|e| {
+        warn!("Ghost key invite request from {} refused: {}", client_ip, e);
+        metrics::get().invite_rejected("ghostkey_invalid");
+        invite_error(
+            StatusCode::UNAUTHORIZED,
+            "The ghost key could not be verified.",
+            None,
+        )
+    })?;
+
+    let proof_id = state
+        .pow
+        .consume_without_work(&request.challenge)
+        .map_err(|e| {
+            warn!(
+                "Invalid ghost key invite challenge from {}: {}",
+                client_ip, e
             );
-            Ok(Json(CreateInviteResponse {
-                invite_code: created.code,
-                room_name: state.room_name.clone(),
-            }))
+            metrics::get().invite_rejected("invalid_proof");
+            invite_error(
+                pow_refusal(&e),
+                "The invite verification could not be completed. Please try again.",
+                None,
+            )
+        })?;
+
+    if !state.global_bucket.try_acquire() {
+        state.pow.release(&proof_id);
+        return Err(ceiling_reached(&state.global_bucket, "global", client_ip));
+    }
+    if !room.bucket.try_acquire() {
+        state.global_bucket.release();
+        state.pow.release(&proof_id);
+        return Err(ceiling_reached(&room.bucket, &room.slug, client_ip));
+    }
+    let refund = || {
+        room.bucket.release();
+        state.global_bucket.release();
+        state.pow.release(&proof_id);
+    };
+
+    match gate.quota.check_and_record_key(&ghostkey) {
+        Ok(true) => {}
+        Ok(false) => {
+            refund();
+            let retry_after = gate.quota.get_retry_after_key(&ghostkey).ok().flatten();
+            info!("Ghost key {} reached its invite quota", ghostkey);
+            metrics::get().invite_rejected("ghostkey_quota");
+            return Err(invite_error(
+                StatusCode::TOO_MANY_REQUESTS,
+                format!(
+                    "Rate limited. A ghost key can request up to {} invites per 24 hours.",
+                    gate.quota.max_per_window()
+                ),
+                retry_after,
+            ));
         }
         Err(e) => {
-            error!("Failed to generate invite: {:?}", e);
-            state.global_bucket.release();
-            state.pow.release(&proof_id);
-            Err(invite_error(
+            refund();
+            error!("Ghost key quota error: {:?}", e);
+            return Err(invite_error(
                 StatusCode::INTERNAL_SERVER_ERROR,
-                "Failed to generate invite. Please try again later.",
+                "Internal server error. Please try again later.",
                 None,
-            ))
+            ));
         }
     }
+
+    let internal_error = || {
+        refund();
+        invite_error(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Failed to generate invite. Please try again later.",
+            None,
+        )
+    };
+    let created = invite::create_invitation(&room.room_owner_vk, &room.inviter_signing_key)
+        .map_err(|e| {
+            error!("Failed to generate ghost key invite: {:?}", e);
+            internal_error()
+        })?;
+    // An invitation that cannot be traced back to its ghost key is not
+    // handed out.
+    if let Err(e) = record_invite(
+        &state,
+        &room,
+        client_ip,
+        &created.member_id,
+        None,
+        Some(ghostkey.clone()),
+    ) {
+        error!(
+            "Failed to record invite in the ledger, discarding it: {}",
+            e
+        );
+        return Err(internal_error());
+    }
+    info!(
+        "Generated invite for ghost key {} room={} member_id={}",
+        ghostkey, room.slug, created.member_id
+    );
+    metrics::get().invite_issued(&room.slug, "ghostkey");
+    Ok(Json(CreateInviteResponse {
+        invite_code: created.code,
+        room: room.slug.clone(),
+        room_name: room.name.clone(),
+    }))
+}
+
+/// The rooms invitations are offered for, for pages that let visitors pick.
+async fn list_rooms(State(state): State<InviteState>) -> impl IntoResponse {
+    let rooms: Vec<_> = state
+        .rooms
+        .iter()
+        .map(|room| serde_json::json!({ "slug": room.slug, "name": room.name }))
+        .collect();
+    Json(serde_json::json!({
+        "default": state.default_room,
+        "rooms": rooms,
+    }))
+}
+
+/// Donation tiers (in dollars) that currently have a notary loaded.
+async fn notary_tiers(State(notaries): State<Arc<NotaryStore>>) -> impl IntoResponse {
+    Json(serde_json::json!({ "tiers": notaries.tiers() }))
+}
+
+/// Live issuance counts per notary plus every master-signed checkpoint, for
+/// anyone to compare against published donation totals.
+async fn transparency(
+    State(state): State<DonationState>,
+) -> Result<Json<serde_json::Value>, StatusCode> {
+    let current = state.issuance.current().map_err(|e| {
+        error!("Cannot read issuance log state: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })?;
+    let master_verifying_key = state
+        .checkpoints
+        .master_verifying_key()
+        .to_base64()
+        .map_err(|_| StatusCode::INTERNAL_SERVER_ERROR)?;
+    Ok(Json(serde_json::json!({
+        "master_verifying_key": master_verifying_key,
+        "current": current,
+        "checkpoints": *state.checkpoints.load(),
+    })))
 }
 
-pub fn get_routes() -> Router {
+pub fn get_routes(state: DonationState) -> Router {
     Router::new()
         .route("/", get(index))
+        .route("/notary-tiers", get(notary_tiers))
+        .route("/transparency", get(transparency))
         .route("/message", get(get_message))
         .route("/sign-certificate", post(sign_certificate_route))
         .route("/create-donation", post(create_donation))
@@ -613,6 +1179,7 @@ pub fn get_routes() -> Router {
             "/check-payment-status/:payment_intent_id",
             get(check_payment_status_route),
         )
+        .with_state(state)
         .layer(CorsLayer::permissive())
 }
 
@@ -630,6 +1197,9 @@ pub fn get_invite_routes(state: InviteState) -> Router {
     Router::new()
         .route("/invite-challenge", get(get_invite_challenge))
         .route("/create-invite", post(create_room_invite))
+        .route("/create-invite/ghostkey", post(create_ghostkey_invite))
+        .route("/rooms", get(list_rooms))
+        .route("/pow-status", get(pow_status))
         .with_state(state)
         .layer(cors)
 }
@@ -637,28 +1207,70 @@ pub fn get_invite_routes(state: InviteState) -> Router {
 #[cfg(test)]
 mod invite_handler_tests {
     use super::*;
-    use crate::invite_pow::{valid_proof, PowManager};
+    use crate::access_policy::{PolicyEntry, PolicyFile};
+    use crate::ghostkey_auth::INVITE_SIGNATURE_DOMAIN;
+    use crate::invite_pow::valid_proof;
+    use crate::rate_limit_store::JsonStore;
+    use crate::tor::TorSource;
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use ed25519_dalek::Signer;
+    use ghostkey_lib::ghost_key_certificate::GhostkeyCertificateV1;
+    use ghostkey_lib::notary_certificate::NotaryCertificateV1;
     use tempfile::TempDir;
 
-    fn state_with(dir: &TempDir, exits: &[&str], ceiling: usize) -> InviteState {
-        let cache = dir.path().join("exits.txt");
-        std::fs::write(&cache, exits.join("\n")).unwrap();
+    fn unused_master_vk() -> VerifyingKey {
+        SigningKey::from_bytes(&[9; 32]).verifying_key()
+    }
+
+    fn room(dir: &TempDir, slug: &str, invites_per_hour: Option<usize>) -> RoomSettings {
         let mut seed = [0u8; 32];
         seed[0] = 7;
+        seed[1] = slug.len() as u8;
         let signing_key = SigningKey::from_bytes(&seed);
-        InviteState {
-            rate_limiter: Arc::new(RateLimiter::new(dir.path().join("rl.json"), 24)),
-            global_bucket: Arc::new(AggregateBucket::new(ceiling, 60)),
-            pow: Arc::new(PowManager::new(4)),
-            tor_exits: Arc::new(TorExitList::new(Some(cache))),
+        RoomSettings {
+            slug: slug.to_string(),
+            name: format!("Room {slug}"),
             room_owner_vk: signing_key.verifying_key(),
             inviter_signing_key: signing_key,
-            room_name: "Test Room".to_string(),
+            rate_limit_store: Arc::new(JsonStore::new(dir.path().join(format!("rl.{slug}.json")))),
+            invites_per_ip: None,
+            invites_per_hour,
+            prefix_limit: None,
+        }
+    }
+
+    fn state_with_rooms(
+        dir: &TempDir,
+        exits: &[&str],
+        ceiling: usize,
+        rooms: Vec<RoomSettings>,
+    ) -> InviteState {
+        let cache = dir.path().join("exits.txt");
+        std::fs::write(&cache, exits.join("\n")).unwrap();
+        let default_room = rooms[0].slug.clone();
+        InviteState::new(
+            rooms,
+            default_room,
+            TorExitList::new(Some(cache), TorSource::default()),
+            Some(ceiling),
+            4,
+            gate(dir, unused_master_vk(), 2),
+        )
+    }
+
+    fn gate(dir: &TempDir, master_vk: VerifyingKey, quota: usize) -> GhostkeyGate {
+        GhostkeyGate {
+            master_vk,
+            quota: RateLimiter::with_limit(dir.path().join("gk.json"), 24, quota),
         }
     }
 
-    fn addr(ip: &str) -> SocketAddr {
-        SocketAddr::new(ip.parse().unwrap(), 12345)
+    fn state_with(dir: &TempDir, exits: &[&str], ceiling: usize) -> InviteState {
+        state_with_rooms(dir, exits, ceiling, vec![room(dir, "test", None)])
+    }
+
+    fn client(ip: &str) -> ClientIp {
+        ClientIp(ip.parse().unwrap())
     }
 
     fn solve(challenge: PowChallenge) -> CreateInviteRequest {
@@ -672,21 +1284,53 @@ mod invite_handler_tests {
         CreateInviteRequest { challenge, nonce }
     }
 
-    async fn challenge(state: &InviteState, ip: &str) -> Result<PowChallenge, StatusCode> {
-        get_invite_challenge(State(state.clone()), ConnectInfo(addr(ip)))
+    fn in_room(slug: Option<&str>) -> Query<InviteQuery> {
+        Query(InviteQuery {
+            room: slug.map(str::to_string),
+            ghostkey: false,
+        })
+    }
+
+    async fn challenge_in(
+        state: &InviteState,
+        ip: &str,
+        room: Option<&str>,
+    ) -> Result<PowChallenge, StatusCode> {
+        get_invite_challenge(State(state.clone()), client(ip), in_room(room))
             .await
             .map(|response| response.0.challenge)
             .map_err(|(status, _)| status)
     }
 
+    async fn challenge(state: &InviteState, ip: &str) -> Result<PowChallenge, StatusCode> {
+        challenge_in(state, ip, None).await
+    }
+
+    async fn request_in(
+        state: &InviteState,
+        ip: &str,
+        room: Option<&str>,
+        request: CreateInviteRequest,
+    ) -> Result<CreateInviteResponse, StatusCode> {
+        create_room_invite(
+            State(state.clone()),
+            client(ip),
+            in_room(room),
+            Json(request),
+        )
+        .await
+        .map(|response| response.0)
+        .map_err(|(code, _)| code)
+    }
+
     async fn request_with(
         state: &InviteState,
         ip: &str,
         request: CreateInviteRequest,
     ) -> StatusCode {
-        match create_room_invite(State(state.clone()), ConnectInfo(addr(ip)), Json(request)).await {
+        match request_in(state, ip, None, request).await {
             Ok(_) => StatusCode::OK,
-            Err((code, _)) => code,
+            Err(code) => code,
         }
     }
 
@@ -695,6 +1339,106 @@ mod invite_handler_tests {
         request_with(state, ip, proof).await
     }
 
+    #[tokio::test]
+    async fn busy_networks_get_harder_challenges_and_show_in_status() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with(&dir, &["185.220.101.1"], 100);
+        let mut last = 0;
+        for host in 1..=40 {
+            last = challenge(&state, &format!("203.0.113.{host}"))
+                .await
+                .unwrap()
+                .difficulty;
+        }
+        // 40 requests against 10 free a minute is two doublings.
+        assert_eq!(last, 6);
+        assert_eq!(challenge(&state, "192.0.2.1").await.unwrap().difficulty, 4);
+
+        let status = pow_status(State(state.clone())).await.into_response();
+        let body = axum::body::to_bytes(status.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        let status: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(status["algorithm"], "sha256-leading-zero-bits-v1");
+        assert_eq!(status["difficulty"], 4);
+        assert_eq!(status["busy_networks"], 1);
+        assert_eq!(status["invites_per_hour_ceiling"], 100);
+    }
+
+    #[tokio::test]
+    async fn issued_invites_are_recorded_in_the_ledger() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("invite_ledger.jsonl");
+        let mut state = state_with(&dir, &["185.220.101.1"], 100);
+        state.ledger = Some(Arc::new(InviteLedger::open(&path).unwrap()));
+        let proof = solve(challenge(&state, "203.0.113.9").await.unwrap());
+        let difficulty = proof.challenge.difficulty;
+        assert_eq!(
+            request_with(&state, "203.0.113.9", proof).await,
+            StatusCode::OK
+        );
+
+        let since = chrono::Utc::now() - chrono::Duration::minutes(1);
+        let records = crate::invite_ledger::read_range(&path, since, None, None).unwrap();
+        assert_eq!(records.len(), 1);
+        let record = &records[0];
+        assert_eq!(record.room, "test");
+        assert_eq!(record.member_id.len(), 8);
+        assert_eq!(record.difficulty, Some(difficulty));
+        assert_eq!(record.ghostkey, None);
+        assert_eq!(
+            (record.network_sha256.clone(), record.network_prefix),
+            state
+                .ledger
+                .as_ref()
+                .unwrap()
+                .network("203.0.113.200".parse().unwrap())
+        );
+    }
+
+    #[tokio::test]
+    async fn reputation_sources_block_add_bits_and_lower_quotas() {
+        use crate::reputation::{ReputationAction, SourceLocation, SourceSettings};
+
+        let dir = tempfile::tempdir().unwrap();
+        let source = |name: &str, range: &str, action| {
+            let path = dir.path().join(format!("{name}.txt"));
+            std::fs::write(&path, format!("{range}\n")).unwrap();
+            SourceSettings {
+                name: name.to_string(),
+                location: SourceLocation::File(path),
+                action,
+                cache: None,
+                min_entries: 1,
+                fail_closed: false,
+                refresh: crate::reputation::DEFAULT_REFRESH,
+            }
+        };
+        let mut state = state_with(&dir, &["185.220.101.1"], 100);
+        state.reputation = Arc::new(NetworkReputation::new(vec![
+            source("vpn", "203.0.113.0/24", ReputationAction::ExtraBits(3)),
+            source("hosting", "198.51.100.0/24", ReputationAction::Quota(1)),
+            source("bad", "192.0.2.0/24", ReputationAction::Block),
+        ]));
+
+        assert_eq!(challenge(&state, "100.64.0.1").await.unwrap().difficulty, 4);
+        assert_eq!(
+            challenge(&state, "203.0.113.5").await.unwrap().difficulty,
+            7
+        );
+        assert_eq!(
+            challenge(&state, "192.0.2.5").await.unwrap_err(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(request(&state, "198.51.100.5").await, StatusCode::OK);
+        assert_eq!(
+            challenge(&state, "198.51.100.5").await.unwrap_err(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(request(&state, "100.64.0.1").await, StatusCode::OK);
+        assert_eq!(request(&state, "100.64.0.1").await, StatusCode::OK);
+    }
+
     #[tokio::test]
     async fn tor_is_blocked_before_work_is_issued() {
         let dir = tempfile::tempdir().unwrap();
@@ -706,6 +1450,64 @@ mod invite_handler_tests {
         assert_eq!(state.global_bucket.current(), 0);
     }
 
+    #[tokio::test]
+    async fn access_policy_denies_before_work_and_allows_past_ip_limits() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("access_policy.toml");
+        let mut file = PolicyFile::default();
+        file.deny
+            .push(PolicyEntry::new("198.51.100.0/24", false, None).unwrap());
+        file.allow
+            .push(PolicyEntry::new("185.220.101.1", true, None).unwrap());
+        file.write(&path).unwrap();
+        let mut state = state_with(&dir, &["185.220.101.1"], 100);
+        state.policy = Arc::new(PolicyStore::load(&path).unwrap());
+
+        assert_eq!(
+            challenge(&state, "198.51.100.7").await.unwrap_err(),
+            StatusCode::FORBIDDEN
+        );
+        let ghostkey = Query(InviteQuery {
+            room: None,
+            ghostkey: true,
+        });
+        assert!(
+            get_invite_challenge(State(state.clone()), client("198.51.100.7"), ghostkey)
+                .await
+                .is_err(),
+            "a ban covers the ghost key path too"
+        );
+
+        // Allowed despite being a listed Tor exit, and past the per-IP limit;
+        // the proof of work is still required.
+        for _ in 0..=MAX_INVITES_PER_WINDOW {
+            assert_eq!(request(&state, "185.220.101.1").await, StatusCode::OK);
+        }
+        let recorded = state
+            .room(None)
+            .unwrap()
+            .rate_limiter
+            .recent_events(GLOBAL_WINDOW_MINUTES)
+            .unwrap();
+        assert_eq!(
+            recorded.len(),
+            MAX_INVITES_PER_WINDOW + 1,
+            "exempt, but still recorded"
+        );
+        let mut unsolved = solve(challenge(&state, "185.220.101.1").await.unwrap());
+        let id: [u8; 16] = hex::decode(&unsolved.challenge.challenge)
+            .unwrap()
+            .try_into()
+            .unwrap();
+        unsolved.nonce = (0..u64::MAX)
+            .find(|nonce| !valid_proof(&id, *nonce, unsolved.challenge.difficulty))
+            .unwrap();
+        assert_ne!(
+            request_with(&state, "185.220.101.1", unsolved).await,
+            StatusCode::OK
+        );
+    }
+
     #[tokio::test]
     async fn missing_tor_list_fails_closed() {
         let dir = tempfile::tempdir().unwrap();
@@ -760,7 +1562,7 @@ mod invite_handler_tests {
         for _ in 0..MAX_INVITES_PER_WINDOW {
             assert_eq!(request(&state, "203.0.113.1").await, StatusCode::OK);
         }
-        let proof = solve(state.pow.issue(state.global_bucket.current()).challenge);
+        let proof = solve(state.pow.issue(4).challenge);
         assert_eq!(
             request_with(&state, "203.0.113.1", proof).await,
             StatusCode::TOO_MANY_REQUESTS
@@ -785,16 +1587,285 @@ mod invite_handler_tests {
             .to_string(),
         )
         .unwrap();
-        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let mut room = room(&dir, "test", Some(50));
+        room.rate_limit_store = Arc::new(JsonStore::new(dir.path().join("rl.json")));
         let state = InviteState::new(
-            dir.path().join("rl.json"),
-            Some(cache),
+            vec![room],
+            "test".to_string(),
+            TorExitList::new(Some(cache), TorSource::default()),
             Some(200),
             4,
-            signing_key.verifying_key(),
-            signing_key,
-            "Test Room".to_string(),
+            gate(&dir, unused_master_vk(), 2),
+        );
+        assert_eq!(state.global_bucket.current(), 1);
+        assert_eq!(state.rooms[0].bucket.current(), 1);
+    }
+
+    #[tokio::test]
+    async fn rooms_are_selected_by_slug() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_rooms(
+            &dir,
+            &["185.220.101.1"],
+            100,
+            vec![room(&dir, "main", None), room(&dir, "dev", None)],
+        );
+
+        let proof = solve(
+            challenge_in(&state, "203.0.113.1", Some("dev"))
+                .await
+                .unwrap(),
+        );
+        let created = request_in(&state, "203.0.113.1", Some("dev"), proof)
+            .await
+            .unwrap();
+        assert_eq!(created.room, "dev");
+        assert_eq!(created.room_name, "Room dev");
+        let code = bs58::decode(&created.invite_code).into_vec().unwrap();
+        let invitation: invite::Invitation = ciborium::de::from_reader(&code[..]).unwrap();
+        assert_eq!(
+            invitation.room,
+            state.room(Some("dev")).unwrap().room_owner_vk
+        );
+
+        let proof = solve(challenge(&state, "203.0.113.1").await.unwrap());
+        let created = request_in(&state, "203.0.113.1", None, proof)
+            .await
+            .unwrap();
+        assert_eq!(created.room, "main");
+
+        assert_eq!(
+            challenge_in(&state, "203.0.113.1", Some("nope"))
+                .await
+                .unwrap_err(),
+            StatusCode::NOT_FOUND
+        );
+    }
+
+    #[tokio::test]
+    async fn room_ceiling_is_separate_but_global_ceiling_is_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with_rooms(
+            &dir,
+            &["185.220.101.1"],
+            3,
+            vec![room(&dir, "main", None), room(&dir, "busy", Some(1))],
+        );
+
+        let proof = solve(
+            challenge_in(&state, "203.0.113.1", Some("busy"))
+                .await
+                .unwrap(),
+        );
+        assert!(request_in(&state, "203.0.113.1", Some("busy"), proof)
+            .await
+            .is_ok());
+        assert_eq!(
+            challenge_in(&state, "203.0.113.2", Some("busy"))
+                .await
+                .unwrap_err(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        // A proof obtained elsewhere still meets the room ceiling at acquire,
+        // and the refused request gives its global slot back.
+        let proof = solve(challenge(&state, "203.0.113.2").await.unwrap());
+        assert_eq!(
+            request_in(&state, "203.0.113.2", Some("busy"), proof)
+                .await
+                .err(),
+            Some(StatusCode::TOO_MANY_REQUESTS)
         );
         assert_eq!(state.global_bucket.current(), 1);
+
+        for i in 3..=4 {
+            assert_eq!(
+                request(&state, &format!("203.0.113.{i}")).await,
+                StatusCode::OK
+            );
+        }
+        assert_eq!(
+            challenge(&state, "203.0.113.5").await.unwrap_err(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[tokio::test]
+    async fn per_ip_allowance_is_per_room() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut strict = room(&dir, "strict", None);
+        strict.invites_per_ip = Some(1);
+        let state = state_with_rooms(
+            &dir,
+            &["185.220.101.1"],
+            100,
+            vec![room(&dir, "main", None), strict],
+        );
+
+        let proof = solve(
+            challenge_in(&state, "203.0.113.1", Some("strict"))
+                .await
+                .unwrap(),
+        );
+        assert!(request_in(&state, "203.0.113.1", Some("strict"), proof)
+            .await
+            .is_ok());
+        assert_eq!(
+            challenge_in(&state, "203.0.113.1", Some("strict"))
+                .await
+                .unwrap_err(),
+            StatusCode::TOO_MANY_REQUESTS
+        );
+        assert_eq!(request(&state, "203.0.113.1").await, StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn ghost_key_challenges_are_refused_at_create_invite() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = state_with(&dir, &["185.220.101.1"], 100);
+        let unworked = get_invite_challenge(
+            State(state.clone()),
+            client("203.0.113.1"),
+            Query(InviteQuery {
+                room: None,
+                ghostkey: true,
+            }),
+        )
+        .await
+        .map(|response| response.0.challenge)
+        .map_err(|(status, _)| status)
+        .unwrap();
+        assert_eq!(
+            request_with(&state, "203.0.113.1", solve(unworked)).await,
+            StatusCode::BAD_REQUEST
+        );
+        assert_eq!(state.global_bucket.current(), 0);
+    }
+
+    async fn ghostkey_request(
+        state: &InviteState,
+        ip: &str,
+        request: GhostkeyInviteRequest,
+    ) -> Result<CreateInviteResponse, StatusCode> {
+        create_ghostkey_invite(
+            State(state.clone()),
+            client(ip),
+            in_room(None),
+            Json(request),
+        )
+        .await
+        .map(|response| response.0)
+        .map_err(|(code, _)| code)
+    }
+
+    #[tokio::test]
+    async fn ghost_keys_replace_ip_limits_with_their_own_quota() {
+        let dir = tempfile::tempdir().unwrap();
+        let master = SigningKey::from_bytes(&[3; 32]);
+        let (notary, notary_sk) =
+            NotaryCertificateV1::new(&master, &"Test notary".to_string()).unwrap();
+        let (certificate, ghost_sk) = GhostkeyCertificateV1::new(&notary, &notary_sk);
+        let mut state = state_with(&dir, &["185.220.101.1"], 100);
+        state.ghostkeys = Arc::new(gate(&dir, master.verifying_key(), 2));
+        let ledger = dir.path().join("invite_ledger.jsonl");
+        state.ledger = Some(Arc::new(InviteLedger::open(&ledger).unwrap()));
+
+        let tor_exit = "185.220.101.1";
+        let signed = |challenge: PowChallenge, key: &SigningKey| {
+            let mut message = INVITE_SIGNATURE_DOMAIN.to_vec();
+            message.extend_from_slice(challenge.challenge.as_bytes());
+            GhostkeyInviteRequest {
+                challenge,
+                ghostkey_certificate: certificate.to_base64().unwrap(),
+                signature: BASE64.encode(key.sign(&message).to_bytes()),
+            }
+        };
+        let ghostkey_challenge = |ip: &'static str| {
+            let state = state.clone();
+            async move {
+                get_invite_challenge(
+                    State(state),
+                    client(ip),
+                    Query(InviteQuery {
+                        room: None,
+                        ghostkey: true,
+                    }),
+                )
+                .await
+                .map(|response| response.0.challenge)
+                .map_err(|(status, _)| status)
+            }
+        };
+
+        // A ghost key does not get a Tor exit past the network checks.
+        assert_eq!(
+            ghostkey_challenge(tor_exit).await.unwrap_err(),
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            ghostkey_request(
+                &state,
+                tor_exit,
+                signed(ghostkey_challenge("203.0.113.8").await.unwrap(), &ghost_sk)
+            )
+            .await
+            .err(),
+            Some(StatusCode::FORBIDDEN)
+        );
+
+        // Otherwise no work is needed.
+        let created = ghostkey_request(
+            &state,
+            "203.0.113.8",
+            signed(ghostkey_challenge("203.0.113.8").await.unwrap(), &ghost_sk),
+        )
+        .await
+        .unwrap();
+        let since = chrono::Utc::now() - chrono::Duration::minutes(1);
+        let records = crate::invite_ledger::read_range(&ledger, since, None, None).unwrap();
+        let code = bs58::decode(&created.invite_code).into_vec().unwrap();
+        let invitation: invite::Invitation = ciborium::de::from_reader(&code[..]).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(
+            records[0].member_id,
+            invite::MemberId::from(invitation.invitee.member.member_vk).short()
+        );
+        assert_eq!(
+            records[0].ghostkey,
+            Some(ghostkey_auth::fingerprint(&certificate.verifying_key))
+        );
+        assert_eq!(records[0].difficulty, None);
+
+        // Signed by the wrong key.
+        let impostor = SigningKey::from_bytes(&[4; 32]);
+        assert_eq!(
+            ghostkey_request(
+                &state,
+                "203.0.113.8",
+                signed(ghostkey_challenge("203.0.113.8").await.unwrap(), &impostor)
+            )
+            .await
+            .err(),
+            Some(StatusCode::UNAUTHORIZED)
+        );
+
+        // The quota of 2 is per ghost key, whatever address it comes from.
+        assert!(ghostkey_request(
+            &state,
+            "203.0.113.9",
+            signed(ghostkey_challenge("203.0.113.9").await.unwrap(), &ghost_sk)
+        )
+        .await
+        .is_ok());
+        assert_eq!(
+            ghostkey_request(
+                &state,
+                "203.0.113.10",
+                signed(ghostkey_challenge("203.0.113.10").await.unwrap(), &ghost_sk)
+            )
+            .await
+            .err(),
+            Some(StatusCode::TOO_MANY_REQUESTS)
+        );
+        assert_eq!(state.global_bucket.current(), 2);
     }
 }
@@ -0,0 +1,210 @@
+//! Stopping on SIGTERM or SIGINT without cutting requests off.
+//!
+//! A deploy sends SIGTERM. The listeners stop accepting at once and requests
+//! already running get the grace period to finish: a `/sign-certificate`
+//! killed after its PaymentIntent is marked would leave the donor charged
+//! with no certificate. Connections still open at the deadline are dropped,
+//! as they are at once on a second signal. Then [`flush_stores`] syncs what
+//! the requests wrote and `main` returns, which closes the rate-limit
+//! databases cleanly.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use axum_server::Handle;
+use log::{error, info, warn};
+use tokio::signal::unix::{self, SignalKind};
+use tokio::sync::watch;
+
+use crate::issuance_log::IssuanceLog;
+use crate::routes::InviteState;
+
+pub const DEFAULT_GRACE: Duration = Duration::from_secs(30);
+
+/// The name of the next SIGTERM or SIGINT, once it arrives.
+pub async fn signal() -> &'static str {
+    let mut term = match unix::signal(SignalKind::terminate()) {
+        Ok(term) => term,
+        Err(e) => {
+            error!("Cannot listen for SIGTERM: {e}");
+            let _ = tokio::signal::ctrl_c().await;
+            return "SIGINT";
+        }
+    };
+    tokio::select! {
+        _ = term.recv() => "SIGTERM",
+        _ = tokio::signal::ctrl_c() => "SIGINT",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Serving,
+    Draining,
+    Closing,
+}
+
+/// Stops every listener together. axum-server listeners follow [`Handle`];
+/// the Unix socket ones, which gkapi serves itself, wait on
+/// [`draining`](Self::draining) and [`closing`](Self::closing).
+#[derive(Clone)]
+pub struct Drain {
+    handle: Handle,
+    phase: Arc<watch::Sender<Phase>>,
+}
+
+impl Default for Drain {
+    fn default() -> Self {
+        Self {
+            handle: Handle::new(),
+            phase: Arc::new(watch::channel(Phase::Serving).0),
+        }
+    }
+}
+
+impl Drain {
+    pub fn handle(&self) -> Handle {
+        self.handle.clone()
+    }
+
+    /// Stop accepting, and close what is still open after `grace`. Only the
+    /// first call counts.
+    pub fn graceful(&self, grace: Duration) {
+        let started = self.phase.send_if_modified(|phase| {
+            let serving = *phase == Phase::Serving;
+            if serving {
+                *phase = Phase::Draining;
+            }
+            serving
+        });
+        if !started {
+            return;
+        }
+        self.handle.graceful_shutdown(Some(grace));
+        let phase = Arc::clone(&self.phase);
+        tokio::spawn(async move {
+            tokio::time::sleep(grace).await;
+            phase.send_replace(Phase::Closing);
+        });
+    }
+
+    /// Close every connection now.
+    pub fn now(&self) {
+        self.phase.send_replace(Phase::Closing);
+        self.handle.shutdown();
+    }
+
+    /// Resolves once listeners should stop accepting.
+    pub async fn draining(&self) {
+        self.wait(|phase| *phase != Phase::Serving).await;
+    }
+
+    /// Resolves once open connections should be dropped.
+    pub async fn closing(&self) {
+        self.wait(|phase| *phase == Phase::Closing).await;
+    }
+
+    async fn wait(&self, reached: impl FnMut(&Phase) -> bool) {
+        // The sender lives in `self`, so the channel cannot close.
+        let _ = self.phase.subscribe().wait_for(reached).await;
+    }
+}
+
+/// Drain the listeners on the first signal, and close their connections on
+/// the second.
+pub fn drain_on_signal(drain: Drain, grace: Duration) {
+    tokio::spawn(async move {
+        let name = signal().await;
+        info!(
+            "{name} received: no longer accepting connections, waiting up to {}s for requests in flight",
+            grace.as_secs()
+        );
+        drain.graceful(grace);
+        let name = signal().await;
+        warn!("{name} received again: closing connections now");
+        drain.now();
+    });
+}
+
+/// Sync every store requests write to. Each failure is logged; false if
+/// there was one.
+pub fn flush_stores(issuance: &IssuanceLog, invites: Option<&InviteState>) -> bool {
+    let mut results = vec![(
+        "issuance log".to_string(),
+        issuance.flush().map_err(|e| e.to_string()),
+    )];
+    if let Some(state) = invites {
+        for room in state.rooms.iter() {
+            results.push((
+                format!("rate limits of room {}", room.slug),
+                room.rate_limiter.flush().map_err(|e| e.to_string()),
+            ));
+        }
+        results.push((
+            "ghost key quota".to_string(),
+            state.ghostkeys.quota.flush().map_err(|e| e.to_string()),
+        ));
+        results.push((
+            "used challenge store".to_string(),
+            state.pow.flush().map_err(|e| e.to_string()),
+        ));
+        if let Some(ledger) = &state.ledger {
+            results.push((
+                "invite ledger".to_string(),
+                ledger.flush().map_err(|e| e.to_string()),
+            ));
+        }
+    }
+    let mut flushed = true;
+    for (name, result) in results {
+        if let Err(e) = result {
+            error!("Failed to flush the {name}: {e}");
+            flushed = false;
+        }
+    }
+    flushed
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+
+    use axum::{routing::get, Router};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn a_request_in_flight_finishes_after_shutdown_starts() {
+        let app = Router::new().route(
+            "/slow",
+            get(|| async {
+                tokio::time::sleep(Duration::from_millis(300)).await;
+                "done"
+            }),
+        );
+        let handle = Handle::new();
+        let server = tokio::spawn(
+            axum_server::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+                .handle(handle.clone())
+                .serve(app.into_make_service()),
+        );
+        let addr = handle.listening().await.unwrap();
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream
+            .write_all(b"GET /slow HTTP/1.1\r\nhost: gkapi\r\n\r\n")
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        handle.graceful_shutdown(Some(Duration::from_secs(5)));
+
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("done"), "{response}");
+        server.await.unwrap().unwrap();
+        assert!(TcpStream::connect(addr).await.is_err());
+    }
+}
@@ -0,0 +1,375 @@
+//! Per-route request throttling and body-size caps for the whole API.
+//!
+//! The invite routes count invitations, but nothing stopped a client from
+//! calling `/create-donation` or `/check-payment-status` in a loop, spending
+//! the Stripe API quota and holding `payment_claim` locks. [`limit`] runs in
+//! front of every route and gives each client a token bucket per route:
+//! `burst` requests at once, refilled at `per_minute`. An empty bucket is a
+//! 429 with `Retry-After`, before the handler runs.
+//!
+//! Clients are keyed on [`ClientIp`], so a trusted proxy's clients are told
+//! apart and nobody else can pick their own key. IPv6 clients are keyed on
+//! their /64, which one host can otherwise rotate through at will.
+//!
+//! Every request body is also capped, by the route's `max_body_bytes` or the
+//! default: a declared length over the cap is refused with 413 at once, and a
+//! body that runs past it is cut off while it is read.
+
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use axum::{
+    body::Body,
+    extract::{MatchedPath, Request, State},
+    http::{
+        header::{CONTENT_LENGTH, RETRY_AFTER},
+        HeaderValue, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Json, Response},
+};
+use http_body_util::Limited;
+use ipnet::Ipv6Net;
+use log::warn;
+
+use crate::client_ip::ClientIp;
+
+/// Bodies on any route are capped at this unless configured otherwise.
+pub const DEFAULT_MAX_BODY_BYTES: usize = 64 * 1024;
+
+/// Bucket count past which idle clients are dropped.
+const PRUNE_AT: usize = 4096;
+
+/// The limits for one route.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RouteLimit {
+    /// Refill rate. 0 turns the bucket off.
+    pub per_minute: u32,
+    /// Requests a client can make at once.
+    pub burst: u32,
+    /// Overrides the default body cap.
+    pub max_body_bytes: Option<usize>,
+}
+
+/// Buckets for the routes that reach Stripe or the notary keys, and for
+/// `/create-invite`, where every proof costs a hash to check. The invite
+/// routes also have their own, much tighter, invitation limits.
+pub fn default_routes() -> HashMap<String, RouteLimit> {
+    let limit = |per_minute, burst| RouteLimit {
+        per_minute,
+        burst,
+        max_body_bytes: None,
+    };
+    HashMap::from([
+        ("/sign-certificate".to_string(), limit(10, 5)),
+        ("/create-invite".to_string(), limit(6, 3)),
+        ("/create-donation".to_string(), limit(6, 3)),
+        ("/update-donation".to_string(), limit(12, 6)),
+        (
+            "/check-payment-status/:payment_intent_id".to_string(),
+            limit(30, 10),
+        ),
+    ])
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+struct RouteState {
+    limit: RouteLimit,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+    prune_at: Mutex<usize>,
+}
+
+impl RouteState {
+    fn new(limit: RouteLimit) -> Self {
+        Self {
+            limit,
+            buckets: Mutex::new(HashMap::new()),
+            prune_at: Mutex::new(PRUNE_AT),
+        }
+    }
+
+    fn rate_per_second(&self) -> f64 {
+        f64::from(self.limit.per_minute) / 60.0
+    }
+
+    /// Take a token for `key`, or say how long until one is free.
+    fn take(&self, key: IpAddr, now: Instant) -> Result<(), Duration> {
+        if self.limit.per_minute == 0 {
+            return Ok(());
+        }
+        let burst = f64::from(self.limit.burst.max(1));
+        let rate = self.rate_per_second();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|e| e.into_inner());
+        let bucket = buckets.entry(key).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+        let result = if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        };
+
+        // A bucket that has refilled is the same as no bucket.
+        let mut prune_at = self.prune_at.lock().unwrap_or_else(|e| e.into_inner());
+        if buckets.len() >= *prune_at {
+            buckets.retain(|_, bucket| {
+                let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+                bucket.tokens + elapsed * rate < burst
+            });
+            *prune_at = (buckets.len() * 2).max(PRUNE_AT);
+        }
+        result
+    }
+}
+
+/// The throttle shared by every request.
+pub struct Throttle {
+    routes: HashMap<String, RouteState>,
+    max_body_bytes: usize,
+}
+
+impl Default for Throttle {
+    fn default() -> Self {
+        Self::new(default_routes(), DEFAULT_MAX_BODY_BYTES)
+    }
+}
+
+impl Throttle {
+    /// `routes` is keyed on the route as written in the router, such as
+    /// `/check-payment-status/:payment_intent_id`.
+    pub fn new(routes: HashMap<String, RouteLimit>, max_body_bytes: usize) -> Self {
+        Self {
+            routes: routes
+                .into_iter()
+                .map(|(path, limit)| (path, RouteState::new(limit)))
+                .collect(),
+            max_body_bytes,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn route_limit(&self, route: &str) -> Option<RouteLimit> {
+        self.routes.get(route).map(|state| state.limit)
+    }
+
+    fn max_body_bytes(&self, route: Option<&str>) -> usize {
+        route
+            .and_then(|route| self.routes.get(route))
+            .and_then(|state| state.limit.max_body_bytes)
+            .unwrap_or(self.max_body_bytes)
+    }
+
+    /// Take a token for `client_ip` on `route`. A route without a limit
+    /// always has one.
+    fn check(&self, route: &str, client_ip: IpAddr, now: Instant) -> Result<(), Duration> {
+        match self.routes.get(route) {
+            Some(state) => state.take(bucket_key(client_ip), now),
+            None => Ok(()),
+        }
+    }
+}
+
+/// IPv6 clients share a bucket with the rest of their /64.
+fn bucket_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => Ipv6Net::new(v6, 64)
+            .map(|net| IpAddr::V6(net.network()))
+            .unwrap_or(ip),
+    }
+}
+
+fn refusal(status: StatusCode, message: &str, retry_after: Option<u64>) -> Response {
+    let mut response = (
+        status,
+        Json(serde_json::json!({
+            "error": message,
+            "retry_after_seconds": retry_after,
+        })),
+    )
+        .into_response();
+    if let Some(seconds) = retry_after {
+        response
+            .headers_mut()
+            .insert(RETRY_AFTER, HeaderValue::from(seconds));
+    }
+    response
+}
+
+/// Middleware for `axum::middleware::from_fn_with_state`. Must sit inside the
+/// layer that supplies the proxy trust, so [`ClientIp`] resolves as the
+/// handlers do.
+pub async fn limit(
+    State(throttle): State<Arc<Throttle>>,
+    ClientIp(client_ip): ClientIp,
+    route: Option<MatchedPath>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let route = route.as_ref().map(MatchedPath::as_str);
+    if let Some(route) = route {
+        if let Err(wait) = throttle.check(route, client_ip, Instant::now()) {
+            // Whole seconds, rounded up: a client that waits exactly this
+            // long gets through.
+            let seconds = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            warn!("Throttled {} from {} for {}s", route, client_ip, seconds);
+            return refusal(
+                StatusCode::TOO_MANY_REQUESTS,
+                "Too many requests. Please slow down.",
+                Some(seconds),
+            );
+        }
+    }
+
+    let max = throttle.max_body_bytes(route);
+    let declared = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if declared.is_some_and(|len| len > max as u64) {
+        return refusal(
+            StatusCode::PAYLOAD_TOO_LARGE,
+            "Request body too large.",
+            None,
+        );
+    }
+    // Extractors answer 413 when the limit cuts a body short.
+    let request = request.map(|body| Body::new(Limited::new(body, max)));
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        extract::ConnectInfo,
+        middleware,
+        routing::{get, post},
+        Router,
+    };
+    use std::net::SocketAddr;
+    use tower::ServiceExt;
+
+    fn throttle(per_minute: u32, burst: u32) -> Throttle {
+        Throttle::new(
+            HashMap::from([(
+                "/pay".to_string(),
+                RouteLimit {
+                    per_minute,
+                    burst,
+                    max_body_bytes: Some(16),
+                },
+            )]),
+            1024,
+        )
+    }
+
+    #[test]
+    fn buckets_allow_a_burst_then_refill() {
+        let throttle = throttle(6, 2);
+        let ip: IpAddr = "203.0.113.7".parse().unwrap();
+        let other: IpAddr = "203.0.113.8".parse().unwrap();
+        let start = Instant::now();
+        assert!(throttle.check("/pay", ip, start).is_ok());
+        assert!(throttle.check("/pay", ip, start).is_ok());
+        // 6 a minute is one every 10 seconds.
+        let wait = throttle.check("/pay", ip, start).unwrap_err();
+        assert_eq!(wait.as_secs(), 10);
+        assert!(throttle.check("/pay", other, start).is_ok());
+        assert!(throttle.check("/other", ip, start).is_ok());
+        assert!(throttle
+            .check("/pay", ip, start + Duration::from_secs(5))
+            .is_err());
+        assert!(throttle
+            .check("/pay", ip, start + Duration::from_secs(10))
+            .is_ok());
+
+        let off = self::throttle(0, 0);
+        for _ in 0..100 {
+            assert!(off.check("/pay", ip, start).is_ok());
+        }
+    }
+
+    #[test]
+    fn ipv6_clients_share_their_64() {
+        let throttle = throttle(1, 1);
+        let start = Instant::now();
+        assert!(throttle
+            .check("/pay", "2001:db8::1".parse().unwrap(), start)
+            .is_ok());
+        assert!(throttle
+            .check("/pay", "2001:db8::ffff".parse().unwrap(), start)
+            .is_err());
+        assert!(throttle
+            .check("/pay", "2001:db8:0:1::1".parse().unwrap(), start)
+            .is_ok());
+    }
+
+    #[test]
+    fn idle_buckets_are_pruned() {
+        let state = RouteState::new(RouteLimit {
+            per_minute: 60,
+            burst: 1,
+            max_body_bytes: None,
+        });
+        let start = Instant::now();
+        for i in 0..PRUNE_AT as u32 - 1 {
+            state.take(IpAddr::from(i.to_be_bytes()), start).unwrap();
+        }
+        let later = start + Duration::from_secs(2);
+        state.take("198.51.100.1".parse().unwrap(), later).unwrap();
+        assert_eq!(state.buckets.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn middleware_refuses_with_retry_after_and_caps_bodies() {
+        let app = Router::new()
+            .route("/pay", post(|body: String| async move { body }))
+            .route("/free", get(|| async { "ok" }))
+            .layer(middleware::from_fn_with_state(
+                Arc::new(throttle(1, 1)),
+                limit,
+            ));
+        let peer = SocketAddr::from(([203, 0, 113, 7], 40000));
+        let request = |uri: &str, body: &str| {
+            let mut request = Request::post(uri)
+                .body(Body::from(body.to_string()))
+                .unwrap();
+            request.extensions_mut().insert(ConnectInfo(peer));
+            request
+        };
+
+        let response = app.clone().oneshot(request("/pay", "hello")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        let response = app.clone().oneshot(request("/pay", "hello")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(response.headers()[RETRY_AFTER], "60");
+
+        let mut get_free = request("/free", "");
+        *get_free.method_mut() = axum::http::Method::GET;
+        assert_eq!(
+            app.clone().oneshot(get_free).await.unwrap().status(),
+            StatusCode::OK
+        );
+
+        // A fresh client, so only the body cap applies.
+        let mut big = request("/pay", &"x".repeat(17));
+        big.extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([203, 0, 113, 8], 40000))));
+        let response = app.clone().oneshot(big).await.unwrap();
+        assert_eq!(response.status(), StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
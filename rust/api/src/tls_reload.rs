@@ -0,0 +1,189 @@
+//! Picking up a renewed `[tls]` certificate without a restart.
+//!
+//! certbot and similar tools replace `fullchain.pem` and `privkey.pem` in
+//! place. The files are watched, and re-read on SIGHUP; a new pair is
+//! swapped into the running listener only once rustls accepts it as a pair
+//! and the certificate has not expired. Otherwise the previous pair keeps
+//! serving, so catching the files halfway through a renewal is harmless.
+//! Connections already open finish on the certificate they started with.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use axum_server::tls_rustls::RustlsConfig;
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+
+use crate::config;
+use crate::x509;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Fingerprint of one file for change polling.
+type FileStamp = Option<(u64, Option<SystemTime>)>;
+
+fn stamp(path: &Path) -> FileStamp {
+    fs::metadata(path)
+        .ok()
+        .map(|metadata| (metadata.len(), metadata.modified().ok()))
+}
+
+pub struct TlsFiles {
+    cert: PathBuf,
+    key: PathBuf,
+    tls: RustlsConfig,
+    stamps: Mutex<(FileStamp, FileStamp)>,
+}
+
+impl TlsFiles {
+    /// Watch `cert` and `key`, which `tls` was loaded from.
+    pub fn new(cert: PathBuf, key: PathBuf, tls: RustlsConfig) -> Self {
+        let stamps = (stamp(&cert), stamp(&key));
+        Self {
+            cert,
+            key,
+            tls,
+            stamps: Mutex::new(stamps),
+        }
+    }
+
+    /// Re-read both files and serve them if they are a valid, unexpired
+    /// pair; the certificate's expiry on success. On error the listener
+    /// keeps its current pair.
+    pub async fn reload(&self) -> Result<DateTime<Utc>, String> {
+        let stamps = (stamp(&self.cert), stamp(&self.key));
+        // Remembered even if the pair is rejected, so a broken file is
+        // reported once rather than on every poll.
+        *self.stamps.lock().unwrap_or_else(|e| e.into_inner()) = stamps;
+        let (cert_pem, key_pem) = config::read_tls_pair(&self.cert, &self.key)?;
+        let leaf = rustls_pemfile::certs(&mut cert_pem.as_slice())
+            .next()
+            .and_then(Result::ok)
+            .ok_or_else(|| format!("{} contains no certificates", self.cert.display()))?;
+        let not_after = x509::not_after(&leaf)
+            .map_err(|e| format!("cannot read the expiry of {}: {e}", self.cert.display()))?;
+        if not_after <= Utc::now() {
+            return Err(format!(
+                "{} expired at {}",
+                self.cert.display(),
+                not_after.to_rfc3339()
+            ));
+        }
+        self.tls
+            .reload_from_pem(cert_pem, key_pem)
+            .await
+            .map_err(|e| format!("rustls rejected the new pair: {e}"))?;
+        Ok(not_after)
+    }
+
+    /// Reload only if either file looks different from the last attempt.
+    async fn reload_if_changed(&self) -> Option<Result<DateTime<Utc>, String>> {
+        let current = (stamp(&self.cert), stamp(&self.key));
+        if *self.stamps.lock().unwrap_or_else(|e| e.into_inner()) == current {
+            return None;
+        }
+        Some(self.reload().await)
+    }
+
+    fn log_reload(&self, trigger: &str, result: Result<DateTime<Utc>, String>) {
+        match result {
+            Ok(not_after) => info!(
+                "Reloaded TLS certificate {} ({trigger}), valid until {}",
+                self.cert.display(),
+                not_after.to_rfc3339()
+            ),
+            Err(e) => error!(
+                "TLS certificate reload ({trigger}) failed, keeping the previous certificate: {e}"
+            ),
+        }
+    }
+}
+
+/// Reload the certificate and key on SIGHUP and whenever either changes.
+pub fn spawn_reloader(files: Arc<TlsFiles>) {
+    let polled = Arc::clone(&files);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            if let Some(result) = polled.reload_if_changed().await {
+                polled.log_reload("file changed", result);
+            }
+        }
+    });
+
+    tokio::spawn(async move {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                warn!(
+                    "Cannot listen for SIGHUP; the TLS certificate reloads on file change only: {e}"
+                );
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            let result = files.reload().await;
+            files.log_reload("SIGHUP", result);
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::x509::tests::{issue, p256_key};
+
+    /// A self-signed certificate and its key, as PEM.
+    fn pair(not_after: DateTime<Utc>) -> (String, String) {
+        let (key, pkcs8) = p256_key();
+        let csr = x509::csr(&key, &["gkapi.test".to_string()]).unwrap();
+        (
+            x509::pem("CERTIFICATE", &issue(&csr, &key, not_after)),
+            x509::pem("PRIVATE KEY", &pkcs8),
+        )
+    }
+
+    #[tokio::test]
+    async fn a_new_pair_is_served_and_a_bad_one_is_refused() {
+        let _ = rustls::crypto::aws_lc_rs::default_provider().install_default();
+        let dir = tempfile::tempdir().unwrap();
+        let cert = dir.path().join("fullchain.pem");
+        let key = dir.path().join("privkey.pem");
+        let later = Utc::now() + chrono::Duration::days(30);
+        let (first_cert, first_key) = pair(later);
+        fs::write(&cert, &first_cert).unwrap();
+        fs::write(&key, &first_key).unwrap();
+        let tls = RustlsConfig::from_pem_file(&cert, &key).await.unwrap();
+        let files = TlsFiles::new(cert.clone(), key.clone(), tls.clone());
+        assert!(files.reload_if_changed().await.is_none());
+
+        let (second_cert, second_key) = pair(later + chrono::Duration::days(60));
+        fs::write(&cert, &second_cert).unwrap();
+        fs::write(&key, &second_key).unwrap();
+        let not_after = files.reload().await.unwrap();
+        assert_eq!(
+            not_after.timestamp(),
+            (later + chrono::Duration::days(60)).timestamp()
+        );
+        let served = tls.get_inner();
+
+        // A new certificate beside the old key, as if caught mid-renewal.
+        let (third_cert, _) = pair(later);
+        fs::write(&cert, &third_cert).unwrap();
+        let err = files.reload().await.unwrap_err();
+        assert!(err.contains("usable pair"), "{err}");
+        assert!(Arc::ptr_eq(&served, &tls.get_inner()));
+
+        let (expired_cert, expired_key) = pair(Utc::now() - chrono::Duration::hours(1));
+        fs::write(&cert, &expired_cert).unwrap();
+        fs::write(&key, &expired_key).unwrap();
+        let err = files.reload().await.unwrap_err();
+        assert!(err.contains("expired"), "{err}");
+        assert!(Arc::ptr_eq(&served, &tls.get_inner()));
+    }
+}
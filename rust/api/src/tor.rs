@@ -43,6 +43,13 @@
 //! must never turn into blocking legitimate users. It must equally never be
 //! read as "everything is Tor" — hence a plain `HashSet` membership test with
 //! no sentinel/unknown state.
+//!
+//! # Sources
+//!
+//! By default the list is [`TOR_BULK_EXIT_LIST_URL`], which only HTTPS
+//! vouches for. [`TorSource::Consensus`] reads exits from a consensus the
+//! directory authorities signed instead, so a compromised path or endpoint
+//! cannot shrink the list; see [`crate::tor_consensus`].
 
 use chrono::{DateTime, Utc};
 use log::{info, warn};
@@ -53,6 +60,9 @@ use std::sync::{Arc, RwLock};
 use std::time::Duration;
 use thiserror::Error;
 
+use crate::reputation::SourceLocation;
+use crate::tor_consensus::{self, ConsensusError};
+
 /// Authoritative bulk exit list published by the Tor Project.
 ///
 /// Plain text, one IP per line. This is the list TorDNSEL is built from and is
@@ -67,6 +77,9 @@ pub const REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
 /// only exists so a corrupted or hostile response cannot exhaust memory.
 const MAX_RESPONSE_BYTES: usize = 4 * 1024 * 1024;
 
+/// A consensus runs to a few MB; allow for growth.
+const MAX_CONSENSUS_BYTES: usize = 16 * 1024 * 1024;
+
 /// Hard cap on retained entries, for the same reason as `MAX_RESPONSE_BYTES`.
 /// The real list is ~2000 exits, so this is ~50x headroom.
 const MAX_EXITS: usize = 100_000;
@@ -97,10 +110,33 @@ pub enum TorListError {
         "exit list shrank implausibly: {got} entries vs {previous} previously; refusing to replace"
     )]
     Shrank { got: usize, previous: usize },
+    #[error("consensus rejected: {0}")]
+    Consensus(#[from] ConsensusError),
     #[error("lock poisoned")]
     Lock,
 }
 
+/// Where [`TorExitList::refresh`] reads exits from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TorSource {
+    /// A plain list of addresses, such as [`TOR_BULK_EXIT_LIST_URL`].
+    BulkList(String),
+    /// A network-status consensus and the authorities' key certificates
+    /// (`/tor/keys/all`), each from a mirror or a local file, verified
+    /// against the pinned authority fingerprints.
+    Consensus {
+        consensus: SourceLocation,
+        certificates: SourceLocation,
+        authorities: Vec<String>,
+    },
+}
+
+impl Default for TorSource {
+    fn default() -> Self {
+        TorSource::BulkList(TOR_BULK_EXIT_LIST_URL.to_string())
+    }
+}
+
 /// Map an IPv4-mapped IPv6 address (`::ffff:a.b.c.d`) to its IPv4 form so both
 /// spellings compare equal. Other addresses pass through unchanged.
 fn canonicalize(ip: &IpAddr) -> IpAddr {
@@ -113,6 +149,9 @@ fn canonicalize(ip: &IpAddr) -> IpAddr {
     }
 }
 
+/// Exit set read back from the on-disk cache, with the cache's mtime.
+type CachedList = (HashSet<IpAddr>, DateTime<Utc>);
+
 #[derive(Default)]
 struct Snapshot {
     exits: HashSet<IpAddr>,
@@ -124,20 +163,17 @@ pub struct TorExitList {
     inner: RwLock<Snapshot>,
     /// Where the last good copy is persisted, so a restart does not start blind.
     cache_path: Option<PathBuf>,
-    url: String,
+    source: TorSource,
 }
 
 impl TorExitList {
-    /// Build a list, seeding from the on-disk cache if one is present.
+    /// Build a list reading exits from `source`, seeding from the on-disk
+    /// cache if one is present. The cache holds the last good set of
+    /// addresses whichever source produced it.
     ///
     /// Never fails: an unreadable or corrupt cache just yields an empty set
     /// (fail open), which is logged.
-    pub fn new(cache_path: Option<PathBuf>) -> Self {
-        Self::with_url(cache_path, TOR_BULK_EXIT_LIST_URL.to_string())
-    }
-
-    /// As [`Self::new`], with an overridable source URL (used by tests).
-    pub fn with_url(cache_path: Option<PathBuf>, url: String) -> Self {
+    pub fn new(cache_path: Option<PathBuf>, source: TorSource) -> Self {
         let mut snapshot = Snapshot::default();
 
         if let Some(path) = cache_path.as_deref() {
@@ -171,7 +207,7 @@ impl TorExitList {
         Self {
             inner: RwLock::new(snapshot),
             cache_path,
-            url,
+            source,
         }
     }
 
@@ -180,11 +216,10 @@ impl TorExitList {
     /// Returns `false` when the list is empty or unavailable — see the
     /// fail-open policy in the module docs.
     pub fn is_exit(&self, ip: &IpAddr) -> bool {
-        // Canonicalize first. gkapi binds 0.0.0.0 today so peers are always
-        // IpAddr::V4, but changing the bind to `::` for dual-stack (a one-line,
-        // plausible change) makes Linux deliver IPv4 peers as ::ffff:a.b.c.d,
-        // which would never match the V4 entries parsed from the list -- every
-        // exit would silently escape metering with nothing in the logs.
+        // Canonicalize first. A dual-stack listener (`[::]:443` in
+        // server.listen) gets IPv4 peers from Linux as ::ffff:a.b.c.d, which
+        // would never match the V4 entries parsed from the list -- every exit
+        // would silently escape metering with nothing in the logs.
         let ip = canonicalize(ip);
         match self.inner.read() {
             Ok(snap) => snap.exits.contains(&ip),
@@ -223,32 +258,32 @@ impl TorExitList {
     /// On any error the previous set is left untouched, so a transient outage
     /// degrades to "keep using the last good list" rather than to an empty one.
     pub async fn refresh(&self) -> Result<usize, TorListError> {
-        let client = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
-
-        let response = client.get(&self.url).send().await?.error_for_status()?;
-
-        // Reject an oversized body up front when the server declares a length.
-        if let Some(len) = response.content_length() {
-            if len > MAX_RESPONSE_BYTES as u64 {
-                return Err(TorListError::TooLarge(len.min(usize::MAX as u64) as usize));
+        let (exits, body) = match &self.source {
+            TorSource::BulkList(url) => {
+                let body = fetch(url, MAX_RESPONSE_BYTES).await?;
+                (Self::parse(&body), body)
             }
-        }
-
-        // Stream with a running total. `response.text()` would buffer the whole
-        // body BEFORE any size check, so a chunked response (no content-length)
-        // could allocate without bound until FETCH_TIMEOUT -- an OOM on a small
-        // VM. The cap has to be enforced while reading, not after.
-        let mut response = response;
-        let mut buf: Vec<u8> = Vec::new();
-        while let Some(chunk) = response.chunk().await? {
-            if buf.len() + chunk.len() > MAX_RESPONSE_BYTES {
-                return Err(TorListError::TooLarge(buf.len() + chunk.len()));
+            TorSource::Consensus {
+                consensus,
+                certificates,
+                authorities,
+            } => {
+                let consensus = read(consensus, MAX_CONSENSUS_BYTES).await?;
+                let certificates = read(certificates, MAX_RESPONSE_BYTES).await?;
+                let exits = tor_consensus::verified_exits(
+                    &consensus,
+                    &certificates,
+                    authorities,
+                    Utc::now(),
+                )?;
+                // Cached in the bulk list's format, so the cache reads back
+                // the same whichever source wrote it.
+                let mut lines: Vec<String> = exits.iter().map(IpAddr::to_string).collect();
+                lines.sort();
+                (exits, lines.join("\n") + "\n")
             }
-            buf.extend_from_slice(&chunk);
-        }
-        let body = String::from_utf8_lossy(&buf).into_owned();
+        };
 
-        let exits = Self::parse(&body);
         // "Non-empty" is NOT enough validation. A truncated 200, or an HTML
         // error page that happens to contain one IP-shaped string, would
         // otherwise replace a good ~1400-entry list with a handful of entries
@@ -311,7 +346,7 @@ impl TorExitList {
         out
     }
 
-    fn read_cache(path: &Path) -> Result<Option<(HashSet<IpAddr>, DateTime<Utc>)>, TorListError> {
+    fn read_cache(path: &Path) -> Result<Option<CachedList>, TorListError> {
         if !path.exists() {
             return Ok(None);
         }
@@ -340,6 +375,48 @@ impl TorExitList {
     }
 }
 
+/// GET `url`, refusing a body over `max_bytes`.
+async fn fetch(url: &str, max_bytes: usize) -> Result<String, TorListError> {
+    let client = reqwest::Client::builder().timeout(FETCH_TIMEOUT).build()?;
+
+    let response = client.get(url).send().await?.error_for_status()?;
+
+    // Reject an oversized body up front when the server declares a length.
+    if let Some(len) = response.content_length() {
+        if len > max_bytes as u64 {
+            return Err(TorListError::TooLarge(len.min(usize::MAX as u64) as usize));
+        }
+    }
+
+    // Stream with a running total. `response.text()` would buffer the whole
+    // body BEFORE any size check, so a chunked response (no content-length)
+    // could allocate without bound until FETCH_TIMEOUT -- an OOM on a small
+    // VM. The cap has to be enforced while reading, not after.
+    let mut response = response;
+    let mut buf: Vec<u8> = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if buf.len() + chunk.len() > max_bytes {
+            return Err(TorListError::TooLarge(buf.len() + chunk.len()));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(String::from_utf8_lossy(&buf).into_owned())
+}
+
+/// [`fetch`] for a URL, or read a local file under the same cap.
+async fn read(location: &SourceLocation, max_bytes: usize) -> Result<String, TorListError> {
+    match location {
+        SourceLocation::Url(url) => fetch(url, max_bytes).await,
+        SourceLocation::File(path) => {
+            let len = tokio::fs::metadata(path).await?.len();
+            if len > max_bytes as u64 {
+                return Err(TorListError::TooLarge(len.min(usize::MAX as u64) as usize));
+            }
+            Ok(tokio::fs::read_to_string(path).await?)
+        }
+    }
+}
+
 /// Refresh `list` immediately, then every [`REFRESH_INTERVAL`], forever.
 ///
 /// Errors are logged and retried on the next tick; they never abort the loop
@@ -457,7 +534,7 @@ not-an-ip
     async fn refresh_fetches_real_tor_exit_list() {
         let dir = tempdir().unwrap();
         let cache = dir.path().join("tor_exits.txt");
-        let list = TorExitList::new(Some(cache.clone()));
+        let list = TorExitList::new(Some(cache.clone()), TorSource::default());
 
         let n = list.refresh().await.expect("refresh should succeed");
         println!("fetched {n} Tor exit addresses");
@@ -471,7 +548,7 @@ not-an-ip
         assert!(cache.exists(), "refresh must persist the cache");
 
         // A refreshed list must round-trip through the cache unchanged.
-        let reloaded = TorExitList::new(Some(cache));
+        let reloaded = TorExitList::new(Some(cache), TorSource::default());
         assert_eq!(reloaded.len(), n, "cache reload must preserve the set");
 
         // Sanity: a non-Tor address must not be flagged.
@@ -481,7 +558,7 @@ not-an-ip
     /// The fail-open contract: with no list, nothing is Tor.
     #[test]
     fn empty_list_treats_everything_as_non_tor() {
-        let list = TorExitList::new(None);
+        let list = TorExitList::new(None, TorSource::default());
         assert!(list.is_empty());
         assert!(!list.is_exit(&IpAddr::V4(Ipv4Addr::new(185, 220, 101, 30))));
         assert!(!list.is_exit(&IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))));
@@ -494,7 +571,7 @@ not-an-ip
         let path = dir.path().join("tor_exits.txt");
         std::fs::write(&path, "185.220.101.30\n192.42.116.15\n").unwrap();
 
-        let list = TorExitList::new(Some(path));
+        let list = TorExitList::new(Some(path), TorSource::default());
         assert_eq!(list.len(), 2);
         assert!(list.is_exit(&IpAddr::V4(Ipv4Addr::new(185, 220, 101, 30))));
         assert!(!list.is_exit(&IpAddr::V4(Ipv4Addr::new(8, 8, 8, 8))));
@@ -508,7 +585,7 @@ not-an-ip
         let path = dir.path().join("tor_exits.txt");
         std::fs::write(&path, "this is not\nan ip list at all\n").unwrap();
 
-        let list = TorExitList::new(Some(path));
+        let list = TorExitList::new(Some(path), TorSource::default());
         assert!(list.is_empty());
         assert!(!list.is_exit(&IpAddr::V4(Ipv4Addr::new(185, 220, 101, 30))));
     }
@@ -516,7 +593,10 @@ not-an-ip
     #[test]
     fn missing_cache_path_fails_open() {
         let dir = tempdir().unwrap();
-        let list = TorExitList::new(Some(dir.path().join("does-not-exist.txt")));
+        let list = TorExitList::new(
+            Some(dir.path().join("does-not-exist.txt")),
+            TorSource::default(),
+        );
         assert!(list.is_empty());
     }
 
@@ -531,7 +611,61 @@ not-an-ip
             "temp file must be renamed away, not left behind"
         );
 
-        let reloaded = TorExitList::new(Some(path));
+        let reloaded = TorExitList::new(Some(path), TorSource::default());
         assert_eq!(reloaded.len(), 1);
     }
+
+    /// A consensus source keeps its list when a refresh brings a consensus
+    /// that does not verify, however plausible its size.
+    #[tokio::test]
+    async fn consensus_source_refuses_unsigned_lists() {
+        use crate::tor_consensus::tests::{consensus, Authority};
+
+        let dir = tempdir().unwrap();
+        let authorities: Vec<Authority> = (0..3).map(|_| Authority::new()).collect();
+        let certificates = dir.path().join("certificates");
+        std::fs::write(
+            &certificates,
+            authorities
+                .iter()
+                .map(|a| a.certificate("2099-01-01 00:00:00"))
+                .collect::<String>(),
+        )
+        .unwrap();
+        let addresses: Vec<String> = (0..250)
+            .map(|n| format!("10.0.{}.{}", n / 200, n % 200 + 1))
+            .collect();
+        let relays: Vec<(&str, &str, &str)> = addresses
+            .iter()
+            .map(|a| (a.as_str(), "Exit", "accept 443"))
+            .collect();
+        let path = dir.path().join("consensus");
+        let signed = consensus(Utc::now(), &relays, &[&authorities[0], &authorities[1]]);
+        std::fs::write(&path, &signed).unwrap();
+
+        let cache = dir.path().join("tor_exits.txt");
+        let list = TorExitList::new(
+            Some(cache.clone()),
+            TorSource::Consensus {
+                consensus: SourceLocation::File(path.clone()),
+                certificates: SourceLocation::File(certificates),
+                authorities: authorities.iter().map(Authority::fingerprint).collect(),
+            },
+        );
+        assert_eq!(list.refresh().await.unwrap(), 250);
+        assert!(list.is_exit(&IpAddr::V4(Ipv4Addr::new(10, 0, 1, 50))));
+        assert_eq!(
+            TorExitList::new(Some(cache), TorSource::default()).len(),
+            250
+        );
+
+        // Same size, but the signatures no longer cover it.
+        std::fs::write(&path, signed.replacen("10.0.0.1 ", "10.9.9.9 ", 1)).unwrap();
+        assert!(matches!(
+            list.refresh().await,
+            Err(TorListError::Consensus(_))
+        ));
+        assert!(list.is_exit(&IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1))));
+        assert!(!list.is_exit(&IpAddr::V4(Ipv4Addr::new(10, 9, 9, 9))));
+    }
 }
@@ -0,0 +1,547 @@
+//! Tor exit addresses from a consensus the directory authorities signed.
+//!
+//! The bulk exit list is served over HTTPS and nothing else vouches for it.
+//! [`crate::tor`]'s plausibility checks stop a truncated or halved response
+//! from replacing a good list, but whoever controls the HTTP path (or the
+//! endpoint) can still shrink the list by under half at each refresh, or feed
+//! a fresh install anything above the minimum.
+//!
+//! A network-status consensus is signed by the directory authorities, whose
+//! identity keys ship with Tor. [`verified_exits`] checks each authority's
+//! key certificate against a pinned identity fingerprint, checks the
+//! consensus signatures made with the certified signing keys, and only then
+//! reads the relays out of it. More than half of the pinned authorities must
+//! have signed, as Tor clients require. The consensus must also be current:
+//! one past its own `valid-until` by more than [`VALID_UNTIL_SLACK_MINUTES`]
+//! is refused, so a stale one cannot be replayed to unblock exits that have
+//! since appeared.
+//!
+//! A relay counts as an exit if it has the `Exit` flag or its policy summary
+//! (`p`) accepts any port. Its OR addresses are what the consensus records;
+//! a relay whose exit traffic leaves from another address is missed, which
+//! the bulk list, built from observed exits, does catch.
+//!
+//! Signatures follow dir-spec: RSA PKCS#1 v1.5 padding over the bare digest,
+//! with no DigestInfo prefix.
+
+use std::collections::HashSet;
+use std::net::IpAddr;
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::{Pkcs1v15Sign, PublicKey, RsaPublicKey};
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+
+/// v3 identity fingerprints of the directory authorities, from Tor's
+/// `src/app/config/auth_dirs.inc`. Overridden by `tor.authorities` when the
+/// set changes before gkapi is updated.
+pub const DIRECTORY_AUTHORITIES: &[(&str, &str)] = &[
+    ("moria1", "F533C81CEF0BC0267857C0B07D18FA3A05CC95E7"),
+    ("tor26", "14C131DFC5C6F93646BE72FA1401C02A8DF2E8B4"),
+    ("dizum", "E8A9C45EDE6D711294FADF8E7951F4DE6CA56B58"),
+    ("gabelmoo", "ED03BB616EB2F60BEC80151114BB25CEF515B226"),
+    ("dannenberg", "0232AF901C31A04EE9848595AF9BB7620D4C5B2E"),
+    ("maatuska", "49015F787433103580E3B66A1707A00E60F2D15B"),
+    ("Faravahar", "EFCBE720AB3A82B99F9E953CD5BF50F7EEFC7B97"),
+    ("longclaw", "23D15D965BC35114467363C165C4F724B64B4F66"),
+    ("bastet", "27102BC123E7AF1D4741AE047E160C91ADC76B21"),
+];
+
+/// How long past its `valid-until` a consensus is still read. The consensus
+/// already carries three hours of validity; this only covers clock skew and a
+/// mirror that has not fetched the next one yet.
+pub const VALID_UNTIL_SLACK_MINUTES: i64 = 60;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ConsensusError {
+    #[error("malformed document: {0}")]
+    Malformed(String),
+    #[error(
+        "only {valid} of {needed} required authority signatures verified ({authorities} pinned)"
+    )]
+    NotEnoughSignatures {
+        valid: usize,
+        needed: usize,
+        authorities: usize,
+    },
+    #[error("consensus expired at {0}")]
+    Expired(DateTime<Utc>),
+    #[error("consensus is not valid until {0}")]
+    NotYetValid(DateTime<Utc>),
+}
+
+fn malformed(what: impl Into<String>) -> ConsensusError {
+    ConsensusError::Malformed(what.into())
+}
+
+/// Uppercase hex fingerprint of a DER-encoded RSA key.
+fn key_digest(der: &[u8]) -> String {
+    hex::encode_upper(Sha1::digest(der))
+}
+
+fn verify(key: &RsaPublicKey, digest: &[u8], signature: &[u8]) -> bool {
+    key.verify(Pkcs1v15Sign::new_raw(), digest, signature)
+        .is_ok()
+}
+
+fn parse_time(s: &str) -> Result<DateTime<Utc>, ConsensusError> {
+    NaiveDateTime::parse_from_str(s.trim(), "%Y-%m-%d %H:%M:%S")
+        .map(|t| t.and_utc())
+        .map_err(|_| malformed(format!("bad time {s:?}")))
+}
+
+/// The decoded body of the object (`-----BEGIN ...-----` block) starting at
+/// `from`, and the offset just past it.
+fn object(doc: &str, from: usize) -> Result<(Vec<u8>, usize), ConsensusError> {
+    let rest = &doc[from..];
+    let begin = rest
+        .strip_prefix("-----BEGIN ")
+        .ok_or_else(|| malformed("expected an object"))?;
+    let header_end = begin
+        .find("-----\n")
+        .ok_or_else(|| malformed("unterminated object header"))?;
+    let body_start = "-----BEGIN ".len() + header_end + "-----\n".len();
+    let end = rest[body_start..]
+        .find("-----END ")
+        .ok_or_else(|| malformed("unterminated object"))?;
+    let body: String = rest[body_start..body_start + end]
+        .lines()
+        .map(str::trim)
+        .collect();
+    let bytes = BASE64
+        .decode(body)
+        .map_err(|e| malformed(format!("bad base64 in object: {e}")))?;
+    let after = body_start + end;
+    let close = rest[after..]
+        .find('\n')
+        .map_or(rest.len(), |i| after + i + 1);
+    Ok((bytes, from + close))
+}
+
+/// Start of the first line at or after `from` whose keyword is `keyword`,
+/// and the start of the line after it.
+fn after_keyword_line(doc: &str, keyword: &str, from: usize) -> Option<(usize, usize)> {
+    let mut pos = from;
+    for line in doc[from..].split_inclusive('\n') {
+        let start = pos;
+        pos += line.len();
+        if line
+            .strip_prefix(keyword)
+            .is_some_and(|rest| rest.starts_with([' ', '\n']) || rest.is_empty())
+        {
+            return Some((start, pos));
+        }
+    }
+    None
+}
+
+/// An authority's signing key, certified by its identity key.
+struct AuthorityKey {
+    identity: String,
+    signing_digest: String,
+    signing_key: RsaPublicKey,
+}
+
+/// Read and check every certificate in a `dir-key-certificate-3` bundle,
+/// such as `/tor/keys/all`. Certificates that do not verify, or that have
+/// expired, are skipped: the threshold decides whether enough remain.
+fn certified_keys(bundle: &str, now: DateTime<Utc>) -> Vec<AuthorityKey> {
+    const START: &str = "dir-key-certificate-version";
+    let mut keys = Vec::new();
+    let starts: Vec<usize> = bundle.match_indices(START).map(|(i, _)| i).collect();
+    for (n, &start) in starts.iter().enumerate() {
+        let end = starts.get(n + 1).copied().unwrap_or(bundle.len());
+        if let Ok(key) = certified_key(&bundle[start..end], now) {
+            keys.push(key);
+        }
+    }
+    keys
+}
+
+fn certified_key(cert: &str, now: DateTime<Utc>) -> Result<AuthorityKey, ConsensusError> {
+    let field = |keyword: &str| {
+        cert.lines()
+            .find_map(|line| line.strip_prefix(keyword)?.strip_prefix(' '))
+            .ok_or_else(|| malformed(format!("certificate has no {keyword}")))
+    };
+    let fingerprint = field("fingerprint")?.trim().to_ascii_uppercase();
+    if parse_time(field("dir-key-expires")?)? < now {
+        return Err(malformed("certificate expired"));
+    }
+    let key_object = |keyword: &str| -> Result<Vec<u8>, ConsensusError> {
+        let (_, next) = after_keyword_line(cert, keyword, 0)
+            .ok_or_else(|| malformed(format!("certificate has no {keyword}")))?;
+        Ok(object(cert, next)?.0)
+    };
+    let identity_der = key_object("dir-identity-key")?;
+    let signing_der = key_object("dir-signing-key")?;
+    let crosscert = key_object("dir-key-crosscert")?;
+    let (_, signed_end) = after_keyword_line(cert, "dir-key-certification", 0)
+        .ok_or_else(|| malformed("certificate has no dir-key-certification"))?;
+    let certification = object(cert, signed_end)?.0;
+
+    if key_digest(&identity_der) != fingerprint {
+        return Err(malformed("identity key does not match fingerprint"));
+    }
+    let identity = RsaPublicKey::from_pkcs1_der(&identity_der)
+        .map_err(|e| malformed(format!("identity key: {e}")))?;
+    let signing_key = RsaPublicKey::from_pkcs1_der(&signing_der)
+        .map_err(|e| malformed(format!("signing key: {e}")))?;
+    if !verify(
+        &identity,
+        &Sha1::digest(&cert.as_bytes()[..signed_end]),
+        &certification,
+    ) {
+        return Err(malformed("certification signature does not verify"));
+    }
+    // The signing key vouches for the identity key in return, so a signing
+    // key cannot be certified under an identity that never held it.
+    if !verify(&signing_key, &Sha1::digest(&identity_der), &crosscert) {
+        return Err(malformed("cross-certificate does not verify"));
+    }
+    Ok(AuthorityKey {
+        identity: fingerprint,
+        signing_digest: key_digest(&signing_der),
+        signing_key,
+    })
+}
+
+/// Exit addresses from `consensus`, once more than half of `authorities`
+/// (v3 identity fingerprints) have been verified as signers through the key
+/// certificates in `certificates`.
+pub fn verified_exits(
+    consensus: &str,
+    certificates: &str,
+    authorities: &[String],
+    now: DateTime<Utc>,
+) -> Result<HashSet<IpAddr>, ConsensusError> {
+    if !consensus.starts_with("network-status-version 3") {
+        return Err(malformed("not a v3 network-status consensus"));
+    }
+    let pinned: HashSet<String> = authorities
+        .iter()
+        .map(|fp| fp.to_ascii_uppercase())
+        .collect();
+    let needed = pinned.len() / 2 + 1;
+    let keys = certified_keys(certificates, now);
+
+    // Every signature covers the document up to and including the first
+    // "directory-signature " (keyword and space).
+    let (first_signature, _) = after_keyword_line(consensus, "directory-signature", 0)
+        .ok_or_else(|| malformed("consensus is unsigned"))?;
+    let signed = &consensus.as_bytes()[..first_signature + "directory-signature ".len()];
+    let sha1 = Sha1::digest(signed);
+    let sha256 = Sha256::digest(signed);
+
+    let mut signers = HashSet::new();
+    let mut pos = first_signature;
+    while let Some((line_start, next)) = after_keyword_line(consensus, "directory-signature", pos) {
+        let args: Vec<&str> = consensus[line_start..next].split_whitespace().collect();
+        let (digest, identity, signing_digest) = match args[..] {
+            [_, identity, signing] => (&sha1[..], identity, signing),
+            [_, "sha1", identity, signing] => (&sha1[..], identity, signing),
+            [_, "sha256", identity, signing] => (&sha256[..], identity, signing),
+            // Unknown algorithms are skipped, as Tor does.
+            _ => {
+                pos = object(consensus, next).map_or(next, |(_, end)| end);
+                continue;
+            }
+        };
+        let (signature, end) = object(consensus, next)?;
+        pos = end;
+        let identity = identity.to_ascii_uppercase();
+        if !pinned.contains(&identity) {
+            continue;
+        }
+        let signing_digest = signing_digest.to_ascii_uppercase();
+        let verified = keys.iter().any(|key| {
+            key.identity == identity
+                && key.signing_digest == signing_digest
+                && verify(&key.signing_key, digest, &signature)
+        });
+        if verified {
+            signers.insert(identity);
+        }
+    }
+    if signers.len() < needed {
+        return Err(ConsensusError::NotEnoughSignatures {
+            valid: signers.len(),
+            needed,
+            authorities: pinned.len(),
+        });
+    }
+
+    // Only read after the signatures, so nothing below trusts unsigned text.
+    let header = |keyword: &str| {
+        consensus
+            .lines()
+            .find_map(|line| line.strip_prefix(keyword)?.strip_prefix(' '))
+            .ok_or_else(|| malformed(format!("consensus has no {keyword}")))
+    };
+    let valid_after = parse_time(header("valid-after")?)?;
+    let valid_until = parse_time(header("valid-until")?)?;
+    if valid_after > now + chrono::Duration::hours(1) {
+        return Err(ConsensusError::NotYetValid(valid_after));
+    }
+    if valid_until + chrono::Duration::minutes(VALID_UNTIL_SLACK_MINUTES) < now {
+        return Err(ConsensusError::Expired(valid_until));
+    }
+    Ok(exits(&consensus[..first_signature]))
+}
+
+/// Addresses of relays that can exit, from the router entries.
+fn exits(consensus: &str) -> HashSet<IpAddr> {
+    let mut out = HashSet::new();
+    let mut addresses: Vec<IpAddr> = Vec::new();
+    let mut exit = false;
+    let mut flush = |addresses: &mut Vec<IpAddr>, exit: &mut bool| {
+        if *exit {
+            out.extend(addresses.iter().map(|ip| ip.to_canonical()));
+        }
+        addresses.clear();
+        *exit = false;
+    };
+    for line in consensus.lines() {
+        let mut fields = line.split_whitespace();
+        match fields.next() {
+            // r nickname identity [digest] date time address orport dirport:
+            // the full flavour has a digest, the microdescriptor one does not.
+            Some("r") => {
+                flush(&mut addresses, &mut exit);
+                let fields: Vec<&str> = fields.collect();
+                if fields.len() >= 7 {
+                    if let Ok(ip) = fields[fields.len() - 3].parse() {
+                        addresses.push(ip);
+                    }
+                }
+            }
+            Some("a") => {
+                let addr = fields.next().unwrap_or("");
+                let host = addr
+                    .rsplit_once(':')
+                    .map_or(addr, |(host, _)| host)
+                    .trim_start_matches('[')
+                    .trim_end_matches(']');
+                if let Ok(ip) = host.parse() {
+                    addresses.push(ip);
+                }
+            }
+            Some("s") => exit |= fields.any(|flag| flag == "Exit"),
+            Some("p") => exit |= fields.next() == Some("accept"),
+            Some("directory-footer") => break,
+            _ => {}
+        }
+    }
+    flush(&mut addresses, &mut exit);
+    out
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use rsa::pkcs1::EncodeRsaPublicKey;
+    use rsa::RsaPrivateKey;
+
+    pub(crate) struct Authority {
+        identity: RsaPrivateKey,
+        signing: RsaPrivateKey,
+    }
+
+    fn pem(label: &str, der: &[u8]) -> String {
+        let body = BASE64.encode(der);
+        let lines: Vec<&str> = body
+            .as_bytes()
+            .chunks(64)
+            .map(|c| std::str::from_utf8(c).unwrap())
+            .collect();
+        format!(
+            "-----BEGIN {label}-----\n{}\n-----END {label}-----\n",
+            lines.join("\n")
+        )
+    }
+
+    fn public_der(key: &RsaPrivateKey) -> Vec<u8> {
+        key.to_public_key()
+            .to_pkcs1_der()
+            .unwrap()
+            .as_bytes()
+            .to_vec()
+    }
+
+    fn sign(key: &RsaPrivateKey, digest: &[u8]) -> Vec<u8> {
+        key.sign(Pkcs1v15Sign::new_raw(), digest).unwrap()
+    }
+
+    impl Authority {
+        pub(crate) fn new() -> Self {
+            let mut rng = rand::thread_rng();
+            Self {
+                identity: RsaPrivateKey::new(&mut rng, 512).unwrap(),
+                signing: RsaPrivateKey::new(&mut rng, 512).unwrap(),
+            }
+        }
+
+        pub(crate) fn fingerprint(&self) -> String {
+            key_digest(&public_der(&self.identity))
+        }
+
+        pub(crate) fn certificate(&self, expires: &str) -> String {
+            let identity_der = public_der(&self.identity);
+            let mut cert = format!(
+                "dir-key-certificate-version 3\nfingerprint {}\n\
+                 dir-key-published 2026-01-01 00:00:00\ndir-key-expires {expires}\n\
+                 dir-identity-key\n{}dir-signing-key\n{}dir-key-crosscert\n{}\
+                 dir-key-certification\n",
+                self.fingerprint(),
+                pem("RSA PUBLIC KEY", &identity_der),
+                pem("RSA PUBLIC KEY", &public_der(&self.signing)),
+                pem(
+                    "ID SIGNATURE",
+                    &sign(&self.signing, &Sha1::digest(&identity_der))
+                ),
+            );
+            let signature = sign(&self.identity, &Sha1::digest(cert.as_bytes()));
+            cert.push_str(&pem("SIGNATURE", &signature));
+            cert
+        }
+
+        fn signature(&self, signed: &str) -> String {
+            format!(
+                "directory-signature sha256 {} {}\n{}",
+                self.fingerprint(),
+                key_digest(&public_der(&self.signing)),
+                pem("SIGNATURE", &sign(&self.signing, &Sha256::digest(signed))),
+            )
+        }
+    }
+
+    /// A consensus listing `relays` (address, flags, policy), valid from
+    /// `valid_after` for three hours and signed by `signers`.
+    pub(crate) fn consensus(
+        valid_after: DateTime<Utc>,
+        relays: &[(&str, &str, &str)],
+        signers: &[&Authority],
+    ) -> String {
+        let time = |t: DateTime<Utc>| t.format("%Y-%m-%d %H:%M:%S").to_string();
+        let mut doc = format!(
+            "network-status-version 3\nvote-status consensus\nvalid-after {}\n\
+             fresh-until {}\nvalid-until {}\n",
+            time(valid_after),
+            time(valid_after + chrono::Duration::hours(1)),
+            time(valid_after + chrono::Duration::hours(3)),
+        );
+        for (n, (address, flags, policy)) in relays.iter().enumerate() {
+            let (v4, v6) = address.split_once(' ').unwrap_or((address, ""));
+            doc.push_str(&format!(
+                "r relay{n} AAAAAAAAAAAAAAAAAAAAAAAAAAA BBBBBBBBBBBBBBBBBBBBBBBBBBB \
+                 2026-01-01 00:00:00 {v4} 9001 0\n"
+            ));
+            if !v6.is_empty() {
+                doc.push_str(&format!("a [{v6}]:9001\n"));
+            }
+            doc.push_str(&format!("s Fast Running Valid {flags}\np {policy}\n"));
+        }
+        doc.push_str("directory-footer\nbandwidth-weights Wbd=0\ndirectory-signature ");
+        let signed = doc.clone();
+        doc.truncate(doc.len() - "directory-signature ".len());
+        for signer in signers {
+            doc.push_str(&signer.signature(&signed));
+        }
+        doc
+    }
+
+    fn setup() -> (Vec<Authority>, Vec<String>, String) {
+        let authorities: Vec<Authority> = (0..3).map(|_| Authority::new()).collect();
+        let pinned = authorities.iter().map(Authority::fingerprint).collect();
+        let certificates = authorities
+            .iter()
+            .map(|a| a.certificate("2099-01-01 00:00:00"))
+            .collect();
+        (authorities, pinned, certificates)
+    }
+
+    const RELAYS: &[(&str, &str, &str)] = &[
+        ("185.220.101.1 2001:db8::1", "Exit Guard", "accept 80,443"),
+        ("192.0.2.10", "Guard", "reject 1-65535"),
+        ("192.0.2.11", "", "accept 6667"),
+        ("192.0.2.12", "Exit", "reject 1-65535"),
+    ];
+
+    #[test]
+    fn exits_are_read_from_a_majority_signed_consensus() {
+        let (authorities, pinned, certificates) = setup();
+        let now = Utc::now();
+        let doc = consensus(now, RELAYS, &[&authorities[0], &authorities[2]]);
+        let exits = verified_exits(&doc, &certificates, &pinned, now).unwrap();
+        let mut exits: Vec<String> = exits.iter().map(IpAddr::to_string).collect();
+        exits.sort();
+        assert_eq!(
+            exits,
+            ["185.220.101.1", "192.0.2.11", "192.0.2.12", "2001:db8::1"]
+        );
+    }
+
+    #[test]
+    fn forged_or_minority_signatures_are_refused() {
+        let (authorities, pinned, certificates) = setup();
+        let now = Utc::now();
+        let minority = consensus(now, RELAYS, &[&authorities[1]]);
+        assert_eq!(
+            verified_exits(&minority, &certificates, &pinned, now),
+            Err(ConsensusError::NotEnoughSignatures {
+                valid: 1,
+                needed: 2,
+                authorities: 3
+            })
+        );
+
+        // Dropping relays after signing breaks every signature.
+        let doc = consensus(now, RELAYS, &[&authorities[0], &authorities[1]]);
+        let tampered = doc.replacen("accept 80,443", "reject 1-65535", 1);
+        assert!(matches!(
+            verified_exits(&tampered, &certificates, &pinned, now),
+            Err(ConsensusError::NotEnoughSignatures { valid: 0, .. })
+        ));
+
+        // An unpinned authority, however well signed, does not count.
+        let outsider = Authority::new();
+        let doc = consensus(now, RELAYS, &[&authorities[0], &outsider]);
+        let certificates_with_outsider = format!(
+            "{certificates}{}",
+            outsider.certificate("2099-01-01 00:00:00")
+        );
+        assert!(verified_exits(&doc, &certificates_with_outsider, &pinned, now).is_err());
+
+        // Nor does a signing key whose certificate has expired.
+        let doc = consensus(now, RELAYS, &[&authorities[0], &authorities[1]]);
+        let stale = format!(
+            "{}{}",
+            authorities[0].certificate("2099-01-01 00:00:00"),
+            authorities[1].certificate("2020-01-01 00:00:00")
+        );
+        assert!(verified_exits(&doc, &stale, &pinned, now).is_err());
+    }
+
+    #[test]
+    fn old_consensuses_are_not_replayed() {
+        let (authorities, pinned, certificates) = setup();
+        let now = Utc::now();
+        let signers = [&authorities[0], &authorities[1]];
+        // valid-until is three hours after valid-after.
+        let old = consensus(now - chrono::Duration::hours(5), RELAYS, &signers);
+        assert!(matches!(
+            verified_exits(&old, &certificates, &pinned, now),
+            Err(ConsensusError::Expired(_))
+        ));
+        let lagging = consensus(now - chrono::Duration::minutes(210), RELAYS, &signers);
+        assert!(verified_exits(&lagging, &certificates, &pinned, now).is_ok());
+        let future = consensus(now + chrono::Duration::hours(5), RELAYS, &signers);
+        assert!(matches!(
+            verified_exits(&future, &certificates, &pinned, now),
+            Err(ConsensusError::NotYetValid(_))
+        ));
+    }
+}
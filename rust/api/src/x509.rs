@@ -0,0 +1,338 @@
+//! The little X.509 that [`acme`](crate::acme) needs: writing a certificate
+//! signing request and reading when a certificate expires.
+//!
+//! Both are a handful of fixed DER structures, so they are written and walked
+//! by hand rather than through a general ASN.1 library.
+
+use base64::{engine::general_purpose::STANDARD, Engine as _};
+use chrono::{DateTime, NaiveDateTime, Utc};
+use ring::rand::SystemRandom;
+use ring::signature::{EcdsaKeyPair, KeyPair};
+
+const SEQUENCE: u8 = 0x30;
+const SET: u8 = 0x31;
+const INTEGER: u8 = 0x02;
+const BIT_STRING: u8 = 0x03;
+const OCTET_STRING: u8 = 0x04;
+const OID: u8 = 0x06;
+const UTF8_STRING: u8 = 0x0c;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+/// `[0]`, constructed.
+const CONTEXT_0: u8 = 0xa0;
+/// `dNSName`, `[2]` implicit, in a GeneralName.
+const DNS_NAME: u8 = 0x82;
+
+const OID_EC_PUBLIC_KEY: &[u64] = &[1, 2, 840, 10045, 2, 1];
+const OID_P256: &[u64] = &[1, 2, 840, 10045, 3, 1, 7];
+const OID_ECDSA_SHA256: &[u64] = &[1, 2, 840, 10045, 4, 3, 2];
+const OID_COMMON_NAME: &[u64] = &[2, 5, 4, 3];
+const OID_EXTENSION_REQUEST: &[u64] = &[1, 2, 840, 113549, 1, 9, 14];
+const OID_SUBJECT_ALT_NAME: &[u64] = &[2, 5, 29, 17];
+
+fn tlv(tag: u8, content: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let len = content.len();
+    if len < 0x80 {
+        out.push(len as u8);
+    } else {
+        let bytes: Vec<u8> = len
+            .to_be_bytes()
+            .into_iter()
+            .skip_while(|&b| b == 0)
+            .collect();
+        out.push(0x80 | bytes.len() as u8);
+        out.extend(bytes);
+    }
+    out.extend_from_slice(content);
+    out
+}
+
+fn constructed(tag: u8, parts: &[Vec<u8>]) -> Vec<u8> {
+    tlv(tag, &parts.concat())
+}
+
+fn oid(arcs: &[u64]) -> Vec<u8> {
+    let mut content = Vec::new();
+    let first = arcs[0] * 40 + arcs[1];
+    for &arc in std::iter::once(&first).chain(&arcs[2..]) {
+        let mut groups = vec![(arc & 0x7f) as u8];
+        let mut rest = arc >> 7;
+        while rest > 0 {
+            groups.push(0x80 | (rest & 0x7f) as u8);
+            rest >>= 7;
+        }
+        content.extend(groups.into_iter().rev());
+    }
+    tlv(OID, &content)
+}
+
+/// A BIT STRING with no unused bits.
+fn bit_string(bytes: &[u8]) -> Vec<u8> {
+    tlv(BIT_STRING, &[&[0][..], bytes].concat())
+}
+
+fn common_name(name: &str) -> Vec<u8> {
+    constructed(
+        SEQUENCE,
+        &[constructed(
+            SET,
+            &[constructed(
+                SEQUENCE,
+                &[oid(OID_COMMON_NAME), tlv(UTF8_STRING, name.as_bytes())],
+            )],
+        )],
+    )
+}
+
+fn p256_public_key_info(public_key: &[u8]) -> Vec<u8> {
+    constructed(
+        SEQUENCE,
+        &[
+            constructed(SEQUENCE, &[oid(OID_EC_PUBLIC_KEY), oid(OID_P256)]),
+            bit_string(public_key),
+        ],
+    )
+}
+
+fn subject_alt_names(domains: &[String]) -> Vec<u8> {
+    let names: Vec<Vec<u8>> = domains
+        .iter()
+        .map(|domain| tlv(DNS_NAME, domain.as_bytes()))
+        .collect();
+    constructed(
+        SEQUENCE,
+        &[
+            oid(OID_SUBJECT_ALT_NAME),
+            tlv(OCTET_STRING, &constructed(SEQUENCE, &names)),
+        ],
+    )
+}
+
+/// A DER PKCS#10 request for `domains`, signed with `key`, which must be a
+/// P-256 key loaded for ASN.1 signatures. The first domain is also the
+/// common name.
+pub fn csr(key: &EcdsaKeyPair, domains: &[String]) -> Result<Vec<u8>, String> {
+    let first = domains.first().ok_or("no domains to request")?;
+    let info = constructed(
+        SEQUENCE,
+        &[
+            tlv(INTEGER, &[0]),
+            common_name(first),
+            p256_public_key_info(key.public_key().as_ref()),
+            constructed(
+                CONTEXT_0,
+                &[constructed(
+                    SEQUENCE,
+                    &[
+                        oid(OID_EXTENSION_REQUEST),
+                        constructed(SET, &[constructed(SEQUENCE, &[subject_alt_names(domains)])]),
+                    ],
+                )],
+            ),
+        ],
+    );
+    let signature = key
+        .sign(&SystemRandom::new(), &info)
+        .map_err(|_| "could not sign the certificate request")?;
+    Ok(constructed(
+        SEQUENCE,
+        &[
+            info,
+            constructed(SEQUENCE, &[oid(OID_ECDSA_SHA256)]),
+            bit_string(signature.as_ref()),
+        ],
+    ))
+}
+
+/// One element: its tag and its content.
+struct Element<'a> {
+    tag: u8,
+    content: &'a [u8],
+}
+
+/// Split the first element off `der`.
+fn next(der: &[u8]) -> Result<(Element<'_>, &[u8]), String> {
+    let truncated = || "truncated DER".to_string();
+    let (&tag, rest) = der.split_first().ok_or_else(truncated)?;
+    let (&first, rest) = rest.split_first().ok_or_else(truncated)?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let count = (first & 0x7f) as usize;
+        if count == 0 || count > std::mem::size_of::<usize>() || rest.len() < count {
+            return Err("bad DER length".to_string());
+        }
+        let len = rest[..count]
+            .iter()
+            .fold(0usize, |len, &b| (len << 8) | b as usize);
+        (len, &rest[count..])
+    };
+    if rest.len() < len {
+        return Err(truncated());
+    }
+    let (content, rest) = rest.split_at(len);
+    Ok((Element { tag, content }, rest))
+}
+
+/// The elements inside a constructed element's content.
+fn children(mut der: &[u8]) -> Result<Vec<Element<'_>>, String> {
+    let mut out = Vec::new();
+    while !der.is_empty() {
+        let (element, rest) = next(der)?;
+        out.push(element);
+        der = rest;
+    }
+    Ok(out)
+}
+
+fn only(der: &[u8], tag: u8) -> Result<&[u8], String> {
+    match next(der)? {
+        (element, []) if element.tag == tag => Ok(element.content),
+        _ => Err(format!("expected a single element with tag {tag:#04x}")),
+    }
+}
+
+fn parse_time(element: &Element) -> Result<DateTime<Utc>, String> {
+    let text = std::str::from_utf8(element.content).map_err(|_| "time is not ASCII")?;
+    let full = match element.tag {
+        // RFC 5280: two-digit years 50 to 99 are 19xx.
+        UTC_TIME if text.as_bytes().first().is_some_and(|&d| d >= b'5') => format!("19{text}"),
+        UTC_TIME => format!("20{text}"),
+        GENERALIZED_TIME => text.to_string(),
+        tag => return Err(format!("unexpected time tag {tag:#04x}")),
+    };
+    NaiveDateTime::parse_from_str(&full, "%Y%m%d%H%M%SZ")
+        .map(|time| time.and_utc())
+        .map_err(|e| format!("bad time {text:?}: {e}"))
+}
+
+/// When the DER certificate `cert` stops being valid.
+pub fn not_after(cert: &[u8]) -> Result<DateTime<Utc>, String> {
+    let certificate = children(only(cert, SEQUENCE)?)?;
+    let tbs = certificate.first().ok_or("empty certificate")?;
+    let mut fields = children(tbs.content)?.into_iter().peekable();
+    // The version is optional; serial number, signature algorithm and issuer
+    // come before the validity.
+    fields.next_if(|field| field.tag == CONTEXT_0);
+    let validity = fields.nth(3).ok_or("certificate has no validity")?;
+    let times = children(validity.content)?;
+    match times.as_slice() {
+        [_, not_after] => parse_time(not_after),
+        _ => Err("malformed validity".to_string()),
+    }
+}
+
+/// PEM armour for DER `der`.
+pub fn pem(label: &str, der: &[u8]) -> String {
+    let encoded = STANDARD.encode(der);
+    let mut out = format!("-----BEGIN {label}-----\n");
+    for line in encoded.as_bytes().chunks(64) {
+        out.push_str(std::str::from_utf8(line).expect("base64 is ASCII"));
+        out.push('\n');
+    }
+    out.push_str(&format!("-----END {label}-----\n"));
+    out
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use ring::signature::{
+        UnparsedPublicKey, ECDSA_P256_SHA256_ASN1, ECDSA_P256_SHA256_ASN1_SIGNING,
+    };
+
+    pub fn p256_key() -> (EcdsaKeyPair, Vec<u8>) {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, &rng).unwrap();
+        let key = EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_ASN1_SIGNING, pkcs8.as_ref(), &rng)
+            .unwrap();
+        (key, pkcs8.as_ref().to_vec())
+    }
+
+    fn utc_time(time: DateTime<Utc>) -> Vec<u8> {
+        tlv(
+            UTC_TIME,
+            time.format("%y%m%d%H%M%SZ").to_string().as_bytes(),
+        )
+    }
+
+    /// A certificate for the key in `csr`, valid until `not_after` and
+    /// signed by `issuer`, as a test CA would issue it.
+    pub fn issue(csr: &[u8], issuer: &EcdsaKeyPair, not_after: DateTime<Utc>) -> Vec<u8> {
+        let request = children(only(csr, SEQUENCE).unwrap()).unwrap();
+        let info = children(request[0].content).unwrap();
+        let subject_key = tlv(info[2].tag, info[2].content);
+        let tbs = constructed(
+            SEQUENCE,
+            &[
+                constructed(CONTEXT_0, &[tlv(INTEGER, &[2])]),
+                tlv(INTEGER, &[1]),
+                constructed(SEQUENCE, &[oid(OID_ECDSA_SHA256)]),
+                common_name("test CA"),
+                constructed(
+                    SEQUENCE,
+                    &[
+                        utc_time(Utc::now() - chrono::Duration::hours(1)),
+                        utc_time(not_after),
+                    ],
+                ),
+                tlv(info[1].tag, info[1].content),
+                subject_key,
+            ],
+        );
+        let signature = issuer.sign(&SystemRandom::new(), &tbs).unwrap();
+        constructed(
+            SEQUENCE,
+            &[
+                tbs,
+                constructed(SEQUENCE, &[oid(OID_ECDSA_SHA256)]),
+                bit_string(signature.as_ref()),
+            ],
+        )
+    }
+
+    #[test]
+    fn the_request_is_signed_by_its_key_and_names_every_domain() {
+        let (key, _) = p256_key();
+        let domains = vec![
+            "gkapi.example.org".to_string(),
+            "api.example.org".to_string(),
+        ];
+        let request = csr(&key, &domains).unwrap();
+        let parts = children(only(&request, SEQUENCE).unwrap()).unwrap();
+        let info = tlv(SEQUENCE, parts[0].content);
+        assert_eq!(parts[2].tag, BIT_STRING);
+        let signature = parts[2].content;
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, key.public_key().as_ref())
+            .verify(&info, &signature[1..])
+            .unwrap();
+        for domain in &domains {
+            assert!(request
+                .windows(domain.len())
+                .any(|window| window == domain.as_bytes()));
+        }
+        assert!(csr(&key, &[]).is_err());
+    }
+
+    #[test]
+    fn expiry_is_read_from_a_certificate() {
+        let (key, _) = p256_key();
+        let request = csr(&key, &["gkapi.example.org".to_string()]).unwrap();
+        let expires = DateTime::parse_from_rfc3339("2031-02-03T04:05:06Z")
+            .unwrap()
+            .to_utc();
+        assert_eq!(not_after(&issue(&request, &key, expires)).unwrap(), expires);
+        assert!(not_after(&request[..10]).is_err());
+    }
+
+    #[test]
+    fn object_identifiers_and_long_lengths_encode() {
+        assert_eq!(oid(OID_EC_PUBLIC_KEY), [6, 7, 42, 134, 72, 206, 61, 2, 1]);
+        let long = tlv(OCTET_STRING, &[0; 300]);
+        assert_eq!(&long[..4], &[OCTET_STRING, 0x82, 0x01, 0x2c]);
+        let (element, rest) = next(&long).unwrap();
+        assert_eq!(element.content.len(), 300);
+        assert!(rest.is_empty());
+    }
+}
@@ -87,6 +87,8 @@ fn https_mode_starts_without_crypto_provider_panic() {
             notary.to_str().unwrap(),
             "--port",
             &port.to_string(),
+            "--issuance-log",
+            dir.path().join("issuance.jsonl").to_str().unwrap(),
         ])
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
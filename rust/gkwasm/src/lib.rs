@@ -193,6 +193,15 @@ pub fn wasm_generate_ghost_key_certificate(
 mod tests {
     use super::*;
 
+    #[test]
+    fn argon2id_attempts_match_the_invite_api() {
+        // The same vector is checked against the verifier in gkapi.
+        let solved = (0..64).find(|nonce| {
+            invite_pow_argon2id_attempt_core(&"11".repeat(16), *nonce, 4, 64, 1, 1).unwrap()
+        });
+        assert_eq!(solved, Some(41));
+    }
+
     #[test]
     fn test_round_trip() {
         let mut rng = ChaCha20Rng::from_seed([0u8; 32]);
@@ -472,3 +481,69 @@ pub fn wasm_verify_signed_message(
         Err(err) => Err(JsValue::from_str(&err)),
     }
 }
+
+/// Must match `ARGON2_DOMAIN` in the invite API's `invite_pow`.
+const INVITE_POW_ARGON2_DOMAIN: &[u8] = b"freenet-river-invite-pow-argon2id-v1";
+
+/// One attempt at an `argon2id-leading-zero-bits-v1` invite challenge:
+/// Argon2id over the domain, challenge id and big-endian nonce, salted with
+/// the challenge id, with a 32-byte output that must start with `difficulty`
+/// zero bits.
+#[allow(dead_code)]
+fn invite_pow_argon2id_attempt_core(
+    challenge_hex: &str,
+    nonce: u64,
+    difficulty: u8,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+) -> Result<bool, String> {
+    let id: [u8; 16] = hex::decode(challenge_hex)
+        .ok()
+        .and_then(|v| v.try_into().ok())
+        .ok_or_else(|| "Invalid challenge".to_string())?;
+    let params = argon2::Params::new(memory_kib, iterations, parallelism, Some(32))
+        .map_err(|e| format!("Invalid Argon2id parameters: {}", e))?;
+    let hasher = argon2::Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+
+    let mut input = INVITE_POW_ARGON2_DOMAIN.to_vec();
+    input.extend_from_slice(&id);
+    input.extend_from_slice(&nonce.to_be_bytes());
+    let mut hash = [0u8; 32];
+    hasher
+        .hash_password_into(&input, &id, &mut hash)
+        .map_err(|e| format!("Argon2id failed: {}", e))?;
+
+    let full_bytes = usize::from(difficulty / 8);
+    let remaining_bits = difficulty % 8;
+    if full_bytes > hash.len() || (full_bytes == hash.len() && remaining_bits > 0) {
+        return Ok(false);
+    }
+    if hash[..full_bytes].iter().any(|byte| *byte != 0) {
+        return Ok(false);
+    }
+    Ok(remaining_bits == 0 || hash[full_bytes] >> (8 - remaining_bits) == 0)
+}
+
+/// Try one nonce for an Argon2id invite challenge. The page calls this in a
+/// loop, yielding between attempts, as no single attempt is cheap.
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+pub fn wasm_invite_pow_argon2id_attempt(
+    challenge_hex: String,
+    nonce: u32,
+    difficulty: u8,
+    memory_kib: u32,
+    iterations: u32,
+    parallelism: u32,
+) -> Result<bool, JsValue> {
+    invite_pow_argon2id_attempt_core(
+        &challenge_hex,
+        u64::from(nonce),
+        difficulty,
+        memory_kib,
+        iterations,
+        parallelism,
+    )
+    .map_err(|e| JsValue::from_str(&e))
+}
@@ -166,7 +166,7 @@ async fn run_test(c: &Client, temp_dir: &Path) -> Result<()> {
     crate::environment::print_task("Verifying ghostkey certificate");
     let master_verifying_key_file = temp_dir.join("master_verifying_key.pem");
     let output = Command::new("cargo")
-        .args(&[
+        .args([
             "run",
             "--manifest-path",
             "../cli/Cargo.toml",
@@ -199,13 +199,10 @@ async fn run_test(c: &Client, temp_dir: &Path) -> Result<()> {
 async fn wait_for_element(client: &Client, locator: Locator<'_>, timeout: Duration) -> Result<fantoccini::elements::Element> {
     let start = Instant::now();
     while start.elapsed() < timeout {
-        match client.find(locator.clone()).await {
-            Ok(element) => {
-                if element.is_displayed().await.unwrap_or(false) {
-                    return Ok(element);
-                }
+        if let Ok(element) = client.find(locator).await {
+            if element.is_displayed().await.unwrap_or(false) {
+                return Ok(element);
             }
-            Err(_) => {}
         }
         tokio::time::sleep(Duration::from_millis(500)).await;
     }
@@ -34,7 +34,7 @@ fn generate_master_key(temp_dir: &Path) -> Result<PathBuf> {
     let master_key_file = temp_dir.join("master_signing_key.pem");
     let cli_dir = std::env::current_dir()?.join("../cli");
     let output = Command::new("cargo")
-        .args(&[
+        .args([
             "run",
             "--quiet",
             "--manifest-path",
@@ -62,7 +62,7 @@ fn generate_master_key(temp_dir: &Path) -> Result<PathBuf> {
 fn generate_notary_keys(master_key_file: &Path, notary_dir: &Path) -> Result<()> {
     let output = Command::new("bash")
         .arg("../cli/generate_notary_keys.sh")
-        .args(&["--master-key", master_key_file.to_str().unwrap()])
+        .args(["--master-key", master_key_file.to_str().unwrap()])
         .arg("--notary-dir")
         .arg(notary_dir)
         .arg("--overwrite")
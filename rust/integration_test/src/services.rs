@@ -1,5 +1,7 @@
 use anyhow::{Context, Result};
 use colored::*;
+use ed25519_dalek::VerifyingKey;
+use ghostkey_lib::armorable::Armorable;
 use std::io::{BufRead, BufReader};
 use std::path::Path;
 use std::process::{Child, Command, Stdio};
@@ -61,7 +63,7 @@ fn is_port_in_use(port: u16) -> bool {
 
 fn kill_process_on_port(port: u16) -> Result<()> {
     let output = Command::new("lsof")
-        .args(&["-t", "-i", &format!(":{}", port)])
+        .args(["-t", "-i", &format!(":{}", port)])
         .output()?;
     let pid = String::from_utf8(output.stdout)?.trim().to_string();
     if !pid.is_empty() {
@@ -72,7 +74,7 @@ fn kill_process_on_port(port: u16) -> Result<()> {
 
 fn start_hugo() -> Result<Child> {
     Command::new("hugo")
-        .args(&["server", "--disableFastRender"])
+        .args(["server", "--disableFastRender"])
         .current_dir("../../hugo-site")
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -82,14 +84,22 @@ fn start_hugo() -> Result<Child> {
 
 async fn start_api(temp_dir: &Path) -> Result<Child> {
     let notary_dir = temp_dir.join("notaries");
+    // The notaries chain to the throwaway master key generated for this run,
+    // not the Freenet one gkapi checks against by default.
+    let master_vk = VerifyingKey::from_file(&temp_dir.join("master_verifying_key.pem"))
+        .context("Failed to read the generated master verifying key")?
+        .to_base64()
+        .map_err(|e| anyhow::anyhow!("Failed to encode the master verifying key: {e}"))?;
     let mut child = Command::new("cargo")
-        .args(&[
+        .args([
             "run",
             "--manifest-path",
             "../api/Cargo.toml",
             "--",
             "--notary-dir",
             notary_dir.to_str().unwrap(),
+            "--master-verifying-key",
+            &master_vk,
         ])
         .stdout(Stdio::piped())
         .stderr(Stdio::piped())
@@ -166,7 +176,7 @@ async fn start_api(temp_dir: &Path) -> Result<Child> {
 async fn is_api_ready() -> Result<()> {
     let client = reqwest::Client::new();
     client
-        .get(&format!("http://localhost:{}/health", API_PORT))
+        .get(format!("http://localhost:{}/health", API_PORT))
         .send()
         .await
         .context("Failed to connect to API health endpoint")?